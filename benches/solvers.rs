@@ -0,0 +1,32 @@
+//! Compares ConvexOrderbookSolver against DefaultOrderbookSolver on 3 Uniswap v2 WETH/USDC pools with different prices and depths
+//! Prints the total output over the steps and the mean time of an optimization of each solver, without any network access
+//! cargo bench --bench solvers
+
+use std::hint::black_box;
+use std::time::Instant;
+
+use tycho_orderbook::core::solver::{ConvexOrderbookSolver, DefaultOrderbookSolver, OrderbookSolver};
+use tycho_orderbook::types::ProtoSimComp;
+use tycho_orderbook::utils::fixtures::{pool, usdc, weth};
+
+const ITERATIONS: u32 = 50;
+
+/// Total output over the steps and mean duration of an optimization
+fn run(name: &str, solver: &dyn OrderbookSolver, pools: &[ProtoSimComp], steps: &[f64]) {
+    let (weth, usdc) = (weth(), usdc());
+    let start = Instant::now();
+    let mut total = 0.;
+    for _ in 0..ITERATIONS {
+        let trades = solver.optimize(black_box(pools), steps.to_vec(), 2000., 0, &weth, &usdc, 2000., 0.0005);
+        total = trades.iter().map(|trade| trade.output).sum::<f64>();
+    }
+    let mean = start.elapsed() / ITERATIONS;
+    println!("{:<8} | total output {:>16.4} USDC | {:>10.3?} per optimization ({} steps)", name, total, mean, steps.len());
+}
+
+fn main() {
+    let pools = vec![pool("0x01", 1_000., 2_000_000.), pool("0x02", 500., 1_010_000.), pool("0x03", 200., 395_000.)];
+    let steps = vec![1., 10., 50., 100., 200., 400.];
+    run("default", &DefaultOrderbookSolver::default(), &pools, &steps);
+    run("convex", &ConvexOrderbookSolver::default(), &pools, &steps);
+}
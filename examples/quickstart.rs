@@ -2,8 +2,15 @@ use std::{collections::HashMap, sync::Arc};
 use tycho_orderbook::{
     adapters::default::DefaultOrderBookAdapter,
     builder::OrderbookBuilder,
-    core::{client, helper::get_original_components, solver::DefaultOrderbookSolver},
-    types::{ExecutionRequest, Orderbook, OrderbookEvent, OrderbookRequestParams},
+    core::{
+        client, exec,
+        helper::{get_original_components, reference_tokens},
+        oracle::CoinGeckoOracle,
+        solver::DefaultOrderbookSolver,
+    },
+    data::fmt::SrzToken,
+    types::{ExecutionRequest, Orderbook, OrderbookEvent, OrderbookRequestParams, Side},
+    utils::r#static::execution::EXEC_DEFAULT_SLIPPAGE,
 };
 
 /// Quickstart example for Tycho Orderbook
@@ -14,7 +21,7 @@ use tycho_orderbook::{
 
 #[tokio::main]
 async fn main() {
-    let filter = tracing_subscriber::EnvFilter::from_default_env(); // Read RUST_tracing env variable
+    let filter = tracing_subscriber::EnvFilter::from_default_env(); // Read RUST_LOG env variable
     tracing_subscriber::fmt().with_env_filter(filter).init(); // <--- Set the tracing level here
     tracing::info!("--- --- --- Launching Quickstart Tycho Orderbook --- --- ---");
 
@@ -45,9 +52,9 @@ async fn main() {
 
     // --- Token list ---
     let tokens = match client::tokens(&network, tychokey.clone()).await {
-        Some(t) => t,
-        None => {
-            tracing::error!("Failed to get tokens. Something anormal, make sure Tycho endpoint is operational. Exiting.");
+        Ok(t) => t,
+        Err(e) => {
+            tracing::error!("{}. Something anormal, make sure Tycho endpoint is operational. Exiting.", e);
             return;
         }
     };
@@ -63,7 +70,16 @@ async fn main() {
             "0x833589fcd6edb6e08f4c7c32d4f71b54bda02913".to_string().to_lowercase(),
             "0xcbB7C0000aB88B473b1f5aFd9ef808440eed33Bf".to_string().to_lowercase(),
         ),
-        _ => panic!("Network not supported"),
+        _ => {
+            // Other networks: looked up by symbol, without crashing if one is missing
+            let srztokens = tokens.iter().cloned().map(SrzToken::from).collect::<Vec<SrzToken>>();
+            let references = reference_tokens(&srztokens, &["USDC", "WBTC"]);
+            let Some(usdc) = references.get("USDC") else {
+                tracing::error!("No USDC on network {}, nothing to quote. Exiting.", network.name);
+                return;
+            };
+            (usdc.address.to_lowercase(), references.get("WBTC").map(|t| t.address.to_lowercase()).unwrap_or_default())
+        }
     };
     let mut tracked: HashMap<String, Option<Orderbook>> = HashMap::new();
     let _btcusdc = format!("{}-{}", btc, usdc); // "0xBTC" "0xUSDC"
@@ -104,7 +120,7 @@ async fn main() {
                                         // If one of the components/pools is updated, we need to update the orderbook too.
                                         let mut refresh = false;
                                         for (x, cp) in cps.iter().enumerate() {
-                                            if updated.contains(&cp.id.to_lowercase()) {
+                                            if updated.contains(&cp.key()) {
                                                 tracing::info!(
                                                     " - Component #{x} {} {} for {}-{} orderbook has changed, need to update it",
                                                     cp.id,
@@ -118,12 +134,16 @@ async fn main() {
                                         if refresh {
                                             tracing::info!(" ⚖️  Orderbook {}-{} has changed, need to update it", current.base.symbol, current.quote.symbol);
 
-                                            if let Ok(book) = obp.get_orderbook(DefaultOrderbookSolver, OrderbookRequestParams { tag: key.clone(), point: None }).await {
+                                            let params = OrderbookRequestParams {
+                                                tag: key.clone(),
+                                                ..Default::default()
+                                            };
+                                            if let Ok(book) = obp.get_orderbook(DefaultOrderbookSolver::default(), &CoinGeckoOracle, params).await {
                                                 let symtag = format!("{}-{}", book.base.symbol, book.quote.symbol);
                                                 tracing::info!("OBP Event: Orderbook {} has been updated", symtag);
                                                 tracked.insert(key.clone(), Some(book.clone()));
 
-                                                let depth = book.depth(None);
+                                                let depth = book.depth(None, None);
                                                 tracing::debug!("Bids ({})", depth.bids.len());
                                                 for d in depth.bids {
                                                     tracing::trace!(" - {:.5} {} at a price of {:.5} {} per {}", d.1, current.base.symbol, d.0, current.quote.symbol, current.base.symbol);
@@ -138,7 +158,8 @@ async fn main() {
                                                     // Execution
                                                     let way = book.mpd_base_to_quote.clone();
                                                     let amount = way.amount / 10.; // By default, the simulation algo provide equivalent amount of 0.01 ETH in base token. So /10 = 0.001 ETH
-                                                    let expected = way.received / 10.; // Same here
+                                                    let expected = book.min_received(Side::Bid, amount, 0.); // Expected output, derived from the book
+                                                    tracing::debug!("Minimum received: {} {}", book.min_received(Side::Bid, amount, EXEC_DEFAULT_SLIPPAGE), book.quote.symbol);
                                                     let request = ExecutionRequest {
                                                         sender: sender.to_string().clone(),
                                                         tag: book.tag.clone(),
@@ -148,17 +169,33 @@ async fn main() {
                                                         expected,
                                                         distribution: way.distribution.clone(),
                                                         components: book.pools.clone(),
+                                                        slippage_bps: None,
+                                                        enforce_min_out: true,
+                                                        simulate_only: false,
+                                                        fork: false,
                                                     };
 
                                                     let mtx = state.read().await;
                                                     let originals = mtx.components.clone();
                                                     drop(mtx);
-                                                    let originals = get_original_components(originals, book.pools.clone());
+                                                    let originals = match get_original_components(originals, book.pools.clone()) {
+                                                        Ok(originals) => originals,
+                                                        Err(err) => {
+                                                            tracing::error!("OBP Event: Can't execute on orderbook {}: {}", symtag, err);
+                                                            continue;
+                                                        }
+                                                    };
 
                                                     tracing::info!("Creating the transactions to execute ...");
                                                     // match book.create(network.clone(), request, originals.clone(), Some(env.pvkey.clone())).await {
                                                     match book.create(network.clone(), request, originals.clone(), pk.clone()).await {
                                                         Ok(payload) => {
+                                                            if let Some(expected) = book.expected_output(Side::Bid, amount) {
+                                                                match exec::simulate_slippage(network.clone(), payload.clone(), book.quote.clone(), expected).await {
+                                                                    Ok(report) => tracing::info!("Slippage vs book: {:.2} bps (expected {} | simulated {})", report.slippage_bps, report.expected, report.realized),
+                                                                    Err(err) => tracing::warn!("Could not measure the slippage: {}", err),
+                                                                }
+                                                            }
                                                             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await; // Wait a bit before executing the transaction, to check the logs.
                                                             if real_exec {
                                                                 if !executed {
@@ -194,10 +231,13 @@ async fn main() {
                                         tracing::info!("🧱 OBP Event: Orderbook {} isn't build yet, building it ...", key.clone());
                                         match obp
                                             .get_orderbook(
-                                                DefaultOrderbookSolver,
+                                                DefaultOrderbookSolver::default(),
+                                                &CoinGeckoOracle,
                                                 OrderbookRequestParams {
                                                     tag: key.clone().to_lowercase(),
                                                     point: None, // If you just need 1 point on the orderbook
+                                                    both_directions: false, // If you also need the exact-out curves
+                                                    ..Default::default()
                                                 },
                                             )
                                             .await
@@ -220,9 +260,24 @@ async fn main() {
                             drop(mtx);
                             tracing::info!("OBP Event: Shared state initialised status: {} | Comp size: {} | Pts size: {}", initialised, cps, pts);
                         }
+                        OrderbookEvent::Reorg(block) => {
+                            tracing::info!("OBP Event: Components removed at block {}, cached orderbooks should be invalidated", block);
+                        }
+                        OrderbookEvent::TokensUpdated(count) => {
+                            tracing::info!("OBP Event: {} new tokens available", count);
+                        }
                         OrderbookEvent::Error(err) => {
                             tracing::error!("OBP Event: Error: {:?}", err);
                         }
+                        OrderbookEvent::QualityAlert { tag, quality } => {
+                            tracing::warn!("OBP Event: Orderbook {} quality degraded to {:?}", tag, quality);
+                        }
+                        OrderbookEvent::BookUpdate { tag, book } => {
+                            tracing::info!("OBP Event: Orderbook {} recomputed at block {}", tag, book.block);
+                        }
+                        OrderbookEvent::DepthUpdate { tag, block, bid_changes, ask_changes } => {
+                            tracing::info!("OBP Event: Orderbook {} depth changed at block {}: {} bids and {} asks levels", tag, block, bid_changes.len(), ask_changes.len());
+                        }
                     }
                 }
             }
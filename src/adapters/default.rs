@@ -1,6 +1,10 @@
 use crate::{
     core::exec,
-    types::{ExchangeInfo, ExecutedPayload, ExecutionRequest, Network, Orderbook, OrderbookDepth, PayloadToExecute},
+    types::{DepthConfig, ExchangeInfo, ExecutedPayload, ExecutionRequest, Network, Orderbook, OrderbookDepth, PayloadToExecute},
+    utils::r#static::{
+        adapters::{DEPTH_DEFAULT_LIMIT, DEPTH_MAX_LIMIT},
+        maths::BPD,
+    },
 };
 use async_trait::async_trait;
 use std::cmp::min;
@@ -17,8 +21,8 @@ use tycho_simulation::protocol::models::ProtocolComponent;
 
 #[async_trait]
 pub trait DefaultOrderBookAdapter: Send + Sync {
-    /// Returns orderbook depth snapshot (limited if specified).
-    fn depth(&self, limit: Option<u64>) -> OrderbookDepth;
+    /// Returns orderbook depth snapshot (limited if specified), optionally bucketed into price bands.
+    fn depth(&self, limit: Option<u64>, bucketing: Option<DepthConfig>) -> OrderbookDepth;
 
     /// Returns static metadata (e.g., name, symbols, fees).
     fn info(&self) -> ExchangeInfo;
@@ -37,21 +41,25 @@ impl DefaultOrderBookAdapter for Orderbook {
     /// See https://developers.binance.com/docs/binance-spot-api-docs/rest-api/general-endpoints#terminology
     /// curl -X GET "https://api.binance.com/api/v3/depth?symbol=ETHUSDC&limit=10"
     /// curl -X GET "https://api.binance.com/api/v3/exchangeInfo?symbol=ETHUSDC" (base = ETH, quote = USDC)
-    fn depth(&self, limit: Option<u64>) -> OrderbookDepth {
+    /// With a DepthConfig, the size added by each level (vs the previous, smaller one) is accumulated into price bands relative to the mid price, up to 'max_levels' bands per side.
+    /// In that case, each band price is the band edge the furthest from the mid price, all levels are bucketed and the limit (if specified) applies to the bands.
+    fn depth(&self, limit: Option<u64>, bucketing: Option<DepthConfig>) -> OrderbookDepth {
         let limit = match limit {
             Some(limit) => limit,
+            None if bucketing.is_some() => u64::MAX,
             None => min(self.bids.len() as u64, self.asks.len() as u64),
         };
+        let levels = if bucketing.is_some() { u64::MAX } else { limit };
         let mut bids_depth = vec![];
         for (x, bid) in self.bids.clone().iter().enumerate() {
-            if x == limit as usize {
+            if x == levels as usize {
                 break;
             }
             bids_depth.push((bid.average_sell_price, bid.amount));
         }
         let mut asks_depth = vec![];
         for (x, ask) in self.asks.clone().iter().enumerate() {
-            if x == limit as usize {
+            if x == levels as usize {
                 break;
             }
             let price_in_quote = 1.0 / ask.average_sell_price;
@@ -61,6 +69,15 @@ impl DefaultOrderBookAdapter for Orderbook {
         // Sort quantities in ascending order. Unwrap is safe here.
         bids_depth.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
         asks_depth.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
+        if let Some(config) = bucketing {
+            if let (Some(best_bid), Some(best_ask)) = (bids_depth.first(), asks_depth.first()) {
+                let mid = (best_bid.0 + best_ask.0) / 2.;
+                bids_depth = bucketize(&bids_depth, mid, &config, -1.);
+                asks_depth = bucketize(&asks_depth, mid, &config, 1.);
+            }
+            bids_depth.truncate(limit as usize);
+            asks_depth.truncate(limit as usize);
+        }
         // let bids_depth_str: Vec<(String, String)> = bids_depth.iter().map(|(price, amount)| (price.to_string(), amount.to_string())).collect();
         // let asks_depth_str: Vec<(String, String)> = asks_depth.iter().map(|(price, amount)| (price.to_string(), amount.to_string())).collect();
         OrderbookDepth {
@@ -73,6 +90,7 @@ impl DefaultOrderBookAdapter for Orderbook {
     /// Get the exchange info
     fn info(&self) -> ExchangeInfo {
         ExchangeInfo {
+            symbol: self.tag.clone(),
             timezone: "UTC".to_string(),
             base: self.base.clone(),
             quote: self.quote.clone(),
@@ -97,3 +115,128 @@ impl DefaultOrderBookAdapter for Orderbook {
         exec::broadcast(network.clone(), payload.clone(), pk).await
     }
 }
+
+/// Depth limit of a GET /depth request: DEPTH_DEFAULT_LIMIT if not given, clamped to [1, DEPTH_MAX_LIMIT]
+pub fn depth_limit(limit: Option<u64>) -> u64 {
+    limit.unwrap_or(DEPTH_DEFAULT_LIMIT).clamp(1, DEPTH_MAX_LIMIT)
+}
+
+/// Levels that changed between two depths, as (price, size) for bids and asks. Levels that disappeared are returned with a size of 0, as exchanges diff streams do.
+pub fn depth_diff(previous: &OrderbookDepth, current: &OrderbookDepth) -> (Vec<(f64, f64)>, Vec<(f64, f64)>) {
+    let diff = |previous: &[(f64, f64)], current: &[(f64, f64)]| {
+        let mut changes: Vec<(f64, f64)> = current.iter().filter(|level| !previous.contains(level)).copied().collect();
+        for (price, _) in previous.iter() {
+            if !current.iter().any(|(p, _)| p == price) {
+                changes.push((*price, 0.));
+            }
+        }
+        changes
+    };
+    (diff(&previous.bids, &current.bids), diff(&previous.asks, &current.asks))
+}
+
+/// Accumulates depth levels (price, size), sorted by increasing size, into price bands of 'bucket_bps' relative to the mid price
+/// Levels are independent trades, so only the size added by each level is accumulated. Direction is -1 for bids (below mid) and 1 for asks.
+fn bucketize(levels: &[(f64, f64)], mid: f64, config: &DepthConfig, direction: f64) -> Vec<(f64, f64)> {
+    let width = config.bucket_bps.max(1) as f64 / BPD;
+    let mut buckets: Vec<(usize, f64)> = vec![];
+    let mut previous = 0.;
+    for (price, size) in levels.iter() {
+        let added = size - previous;
+        previous = *size;
+        if added <= 0. {
+            continue;
+        }
+        let distance = (direction * (price - mid) / mid).max(0.);
+        let index = (distance / width).floor() as usize;
+        match buckets.iter_mut().find(|b| b.0 == index) {
+            Some(bucket) => bucket.1 += added,
+            None => buckets.push((index, added)),
+        }
+    }
+    buckets.sort_by_key(|b| b.0);
+    buckets.truncate(config.max_levels);
+    buckets.iter().map(|(index, size)| (mid * (1. + direction * (*index as f64 + 1.) * width), *size)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fixtures;
+
+    #[test]
+    fn test_depth_json_matches_binance() {
+        let depth = OrderbookDepth {
+            last_update_id: 42,
+            bids: vec![(1_999.5, 1.)],
+            asks: vec![(2_000.5, 2.)],
+        };
+        let json = serde_json::to_value(&depth).unwrap();
+        assert_eq!(json, serde_json::json!({ "lastUpdateId": 42, "bids": [["1999.5", "1"]], "asks": [["2000.5", "2"]] }));
+        let parsed: OrderbookDepth = serde_json::from_value(json).unwrap();
+        assert_eq!((parsed.bids, parsed.asks), (depth.bids.clone(), depth.asks.clone()));
+        assert_eq!(depth_limit(None), DEPTH_DEFAULT_LIMIT);
+        assert_eq!(depth_limit(Some(0)), 1);
+        assert_eq!(depth_limit(Some(1_000_000)), DEPTH_MAX_LIMIT);
+    }
+
+    #[test]
+    fn test_depth_diff() {
+        let previous = OrderbookDepth {
+            last_update_id: 1,
+            bids: vec![(1_999., 1.), (1_998., 2.)],
+            asks: vec![(2_001., 1.)],
+        };
+        let current = OrderbookDepth {
+            last_update_id: 2,
+            bids: vec![(1_999., 1.), (1_998., 3.)],
+            asks: vec![(2_002., 1.)],
+        };
+        let (bids, asks) = depth_diff(&previous, &current);
+        assert_eq!(bids, vec![(1_998., 3.)]);
+        assert_eq!(asks, vec![(2_002., 1.), (2_001., 0.)]);
+        let (bids, asks) = depth_diff(&current, &current);
+        assert!(bids.is_empty() && asks.is_empty());
+    }
+
+    #[test]
+    fn test_depth_bucketing_merges_adjacent_trades() {
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            // Prices 1999, 1998.5 and 1995 => 5, 7.5 and 25 bps below the 2000 mid
+            bids: vec![fixtures::trade(1., 1_999.), fixtures::trade(2., 3_997.), fixtures::trade(3., 5_985.)],
+            asks: vec![fixtures::trade(2_001., 1.)],
+            ..Default::default()
+        };
+        assert_eq!(book.depth(None, None).bids.len(), 1); // Limited by the asks count
+        let depth = book.depth(None, Some(DepthConfig { bucket_bps: 10, max_levels: 50 }));
+        assert_eq!(depth.bids.len(), 2);
+        assert_eq!(depth.bids[0].1, 2.);
+        assert_eq!(depth.bids[1].1, 1.);
+        assert!((depth.bids[0].0 - 1_998.).abs() < 1e-9);
+        assert!((depth.bids[1].0 - 1_994.).abs() < 1e-9);
+        assert_eq!(depth.asks.len(), 1);
+        let depth = book.depth(None, Some(DepthConfig { bucket_bps: 10, max_levels: 1 }));
+        assert_eq!(depth.bids.len(), 1);
+    }
+
+    #[test]
+    fn test_depth_bucketing_limits_bands() {
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            // Prices 5, 15 and 25 bps away from the 2000 mid on each side => 3 bands of 10 bps per side
+            bids: vec![fixtures::trade(1., 1_999.), fixtures::trade(2., 3_994.), fixtures::trade(3., 5_985.)],
+            asks: vec![fixtures::trade(2_001., 1.), fixtures::trade(4_006., 2.), fixtures::trade(6_015., 3.)],
+            ..Default::default()
+        };
+        let config = DepthConfig { bucket_bps: 10, max_levels: 50 };
+        let depth = book.depth(None, Some(config.clone()));
+        assert_eq!((depth.bids.len(), depth.asks.len()), (3, 3));
+        let depth = book.depth(Some(2), Some(config));
+        assert_eq!((depth.bids.len(), depth.asks.len()), (2, 2));
+        assert!((depth.bids[1].0 - 1_996.).abs() < 1e-9);
+        assert!((depth.asks[1].0 - 2_004.).abs() < 1e-9);
+    }
+}
@@ -1,13 +1,15 @@
+use std::collections::HashMap;
+
 use tycho_simulation::evm::stream::ProtocolStreamBuilder;
 use tycho_simulation::models::Token;
 use tycho_simulation::tycho_client::stream::StreamError;
 
-use crate::core::helper::default_protocol_stream_builder;
+use crate::core::helper::registered_protocol_stream_builder;
 use crate::core::solver::DefaultOrderbookSolver;
 use crate::data::fmt::SrzToken;
 use crate::provider::OrderbookProvider;
-use crate::types::Network;
-use crate::utils::r#static::filter::ADD_TVL_THRESHOLD;
+use crate::types::{Network, StateSnapshot};
+use crate::utils::r#static::filter::DEFAULT_TVL_RANGE;
 use tycho_simulation::tycho_client::feed::component_tracker::ComponentFilter;
 
 #[derive(Clone)]
@@ -15,24 +17,86 @@ pub struct OrderbookBuilderConfig {
     pub filter: ComponentFilter,
 }
 
+impl Default for OrderbookBuilderConfig {
+    fn default() -> Self {
+        OrderbookBuilderConfig::tvl_range(DEFAULT_TVL_RANGE)
+    }
+}
+
+impl OrderbookBuilderConfig {
+    /// Components are added to the stream above `max` TVL (in ETH), and removed below `min`
+    pub fn tvl_range((min, max): (f64, f64)) -> Self {
+        OrderbookBuilderConfig {
+            filter: ComponentFilter::with_tvl_range(min, max),
+        }
+    }
+}
+
 pub struct OrderbookBuilder {
     pub network: Network,
     pub psb: ProtocolStreamBuilder,
     pub tokens: Vec<SrzToken>,
     pub key: Option<String>,
+    /// TVL range (min, max) in ETH of the components tracked by the default ProtocolStreamBuilder (see with_tvl_range)
+    pub tvl_range: (f64, f64),
+    /// Protocol systems registered on the default ProtocolStreamBuilder (see Network::protocols), empty with a custom one
+    pub registered: Vec<String>,
+    /// Whether the ProtocolStreamBuilder was given by the caller (see new and psb), in which case it's never replaced by the default one
+    pub custom: bool,
+}
+
+/// Overrides the Tycho endpoint of the network with the 'TYCHO_ENDPOINT_{NETWORK}' env variable if set (e.g. TYCHO_ENDPOINT_ETHEREUM=localhost:4242)
+/// Useful to target a local or staging Tycho instance without editing the networks config
+pub fn tycho_endpoint_override(network: Network) -> Network {
+    tycho_endpoint_override_from(network, |var| std::env::var(var).ok())
+}
+
+/// Same as tycho_endpoint_override, reading the variable through `lookup` instead of the process environment
+pub fn tycho_endpoint_override_from(mut network: Network, lookup: impl Fn(&str) -> Option<String>) -> Network {
+    let var = format!("TYCHO_ENDPOINT_{}", network.name.to_uppercase());
+    if let Some(endpoint) = lookup(&var) {
+        if !endpoint.is_empty() {
+            tracing::info!("Using Tycho endpoint '{}' for network {} (from {})", endpoint, network.name, var);
+            network.tycho = endpoint;
+        }
+    }
+    network
+}
+
+/// Keeps at most `max` tokens: the network wrapped native token and valuation references first (needed to value the pairs), then the others by descending `rank`
+/// `rank` gives a score per lowercased address (e.g. the Tycho quality, or the TVL of the token pools), 0 if missing. Ties are broken by address, so the result doesn't depend on the order of `tokens`
+pub fn cap_tokens(network: &Network, tokens: Vec<SrzToken>, max: Option<usize>, rank: &HashMap<String, f64>) -> Vec<SrzToken> {
+    let Some(max) = max.filter(|max| tokens.len() > *max) else {
+        return tokens;
+    };
+    let refs = network.valuation_refs();
+    let priority = |t: &SrzToken| refs.iter().position(|r| r.eq_ignore_ascii_case(&t.address)).unwrap_or(refs.len());
+    let score = |t: &SrzToken| rank.get(&t.address.to_lowercase()).copied().unwrap_or_default();
+    let mut kept = tokens;
+    kept.sort_by(|a, b| {
+        priority(a)
+            .cmp(&priority(b))
+            .then_with(|| score(b).partial_cmp(&score(a)).unwrap_or(std::cmp::Ordering::Equal))
+            .then_with(|| a.address.to_lowercase().cmp(&b.address.to_lowercase()))
+    });
+    kept.truncate(max);
+    tracing::info!("Keeping {} tokens on {}, pairs involving the others won't be quotable", kept.len(), network.name);
+    kept
 }
 
 /// OrderbookBuilder is a struct that allows the creation of an OrderbookProvider instance, using a default or custom ProtocolStreamBuilder from Tycho.
 impl OrderbookBuilder {
     /// Default logic to create a ProtocolStreamBuilder, used to build a OrderbookProvider
     /// For more advanced use-cases, you can create your own ProtocolStreamBuilder and pass it to custom() fn
+    /// The Tycho endpoint of the network can be overridden with the 'TYCHO_ENDPOINT_{NETWORK}' env variable (see tycho_endpoint_override)
     pub async fn new(network: Network, psb: Option<ProtocolStreamBuilder>, key: String, tokens: Vec<Token>) -> Self {
-        let psb = match psb {
-            Some(psb) => psb,
+        let network = tycho_endpoint_override(network);
+        let custom = psb.is_some();
+        let (psb, registered) = match psb {
+            Some(psb) => (psb, vec![]),
             None => {
                 // --- Create Protocol stream builder --- Create your own protocol stream builder if you want to custom it.
-                let filter = ComponentFilter::with_tvl_range(ADD_TVL_THRESHOLD, ADD_TVL_THRESHOLD);
-                default_protocol_stream_builder(network.clone(), key.clone(), OrderbookBuilderConfig { filter }, tokens.clone()).await
+                registered_protocol_stream_builder(network.clone(), key.clone(), OrderbookBuilderConfig::default(), tokens.clone()).await
             }
         };
         let mut srztokens = vec![];
@@ -44,6 +108,9 @@ impl OrderbookBuilder {
             psb,
             tokens: srztokens,
             key: Some(key.clone()),
+            tvl_range: DEFAULT_TVL_RANGE,
+            registered,
+            custom,
         }
     }
 
@@ -54,6 +121,8 @@ impl OrderbookBuilder {
 
     pub fn psb(mut self, psb: ProtocolStreamBuilder) -> Self {
         self.psb = psb;
+        self.registered = vec![];
+        self.custom = true;
         self
     }
 
@@ -67,9 +136,198 @@ impl OrderbookBuilder {
         self
     }
 
+    /// Keeps at most `max` tokens (see cap_tokens), to bound the stream memory and the build time on chains with a huge token set, and rebuilds the default ProtocolStreamBuilder with them
+    /// Without ranking, the tokens kept after the valuation references are the lowest addresses, not the most liquid ones: see max_tokens_ranked
+    /// Pairs involving an excluded token won't be quotable. A custom ProtocolStreamBuilder (see psb) is kept as is: pass it the capped tokens yourself
+    pub async fn max_tokens(self, max: Option<usize>) -> Self {
+        self.max_tokens_ranked(max, &HashMap::new()).await
+    }
+
+    /// Same as max_tokens, keeping the tokens with the highest `rank` (score per lowercased address, e.g. the Tycho quality or the TVL of the token pools)
+    pub async fn max_tokens_ranked(mut self, max: Option<usize>, rank: &HashMap<String, f64>) -> Self {
+        if max.is_none_or(|max| self.tokens.len() <= max) {
+            return self;
+        }
+        self.tokens = cap_tokens(&self.network, self.tokens, max, rank);
+        self.refresh_psb().await;
+        self
+    }
+
+    /// Sets the TVL range (in ETH) of the tracked components, passed to every protocol of the stream: components are added above `max` and removed below `min`
+    /// Widen it to capture long-tail pairs, or narrow it for performance. Rebuilds the default ProtocolStreamBuilder, a custom one (see psb) is kept as is.
+    pub async fn with_tvl_range(mut self, min: f64, max: f64) -> Result<Self, anyhow::Error> {
+        if min.is_nan() || max.is_nan() || min < 0. || min > max {
+            return Err(anyhow::anyhow!("Invalid TVL range: min ({}) must be positive and lower or equal to max ({})", min, max));
+        }
+        self.tvl_range = (min, max);
+        self.refresh_psb().await;
+        Ok(self)
+    }
+
+    /// Only keeps in the shared state the components whose tokens are all in `tokens` (addresses), e.g. the majors only, to shrink the memory and build time of a focused deployment
+    /// Pairs involving a token outside of the whitelist won't be quotable (see Network::token_whitelist)
+    pub fn with_token_whitelist(mut self, tokens: Vec<String>) -> Self {
+        self.network.token_whitelist = tokens.into_iter().map(|t| t.to_lowercase()).collect();
+        self
+    }
+
+    /// Applies each block update only once `confirmations` blocks are received on top of it (see Network::confirmations)
+    /// The served state lags the chain tip by as many blocks, in exchange of not serving the updates of short reorgs
+    pub fn with_confirmations(mut self, confirmations: u64) -> Self {
+        self.network.confirmations = confirmations;
+        self
+    }
+
+    /// Rebuilds the default ProtocolStreamBuilder with the current settings, unless a custom one was given
+    async fn refresh_psb(&mut self) {
+        if self.custom {
+            tracing::warn!("Custom ProtocolStreamBuilder kept on {}: the tokens and TVL range of the builder are not applied to it", self.network.name);
+            return;
+        }
+        (self.psb, self.registered) = self.default_psb().await;
+    }
+
+    /// Default ProtocolStreamBuilder for the builder network, key, tokens and TVL range
+    async fn default_psb(&self) -> (ProtocolStreamBuilder, Vec<String>) {
+        let tokens = self.tokens.iter().cloned().map(Token::from).collect::<Vec<Token>>();
+        registered_protocol_stream_builder(self.network.clone(), self.key.clone().unwrap_or_default(), OrderbookBuilderConfig::tvl_range(self.tvl_range), tokens).await
+    }
+
+    /// Builds an OrderbookProvider serving the static state dumped by OrderbookProvider::snapshot, without connecting to Tycho (e.g. for backtesting)
+    pub async fn from_snapshot(path: &str) -> Result<OrderbookProvider<DefaultOrderbookSolver>, anyhow::Error> {
+        let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read snapshot {}: {}", path, e))?;
+        let snapshot: StateSnapshot = serde_json::from_str(&content).map_err(|e| anyhow::anyhow!("Invalid snapshot {}: {}", path, e))?;
+        OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await
+    }
+
     // Default ProtocolStreamBuilder
     pub async fn build(self) -> Result<OrderbookProvider<DefaultOrderbookSolver>, StreamError> {
         tracing::debug!("Building OrderbookProvider ... (with env API key)");
-        OrderbookProvider::new(self.network, self.psb, self.tokens, self.key.clone(), DefaultOrderbookSolver).await
+        OrderbookProvider::new(self.network, self.psb, self.tokens, self.key.clone(), DefaultOrderbookSolver::default()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshot_round_trip() {
+        use crate::data::fmt::SrzProtocolComponent;
+        use crate::utils::fixtures::{self, USDC, WETH};
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_100_000.)];
+        // Static attributes are hex encoded, as streamed by Tycho
+        let components = pools
+            .iter()
+            .map(|pt| SrzProtocolComponent {
+                static_attributes: vec![("fee".to_string(), "0x1e".to_string())],
+                ..pt.component.clone()
+            })
+            .collect::<Vec<_>>();
+        let mut state = crate::types::TychoStreamState::default();
+        state.initialise(&fixtures::update(7, &pools, &components));
+        let network = Network {
+            name: "ethereum".to_string(),
+            ..Default::default()
+        };
+        // Unique per run, so that concurrent test runs don't share the file
+        let path = std::env::temp_dir().join(format!("tycho-orderbook-snapshot-{}-{}.json", std::process::id(), crate::utils::misc::current_timestamp_ms()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, serde_json::to_string(&state.to_snapshot(&network, &[fixtures::weth(), fixtures::usdc()])).unwrap()).unwrap();
+        let provider = OrderbookBuilder::from_snapshot(path).await.unwrap();
+        let restored = provider.state.read().await;
+        assert_eq!(restored.block, 7);
+        assert!(restored.initialised);
+        assert_eq!(restored.protosims.len(), 2);
+        for pt in pools.iter() {
+            let original = state.spot_price(&pt.component.id, WETH, USDC).unwrap();
+            assert_eq!(restored.spot_price(&pt.component.id, WETH, USDC).unwrap(), original);
+            assert_eq!(SrzProtocolComponent::from(restored.components[&pt.component.key()].clone()).fee, 30);
+        }
+        drop(restored);
+        assert_eq!(provider.tokens.read().await.len(), 2);
+        assert_eq!(provider.snapshot(path).await.unwrap(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cap_tokens() {
+        use crate::utils::fixtures::{self, WETH};
+        let network = Network {
+            name: "ethereum".to_string(),
+            eth: WETH.to_string(),
+            ..Default::default()
+        };
+        let token = |x: u8| SrzToken {
+            address: format!("0x{:040x}", x),
+            decimals: 18,
+            symbol: format!("T{}", x),
+            gas: "0".to_string(),
+        };
+        let tokens = vec![token(3), token(2), fixtures::usdc(), token(1), fixtures::weth()];
+        let kept = cap_tokens(&network, tokens.clone(), Some(3), &HashMap::new());
+        assert_eq!(kept.len(), 3);
+        assert_eq!(kept[0].address, WETH);
+        // Unranked: lowest addresses, whatever the input order
+        assert_eq!(kept[1].symbol, "T1");
+        let reversed = tokens.iter().rev().cloned().collect::<Vec<SrzToken>>();
+        assert_eq!(cap_tokens(&network, reversed, Some(3), &HashMap::new()).iter().map(|t| t.symbol.clone()).collect::<Vec<_>>(), kept.iter().map(|t| t.symbol.clone()).collect::<Vec<_>>());
+        // Ranked: highest scores
+        let rank = HashMap::from([(fixtures::USDC.to_string(), 10.), (token(3).address, 5.)]);
+        let ranked = cap_tokens(&network, tokens.clone(), Some(3), &rank);
+        assert_eq!(ranked.iter().map(|t| t.symbol.clone()).collect::<Vec<_>>(), vec!["WETH", "USDC", "T3"]);
+        assert_eq!(cap_tokens(&network, tokens.clone(), None, &rank).len(), 5);
+        assert_eq!(cap_tokens(&network, tokens, Some(10), &rank).len(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_with_tvl_range() {
+        let network = Network {
+            name: "ethereum".to_string(),
+            tycho: "tycho-beta.propellerheads.xyz".to_string(),
+            ..Default::default()
+        };
+        let builder = OrderbookBuilder::new(network, None, "key".to_string(), vec![]).await;
+        assert_eq!(builder.tvl_range, DEFAULT_TVL_RANGE);
+        let builder = builder.with_tvl_range(10., 50.).await.unwrap();
+        assert_eq!(builder.tvl_range, (10., 50.));
+        assert!(builder.with_tvl_range(50., 10.).await.is_err());
+        // A custom ProtocolStreamBuilder is kept
+        let network = Network {
+            name: "ethereum".to_string(),
+            tycho: "tycho-beta.propellerheads.xyz".to_string(),
+            ..Default::default()
+        };
+        let (_, _, chain) = crate::types::chain(network.name.clone()).unwrap();
+        let psb = ProtocolStreamBuilder::new(&network.tycho, chain);
+        let builder = OrderbookBuilder::new(network, Some(psb), "key".to_string(), vec![]).await.with_tvl_range(10., 50.).await.unwrap();
+        assert!(builder.custom && builder.registered.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_ekubo_registered() {
+        let network = |name: &str| Network {
+            name: name.to_string(),
+            tycho: "tycho-beta.propellerheads.xyz".to_string(),
+            ..Default::default()
+        };
+        let ethereum = OrderbookBuilder::new(network("ethereum"), None, "key".to_string(), vec![]).await;
+        assert!(ethereum.registered.contains(&"ekubo_v2".to_string()));
+        let base = OrderbookBuilder::new(network("base"), None, "key".to_string(), vec![]).await;
+        assert!(!base.registered.is_empty());
+        assert!(!base.registered.contains(&"ekubo_v2".to_string()));
+    }
+
+    #[test]
+    fn test_tycho_endpoint_override() {
+        let network = Network {
+            name: "overridenet".to_string(),
+            tycho: "tycho-beta.propellerheads.xyz".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(tycho_endpoint_override_from(network.clone(), |_| None).tycho, "tycho-beta.propellerheads.xyz");
+        assert_eq!(tycho_endpoint_override_from(network.clone(), |_| Some(String::new())).tycho, "tycho-beta.propellerheads.xyz");
+        let env = HashMap::from([("TYCHO_ENDPOINT_OVERRIDENET".to_string(), "localhost:4242".to_string())]);
+        assert_eq!(tycho_endpoint_override_from(network, |var| env.get(var).cloned()).tycho, "localhost:4242");
     }
 }
@@ -1,37 +1,222 @@
 use chrono::DateTime;
+use tycho_simulation::evm::protocol::uniswap_v3::state::UniswapV3State;
+use tycho_simulation::evm::protocol::uniswap_v4::state::UniswapV4State;
 use tycho_simulation::models::Token;
 
 use crate::{
     core::{
-        client::{self, build_tycho_client},
+        client::{build_tycho_client, BalanceSource, TychoBalanceSource},
         gas,
     },
-    data::fmt::{SrzProtocolComponent, SrzToken},
-    maths::{self},
-    types::{MidPriceData, Network, Orderbook, OrderbookRequestParams, ProtoSimComp, TradeResult},
-    utils::{self},
+    data::fmt::{SrzProtocolComponent, SrzToken, SrzUniswapV3State, SrzUniswapV4State},
+    maths::{
+        self,
+        fill::{FillModel, RandomWalkFillModel},
+    },
+    types::{
+        BalanceCache, BookQuality, BookQualityConfig, CumulativeLevel, CumulativeOrderbook, LiquidityTickAmounts, MarketContext, MidPriceData, Network, Orderbook, OrderbookError, OrderbookRequestParams, ProtoSimComp, Side, TradeResult,
+    },
+    utils::{
+        self,
+        r#static::{
+            filter::{NATIVE_ADDRESS, NULL_ADDRESS},
+            maths::{ONE_HD, SKEW_NOTIONALS_USD},
+        },
+    },
 };
+use num_bigint::BigUint;
 use std::{
     collections::HashMap,
     time::{Duration, UNIX_EPOCH},
 };
 
-use super::solver::OrderbookSolver; // Ensure Rayon is in your dependencies.
+use tokio::sync::Mutex;
+
+use super::oracle::PriceOracle;
+use super::solver::{self, OrderbookSolver}; // Ensure Rayon is in your dependencies.
 
 /// @notice Reading 'state' from Redis DB while using TychoStreamState state and functions to compute/simulate might create a inconsistency
+/// @notice 'state' is a snapshot of the protosims taken at 'block', balances are fetched at the same block so the whole book is consistent to one block
+/// @notice Balances already fetched at 'block' by a previous build are reused from 'cache'
+/// @notice ETH price in USD comes from the given oracle, and an error is returned if it can't be fetched (rather than valuing the book with a made up price)
 /// @notice It's assumed that the first token is the base and the second is the quote, so bid = 'buy base', and ask = 'sell base'. It's the responsibility of the caller to ensure this.
 #[allow(clippy::too_many_arguments)]
 pub async fn build<S: OrderbookSolver>(
     solver: S,
+    oracle: &dyn PriceOracle,
     network: Network,
     tycho_token_api: Option<String>,
     state: Vec<ProtoSimComp>,
+    block: u64,
+    tokens: Vec<SrzToken>,
+    query: OrderbookRequestParams,
+    base_worth_eth: f64,
+    quote_worth_eth: f64,
+    cache: &Mutex<BalanceCache>,
+) -> Result<Orderbook, anyhow::Error> {
+    let client = match build_tycho_client(&network, tycho_token_api.clone()) {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Error while building Tycho client: {}", e);
+            return Err(anyhow::anyhow!("Error while building Tycho client: {}", e));
+        }
+    };
+    build_from(&TychoBalanceSource(client), solver, oracle, network, state, block, tokens, query, base_worth_eth, quote_worth_eth, cache).await
+}
+
+/// Same as build, with the uncached balances fetched from the given source
+#[allow(clippy::too_many_arguments)]
+pub async fn build_from<S: OrderbookSolver>(
+    source: &dyn BalanceSource,
+    solver: S,
+    oracle: &dyn PriceOracle,
+    network: Network,
+    state: Vec<ProtoSimComp>,
+    block: u64,
+    tokens: Vec<SrzToken>,
+    query: OrderbookRequestParams,
+    base_worth_eth: f64,
+    quote_worth_eth: f64,
+    cache: &Mutex<BalanceCache>,
+) -> Result<Orderbook, anyhow::Error> {
+    tracing::debug!("Building orderbook ... Got {} pools to compute for pair: '{}' at block {}", state.len(), query.tag, block);
+    let (balances, missing_balances) = BalanceCache::fetch(cache, source, &network, &state, block).await;
+    if !state.is_empty() && missing_balances.len() == state.len() {
+        // Without any balance, the liquidity used to generate the steps would be zero and the book meaningless
+        return Err(anyhow::anyhow!("Failed to fetch the balances of all {} components for pair '{}' at block {}", state.len(), query.tag, block));
+    }
+    let (eth_usd, eth_usd_stale) = oracle.eth_usd_checked(&network).await.map_err(|e| {
+        tracing::error!("Failed to fetch the ETH price on {}: {}", network.name, e);
+        OrderbookError::EthQuoteFailed
+    })?;
+    let gas_price = gas::gas_price(network.rpc.clone()).await;
+    let context = MarketContext { block, eth_usd, gas_price };
+    let mut book = assemble(solver, network, context, state, tokens, query, &balances, base_worth_eth, quote_worth_eth).await?;
+    book.missing_balances = missing_balances;
+    book.eth_usd_stale = eth_usd_stale;
+    Ok(book)
+}
+
+impl BalanceCache {
+    /// Balances of the given components at 'block', fetched from the source unless already cached for that block (any other block invalidates the cache)
+    /// The uncached ones are fetched in one batch (see BalanceSource::balances_batch), those missing from it are retried one by one
+    /// The cache is only locked to read and write its entries, never during the fetches: the uncached components are reserved (see pending) and fetched outside the lock,
+    /// so that concurrent builds don't wait on each other's RPCs, and a build needing a component another one is fetching waits for it instead of fetching it again
+    /// Returns the balances indexed by component ID (see assemble), and the IDs of the components whose balances couldn't be fetched (not cached, so retried on the next build)
+    pub async fn fetch(cache: &Mutex<BalanceCache>, source: &dyn BalanceSource, network: &Network, state: &[ProtoSimComp], block: u64) -> (HashMap<String, HashMap<String, u128>>, Vec<String>) {
+        let mut balances = HashMap::new();
+        let mut missing = vec![];
+        // Dropped once the reserved components are recorded, which wakes the builds waiting for them
+        let (done, reserved) = tokio::sync::watch::channel(());
+        let (mut owned, mut awaited) = (vec![], vec![]);
+        {
+            let mut cache = cache.lock().await;
+            if block != cache.block {
+                cache.entries.clear();
+                cache.pending.clear();
+                cache.block = block;
+            }
+            for pdata in state.iter() {
+                let key = pdata.component.key();
+                if let Some(cached) = cache.entries.get(&key).cloned() {
+                    cache.hits += 1;
+                    balances.insert(pdata.component.id.to_lowercase(), cached);
+                } else if let Some(fetching) = cache.pending.get(&key).cloned() {
+                    awaited.push((pdata, fetching));
+                } else {
+                    cache.misses += 1;
+                    cache.pending.insert(key, reserved.clone());
+                    owned.push(pdata);
+                }
+            }
+        }
+        let mut resolve = |pdata: &ProtoSimComp, cpbs: Option<HashMap<String, u128>>| match cpbs {
+            Some(cpbs) => {
+                balances.insert(pdata.component.id.to_lowercase(), cpbs);
+            }
+            None => {
+                tracing::warn!("Failed to fetch balances of component {} at block {}", pdata.component.id, block);
+                balances.insert(pdata.component.id.to_lowercase(), HashMap::new()); // No liquidity
+                missing.push(pdata.component.id.clone());
+            }
+        };
+        // --- Reserved components, fetched outside the lock ---
+        let uncached = owned.iter().map(|pdata| (pdata.component.id.clone(), pdata.component.protocol_system.clone())).collect::<Vec<(String, String)>>();
+        let mut fetched = match uncached.is_empty() {
+            true => HashMap::new(),
+            false => source.balances_batch(network, &uncached, block).await,
+        };
+        let mut results = vec![];
+        for pdata in owned {
+            let cpbs = match fetched.remove(&pdata.component.id.to_lowercase()) {
+                Some(cpbs) => Some(cpbs),
+                None => source.balances(network, &pdata.component.id, &pdata.component.protocol_system, block).await,
+            };
+            results.push((pdata, cpbs));
+        }
+        {
+            let mut cache = cache.lock().await;
+            for (pdata, cpbs) in results.iter() {
+                cache.record(block, pdata.component.key(), cpbs);
+            }
+        }
+        drop(done);
+        for (pdata, cpbs) in results {
+            resolve(pdata, cpbs);
+        }
+        // --- Components reserved by concurrent builds ---
+        for (pdata, mut fetching) in awaited {
+            let _ = fetching.changed().await; // Errs once the reserving build is done, or was dropped
+            let key = pdata.component.key();
+            let cached = {
+                let mut cache = cache.lock().await;
+                let cached = cache.entries.get(&key).cloned();
+                match cached.is_some() {
+                    true => cache.hits += 1,
+                    false => cache.misses += 1,
+                }
+                cached
+            };
+            // Not cached: its fetch failed, or the build reserving it was dropped. Fetched again, alone
+            let cpbs = match cached {
+                Some(cpbs) => Some(cpbs),
+                None => {
+                    let cpbs = source.balances(network, &pdata.component.id, &pdata.component.protocol_system, block).await;
+                    cache.lock().await.record(block, key, &cpbs);
+                    cpbs
+                }
+            };
+            resolve(pdata, cpbs);
+        }
+        (balances, missing)
+    }
+
+    /// Releases a reserved component, caching its balances if fetched and still at the same block
+    fn record(&mut self, block: u64, key: String, cpbs: &Option<HashMap<String, u128>>) {
+        if block != self.block {
+            return;
+        }
+        self.pending.remove(&key);
+        if let Some(cpbs) = cpbs {
+            self.entries.insert(key, cpbs.clone());
+        }
+    }
+}
+
+/// Builds the orderbook from already known component balances, without any network call (e.g. for backtests, or with balances from your own indexer)
+/// @notice 'balances' are indexed by component ID, then by token address, in the token smallest unit. Every component of 'state' must be in it.
+#[allow(clippy::too_many_arguments)]
+pub async fn assemble<S: OrderbookSolver>(
+    solver: S,
+    network: Network,
+    context: MarketContext,
+    state: Vec<ProtoSimComp>,
     tokens: Vec<SrzToken>,
     query: OrderbookRequestParams,
+    balances: &HashMap<String, HashMap<String, u128>>,
     base_worth_eth: f64,
     quote_worth_eth: f64,
 ) -> Result<Orderbook, anyhow::Error> {
-    tracing::debug!("Building orderbook ... Got {} pools to compute for pair: '{}'", state.len(), query.tag);
     let mut pools = Vec::new();
     let mut prices_base_to_quote = vec![];
     let mut prices_quote_to_base = vec![];
@@ -42,85 +227,80 @@ pub async fn build<S: OrderbookSolver>(
     let (base, quote) = (t0, t1);
     let mut base_lqdty = vec![];
     let mut quote_lqdty = vec![];
-    let mut balances = HashMap::new();
-
-    match build_tycho_client(&network, tycho_token_api.clone()) {
-        Ok(client) => {
-            for pdata in state.clone() {
-                pools.push(pdata.clone());
-                let proto = pdata.protosim.clone();
-                let price_base_to_quote = proto.spot_price(&base, &quote).unwrap_or_default();
-                let price_quote_to_base = proto.spot_price(&quote, &base).unwrap_or_default();
-                let d = UNIX_EPOCH + Duration::from_secs(pdata.component.last_updated_at);
-                let datetime = DateTime::<chrono::Utc>::from(d);
-                let timestamp = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
-
-                prices_base_to_quote.push(price_base_to_quote);
-                prices_quote_to_base.push(price_quote_to_base);
-                tracing::trace!(
-                    "- Pool: {} | {} | Spot price for {}-{} => price_base_to_quote = {} and price_quote_to_base = {} | Fee = {} | Last updated at {}",
-                    pdata.component.id,
-                    pdata.component.protocol_type_name,
-                    base.symbol,
-                    quote.symbol,
-                    price_base_to_quote,
-                    price_quote_to_base,
-                    pdata.component.fee,
-                    timestamp
-                );
-                if let Some(cpbs) = client::get_component_balances(&client, network.clone(), pdata.component.id.clone(), pdata.component.protocol_system.clone()).await {
-                    let base_bal = cpbs.get(&srzt0.address.to_lowercase()).unwrap_or(&0u128);
-                    let base_bal = *base_bal as f64 / 10f64.powi(srzt0.decimals as i32);
-                    base_lqdty.push(base_bal);
-                    let quote_bal = cpbs.get(&srzt1.address.to_lowercase()).unwrap_or(&0u128);
-                    let quote_bal = *quote_bal as f64 / 10f64.powi(srzt1.decimals as i32);
-                    quote_lqdty.push(quote_bal);
-                    let mut tmpb = HashMap::new();
-                    tmpb.insert(srzt0.address.clone(), base_bal);
-                    tmpb.insert(srzt1.address.clone(), quote_bal);
-                    balances.insert(pdata.component.id.clone().to_lowercase(), tmpb);
-                } else {
-                    base_lqdty.push(0f64);
-                    quote_lqdty.push(0f64);
-                    balances.insert(pdata.component.id.clone().to_lowercase(), HashMap::new());
-                }
-            }
-            let cps: Vec<SrzProtocolComponent> = pools.clone().iter().map(|p| p.component.clone()).collect();
-            let aggregated = maths::steps::depth(cps.clone(), tokens.clone(), balances.clone());
-            let avg_price_base_to_quote = prices_base_to_quote.iter().sum::<f64>() / prices_base_to_quote.len() as f64;
-            let avg_price_quote_to_base = prices_quote_to_base.iter().sum::<f64>() / prices_quote_to_base.len() as f64; // Ponderation by TVL ?
-            tracing::trace!("Average price 0to1: {} | Average price 1to0: {}", avg_price_base_to_quote, avg_price_quote_to_base);
-            match simulate(
-                solver,
-                network.clone(),
-                pools.clone(),
-                tokens,
-                query.clone(),
-                aggregated.clone(),
-                base_worth_eth,
-                quote_worth_eth,
-                avg_price_base_to_quote,
-                avg_price_quote_to_base,
-            )
-            .await
-            {
-                Ok(mut pso) => {
-                    pso.prices_base_to_quote = prices_base_to_quote;
-                    pso.prices_quote_to_base = prices_quote_to_base;
-                    pso.base_lqdty = base_lqdty.clone();
-                    pso.quote_lqdty = quote_lqdty.clone();
-                    tracing::debug!("Done. Returning simulated orderbook for pair (base-quote) => '{}-{}'", base.symbol, quote.symbol);
-                    Ok(pso)
-                }
-                Err(e) => {
-                    tracing::error!("Error while simulating orderbook: {}", e);
-                    Err(anyhow::anyhow!("Error while simulating orderbook: {}", e))
-                }
-            }
+    let mut cpbalances = HashMap::new();
+    let mut tvls = vec![];
+
+    for pdata in state.clone() {
+        pools.push(pdata.clone());
+        let proto = pdata.protosim.clone();
+        let price_base_to_quote = proto.spot_price(&base, &quote).unwrap_or_default();
+        let price_quote_to_base = proto.spot_price(&quote, &base).unwrap_or_default();
+        let d = UNIX_EPOCH + Duration::from_secs(pdata.component.last_updated_at);
+        let datetime = DateTime::<chrono::Utc>::from(d);
+        let timestamp = datetime.format("%Y-%m-%d %H:%M:%S").to_string();
+
+        prices_base_to_quote.push(price_base_to_quote);
+        prices_quote_to_base.push(price_quote_to_base);
+        tracing::trace!(
+            "- Pool: {} | {} | Spot price for {}-{} => price_base_to_quote = {} and price_quote_to_base = {} | Fee = {} | Last updated at {}",
+            pdata.component.id,
+            pdata.component.protocol_type_name,
+            base.symbol,
+            quote.symbol,
+            price_base_to_quote,
+            price_quote_to_base,
+            pdata.component.fee,
+            timestamp
+        );
+        let cpbs = balances
+            .get(&pdata.component.id.to_lowercase())
+            .ok_or_else(|| anyhow::anyhow!("Missing balances for component {}", pdata.component.id))?;
+        let base_bal = cpbs.get(&srzt0.address.to_lowercase()).unwrap_or(&0u128);
+        let base_bal = *base_bal as f64 / 10f64.powi(srzt0.decimals as i32);
+        base_lqdty.push(base_bal);
+        let quote_bal = cpbs.get(&srzt1.address.to_lowercase()).unwrap_or(&0u128);
+        let quote_bal = *quote_bal as f64 / 10f64.powi(srzt1.decimals as i32);
+        quote_lqdty.push(quote_bal);
+        tvls.push((base_bal * base_worth_eth + quote_bal * quote_worth_eth) * context.eth_usd);
+        let mut tmpb = HashMap::new();
+        tmpb.insert(srzt0.address.clone(), base_bal);
+        tmpb.insert(srzt1.address.clone(), quote_bal);
+        cpbalances.insert(pdata.component.key(), tmpb);
+    }
+    let cps: Vec<SrzProtocolComponent> = pools.clone().iter().map(|p| p.component.clone()).collect();
+    let aggregated = maths::steps::depth(cps.clone(), tokens.clone(), cpbalances.clone());
+    // Weighted by TVL, so that a tiny pool with a stale price doesn't skew the reference price
+    let avg_price_base_to_quote = tvl_weighted_mean(&prices_base_to_quote, &tvls);
+    let avg_price_quote_to_base = tvl_weighted_mean(&prices_quote_to_base, &tvls);
+    tracing::trace!("Average price 0to1: {} | Average price 1to0: {}", avg_price_base_to_quote, avg_price_quote_to_base);
+    match simulate(
+        solver,
+        network.clone(),
+        context,
+        pools.clone(),
+        tokens,
+        query.clone(),
+        aggregated.clone(),
+        &base_lqdty,
+        &quote_lqdty,
+        base_worth_eth,
+        quote_worth_eth,
+        avg_price_base_to_quote,
+        avg_price_quote_to_base,
+    )
+    .await
+    {
+        Ok(mut pso) => {
+            pso.prices_base_to_quote = prices_base_to_quote;
+            pso.prices_quote_to_base = prices_quote_to_base;
+            pso.base_lqdty = base_lqdty.clone();
+            pso.quote_lqdty = quote_lqdty.clone();
+            tracing::debug!("Done. Returning simulated orderbook for pair (base-quote) => '{}-{}'", base.symbol, quote.symbol);
+            Ok(pso)
         }
         Err(e) => {
-            tracing::error!("Error while building Tycho client: {}", e);
-            Err(anyhow::anyhow!("Error while building Tycho client: {}", e))
+            tracing::error!("Error while simulating orderbook: {}", e);
+            Err(anyhow::anyhow!("Error while simulating orderbook: {}", e))
         }
     }
 }
@@ -129,24 +309,27 @@ pub async fn build<S: OrderbookSolver>(
 /// The function generates a set of test amounts for ETH and USDC, then runs the optimizer for each amount.
 /// The optimizer uses a simple gradient-based approach to move a fixed fraction of the allocation from the pool with the lowest marginal return to the one with the highest.
 /// If the query specifies a specific token to sell with a specific amount, the optimizer will only run for that token and amount.
+/// Market data (block, ETH price, gas price) is given by the caller, so no network call is made here.
 #[allow(clippy::too_many_arguments)]
 pub async fn simulate<S: OrderbookSolver>(
     solver: S,
     network: Network,
+    context: MarketContext,
     pcsdata: Vec<ProtoSimComp>,
     tokens: Vec<SrzToken>,
     body: OrderbookRequestParams,
     balances: HashMap<String, f64>,
+    base_lqdty: &[f64],
+    quote_lqdty: &[f64],
     base_worth_eth: f64,
     quote_worth_eth: f64,
     price_base_to_quote: f64,
     price_quote_to_base: f64,
 ) -> Result<Orderbook, anyhow::Error> {
+    let started = std::time::Instant::now();
     let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("Time went backwards").as_secs();
-    // let eth_worth_usd = client::get_eth_usd_chainlink(network.rpc.clone(), network.chainlink.clone()).await.unwrap_or(2500.);
-    let eth_worth_usd = client::coingecko().await.unwrap_or(2500.);
-    let gas_price = gas::gas_price(network.rpc.clone()).await;
-    let latest = client::get_latest_block(network.rpc.clone()).await;
+    let eth_worth_usd = context.eth_usd;
+    let gas_price = context.gas_price;
     let base = tokens[0].clone();
     let quote = tokens[1].clone();
 
@@ -159,10 +342,17 @@ pub async fn simulate<S: OrderbookSolver>(
         None => return Err(anyhow::anyhow!("Quote balance not found for token {}", quote.address)),
     };
 
+    if *total_balance_base <= 0. || *total_balance_quote <= 0. {
+        // The liquidity ratio below would be NaN or infinite, corrupting the whole book
+        return Err(anyhow::anyhow!("No liquidity found for pair {}-{} (aggregated balances: {} and {})", base.symbol, quote.symbol, total_balance_base, total_balance_quote));
+    }
+
     let total_balance_base_worth_usd = (total_balance_base) * base_worth_eth * eth_worth_usd;
     let total_balance_quote_worth_usd = (total_balance_quote) * quote_worth_eth * eth_worth_usd;
-    let base_to_quote_liquidity_ratio = total_balance_base_worth_usd / total_balance_quote_worth_usd;
-    let base_liquidity_share = total_balance_base_worth_usd / (total_balance_base_worth_usd + total_balance_quote_worth_usd);
+    // Valued in ETH, where the ETH price cancels out: still defined when it's unknown (0)
+    let (total_balance_base_worth_eth, total_balance_quote_worth_eth) = (total_balance_base * base_worth_eth, total_balance_quote * quote_worth_eth);
+    let base_to_quote_liquidity_ratio = total_balance_base_worth_eth / total_balance_quote_worth_eth;
+    let base_liquidity_share = total_balance_base_worth_eth / (total_balance_base_worth_eth + total_balance_quote_worth_eth);
 
     // E.g.: Liquidity ratio for WBTC-USDT: Agg Base worth: 41728361.72503823 $ | Agg Quote worth: 19582431.73275704 $ | base_to_quote_liquidity_ratio: 2.130908065683997 | base_liquidity_share: 0.6806038443094515
     // = 41 728 361 $ / 19 582 431 $
@@ -196,30 +386,54 @@ pub async fn simulate<S: OrderbookSolver>(
         adjusted_total_balance_quote
     );
 
-    let pools = pcsdata.iter().map(|x| x.component.clone()).collect::<Vec<SrzProtocolComponent>>();
-    let amount_eth = utils::r#static::maths::BEST_BID_ASK_ETH_BPS / utils::r#static::maths::BPD; // 1/100 of ETH = ~2$ (for 2000$ ETH)
-    let amount_test_best_base_to_quote = amount_eth / base_worth_eth;
-    let amount_test_best_quote_to_base = amount_eth / quote_worth_eth;
+    let mut pools = pcsdata.iter().map(|x| x.component.clone()).collect::<Vec<SrzProtocolComponent>>();
+    for pool in pools.iter_mut() {
+        if let Some(fee) = body.fee_overrides.iter().find(|(id, _)| id.eq_ignore_ascii_case(&pool.id)).map(|(_, fee)| *fee) {
+            tracing::debug!("Overriding fee of component {}: {} bps instead of {} bps", pool.id, fee, pool.fee);
+            pool.fee = fee;
+        }
+    }
+    let amount_test_best_base_to_quote = probe_amount(solver.probe_fraction(), *total_balance_base, base_worth_eth);
+    let amount_test_best_quote_to_base = probe_amount(solver.probe_fraction(), *total_balance_quote, quote_worth_eth);
     let best_base_to_quote = compute_best_trade(&pcsdata, eth_worth_usd, gas_price, &base, &quote, amount_test_best_base_to_quote, price_base_to_quote, quote_worth_eth);
     let best_quote_to_base = compute_best_trade(&pcsdata, eth_worth_usd, gas_price, &quote, &base, amount_test_best_quote_to_base, price_quote_to_base, base_worth_eth);
-    let mpd_base_to_quote = derive_mid_price(best_base_to_quote.clone(), best_quote_to_base.clone());
-    let mpd_quote_to_base = derive_mid_price(best_quote_to_base.clone(), best_base_to_quote.clone());
+    // Steps below 3 times the ETH-based probe are dust, whatever the probe fraction of the solver, so that a larger probe doesn't thin out the book
+    let dust_base = probe_amount(None, *total_balance_base, base_worth_eth) * 3.;
+    let dust_quote = probe_amount(None, *total_balance_quote, quote_worth_eth) * 3.;
+    let mpd_base_to_quote = derive_mid_price(best_base_to_quote.clone(), best_quote_to_base.clone(), total_balance_quote_worth_usd, total_balance_base_worth_usd);
+    let mpd_quote_to_base = derive_mid_price(best_quote_to_base.clone(), best_base_to_quote.clone(), total_balance_base_worth_usd, total_balance_quote_worth_usd);
 
     let tag = format!("{}-{}", base.address.to_lowercase(), quote.address.to_lowercase());
     let mut result = Orderbook {
         tag,
-        block: latest,
+        block: context.block,
         timestamp,
         base: tokens[0].clone(),
         quote: tokens[1].clone(),
         pools: pools.clone(),
+        fees_bps: pools.iter().map(|pool| pool.fee).collect(),
+        effective_fee_bps: 0.,        // Set later
+        truncated: 0,                 // Set later
         bids: vec![],                 // Set depending query params
         asks: vec![],                 // Set depending query params
+        bids_exact_out: vec![],       // Set depending query params
+        asks_exact_out: vec![],       // Set depending query params
+        bids_chart: vec![],           // Set depending query params
+        asks_chart: vec![],           // Set depending query params
         prices_base_to_quote: vec![], // Set later
         prices_quote_to_base: vec![], // Set later
         base_lqdty: vec![],           // Set later
         quote_lqdty: vec![],          // Set later
+        missing_balances: vec![],     // Set later
+        unsupported_pools: vec![],    // Set by the provider
+        base_valuation_ref: None,     // Set by the provider
+        quote_valuation_ref: None,    // Set by the provider
+        usd_unavailable: false,       // Set by the provider
         eth_usd: eth_worth_usd,
+        eth_usd_stale: false, // Set later
+        gas_price,
+        volatility: 0., // Set by the provider, from its mid history
+        crossed: false, // Set later
         mpd_base_to_quote: mpd_base_to_quote.clone(),
         mpd_quote_to_base: mpd_quote_to_base.clone(),
         base_worth_eth,
@@ -227,53 +441,166 @@ pub async fn simulate<S: OrderbookSolver>(
         // Optional, but still usefull
         aggregated_balance_base_worth_usd: total_balance_base_worth_usd,
         aggregated_balance_quote_worth_usd: total_balance_quote_worth_usd,
+        currency: None,
+        protocols: body.protocols.clone(),
     };
     match body.point {
         Some(point) => {
-            tracing::trace!(" 🎯 Partial Optimisation: input: {} and amount: {}", point.input, point.amount);
             if point.input.to_lowercase() == base.address.to_lowercase() {
-                result.bids = vec![maths::opti::gradient(
-                    point.amount,
-                    &pcsdata,
-                    base.clone(),
-                    quote.clone(),
-                    eth_worth_usd,
-                    gas_price,
-                    price_base_to_quote,
-                    quote_worth_eth,
-                )];
+                let amount = point.resolve(*total_balance_base)?;
+                let amount = point.to_input_units(amount, &base.address, &quote.address, price_base_to_quote)?;
+                tracing::trace!(" 🎯 Partial Optimisation: input: {} and amount: {}", point.input, amount);
+                let bid = maths::opti::gradient(amount, &pcsdata, base.clone(), quote.clone(), eth_worth_usd, gas_price, price_base_to_quote, quote_worth_eth);
+                result.bids = clip_levels(vec![bid], quote_lqdty, &pcsdata, &base, &quote, eth_worth_usd, gas_price, price_base_to_quote, quote_worth_eth);
             } else if point.input.to_lowercase() == quote.address.to_lowercase() {
-                result.asks = vec![maths::opti::gradient(
-                    point.amount,
-                    &pcsdata,
-                    quote.clone(),
-                    base.clone(),
-                    eth_worth_usd,
-                    gas_price,
-                    price_quote_to_base,
-                    base_worth_eth,
-                )];
+                let amount = point.resolve(*total_balance_quote)?;
+                let amount = point.to_input_units(amount, &quote.address, &base.address, price_quote_to_base)?;
+                tracing::trace!(" 🎯 Partial Optimisation: input: {} and amount: {}", point.input, amount);
+                let ask = maths::opti::gradient(amount, &pcsdata, quote.clone(), base.clone(), eth_worth_usd, gas_price, price_quote_to_base, base_worth_eth);
+                result.asks = clip_levels(vec![ask], base_lqdty, &pcsdata, &quote, &base, eth_worth_usd, gas_price, price_quote_to_base, base_worth_eth);
             }
         }
         None => {
-            let steps = solver.generate_steps(adjusted_total_balance_base);
-            let steps: Vec<f64> = steps.iter().cloned().filter(|&s| s > amount_test_best_base_to_quote * 3.).collect();
+            let grid = solver.generate_side_steps(Side::Bid, adjusted_total_balance_base);
+            let steps: Vec<f64> = grid.iter().cloned().filter(|&s| s > dust_base).collect();
             let bids = solver.optimize(&pcsdata, steps.clone(), eth_worth_usd, gas_price, &base, &quote, price_base_to_quote, quote_worth_eth);
+            let bids = clip_levels(bids, quote_lqdty, &pcsdata, &base, &quote, eth_worth_usd, gas_price, price_base_to_quote, quote_worth_eth);
+            let bids = truncate(bids, solver.max_price_impact(), &mut result.truncated);
+            if body.pad_levels {
+                result.bids_chart = pad_levels(&bids, &grid);
+            }
             result.bids = bids;
             tracing::trace!(" 🔄  Bids done, now switching to asks");
-            let steps = solver.generate_steps(adjusted_total_balance_quote);
-            let steps: Vec<f64> = steps.iter().cloned().filter(|&s| s > amount_test_best_quote_to_base * 3.).collect();
+            let grid = solver.generate_side_steps(Side::Ask, adjusted_total_balance_quote);
+            let steps: Vec<f64> = grid.iter().cloned().filter(|&s| s > dust_quote).collect();
             let asks = solver.optimize(&pcsdata, steps.clone(), eth_worth_usd, gas_price, &quote, &base, price_quote_to_base, base_worth_eth);
+            let asks = clip_levels(asks, base_lqdty, &pcsdata, &quote, &base, eth_worth_usd, gas_price, price_quote_to_base, base_worth_eth);
+            let asks = truncate(asks, solver.max_price_impact(), &mut result.truncated);
+            if body.pad_levels {
+                result.asks_chart = pad_levels(&asks, &grid);
+            }
             result.asks = asks;
+            if body.both_directions {
+                tracing::trace!(" 🔄  Exact-in done, now computing the exact-out curves");
+                let targets = result.bids.iter().map(|t| t.output).collect();
+                result.bids_exact_out = solver::optimize_exact_out(&pcsdata, targets, eth_worth_usd, gas_price, &base, &quote, price_base_to_quote, quote_worth_eth);
+                let targets = result.asks.iter().map(|t| t.output).collect();
+                result.asks_exact_out = solver::optimize_exact_out(&pcsdata, targets, eth_worth_usd, gas_price, &quote, &base, price_quote_to_base, base_worth_eth);
+            }
         }
     }
+    if body.with_components {
+        let ids = pools.iter().map(|pool| pool.id.clone()).collect::<Vec<String>>();
+        for trade in result.bids.iter_mut().chain(result.asks.iter_mut()).chain(result.bids_exact_out.iter_mut()).chain(result.asks_exact_out.iter_mut()) {
+            trade.component_ids = Some(ids.clone());
+        }
+    }
+    // Best bid, or best ask if only asks were requested
+    if let Some(best) = result.bids.iter().chain(result.asks.iter()).find(|trade| trade.output > 0.) {
+        result.effective_fee_bps = result.blended_fee_bps(best);
+    }
+    result.flag_crossed();
+    solver.metrics().on_orderbook_built(&result.tag, started.elapsed(), result.pools.len());
     Ok(result)
 }
 
+/// Clips the trades of a side so that no pool gives more than its balance of the output token (`caps`, one per pool, see maths::opti::clip), no clipping if empty
+/// Trades reduced to the same amount (partial fills of the largest steps) are deduplicated, keeping the first one, so that the amounts stay increasing
+#[allow(clippy::too_many_arguments)]
+fn clip_levels(trades: Vec<TradeResult>, caps: &[f64], pools: &[ProtoSimComp], from: &SrzToken, to: &SrzToken, eth_usd: f64, gas_price: u128, spot: f64, worth: f64) -> Vec<TradeResult> {
+    if caps.is_empty() {
+        return trades;
+    }
+    let mut levels: Vec<TradeResult> = Vec::with_capacity(trades.len());
+    for trade in trades.iter() {
+        let clipped = maths::opti::clip(trade, caps, pools, from.clone(), to.clone(), eth_usd, gas_price, spot, worth);
+        if levels.last().is_some_and(|last| clipped.amount <= last.amount * (1. + 1e-9)) {
+            continue;
+        }
+        levels.push(clipped);
+    }
+    if levels.len() < trades.len() {
+        tracing::debug!("Dropped {} levels clipped to the same amount by the pools balances", trades.len() - levels.len());
+    }
+    levels
+}
+
+/// Truncates the trades beyond the maximum price impact if any (see solver::truncate_price_impact), adding the dropped ones to `truncated`
+fn truncate(trades: Vec<TradeResult>, max_price_impact: Option<f64>, truncated: &mut usize) -> Vec<TradeResult> {
+    let Some(max) = max_price_impact else {
+        return trades;
+    };
+    let (kept, dropped) = solver::truncate_price_impact(trades, max);
+    if dropped > 0 {
+        tracing::debug!("Truncated {} trades beyond {:.0}% of price impact", dropped, max * 100.);
+    }
+    *truncated += dropped;
+    kept
+}
+
+/// Adds a zero-output level for each step of the grid without a trade (filtered out, or dropped by the solver), sorted by amount, for depth charts only
+/// Only the steps up to the largest trade are padded: the ones beyond it were truncated or clipped away, and the book stops there
+/// The price of a padded level is interpolated linearly between the neighbouring trades (or copied from the nearest one at the edges)
+pub fn pad_levels(trades: &[TradeResult], steps: &[f64]) -> Vec<TradeResult> {
+    if trades.is_empty() {
+        return vec![];
+    }
+    let covered = |step: f64| trades.iter().any(|t| (t.amount - step).abs() <= step.abs() * 1e-9);
+    let mut sorted = trades.to_vec();
+    sorted.sort_by(|a, b| a.amount.partial_cmp(&b.amount).unwrap_or(std::cmp::Ordering::Equal));
+    let pools = sorted[0].distribution.len();
+    let largest = sorted[sorted.len() - 1].amount;
+    let mut padded = sorted.clone();
+    for step in steps.iter().cloned().filter(|&s| s < largest && !covered(s)) {
+        let below = sorted.iter().rev().find(|t| t.amount < step);
+        let above = sorted.iter().find(|t| t.amount > step);
+        let price = match (below, above) {
+            (Some(b), Some(a)) => b.average_sell_price + (a.average_sell_price - b.average_sell_price) * (step - b.amount) / (a.amount - b.amount),
+            (Some(t), None) | (None, Some(t)) => t.average_sell_price,
+            (None, None) => 0.,
+        };
+        padded.push(TradeResult {
+            amount: step,
+            output: 0.,
+            distribution: vec![0.; pools],
+            distributed: vec![0.; pools],
+            gas_costs: vec![0; pools],
+            gas_costs_usd: vec![0.; pools],
+            gas_costs_input_token: vec![0.; pools],
+            average_sell_price: price,
+            price_impact: 0.,
+            pruned: 0,
+            capped: false,
+            component_ids: sorted[0].component_ids.clone(),
+        });
+    }
+    padded.sort_by(|a, b| a.amount.partial_cmp(&b.amount).unwrap_or(std::cmp::Ordering::Equal));
+    padded
+}
+
+/// Quotes a single exact-in trade of 'amount' (human-readable) from one token to the other across the given pools, without building the book
+/// The spot price used as price impact reference is the best one among the pools, as the component balances (needed to weight them by TVL) aren't fetched
+pub fn quote(context: &MarketContext, pcsdata: &[ProtoSimComp], from: &SrzToken, to: &SrzToken, amount: f64, output_worth_eth: f64) -> TradeResult {
+    let (input, output) = (Token::from(from.clone()), Token::from(to.clone()));
+    let spot_price = pcsdata.iter().filter_map(|pdata| pdata.protosim.spot_price(&input, &output).ok()).fold(0., f64::max);
+    maths::opti::gradient(amount, pcsdata, from.clone(), to.clone(), context.eth_usd, context.gas_price, spot_price, output_worth_eth)
+}
+
+/// Amount in (human-readable) of the trades probing the best bid/ask, from which the mid price data is derived (see derive_mid_price)
+/// A fraction of the aggregated liquidity of the input token if set (e.g. 1 / TEN_MILLIONS), BEST_BID_ASK_ETH_BPS of ETH (0.01 ETH) worth of it otherwise
+/// The larger the probe, the deeper it walks into the pools: its ask and bid move apart, so mpd_base_to_quote gets a wider spread and a mid less sensitive to dust liquidity
+/// The probe doesn't change the steps of the book: the ones filtered out as dust are always below 3 times the ETH-based probe
+pub fn probe_amount(fraction: Option<f64>, liquidity: f64, worth_eth: f64) -> f64 {
+    match fraction {
+        Some(fraction) => liquidity * fraction,
+        None => utils::r#static::maths::BEST_BID_ASK_ETH_BPS / utils::r#static::maths::BPD / worth_eth,
+    }
+}
+
 /// Computes the mid price for a given token pair
 /// We cannot replicate the logic of a classic orderbook as we don't have best bid/ask exacly
 /// In theory it would be : Mid Price = (Best Bid Price + Best Ask Price) / 2
-/// Applied to AMM, we choose to use a small amountIn = 1 / TEN_MILLIONS of the aggregated liquidity
+/// Applied to AMM, we choose to use a small amountIn (see probe_amount)
 /// Doing that for 0to1 and 1to0 we have our best bid/ask, then we can compute the mid price
 /// --- --- --- --- ---
 /// Amount out is net of gas cost
@@ -295,13 +622,15 @@ pub fn compute_best_trade(pcs: &[ProtoSimComp], eth_worth_usd: f64, gas_price: u
 
 /// Computes the mid price for a given token pair using the best bid and ask
 /// ! We assume that => trade_base_to_quote = ask and trade_quote_to_base = bid
-pub fn derive_mid_price(trade_base_to_quote: TradeResult, trade_quote_to_base: TradeResult) -> MidPriceData {
+/// The liquidity (in USD) is the aggregated liquidity of the output token of each trade, used to compute the liquidity-weighted mid price
+pub fn derive_mid_price(trade_base_to_quote: TradeResult, trade_quote_to_base: TradeResult, liquidity_base_to_quote_usd: f64, liquidity_quote_to_base_usd: f64) -> MidPriceData {
     let amount = trade_base_to_quote.amount;
     let received = trade_base_to_quote.output;
     let distribution = trade_base_to_quote.distribution.clone();
     let ask = trade_base_to_quote.average_sell_price; // buy quote
     let bid = 1. / trade_quote_to_base.average_sell_price; // buy base
     let mid = (ask + bid) / 2.;
+    let weighted_mid = weighted_mid(ask, bid, liquidity_base_to_quote_usd, liquidity_quote_to_base_usd);
     let spread = (ask - bid).abs();
     let spread_pct = (spread / mid) * 100.;
     MidPriceData {
@@ -311,12 +640,1305 @@ pub fn derive_mid_price(trade_base_to_quote: TradeResult, trade_quote_to_base: T
         ask,
         bid,
         mid,
+        weighted_mid,
         spread,
         spread_pct,
     }
 }
 
+/// Mid price with the ask and bid weighted by the liquidity (in USD) backing each of them, so that it shifts toward the deeper side
+/// Falls back to the simple mid price if a liquidity is unknown
+pub fn weighted_mid(ask: f64, bid: f64, ask_liquidity_usd: f64, bid_liquidity_usd: f64) -> f64 {
+    if ask_liquidity_usd <= 0. || bid_liquidity_usd <= 0. {
+        return (ask + bid) / 2.;
+    }
+    (ask * ask_liquidity_usd + bid * bid_liquidity_usd) / (ask_liquidity_usd + bid_liquidity_usd)
+}
+
+/// Mean of the pools spot prices weighted by their TVL (in USD), or the arithmetic mean if all TVLs are zero
+pub fn tvl_weighted_mean(prices: &[f64], tvls: &[f64]) -> f64 {
+    let total = tvls.iter().sum::<f64>();
+    if total <= 0. {
+        return prices.iter().sum::<f64>() / prices.len() as f64;
+    }
+    prices.iter().zip(tvls.iter()).map(|(price, tvl)| price * tvl).sum::<f64>() / total
+}
+
+/// Check if the address is the network native token placeholder (null or 0xeee..e address)
+pub fn is_native(address: &str) -> bool {
+    address.eq_ignore_ascii_case(NULL_ADDRESS) || address.eq_ignore_ascii_case(NATIVE_ADDRESS)
+}
+
+/// Check if the pair is made of the network native token (null or 0xeee..e address) and its wrapper (e.g. ETH/WETH)
+pub fn is_wrapper_pair(network: &Network, base: &str, quote: &str) -> bool {
+    let wrapper = |t: &str| t.eq_ignore_ascii_case(&network.eth);
+    (is_native(base) && wrapper(quote)) || (wrapper(base) && is_native(quote))
+}
+
 /// Check if a component has the desired tokens
 pub fn matchcp(cptks: Vec<SrzToken>, tokens: Vec<SrzToken>) -> bool {
     tokens.iter().all(|token| cptks.iter().any(|cptk| cptk.address.eq_ignore_ascii_case(&token.address)))
 }
+
+impl Orderbook {
+    /// Returns the input and output tokens for a given side, with the average spot price (output per input) and the ETH worth of one unit of output
+    pub fn direction(&self, side: Side) -> (SrzToken, SrzToken, f64, f64) {
+        let mean = |prices: &Vec<f64>| if prices.is_empty() { 0. } else { prices.iter().sum::<f64>() / prices.len() as f64 };
+        match side {
+            Side::Bid => (self.base.clone(), self.quote.clone(), mean(&self.prices_base_to_quote), self.quote_worth_eth),
+            Side::Ask => (self.quote.clone(), self.base.clone(), mean(&self.prices_quote_to_base), self.base_worth_eth),
+        }
+    }
+
+    /// Quotes our trade after a prior trade (e.g. a pending one seen in the mempool) has been executed on the pools
+    /// The prior trade is optimized like any other, then each pool state is advanced with its share of the prior trade, using the protosims 'get_amount_out' new state.
+    /// Protosims must be the ones of the book pools (see OrderbookProvider::protosims), they are cloned and never mutated.
+    /// Returns None if the prior trade can't be applied to one of the pools.
+    pub fn quote_after(&self, pts: &[ProtoSimComp], prior_trade: (Side, f64), our_trade: (Side, f64)) -> Option<TradeResult> {
+        let (prior_side, prior_amount) = prior_trade;
+        let (from, to, spot, worth) = self.direction(prior_side);
+        let prior = maths::opti::gradient(prior_amount, pts, from.clone(), to.clone(), self.eth_usd, self.gas_price, spot, worth);
+        let (tkin, tkout) = (Token::from(from.clone()), Token::from(to.clone()));
+        let amountpow = (prior_amount * 10f64.powi(from.decimals as i32)).round();
+        let mut advanced = Vec::with_capacity(pts.len());
+        for (x, pt) in pts.iter().enumerate() {
+            let share = prior.distribution.get(x).copied().unwrap_or_default();
+            if share <= 0. {
+                advanced.push(pt.clone());
+                continue;
+            }
+            let alloc = BigUint::from((amountpow * share / ONE_HD) as u128);
+            match pt.protosim.get_amount_out(alloc, &tkin, &tkout) {
+                Ok(result) => advanced.push(ProtoSimComp {
+                    component: pt.component.clone(),
+                    protosim: result.new_state,
+                }),
+                Err(e) => {
+                    tracing::error!("Failed to apply prior trade on pool {}: {:?}", pt.component.id, e);
+                    return None;
+                }
+            }
+        }
+        let (our_side, our_amount) = our_trade;
+        let (from, to, spot, worth) = self.direction(our_side);
+        Some(maths::opti::gradient(our_amount, &advanced, from, to, self.eth_usd, self.gas_price, spot, worth))
+    }
+
+    /// Effective price (output per input, net of gas) of a trade of `size` on the given side, for each of the given gas prices (in wei)
+    /// The trade is re-optimized at each gas price, so the split may change too (gas pushes towards fewer pools). Protosims must be the ones of the book pools.
+    pub fn gas_sensitivity(&self, pts: &[ProtoSimComp], side: Side, size: f64, gas_prices: Vec<u128>) -> Vec<(u128, f64)> {
+        let (from, to, spot, worth) = self.direction(side);
+        gas_prices
+            .into_iter()
+            .map(|gas_price| {
+                let trade = maths::opti::gradient(size, pts, from.clone(), to.clone(), self.eth_usd, gas_price, spot, worth);
+                (gas_price, trade.average_sell_price)
+            })
+            .collect()
+    }
+
+    /// Liquidity distribution of each pool, indexed by component ID, with amount0 in base and amount1 in quote (p0to1 in base per quote)
+    /// Concentrated liquidity pools (Uniswap v3/v4) have one bucket per initialized tick range (see maths::ticks::range_amounts), the others a single bucket with their balances
+    /// Protosims must be the ones of the book pools (see OrderbookProvider::protosims)
+    pub fn tick_liquidity(&self, pts: &[ProtoSimComp]) -> HashMap<String, Vec<LiquidityTickAmounts>> {
+        let base_first = self.base.address.to_lowercase() < self.quote.address.to_lowercase();
+        let (t0, t1) = if base_first { (self.base.clone(), self.quote.clone()) } else { (self.quote.clone(), self.base.clone()) };
+        let mut output = HashMap::new();
+        for pt in pts.iter() {
+            let id = pt.component.id.to_lowercase();
+            let any = pt.protosim.as_any();
+            let ticks = if let Some(state) = any.downcast_ref::<UniswapV3State>() {
+                let srz = SrzUniswapV3State::from((state.clone(), id.clone()));
+                Some((srz.sqrt_price, srz.ticks))
+            } else {
+                any.downcast_ref::<UniswapV4State>().map(|state| {
+                    let srz = SrzUniswapV4State::from((state.clone(), id.clone()));
+                    (srz.sqrt_price, srz.ticks)
+                })
+            };
+            let buckets = match ticks {
+                Some((sqrt_price, ticks)) => {
+                    let amounts = maths::ticks::range_amounts(sqrt_price, &ticks, t0.clone(), t1.clone());
+                    // Ticks amounts follow the pool token order (token0 < token1)
+                    match base_first {
+                        true => amounts,
+                        false => amounts
+                            .into_iter()
+                            .map(|a| LiquidityTickAmounts {
+                                index: a.index,
+                                amount0: a.amount1,
+                                amount1: a.amount0,
+                                p0to1: a.p1to0,
+                                p1to0: a.p0to1,
+                            })
+                            .collect(),
+                    }
+                }
+                None => {
+                    let Some(x) = self.pools.iter().position(|pool| pool.id.eq_ignore_ascii_case(&id)) else {
+                        continue;
+                    };
+                    let at = |values: &Vec<f64>| values.get(x).copied().unwrap_or_default();
+                    vec![LiquidityTickAmounts {
+                        index: 0,
+                        amount0: at(&self.base_lqdty),
+                        amount1: at(&self.quote_lqdty),
+                        p0to1: at(&self.prices_quote_to_base),
+                        p1to0: at(&self.prices_base_to_quote),
+                    }]
+                }
+            };
+            output.insert(id, buckets);
+        }
+        output
+    }
+
+    /// Whether the best bid exceeds the best ask (negative spread), e.g. because of stale pools: such a book shouldn't be trusted
+    /// Best bid and ask are the first trades of each side, in quote per base. A book missing a side isn't crossed.
+    pub fn is_crossed(&self) -> bool {
+        match (self.bids.first(), self.asks.first()) {
+            (Some(bid), Some(ask)) => {
+                let best_ask = if ask.average_sell_price > 0. { 1. / ask.average_sell_price } else { f64::INFINITY };
+                bid.average_sell_price > best_ask
+            }
+            _ => false,
+        }
+    }
+
+    /// Sets the crossed flag of the book (see is_crossed), with a warning if it's crossed. Returns the flag
+    pub fn flag_crossed(&mut self) -> bool {
+        self.crossed = self.is_crossed();
+        if self.crossed {
+            tracing::warn!("Orderbook {} at block {} is crossed: best bid is above the best ask", self.tag, self.block);
+        }
+        self.crossed
+    }
+
+    /// Assesses the quality of the book at the given (current) block. Stale takes precedence over Crossed, and Crossed over Thin.
+    /// Best bid and ask are the first trades of each side, in quote per base.
+    pub fn quality(&self, block: u64, config: &BookQualityConfig) -> BookQuality {
+        if block.saturating_sub(self.block) > config.max_age_blocks {
+            return BookQuality::Stale;
+        }
+        if self.is_crossed() {
+            return BookQuality::Crossed;
+        }
+        let depth = self.aggregated_balance_base_worth_usd + self.aggregated_balance_quote_worth_usd;
+        if self.bids.is_empty() || self.asks.is_empty() || depth < config.min_depth_usd {
+            return BookQuality::Thin;
+        }
+        BookQuality::Healthy
+    }
+
+    /// Returns the single pool giving the best output, net of gas, for the given trade, with the trade result routed 100% through it
+    /// For small trades, splitting across pools costs more gas than it saves in price impact, so a single pool is often the best route.
+    /// Protosims must be the ones of the book pools (see OrderbookProvider::protosims). Returns None if no pool can quote the trade.
+    pub fn best_single_pool(&self, pts: &[ProtoSimComp], side: Side, amount: f64) -> Option<(String, TradeResult)> {
+        let (from, to, spot, worth) = self.direction(side);
+        let mut best: Option<(String, TradeResult)> = None;
+        for (x, pt) in pts.iter().enumerate() {
+            let mut distribution = vec![0.; pts.len()];
+            distribution[x] = ONE_HD;
+            let result = maths::opti::evaluate(amount, pts, from.clone(), to.clone(), &distribution, self.eth_usd, self.gas_price, spot, worth);
+            if result.output > 0. && best.as_ref().map(|(_, b)| result.output > b.output).unwrap_or(true) {
+                best = Some((pt.component.id.clone(), result));
+            }
+        }
+        best
+    }
+
+    /// Expected output (output token, human-readable) for a trade of `amount` input on the given side, None if the side has no trade
+    /// The output is linearly interpolated between the optimized trades of the book, beyond them the price of the nearest one is used.
+    pub fn expected_output(&self, side: Side, amount: f64) -> Option<f64> {
+        let trades = match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        };
+        let mut points = trades.iter().filter(|t| t.amount > 0.).map(|t| (t.amount, t.output)).collect::<Vec<(f64, f64)>>();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let (first, last) = (points.first().copied()?, points.last().copied()?);
+        let expected = if amount <= first.0 {
+            amount * first.1 / first.0
+        } else if amount >= last.0 {
+            amount * last.1 / last.0
+        } else {
+            let x = points.windows(2).position(|w| amount <= w[1].0).unwrap_or_default();
+            let ((x0, y0), (x1, y1)) = (points[x], points[x + 1]);
+            y0 + (y1 - y0) * (amount - x0) / (x1 - x0)
+        };
+        Some(expected)
+    }
+
+    /// Average price (quote per base) of a trade sized to each USD notional (e.g. 1k, 5k, 10k), to compare the books of pairs with different prices
+    /// Each notional is converted in input token with eth_usd and the input worth in ETH, then quoted with expected_output. Steps that can't be sized or quoted are skipped
+    pub fn depth_at_notionals(&self, side: Side, usd_steps: Vec<f64>) -> Vec<(f64, f64)> {
+        let input_worth_usd = match side {
+            Side::Bid => self.base_worth_eth * self.eth_usd,
+            Side::Ask => self.quote_worth_eth * self.eth_usd,
+        };
+        if input_worth_usd <= 0. {
+            return vec![];
+        }
+        usd_steps
+            .into_iter()
+            .filter(|usd| *usd > 0.)
+            .filter_map(|usd| {
+                let amount = usd / input_worth_usd;
+                let output = self.expected_output(side, amount).filter(|output| *output > 0.)?;
+                let price = match side {
+                    Side::Bid => output / amount,
+                    Side::Ask => amount / output,
+                };
+                Some((usd, price))
+            })
+            .collect()
+    }
+
+    /// Normalized skew of the book, in [-1, 1]: (bid impact - ask impact) / (bid impact + ask impact), summed over the SKEW_NOTIONALS_USD sizes covered by both sides
+    /// The price impact of a side at a USD size is interpolated between its levels (see price_impact_at), so the result doesn't depend on the steps grid of each side.
+    /// Positive when selling quote for base moves the price less than selling base for quote, 0 if no size is covered by both sides.
+    pub fn skew(&self) -> f64 {
+        let (bid_worth_usd, ask_worth_usd) = (self.base_worth_eth * self.eth_usd, self.quote_worth_eth * self.eth_usd);
+        if bid_worth_usd <= 0. || ask_worth_usd <= 0. {
+            return 0.;
+        }
+        let (mut bid_impact, mut ask_impact) = (0., 0.);
+        for usd in SKEW_NOTIONALS_USD.iter() {
+            if let (Some(bid), Some(ask)) = (self.price_impact_at(Side::Bid, usd / bid_worth_usd), self.price_impact_at(Side::Ask, usd / ask_worth_usd)) {
+                bid_impact += bid;
+                ask_impact += ask;
+            }
+        }
+        if bid_impact + ask_impact <= 0. {
+            return 0.;
+        }
+        (bid_impact - ask_impact) / (bid_impact + ask_impact)
+    }
+
+    /// Price impact (0-1) of a trade of `amount` input on the given side, linearly interpolated between the trades of the book (from 0 below the smallest one)
+    /// None beyond the largest trade, or if the side has no trade.
+    pub fn price_impact_at(&self, side: Side, amount: f64) -> Option<f64> {
+        let trades = match side {
+            Side::Bid => &self.bids,
+            Side::Ask => &self.asks,
+        };
+        let mut points = trades.iter().filter(|t| t.amount > 0.).map(|t| (t.amount, t.price_impact)).collect::<Vec<(f64, f64)>>();
+        points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+        let (first, last) = (points.first().copied()?, points.last().copied()?);
+        if amount > last.0 {
+            return None;
+        }
+        if amount <= first.0 {
+            return Some(first.1 * amount / first.0);
+        }
+        let x = points.windows(2).position(|w| amount <= w[1].0).unwrap_or_default();
+        let ((x0, y0), (x1, y1)) = (points[x], points[x + 1]);
+        Some(y0 + (y1 - y0) * (amount - x0) / (x1 - x0))
+    }
+
+    /// Minimum amount received (output token, human-readable) for a trade of `amount` input on the given side, with a slippage (e.g. 0.0025 = 0.25%)
+    /// Based on the expected output (see expected_output), 0 if the side has no trade.
+    /// The result is truncated to the output decimals, like the checked amount enforced by the execution (see exec::solution) for the same expected output and slippage.
+    pub fn min_received(&self, side: Side, amount: f64, slippage: f64) -> f64 {
+        let output = match side {
+            Side::Bid => &self.quote,
+            Side::Ask => &self.base,
+        };
+        let Some(expected) = self.expected_output(side, amount) else {
+            return 0.;
+        };
+        let pow = 10f64.powi(output.decimals as i32);
+        (expected * pow * (1. - slippage)).floor() / pow
+    }
+
+    /// Probability that a resting order at `price` (quote per base) gets filled within `horizon_blocks`, with the random walk model (see maths::fill::RandomWalkFillModel)
+    /// A first-cut heuristic, seeded by the book volatility: 0 if the volatility is unknown, unless the order is marketable
+    pub fn fill_probability(&self, price: f64, side: Side, horizon_blocks: u64) -> f64 {
+        self.fill_probability_with(&RandomWalkFillModel, price, side, horizon_blocks)
+    }
+
+    /// Same as fill_probability, with the given fill model
+    pub fn fill_probability_with(&self, model: &dyn FillModel, price: f64, side: Side, horizon_blocks: u64) -> f64 {
+        model.probability(self.mpd_base_to_quote.mid, price, side, self.volatility, horizon_blocks)
+    }
+
+    /// Converts the USD figures of the book (eth_usd, gas costs, TVL) to another currency (e.g. "EUR"), with its amount for 1 USD, and records the currency
+    /// Figures keep their '_usd' names. Rates are given by the caller (FX source or static config). Fails if the book was already converted or the rate isn't positive.
+    /// Methods pricing in ETH then work in that currency (e.g. the gas costs of requotes, the notionals of depth_at_notionals), while rescale_usd refuses a converted book.
+    /// The crate has no API envelope: a server exposing a '?currency=' param converts the book with this before wrapping it.
+    pub fn convert_currency(&mut self, currency: &str, usd_rate: f64) -> Result<(), anyhow::Error> {
+        if let Some(current) = &self.currency {
+            return Err(anyhow::anyhow!("Orderbook figures are already in {}", current));
+        }
+        if usd_rate <= 0. || !usd_rate.is_finite() {
+            return Err(anyhow::anyhow!("Invalid USD to {} rate: {}", currency, usd_rate));
+        }
+        if currency.eq_ignore_ascii_case("USD") {
+            return Ok(());
+        }
+        self.eth_usd *= usd_rate;
+        self.aggregated_balance_base_worth_usd *= usd_rate;
+        self.aggregated_balance_quote_worth_usd *= usd_rate;
+        let trades = self.bids.iter_mut().chain(self.asks.iter_mut()).chain(self.bids_exact_out.iter_mut()).chain(self.asks_exact_out.iter_mut());
+        for trade in trades.chain(self.bids_chart.iter_mut()).chain(self.asks_chart.iter_mut()) {
+            trade.gas_costs_usd.iter_mut().for_each(|cost| *cost *= usd_rate);
+        }
+        self.currency = Some(currency.to_uppercase());
+        Ok(())
+    }
+
+    /// Copy of the book with its USD-derived figures (eth_usd, gas costs, TVL) recomputed for another ETH price, without re-optimizing
+    /// Token amounts don't depend on the ETH price, so bids/asks are kept as is. Returns an unchanged copy if either price isn't positive, or if the book was converted to another currency.
+    pub fn rescale_usd(&self, eth_usd: f64) -> Orderbook {
+        let mut book = self.clone();
+        if let Some(currency) = &self.currency {
+            tracing::warn!("Can't rescale the {} book to an ETH price in USD, its figures are in {}", self.tag, currency);
+            return book;
+        }
+        if self.eth_usd <= 0. || eth_usd <= 0. || !eth_usd.is_finite() {
+            tracing::warn!("Can't rescale the {} book from ETH at {} to {} USD", self.tag, self.eth_usd, eth_usd);
+            return book;
+        }
+        let ratio = eth_usd / self.eth_usd;
+        book.eth_usd = eth_usd;
+        book.aggregated_balance_base_worth_usd *= ratio;
+        book.aggregated_balance_quote_worth_usd *= ratio;
+        let trades = book.bids.iter_mut().chain(book.asks.iter_mut()).chain(book.bids_exact_out.iter_mut()).chain(book.asks_exact_out.iter_mut());
+        for trade in trades.chain(book.bids_chart.iter_mut()).chain(book.asks_chart.iter_mut()) {
+            trade.gas_costs_usd.iter_mut().for_each(|cost| *cost *= ratio);
+        }
+        book
+    }
+
+    /// Flags a book built without valuation of its tokens (see usd_unavailable), zeroing the figures derived from the placeholder worths
+    /// Gas costs in USD are kept, as they only depend on the ETH price
+    pub fn unvalued(&mut self) {
+        self.usd_unavailable = true;
+        self.base_worth_eth = 0.;
+        self.quote_worth_eth = 0.;
+        self.aggregated_balance_base_worth_usd = 0.;
+        self.aggregated_balance_quote_worth_usd = 0.;
+    }
+
+    /// Protocol system holding most of the book liquidity (in ETH worth of both tokens), or the most frequent one if the liquidity is unknown
+    pub fn dominant_protocol(&self) -> Option<String> {
+        let mut shares: HashMap<String, f64> = HashMap::new();
+        let known = self.base_lqdty.len() == self.pools.len() && self.quote_lqdty.len() == self.pools.len();
+        for (x, pool) in self.pools.iter().enumerate() {
+            let worth = if known { self.base_lqdty[x] * self.base_worth_eth + self.quote_lqdty[x] * self.quote_worth_eth } else { 1. };
+            *shares.entry(pool.protocol_system.clone()).or_default() += worth;
+        }
+        shares.into_iter().max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal)).map(|(protocol, _)| protocol)
+    }
+
+    /// Average fee (in bps) paid by a trade of the book, weighted by its distribution across the book pools
+    pub fn blended_fee_bps(&self, trade: &TradeResult) -> f64 {
+        self.pools.iter().zip(trade.distribution.iter()).map(|(pool, pct)| pool.fee as f64 * pct / ONE_HD).sum()
+    }
+
+    /// Transforms the independent optimized trades into cumulative levels, with sizes in base and prices in quote per base
+    /// Each trade of the ladder is a standalone quote for its whole amount, so each level's size already includes all smaller trades,
+    /// and the marginal price is the price of the increment between two consecutive trades.
+    pub fn cumulative(&self) -> CumulativeOrderbook {
+        // Bids sell base for quote, asks sell quote for base
+        let bids = self.bids.iter().map(|t| (t.amount, t.output)).collect::<Vec<_>>();
+        let asks = self.asks.iter().map(|t| (t.output, t.amount)).collect::<Vec<_>>();
+        CumulativeOrderbook {
+            base: self.base.clone(),
+            quote: self.quote.clone(),
+            bids: levels(bids),
+            asks: levels(asks),
+        }
+    }
+}
+
+/// Builds cumulative levels from (size in base, total in quote) points
+/// Points are sorted by size, and those not increasing the size are dropped.
+fn levels(mut points: Vec<(f64, f64)>) -> Vec<CumulativeLevel> {
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let mut output: Vec<CumulativeLevel> = Vec::with_capacity(points.len());
+    let (mut size, mut total) = (0., 0.);
+    for (x, y) in points {
+        if x <= size || x <= 0. {
+            continue;
+        }
+        output.push(CumulativeLevel {
+            size: x,
+            total: y,
+            price: y / x,
+            marginal_price: (y - total) / (x - size),
+        });
+        (size, total) = (x, y);
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::core::oracle::FixedOracle;
+    use crate::types::{OrderbookError, SinglePointSimulation};
+    use crate::core::solver::DefaultOrderbookSolver;
+    use crate::utils::fixtures::{self, DAI, USDC, WETH};
+
+    #[tokio::test]
+    async fn test_simulate_padded_chart() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 3_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            pad_levels: true,
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        // Zero-output levels only go to the chart levels, never to the tradable ones
+        assert!(book.bids.iter().chain(book.asks.iter()).all(|t| t.output > 0.));
+        assert!(book.bids_chart.len() > book.bids.len());
+        assert!(book.bids_chart.iter().any(|t| t.output == 0.));
+        assert!(book.bids_chart.last().unwrap().amount <= book.bids.last().unwrap().amount);
+    }
+
+    #[tokio::test]
+    async fn test_simulate_with_components() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 3_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            with_components: true,
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        let ids = book.pools.iter().map(|pool| pool.id.clone()).collect::<Vec<String>>();
+        for trade in book.bids.iter().chain(book.asks.iter()) {
+            assert_eq!(trade.component_ids.as_ref(), Some(&ids));
+            assert_eq!(trade.distribution.len(), ids.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_sink() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 3_000_000.)]);
+        let sink = std::sync::Arc::new(fixtures::CapturingMetricsSink::default());
+        let solver = DefaultOrderbookSolver {
+            config: solver::OrderbookSolverConfig {
+                metrics: sink.clone(),
+                ..Default::default()
+            },
+        };
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let book = simulate(solver, Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005).await.unwrap();
+        assert_eq!(*sink.builds.lock().unwrap(), vec![(book.tag.clone(), 2)]);
+        assert!(*sink.steps.lock().unwrap() >= book.bids.len() + book.asks.len());
+    }
+
+    #[tokio::test]
+    async fn test_effective_fee() {
+        let mut pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 1_000., 2_000_000.)];
+        pools[1].component.fee = 5;
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 2_000.), (USDC.to_string(), 4_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005).await.unwrap();
+        assert_eq!(book.fees_bps, vec![30, 5]);
+        assert!(book.effective_fee_bps >= 5. && book.effective_fee_bps <= 30.);
+        assert_eq!(book.effective_fee_bps, book.blended_fee_bps(&book.bids[0]));
+    }
+
+    #[tokio::test]
+    async fn test_clip_levels() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_000.), (USDC.to_string(), 2_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        // The pool only holds 200k USDC out of the 2M its reserves tell: the largest bids are partial fills of the same amount
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[1_000.], &[200_000.], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        assert!(book.bids.iter().all(|bid| bid.output <= 200_000. * (1. + 1e-3)));
+        assert!(book.bids.windows(2).all(|pair| pair[1].amount > pair[0].amount));
+        assert_eq!(book.bids.iter().filter(|bid| bid.capped).count(), 1);
+        assert!(book.bids.last().unwrap().capped);
+        assert!(book.asks.iter().all(|ask| !ask.capped));
+    }
+
+    #[tokio::test]
+    async fn test_steps_per_side() {
+        // Coarse grid on bids, fine one on asks
+        let pools = vec![fixtures::pool("0x01", 100., 2_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 100.), (USDC.to_string(), 2_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        fn coarse(liquidity: f64) -> Vec<f64> {
+            vec![liquidity / 1_000., liquidity / 100.]
+        }
+        let solver = DefaultOrderbookSolver {
+            config: solver::OrderbookSolverConfig {
+                steps_bid: Some(coarse),
+                steps_ask: Some(solver::linear),
+                ..Default::default()
+            },
+        };
+        assert_eq!(solver.generate_side_steps(Side::Bid, 1_000.), coarse(1_000.));
+        assert_eq!(solver.generate_side_steps(Side::Ask, 1_000.), solver::linear(1_000.));
+        let book = simulate(solver, Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 20_000., 0.00005).await.unwrap();
+        assert_eq!(book.bids.len(), 2);
+        assert!(book.asks.len() > book.bids.len());
+    }
+
+    #[tokio::test]
+    async fn test_probe_fraction() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_000.), (USDC.to_string(), 2_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let solver = |probe_fraction| DefaultOrderbookSolver {
+            config: solver::OrderbookSolverConfig {
+                probe_fraction,
+                ..Default::default()
+            },
+        };
+        let mut spreads = vec![];
+        let mut levels = vec![];
+        for fraction in [1e-6, 1e-2] {
+            let book = simulate(solver(Some(fraction)), Network::default(), MarketContext::default(), pools.clone(), tokens.clone(), params.clone(), balances.clone(), &[], &[], 1., 0.0005, 2000., 0.0005)
+                .await
+                .unwrap();
+            assert!((book.mpd_base_to_quote.amount - 1_000. * fraction).abs() < 1e-9);
+            spreads.push(book.mpd_base_to_quote.spread_pct);
+            levels.push((book.bids.len(), book.asks.len()));
+        }
+        assert!(spreads[1] > spreads[0], "spreads: {:?}", spreads);
+        // The steps filtered out as dust don't depend on the probe
+        assert_eq!(levels[0], levels[1]);
+        assert!((probe_amount(None, 1_000., 0.0005) - 20.).abs() < 1e-9); // 0.01 ETH worth of USDC
+    }
+
+    #[tokio::test]
+    async fn test_price_impact_truncation() {
+        // Shallow pool: the deep steps move its price by several %
+        let pools = vec![fixtures::pool("0x01", 10., 20_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 10.), (USDC.to_string(), 20_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let solver = |max_price_impact| DefaultOrderbookSolver {
+            config: solver::OrderbookSolverConfig {
+                max_price_impact,
+                ..Default::default()
+            },
+        };
+        let full = simulate(solver(None), Network::default(), MarketContext::default(), pools.clone(), tokens.clone(), params.clone(), balances.clone(), &[], &[], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        assert_eq!(full.truncated, 0);
+        assert!(full.bids.iter().any(|trade| trade.price_impact > 0.02));
+        let book = simulate(solver(Some(0.02)), Network::default(), MarketContext::default(), pools.clone(), tokens.clone(), params.clone(), balances.clone(), &[], &[], 1., 0.0005, 2000., 0.0005).await.unwrap();
+        assert!(book.truncated > 0);
+        assert_eq!(book.bids.len() + book.asks.len() + book.truncated, full.bids.len() + full.asks.len());
+        assert!(book.bids.iter().chain(book.asks.iter()).all(|trade| trade.price_impact <= 0.02));
+        let amounts = |trades: &[TradeResult]| trades.iter().map(|trade| trade.amount).collect::<Vec<f64>>();
+        assert_eq!(amounts(&book.bids), amounts(&full.bids[..book.bids.len()]));
+        // Padding doesn't bring back the truncated steps
+        let params = OrderbookRequestParams { pad_levels: true, both_directions: true, ..params };
+        let padded = simulate(solver(Some(0.02)), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005).await.unwrap();
+        assert_eq!(padded.truncated, book.truncated);
+        assert_eq!(amounts(&padded.bids), amounts(&book.bids));
+        let largest = book.bids.iter().map(|trade| trade.amount).fold(0., f64::max);
+        assert!(!padded.bids_chart.is_empty() && padded.bids_chart.iter().all(|trade| trade.amount <= largest));
+        assert!(padded.bids_exact_out.len() <= padded.bids.len());
+    }
+
+    #[tokio::test]
+    async fn test_simulate_gas_costs_with_fixed_oracle() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 3_000_000.)]);
+        let oracle = FixedOracle(3000.);
+        let context = MarketContext {
+            block: 1,
+            eth_usd: oracle.eth_usd(&Network::default()).await.unwrap(),
+            gas_price: 2_000_000_000,
+        };
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), context, pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        assert_eq!(book.eth_usd, 3000.);
+        for trade in book.bids.iter().chain(book.asks.iter()) {
+            for (units, usd) in trade.gas_costs.iter().zip(trade.gas_costs_usd.iter()) {
+                let expected = (*units as f64 * 2_000_000_000. / 1e18) * 3000.;
+                assert!((usd - expected).abs() < 1e-9);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_assemble_from_supplied_balances() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let mut balances = HashMap::from([
+            ("0x01".to_string(), HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (USDC.to_string(), 2_000_000 * 10u128.pow(6))])),
+            ("0x02".to_string(), HashMap::from([(WETH.to_string(), 500 * 10u128.pow(18)), (USDC.to_string(), 1_000_000 * 10u128.pow(6))])),
+        ]);
+        let context = MarketContext {
+            block: 7,
+            eth_usd: 2000.,
+            gas_price: 1_000_000_000,
+        };
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let book = assemble(DefaultOrderbookSolver::default(), Network::default(), context.clone(), pools.clone(), tokens.clone(), params.clone(), &balances, 1., 0.0005)
+            .await
+            .unwrap();
+        assert_eq!(book.block, 7);
+        assert_eq!(book.base_lqdty, vec![1_000., 500.]);
+        assert_eq!(book.quote_lqdty, vec![2_000_000., 1_000_000.]);
+        assert!(!book.bids.is_empty() && !book.asks.is_empty());
+        // A component missing from the supplied balances is an error
+        balances.remove("0x02");
+        let book = assemble(DefaultOrderbookSolver::default(), Network::default(), context, pools, tokens, params, &balances, 1., 0.0005).await;
+        assert!(book.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_simulate_both_directions() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 3_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            both_directions: true,
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        assert!(!book.bids_exact_out.is_empty() && !book.asks_exact_out.is_empty());
+        assert_eq!(book.bids_exact_out.len(), book.bids.len());
+        // Receiving the output of an exact-in trade costs roughly its input
+        for (exact_in, exact_out) in book.bids.iter().zip(book.bids_exact_out.iter()) {
+            assert!((exact_out.amount - exact_in.amount).abs() / exact_in.amount < 1e-3);
+            assert!(exact_out.output >= exact_in.output * (1. - 1e-9));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fee_override_in_blended_fee() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 1_000., 2_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 2_000.), (USDC.to_string(), 4_000_000.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            point: Some(SinglePointSimulation {
+                input: WETH.to_string(),
+                amount: 100.,
+                amount_pct_of_liquidity: None,
+                denomination: None,
+            }),
+            fee_overrides: HashMap::from([("0x02".to_string(), 100)]),
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools.clone(), tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        assert_eq!(book.pools[0].fee, 30);
+        assert_eq!(book.pools[1].fee, 100);
+        let trade = &book.bids[0];
+        let expected = 30. * trade.distribution[0] / 100. + 100. * trade.distribution[1] / 100.;
+        assert!((book.blended_fee_bps(trade) - expected).abs() < 1e-9);
+        assert!(book.blended_fee_bps(trade) > 30.);
+        // The simulation itself is untouched
+        assert_eq!(pools[1].component.fee, 30);
+    }
+
+    #[tokio::test]
+    async fn test_single_point_pct_of_liquidity() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 3_000_000.)]);
+        let point = SinglePointSimulation {
+            input: WETH.to_string(),
+            amount: 0.,
+            amount_pct_of_liquidity: Some(5.),
+            denomination: None,
+        };
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            point: Some(point.clone()),
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005)
+            .await
+            .unwrap();
+        assert_eq!(book.bids.len(), 1);
+        assert!((book.bids[0].amount - 75.).abs() < 1e-9);
+        let both = SinglePointSimulation { amount: 10., ..point.clone() };
+        assert!(both.resolve(1_500.).is_err());
+        let out_of_range = SinglePointSimulation {
+            amount_pct_of_liquidity: Some(150.),
+            ..point
+        };
+        assert!(out_of_range.resolve(1_500.).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_single_point_denomination() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 3_000_000.)]);
+        let run = |input: &str, amount: f64, denomination: Option<&str>| {
+            let params = OrderbookRequestParams {
+                tag: format!("{}-{}", WETH, USDC),
+                point: Some(SinglePointSimulation {
+                    input: input.to_string(),
+                    amount,
+                    amount_pct_of_liquidity: None,
+                    denomination: denomination.map(|d| d.to_string()),
+                }),
+                ..Default::default()
+            };
+            simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools.clone(), tokens.clone(), params, balances.clone(), &[], &[], 1., 0.0005, 2000., 0.0005)
+        };
+        // Sell 1 WETH
+        let book = run(WETH, 1., None).await.unwrap();
+        assert_eq!((book.bids.len(), book.asks.len()), (1, 0));
+        assert_eq!(book.bids[0].amount, 1.);
+        assert!(book.bids[0].output > 1_990. && book.bids[0].output < 2_000.);
+        // Spend 2000 USDC
+        let book = run(USDC, 2_000., Some(USDC)).await.unwrap();
+        assert_eq!((book.bids.len(), book.asks.len()), (0, 1));
+        assert_eq!(book.asks[0].amount, 2_000.);
+        assert!(book.asks[0].output > 0.99 && book.asks[0].output < 1.);
+        // Sell WETH worth 2000 USDC, converted at the spot price
+        let book = run(WETH, 2_000., Some(USDC)).await.unwrap();
+        assert!((book.bids[0].amount - 1.).abs() < 1e-9);
+        assert!(run(WETH, 2_000., Some(DAI)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_simulate_rejects_missing_liquidity() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let balances = HashMap::from([(WETH.to_string(), 1_500.), (USDC.to_string(), 0.)]);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let book = simulate(DefaultOrderbookSolver::default(), Network::default(), MarketContext::default(), pools, tokens, params, balances, &[], &[], 1., 0.0005, 2000., 0.0005).await;
+        assert!(book.is_err());
+    }
+
+    /// Counts the balance fetches, and returns the same balances for any component
+    struct CountingBalanceSource(std::sync::atomic::AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl BalanceSource for CountingBalanceSource {
+        async fn balances(&self, _network: &Network, _id: &str, _protosys: &str, _block: u64) -> Option<HashMap<String, u128>> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Some(HashMap::from([(WETH.to_string(), 1_000), (USDC.to_string(), 2_000)]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_balance_cache_same_block() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let source = CountingBalanceSource(std::sync::atomic::AtomicUsize::new(0));
+        let cache = Mutex::new(BalanceCache::default());
+        let network = Network::default();
+        let (first, missing) = BalanceCache::fetch(&cache, &source, &network, &pools, 10).await;
+        let (second, _) = BalanceCache::fetch(&cache, &source, &network, &pools, 10).await;
+        assert!(missing.is_empty());
+        assert_eq!(first, second);
+        assert_eq!(source.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+        let stats = |cache: &BalanceCache| (cache.hits, cache.misses);
+        assert_eq!(stats(&*cache.lock().await), (2, 2));
+        // A new block invalidates the entries
+        BalanceCache::fetch(&cache, &source, &network, &pools, 11).await;
+        assert_eq!(source.0.load(std::sync::atomic::Ordering::SeqCst), 4);
+        assert_eq!(cache.lock().await.entries.len(), 2);
+    }
+
+    /// Same as CountingBalanceSource, slowed down so that concurrent builds overlap
+    struct SlowBalanceSource(std::sync::atomic::AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl BalanceSource for SlowBalanceSource {
+        async fn balances(&self, _network: &Network, _id: &str, _protosys: &str, _block: u64) -> Option<HashMap<String, u128>> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            Some(HashMap::from([(WETH.to_string(), 1_000)]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_balance_cache_concurrent_builds() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let source = SlowBalanceSource(std::sync::atomic::AtomicUsize::new(0));
+        let cache = Mutex::new(BalanceCache::default());
+        let network = Network::default();
+        // The second build waits for the components the first one reserved, instead of fetching them again
+        let ((first, _), (second, missing)) = tokio::join!(BalanceCache::fetch(&cache, &source, &network, &pools, 10), BalanceCache::fetch(&cache, &source, &network, &pools, 10));
+        assert!(missing.is_empty());
+        assert_eq!(first, second);
+        assert_eq!(source.0.load(std::sync::atomic::Ordering::SeqCst), 2);
+        let cache = cache.lock().await;
+        assert_eq!((cache.hits, cache.misses), (2, 2));
+        assert!(cache.pending.is_empty());
+        // Entries are keyed by component key, not by ID
+        assert!(cache.entries.contains_key(&pools[0].component.key()));
+    }
+
+    /// Batches all the fetches in one call, but never returns the '0x02' component
+    struct BatchBalanceSource(std::sync::atomic::AtomicUsize, std::sync::atomic::AtomicUsize);
+
+    #[async_trait::async_trait]
+    impl BalanceSource for BatchBalanceSource {
+        async fn balances(&self, _network: &Network, id: &str, _protosys: &str, _block: u64) -> Option<HashMap<String, u128>> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            (id != "0x02").then(|| HashMap::from([(WETH.to_string(), 1_000)]))
+        }
+
+        async fn balances_batch(&self, _network: &Network, components: &[(String, String)], _block: u64) -> HashMap<String, HashMap<String, u128>> {
+            self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            components.iter().filter(|(id, _)| id != "0x02").map(|(id, _)| (id.clone(), HashMap::from([(WETH.to_string(), 1_000)]))).collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_balance_cache_batch() {
+        let pools = (1..=12).map(|x| fixtures::pool(&format!("0x{:02x}", x), 1_000., 2_000_000.)).collect::<Vec<_>>();
+        let source = BatchBalanceSource(std::sync::atomic::AtomicUsize::new(0), std::sync::atomic::AtomicUsize::new(0));
+        let cache = Mutex::new(BalanceCache::default());
+        let (balances, missing) = BalanceCache::fetch(&cache, &source, &Network::default(), &pools, 10).await;
+        assert_eq!(source.1.load(std::sync::atomic::Ordering::SeqCst), 1);
+        // Only the component missing from the batch falls back to a single fetch, which fails too
+        assert_eq!(source.0.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert_eq!(missing, vec!["0x02".to_string()]);
+        assert_eq!(balances.len(), 12);
+    }
+
+    /// Returns the balances of the fixture pools, except for the '0x02' component
+    struct PartialBalanceSource;
+
+    #[async_trait::async_trait]
+    impl BalanceSource for PartialBalanceSource {
+        async fn balances(&self, _network: &Network, id: &str, _protosys: &str, _block: u64) -> Option<HashMap<String, u128>> {
+            (id != "0x02").then(|| HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (USDC.to_string(), 2_000_000 * 10u128.pow(6))]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_balances() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let cache = Mutex::new(BalanceCache::default());
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let book = build_from(&PartialBalanceSource, DefaultOrderbookSolver::default(), &FixedOracle(2_000.), fixtures::offline_network(), pools, 1, tokens, params, 1., 1. / 2_000., &cache)
+            .await
+            .unwrap();
+        assert_eq!(book.missing_balances, vec!["0x02".to_string()]);
+        // The book is still built, from the liquidity of the other pool only
+        assert!(!book.bids.is_empty() && !book.asks.is_empty());
+        assert!((book.base_lqdty.iter().sum::<f64>() - 1_000.).abs() < 1e-6);
+        assert!((book.quote_lqdty.iter().sum::<f64>() - 2_000_000.).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_tick_liquidity_single_bucket() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            pools: vec![pools[0].component.clone()],
+            base_lqdty: vec![1_000.],
+            quote_lqdty: vec![2_000_000.],
+            prices_base_to_quote: vec![2_000.],
+            prices_quote_to_base: vec![0.0005],
+            ..Default::default()
+        };
+        let buckets = book.tick_liquidity(&pools);
+        assert_eq!(buckets.len(), 1);
+        let bucket = &buckets["0x01"];
+        assert_eq!(bucket.len(), 1);
+        assert_eq!((bucket[0].amount0, bucket[0].amount1, bucket[0].p1to0), (1_000., 2_000_000., 2_000.));
+    }
+
+    #[test]
+    fn test_gas_sensitivity() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            eth_usd: 2000.,
+            prices_base_to_quote: vec![2000.],
+            quote_worth_eth: 0.0005,
+            ..Default::default()
+        };
+        let gas_prices = vec![0, 1_000_000_000, 50_000_000_000, 200_000_000_000];
+        let sensitivity = book.gas_sensitivity(&pools, Side::Bid, 1., gas_prices.clone());
+        assert_eq!(sensitivity.iter().map(|(gas, _)| *gas).collect::<Vec<u128>>(), gas_prices);
+        for pair in sensitivity.windows(2) {
+            assert!(pair[1].1 < pair[0].1);
+        }
+    }
+
+    #[test]
+    fn test_weighted_mid_shifts_toward_deeper_side() {
+        let b2q = fixtures::trade(1., 2_010.);
+        let q2b = fixtures::trade(1_990., 1.);
+        // 3x more quote than base: the ask (selling base for quote) is the deeper side
+        let mpd = derive_mid_price(b2q.clone(), q2b.clone(), 3_000_000., 1_000_000.);
+        assert!((mpd.mid - 2_000.).abs() < 1e-9);
+        assert!((mpd.weighted_mid - 2_005.).abs() < 1e-9);
+        let mpd = derive_mid_price(b2q.clone(), q2b.clone(), 1_000_000., 3_000_000.);
+        assert!((mpd.weighted_mid - 1_995.).abs() < 1e-9);
+        // Unknown liquidity: same as the simple mid
+        assert!((derive_mid_price(b2q, q2b, 0., 1_000_000.).weighted_mid - 2_000.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_min_received() {
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            bids: vec![fixtures::trade(2., 3_980.), fixtures::trade(1., 2_000.), fixtures::trade(4., 7_800.)],
+            asks: vec![fixtures::trade(2_010., 1.)],
+            ..Default::default()
+        };
+        assert_eq!(book.min_received(Side::Bid, 1., 0.), 2_000.);
+        assert_eq!(book.min_received(Side::Bid, 3., 0.), 5_890.);
+        assert_eq!(book.min_received(Side::Bid, 0.5, 0.), 1_000.);
+        assert_eq!(book.min_received(Side::Bid, 8., 0.), 15_600.);
+        assert!((book.min_received(Side::Bid, 1., 0.0025) - 1_995.).abs() < 1e-6);
+        // Truncated to the output decimals (USDC: 6)
+        assert_eq!(book.min_received(Side::Bid, 1. / 3., 0.), 666.666666);
+        assert!(book.min_received(Side::Ask, 1_005., 0.01) < 0.5);
+        let empty = Orderbook::default();
+        assert_eq!(empty.min_received(Side::Bid, 1., 0.), 0.);
+    }
+
+    #[test]
+    fn test_expected_output() {
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            bids: vec![fixtures::trade(1., 2_000.), fixtures::trade(2., 3_980.)],
+            asks: vec![fixtures::trade(2_010., 1.)],
+            ..Default::default()
+        };
+        // A probe amount on a level of the book gets exactly that level output
+        for level in book.bids.iter() {
+            assert_eq!(book.expected_output(Side::Bid, level.amount), Some(level.output));
+        }
+        assert_eq!(book.expected_output(Side::Ask, 2_010.), Some(1.));
+        assert_eq!(book.expected_output(Side::Bid, 1.5), Some(2_990.));
+        assert_eq!(Orderbook::default().expected_output(Side::Ask, 1.), None);
+    }
+
+    #[test]
+    fn test_depth_at_notionals() {
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            bids: vec![fixtures::trade(1., 2_000.), fixtures::trade(2., 3_980.)],
+            asks: vec![fixtures::trade(2_010., 1.), fixtures::trade(4_040., 2.)],
+            eth_usd: 2_000.,
+            base_worth_eth: 1.,
+            quote_worth_eth: 1. / 2_000.,
+            ..Default::default()
+        };
+        let bids = book.depth_at_notionals(Side::Bid, vec![2_000., 3_000., 4_000.]);
+        assert_eq!(bids.len(), 3);
+        assert!((bids[0].1 - 2_000.).abs() < 1e-6);
+        assert!((bids[1].1 - 2_990. / 1.5).abs() < 1e-6);
+        assert!((bids[2].1 - 1_990.).abs() < 1e-6);
+        let asks = book.depth_at_notionals(Side::Ask, vec![2_010., 0.]);
+        assert_eq!(asks.len(), 1);
+        assert!((asks[0].1 - 2_010.).abs() < 1e-6);
+        assert!(Orderbook::default().depth_at_notionals(Side::Bid, vec![1_000.]).is_empty());
+    }
+
+    #[test]
+    fn test_cumulative_levels() {
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            bids: vec![fixtures::trade(2., 3_980.), fixtures::trade(1., 2_000.), fixtures::trade(4., 7_800.)],
+            asks: vec![fixtures::trade(2_010., 1.), fixtures::trade(4_060., 2.)],
+            ..Default::default()
+        };
+        let cumulative = book.cumulative();
+        let sizes = cumulative.bids.iter().map(|l| l.size).collect::<Vec<_>>();
+        assert_eq!(sizes, vec![1., 2., 4.]);
+        assert_eq!(cumulative.bids[1].price, 1_990.);
+        assert_eq!(cumulative.bids[1].marginal_price, 1_980.);
+        assert_eq!(cumulative.bids[2].marginal_price, 1_910.);
+        assert_eq!(cumulative.asks[0].price, 2_010.);
+        assert_eq!(cumulative.asks[1].total, 4_060.);
+        assert_eq!(cumulative.asks[1].marginal_price, 2_050.);
+    }
+
+    #[test]
+    fn test_book_quality() {
+        let config = BookQualityConfig::default();
+        let healthy = Orderbook {
+            block: 100,
+            bids: vec![fixtures::trade(1., 1_990.)],
+            asks: vec![fixtures::trade(2_010., 1.)],
+            aggregated_balance_base_worth_usd: 1_000_000.,
+            aggregated_balance_quote_worth_usd: 1_000_000.,
+            ..Default::default()
+        };
+        assert_eq!(healthy.quality(100, &config), BookQuality::Healthy);
+        assert_eq!(healthy.quality(100 + config.max_age_blocks + 1, &config), BookQuality::Stale);
+        let crossed = Orderbook {
+            bids: vec![fixtures::trade(1., 2_050.)],
+            ..healthy.clone()
+        };
+        assert_eq!(crossed.quality(100, &config), BookQuality::Crossed);
+        assert!(!healthy.is_crossed());
+        let mut flagged = crossed.clone();
+        assert!(flagged.flag_crossed() && flagged.crossed);
+        let thin = Orderbook {
+            aggregated_balance_base_worth_usd: 100.,
+            aggregated_balance_quote_worth_usd: 100.,
+            ..healthy.clone()
+        };
+        assert_eq!(thin.quality(100, &config), BookQuality::Thin);
+        let empty = Orderbook { asks: vec![], ..healthy };
+        assert_eq!(empty.quality(100, &config), BookQuality::Thin);
+    }
+
+    #[test]
+    fn test_convert_currency() {
+        let mut bid = fixtures::trade(1., 2_000.);
+        bid.gas_costs_usd = vec![2.];
+        let mut book = Orderbook {
+            bids_chart: vec![bid.clone()],
+            bids: vec![bid],
+            eth_usd: 2_000.,
+            aggregated_balance_base_worth_usd: 1_000.,
+            ..Default::default()
+        };
+        book.convert_currency("eur", 0.9).unwrap();
+        assert_eq!(book.currency.as_deref(), Some("EUR"));
+        assert!((book.eth_usd - 1_800.).abs() < 1e-9);
+        assert!((book.aggregated_balance_base_worth_usd - 900.).abs() < 1e-9);
+        assert!((book.bids[0].gas_costs_usd[0] - 1.8).abs() < 1e-9);
+        assert!((book.bids_chart[0].gas_costs_usd[0] - 1.8).abs() < 1e-9);
+        // Prices in tokens are unchanged
+        assert_eq!(book.bids[0].output, 2_000.);
+        assert!(book.convert_currency("GBP", 0.8).is_err());
+        // The ETH price is no longer in USD
+        assert_eq!(book.rescale_usd(3_000.).eth_usd, book.eth_usd);
+        assert!(Orderbook::default().convert_currency("GBP", 0.).is_err());
+    }
+
+    #[test]
+    fn test_rescale_usd() {
+        let mut bid = fixtures::trade(1., 2_000.);
+        bid.gas_costs_usd = vec![2., 1.];
+        let mut ask = fixtures::trade(2_000., 0.99);
+        ask.gas_costs_usd = vec![3.];
+        let book = Orderbook {
+            bids_chart: vec![bid.clone()],
+            asks_chart: vec![ask.clone()],
+            bids: vec![bid],
+            asks: vec![ask],
+            eth_usd: 2_000.,
+            aggregated_balance_base_worth_usd: 1_000.,
+            aggregated_balance_quote_worth_usd: 500.,
+            ..Default::default()
+        };
+        for price in [1_000., 3_000., 4_000.] {
+            let ratio = price / 2_000.;
+            let scenario = book.rescale_usd(price);
+            assert_eq!(scenario.eth_usd, price);
+            assert!((scenario.aggregated_balance_base_worth_usd - 1_000. * ratio).abs() < 1e-9);
+            assert!((scenario.aggregated_balance_quote_worth_usd - 500. * ratio).abs() < 1e-9);
+            assert!((scenario.bids[0].gas_costs_usd[1] - ratio).abs() < 1e-9);
+            assert!((scenario.asks[0].gas_costs_usd[0] - 3. * ratio).abs() < 1e-9);
+            assert!((scenario.bids_chart[0].gas_costs_usd[1] - ratio).abs() < 1e-9);
+            assert!((scenario.asks_chart[0].gas_costs_usd[0] - 3. * ratio).abs() < 1e-9);
+            // Token amounts are unchanged
+            assert_eq!(scenario.bids[0].amount, book.bids[0].amount);
+            assert_eq!(scenario.bids[0].output, book.bids[0].output);
+            assert_eq!(scenario.asks[0].output, book.asks[0].output);
+        }
+        assert_eq!(book.rescale_usd(0.).eth_usd, 2_000.);
+    }
+
+    #[test]
+    fn test_pad_levels() {
+        let steps = (1..=10).map(|x| x as f64).collect::<Vec<f64>>();
+        // Steps 1 and 2 were filtered out, 5 and 6 dropped by the solver
+        let trades = [3., 4., 7., 8., 9., 10.].iter().map(|&a| fixtures::trade(a, a * (2_000. - a))).collect::<Vec<_>>();
+        let padded = pad_levels(&trades, &steps);
+        assert_eq!(padded.len(), steps.len());
+        let sizes = padded.iter().map(|t| t.amount).collect::<Vec<f64>>();
+        assert!(sizes.windows(2).all(|w| (w[1] - w[0] - 1.).abs() < 1e-12));
+        assert_eq!(padded[4].output, 0.);
+        // Interpolated between 4 (1996) and 7 (1993)
+        assert!((padded[4].average_sell_price - 1_995.).abs() < 1e-9);
+        assert_eq!(padded[0].average_sell_price, padded[2].average_sell_price);
+        assert_eq!(padded[2].output, trades[0].output);
+        assert!(pad_levels(&[], &steps).is_empty());
+        // Steps beyond the largest trade (truncated or clipped away) are not padded
+        let padded = pad_levels(&trades[..4], &steps);
+        assert_eq!(padded.last().map(|t| t.amount), Some(8.));
+        assert_eq!(padded.len(), 8);
+    }
+
+    #[test]
+    fn test_quote_scales_with_input() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let context = MarketContext { block: 1, eth_usd: 2_000., gas_price: 1 };
+        let small = quote(&context, &pools, &fixtures::weth(), &fixtures::usdc(), 1., 1. / 2_000.);
+        let double = quote(&context, &pools, &fixtures::weth(), &fixtures::usdc(), 2., 1. / 2_000.);
+        assert!(small.output > 1_900. && small.output < 2_000.);
+        assert!((double.output / small.output - 2.).abs() < 0.02);
+        assert!(double.price_impact >= small.price_impact);
+        assert_eq!(small.distribution.len(), 2);
+    }
+
+    #[test]
+    fn test_skew() {
+        // At equal USD sizes, selling quote moves the price half as much as selling base, whatever the amounts of the levels
+        let trade = |amount: f64, output: f64, price_impact: f64| TradeResult { price_impact, ..fixtures::trade(amount, output) };
+        let bids = [1., 2., 5., 10., 50.].iter().map(|&a| trade(a, a * 2_000., a * 2e-3)).collect::<Vec<_>>();
+        let asks = [1_000., 10_000., 30_000., 60_000., 90_000.].iter().map(|&a| trade(a, a / 2_000., a * 5e-7)).collect::<Vec<_>>();
+        let book = Orderbook {
+            bids,
+            asks,
+            eth_usd: 2_000.,
+            base_worth_eth: 1.,
+            quote_worth_eth: 0.0005,
+            ..Default::default()
+        };
+        assert!((book.price_impact_at(Side::Bid, 0.5).unwrap() - 1e-3).abs() < 1e-12);
+        assert!(book.price_impact_at(Side::Ask, 100_000.).is_none());
+        // The 100k size is beyond the asks: (66k - 33k) / (66k + 33k) in millionths
+        assert!((book.skew() - 1. / 3.).abs() < 1e-9);
+        let regridded = Orderbook {
+            asks: [500., 2_000., 7_000., 45_000., 95_000.].iter().map(|&a| trade(a, a / 2_000., a * 5e-7)).collect::<Vec<_>>(),
+            ..book.clone()
+        };
+        assert!((regridded.skew() - book.skew()).abs() < 1e-9);
+        let mirrored = Orderbook {
+            bids: book.asks.clone(),
+            asks: book.bids.clone(),
+            base_worth_eth: 0.0005,
+            quote_worth_eth: 1.,
+            ..book.clone()
+        };
+        assert!((mirrored.skew() + 1. / 3.).abs() < 1e-9);
+        assert_eq!(Orderbook::default().skew(), 0.);
+    }
+
+    #[test]
+    fn test_tvl_weighted_mean() {
+        // A large pool at 2000 and a tiny one with a stale price at 3000
+        let prices = vec![2_000., 3_000.];
+        let weighted = tvl_weighted_mean(&prices, &[4_000_000., 1_000.]);
+        assert!((weighted - 2_000.).abs() < 1.);
+        assert_eq!(tvl_weighted_mean(&prices, &[0., 0.]), 2_500.);
+    }
+
+    #[test]
+    fn test_is_wrapper_pair() {
+        let network = Network {
+            eth: WETH.to_uppercase().replace("0X", "0x"),
+            ..Default::default()
+        };
+        assert!(is_wrapper_pair(&network, NULL_ADDRESS, WETH));
+        assert!(is_wrapper_pair(&network, WETH, "0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE"));
+        assert!(!is_wrapper_pair(&network, WETH, USDC));
+        assert!(!is_wrapper_pair(&network, NULL_ADDRESS, USDC));
+        let error: anyhow::Error = OrderbookError::WrapperPair {
+            base: "ETH".to_string(),
+            quote: "WETH".to_string(),
+        }
+        .into();
+        assert!(matches!(error.downcast_ref::<OrderbookError>(), Some(OrderbookError::WrapperPair { .. })));
+    }
+
+    #[test]
+    fn test_best_single_pool_for_small_trade() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 990., 2_000_000.)];
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            prices_base_to_quote: vec![2000., 2000.],
+            prices_quote_to_base: vec![0.0005, 0.0005],
+            eth_usd: 2000.,
+            gas_price: 20_000_000_000,
+            base_worth_eth: 1.,
+            quote_worth_eth: 0.0005,
+            ..Default::default()
+        };
+        let split = maths::opti::gradient(0.1, &pools, fixtures::weth(), fixtures::usdc(), 2000., 20_000_000_000, 2000., 0.0005);
+        let (id, single) = book.best_single_pool(&pools, Side::Bid, 0.1).unwrap();
+        // Pool 0x02 has less WETH for the same USDC, so selling WETH gets more USDC there
+        assert_eq!(id, "0x02");
+        assert_eq!(single.distribution, vec![0., 100.]);
+        assert!(single.output >= split.output * (1. - 1e-9), "single {} < split {}", single.output, split.output);
+    }
+
+    #[test]
+    fn test_quote_after_adverse_trade() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.)];
+        let book = Orderbook {
+            base: fixtures::weth(),
+            quote: fixtures::usdc(),
+            prices_base_to_quote: vec![2000., 2000.],
+            prices_quote_to_base: vec![0.0005, 0.0005],
+            eth_usd: 2000.,
+            gas_price: 1_000_000_000,
+            base_worth_eth: 1.,
+            quote_worth_eth: 0.0005,
+            ..Default::default()
+        };
+        let alone = maths::opti::gradient(10., &pools, fixtures::weth(), fixtures::usdc(), 2000., 1_000_000_000, 2000., 0.0005);
+        let after = book.quote_after(&pools, (Side::Bid, 500.), (Side::Bid, 10.)).unwrap();
+        assert!(after.output < alone.output);
+        // The original protosims are untouched
+        let again = maths::opti::gradient(10., &pools, fixtures::weth(), fixtures::usdc(), 2000., 1_000_000_000, 2000., 0.0005);
+        assert_eq!(again.output, alone.output);
+    }
+}
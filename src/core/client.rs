@@ -1,7 +1,9 @@
 use std::collections::HashMap;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
+use async_trait::async_trait;
 use alloy::providers::Provider;
 use alloy::providers::ProviderBuilder;
 use alloy::providers::RootProvider;
@@ -12,8 +14,11 @@ use reqwest::Client;
 use tycho_client::rpc::HttpRPCClient;
 use tycho_client::rpc::RPCClient;
 
+use tycho_common::dto::BlockParam;
+use tycho_common::dto::ComponentTvlRequestBody;
 use tycho_common::dto::PaginationParams;
 use tycho_common::dto::ProtocolStateRequestBody;
+use tycho_common::dto::ResponseProtocolState;
 use tycho_common::dto::VersionParam;
 use tycho_simulation::models::Token;
 
@@ -21,22 +26,37 @@ use crate::types;
 use crate::types::CoinGeckoResponse;
 use crate::types::IChainLinkPF;
 use crate::types::Network;
+use crate::types::TokensError;
 use crate::types::IERC20;
 use crate::utils::misc::filter_valid_strings;
 use crate::utils::r#static::endpoints::COINGECKO_ETH_USD;
+use crate::utils::r#static::retry::{TOKENS_MAX_ATTEMPTS, TOKENS_RETRY_BASE_DELAY_MS};
 
 /// ========================================================================================= Tycho Client =============================================================================================
 /// Get the balances of the component in the specified protocol system.
 /// Returns a HashMap of component addresses and their balances.
 /// Balance is returned as a u128, with decimals.
-pub async fn get_component_balances(client: &HttpRPCClient, network: Network, cp: String, protosys: String) -> Option<HashMap<String, u128>> {
+/// If a block is given, balances are fetched at that block, else at the latest one.
+#[allow(deprecated)]
+pub async fn get_component_balances(client: &HttpRPCClient, network: Network, cp: String, protosys: String, block: Option<u64>) -> Option<HashMap<String, u128>> {
     let (chain, _, _) = types::chain(network.name.clone()).expect("Invalid chain");
+    let version = match block {
+        Some(number) => VersionParam {
+            timestamp: None,
+            block: Some(BlockParam {
+                hash: None,
+                chain: Some(chain),
+                number: Some(number as i64),
+            }),
+        },
+        None => VersionParam::default(), // { timestamp: None, block: None },
+    };
     let body = ProtocolStateRequestBody {
         protocol_ids: Some(vec![cp.clone()]),
         protocol_system: protosys.to_string(), // Single, so cannot use protocol_ids vec of different protocols ?
         chain,
-        include_balances: true,           // We want to include account balances.
-        version: VersionParam::default(), // { timestamp: None, block: None },
+        include_balances: true, // We want to include account balances.
+        version,
         pagination: PaginationParams {
             page: 0,        // Start at the first page.
             page_size: 100, // Maximum page size supported is 100.
@@ -63,50 +83,224 @@ pub async fn get_component_balances(client: &HttpRPCClient, network: Network, cp
     }
 }
 
-/// Get the tokens from the Tycho API
-/// Filters are hardcoded for now.
-pub async fn tokens(network: &Network, apikey: String) -> Option<Vec<Token>> {
-    tracing::info!("Getting tokens for network {}", network.name);
-    match HttpRPCClient::new(format!("https://{}", &network.tycho).as_str(), Some(apikey.as_str())) {
-        Ok(client) => {
-            let time = std::time::SystemTime::now();
-            let (chain, _, _) = types::chain(network.name.clone()).expect("Invalid chain");
-            match client.get_all_tokens(chain, Some(100), Some(1), 500).await {
-                Ok(result) => {
-                    let mut tokens = vec![];
-                    for t in result.iter() {
-                        let g = t.gas.first().unwrap_or(&Some(0u64)).unwrap_or_default();
-                        if t.symbol.len() >= 20 {
-                            continue; // Symbol has been mistaken for a contract address, possibly.
-                        }
-                        if let Ok(addr) = tycho_simulation::tycho_core::Bytes::from_str(t.address.clone().to_string().as_str()) {
-                            tokens.push(Token {
-                                address: addr,
-                                decimals: t.decimals as usize,
-                                symbol: t.symbol.clone(),
-                                gas: BigUint::from(g),
-                            });
-                        }
+/// Get the balances of many components in one request per protocol system (Tycho protocol states are queried per system, 100 IDs per page)
+/// 'components' are (component ID, protocol system) pairs. Returns the balances indexed by lowercased component ID, the ones that couldn't be fetched being absent.
+#[allow(deprecated)]
+pub async fn get_component_balances_batch(client: &HttpRPCClient, network: Network, components: &[(String, String)], block: Option<u64>) -> HashMap<String, HashMap<String, u128>> {
+    let (chain, _, _) = types::chain(network.name.clone()).expect("Invalid chain");
+    let version = match block {
+        Some(number) => VersionParam {
+            timestamp: None,
+            block: Some(BlockParam {
+                hash: None,
+                chain: Some(chain),
+                number: Some(number as i64),
+            }),
+        },
+        None => VersionParam::default(),
+    };
+    let mut systems: HashMap<String, Vec<String>> = HashMap::new();
+    for (id, protosys) in components.iter() {
+        systems.entry(protosys.clone()).or_default().push(id.clone());
+    }
+    let mut result = HashMap::new();
+    for (protosys, ids) in systems.iter() {
+        for chunk in ids.chunks(100) {
+            let body = ProtocolStateRequestBody {
+                protocol_ids: Some(chunk.to_vec()),
+                protocol_system: protosys.clone(),
+                chain,
+                include_balances: true,
+                version: version.clone(),
+                pagination: PaginationParams { page: 0, page_size: 100 },
+            };
+            match client.get_protocol_states(&body).await {
+                Ok(response) => {
+                    for state in response.states.iter() {
+                        let balances = state
+                            .balances
+                            .iter()
+                            .filter_map(|(token, balance)| u128::from_str_radix(balance.to_string().trim_start_matches("0x"), 16).ok().map(|b| (token.to_string().to_lowercase(), b)))
+                            .collect::<HashMap<String, u128>>();
+                        result.insert(state.component_id.to_lowercase(), balances);
                     }
-                    tokens = filter_valid_strings(tokens);
-                    let elasped = time.elapsed().unwrap_or_default().as_millis();
-                    tracing::debug!("Took {:?} ms to get {} tokens on {}", elasped, tokens.len(), network.name);
-
-                    Some(tokens)
-                }
-                Err(e) => {
-                    tracing::error!("Failed to get tokens on network {}: {:?}", network.name, e.to_string());
-                    None
                 }
+                Err(e) => tracing::error!("Failed to get the protocol states of {} {} components: {:?}", chunk.len(), protosys, e.to_string()),
+            }
+        }
+    }
+    result
+}
+
+/// Get the TVL (in the chain native token) of many components, in one request per protocol system and page of 100 IDs
+/// 'components' are (component ID, protocol system) pairs. Returns the TVLs indexed by lowercased component ID, the ones that couldn't be fetched being absent.
+pub async fn get_component_tvls(client: &HttpRPCClient, network: Network, components: &[(String, String)]) -> HashMap<String, f64> {
+    let (chain, _, _) = types::chain(network.name.clone()).expect("Invalid chain");
+    let mut systems: HashMap<String, Vec<String>> = HashMap::new();
+    for (id, protosys) in components.iter() {
+        systems.entry(protosys.clone()).or_default().push(id.clone());
+    }
+    let mut result = HashMap::new();
+    for (protosys, ids) in systems.iter() {
+        for chunk in ids.chunks(100) {
+            let body = ComponentTvlRequestBody {
+                chain,
+                protocol_system: Some(protosys.clone()),
+                component_ids: Some(chunk.to_vec()),
+                pagination: PaginationParams { page: 0, page_size: 100 },
+            };
+            match client.get_component_tvl(&body).await {
+                Ok(response) => result.extend(response.tvl.into_iter().map(|(id, tvl)| (id.to_lowercase(), tvl))),
+                Err(e) => tracing::error!("Failed to get the TVL of {} {} components: {:?}", chunk.len(), protosys, e.to_string()),
             }
         }
+    }
+    result
+}
+
+/// Source of the component balances, abstracted so that the balance fetches of an orderbook build can be counted or mocked
+#[async_trait]
+pub trait BalanceSource: Send + Sync {
+    /// Balances of the component (token address => balance, in smallest unit) at the given block, None if they can't be fetched
+    async fn balances(&self, network: &Network, id: &str, protosys: &str, block: u64) -> Option<HashMap<String, u128>>;
+
+    /// Balances of many components ((ID, protocol system) pairs) at the given block, indexed by lowercased ID, the ones that couldn't be fetched being absent
+    /// Fetches them one by one by default, override it when the source can batch them
+    async fn balances_batch(&self, network: &Network, components: &[(String, String)], block: u64) -> HashMap<String, HashMap<String, u128>> {
+        let mut result = HashMap::new();
+        for (id, protosys) in components.iter() {
+            if let Some(balances) = self.balances(network, id, protosys, block).await {
+                result.insert(id.to_lowercase(), balances);
+            }
+        }
+        result
+    }
+}
+
+/// Fetches the component balances from the Tycho RPC (see get_component_balances)
+pub struct TychoBalanceSource(pub HttpRPCClient);
+
+#[async_trait]
+impl BalanceSource for TychoBalanceSource {
+    async fn balances(&self, network: &Network, id: &str, protosys: &str, block: u64) -> Option<HashMap<String, u128>> {
+        get_component_balances(&self.0, network.clone(), id.to_string(), protosys.to_string(), Some(block)).await
+    }
+
+    async fn balances_batch(&self, network: &Network, components: &[(String, String)], block: u64) -> HashMap<String, HashMap<String, u128>> {
+        get_component_balances_batch(&self.0, network.clone(), components, Some(block)).await
+    }
+}
+
+/// Get the raw state (attributes and balances) of a component in the specified protocol system.
+pub async fn get_component_state(client: &HttpRPCClient, network: Network, cp: String, protosys: String) -> Option<ResponseProtocolState> {
+    match fetch_component_state(client, &network, &cp, &protosys).await {
+        Ok(state) => state,
         Err(e) => {
-            tracing::error!("Failed to create client: {:?}", e.to_string());
+            tracing::error!("Failed to get protocol state: {}: {:?}", cp.clone(), e.to_string());
             None
         }
     }
 }
 
+/// Same as get_component_state, telling a failed query (error) from a component without state (None)
+pub async fn fetch_component_state(client: &HttpRPCClient, network: &Network, cp: &str, protosys: &str) -> Result<Option<ResponseProtocolState>, anyhow::Error> {
+    let (chain, _, _) = types::chain(network.name.clone()).ok_or_else(|| anyhow::anyhow!("Invalid chain {}", network.name))?;
+    let body = ProtocolStateRequestBody {
+        protocol_ids: Some(vec![cp.to_string()]),
+        protocol_system: protosys.to_string(),
+        chain,
+        include_balances: true,
+        version: VersionParam::default(),
+        pagination: PaginationParams { page: 0, page_size: 100 },
+    };
+    let response = client.get_protocol_states(&body).await?;
+    Ok(response.states.into_iter().find(|state| state.component_id.eq_ignore_ascii_case(cp)))
+}
+
+/// Source of the component states, abstracted so that the stale refresh and the onchain checks can be tested without the Tycho RPC (see OrderbookProvider::refresh_stale)
+/// Errors if the query fails, None if the component has no state
+#[async_trait]
+pub trait ComponentStateSource: Send + Sync {
+    async fn state(&self, network: &Network, id: &str, protocol_system: &str) -> Result<Option<ResponseProtocolState>, anyhow::Error>;
+}
+
+/// Component states from the Tycho RPC (see fetch_component_state)
+pub struct TychoStateSource(pub HttpRPCClient);
+
+#[async_trait]
+impl ComponentStateSource for TychoStateSource {
+    async fn state(&self, network: &Network, id: &str, protocol_system: &str) -> Result<Option<ResponseProtocolState>, anyhow::Error> {
+        fetch_component_state(&self.0, network, id, protocol_system).await
+    }
+}
+
+/// Source of the token list, abstracted so that the fetch retries can be tested without the Tycho API
+#[async_trait]
+pub trait TokenSource: Send + Sync {
+    async fn fetch(&self, network: &Network) -> Result<Vec<Token>, anyhow::Error>;
+}
+
+/// Get the tokens from the Tycho API, with the given API key
+/// Filters are hardcoded for now.
+pub struct TychoTokenSource(pub String);
+
+#[async_trait]
+impl TokenSource for TychoTokenSource {
+    async fn fetch(&self, network: &Network) -> Result<Vec<Token>, anyhow::Error> {
+        tracing::info!("Getting tokens for network {}", network.name);
+        let client = HttpRPCClient::new(format!("https://{}", &network.tycho).as_str(), Some(self.0.as_str())).map_err(|e| anyhow::anyhow!("Failed to create client: {:?}", e.to_string()))?;
+        let time = std::time::SystemTime::now();
+        let (chain, _, _) = types::chain(network.name.clone()).ok_or_else(|| anyhow::anyhow!("Invalid chain: {}", network.name))?;
+        let result = client.get_all_tokens(chain, Some(100), Some(1), 500).await.map_err(|e| anyhow::anyhow!("Failed to get tokens on network {}: {:?}", network.name, e.to_string()))?;
+        let mut tokens = vec![];
+        for t in result.iter() {
+            let g = t.gas.first().unwrap_or(&Some(0u64)).unwrap_or_default();
+            if t.symbol.len() >= 20 {
+                continue; // Symbol has been mistaken for a contract address, possibly.
+            }
+            if let Ok(addr) = tycho_simulation::tycho_core::Bytes::from_str(t.address.clone().to_string().as_str()) {
+                tokens.push(Token {
+                    address: addr,
+                    decimals: t.decimals as usize,
+                    symbol: t.symbol.clone(),
+                    gas: BigUint::from(g),
+                });
+            }
+        }
+        tokens = filter_valid_strings(tokens);
+        let elasped = time.elapsed().unwrap_or_default().as_millis();
+        tracing::debug!("Took {:?} ms to get {} tokens on {}", elasped, tokens.len(), network.name);
+        Ok(tokens)
+    }
+}
+
+/// Fetch the tokens from the given source, retrying up to `max_attempts` times with an exponential backoff (base_delay, then doubled at each attempt)
+/// so that a transient Tycho or RPC error doesn't abort the startup. Returns TokensError::Exhausted with the last error once all attempts failed.
+pub async fn tokens_with_retry(source: &dyn TokenSource, network: &Network, max_attempts: u32, base_delay: Duration) -> Result<Vec<Token>, TokensError> {
+    let mut delay = base_delay;
+    let mut last = String::new();
+    for attempt in 1..=max_attempts.max(1) {
+        match source.fetch(network).await {
+            Ok(tokens) => return Ok(tokens),
+            Err(e) => {
+                last = e.to_string();
+                tracing::warn!("Failed to get tokens on {} (attempt {}/{}): {}", network.name, attempt, max_attempts, last);
+                if attempt < max_attempts {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+            }
+        }
+    }
+    tracing::error!("Failed to get tokens on {} after {} attempts", network.name, max_attempts);
+    Err(TokensError::Exhausted { attempts: max_attempts, last })
+}
+
+/// Get the tokens from the Tycho API, retried with the default backoff (see tokens_with_retry)
+pub async fn tokens(network: &Network, apikey: String) -> Result<Vec<Token>, TokensError> {
+    tokens_with_retry(&TychoTokenSource(apikey), network, TOKENS_MAX_ATTEMPTS, Duration::from_millis(TOKENS_RETRY_BASE_DELAY_MS)).await
+}
+
 /// Get the tokens from the Tycho API
 /// Filters are hardcoded for now.
 pub fn build_tycho_client(network: &Network, key: Option<String>) -> Result<HttpRPCClient, anyhow::Error> {
@@ -161,6 +355,29 @@ pub async fn erc20b(provider: &RootProvider<Http<Client>>, owner: String, tokens
     Ok(balances)
 }
 
+/// Reads the bytecode deployed at an address. Abstracted so that onchain existence checks can be mocked.
+#[async_trait]
+pub trait CodeReader: Send + Sync {
+    async fn code(&self, address: &str) -> Result<Vec<u8>, anyhow::Error>;
+}
+
+/// Reads the bytecode via the RPC of the network
+pub struct RpcCodeReader(pub String);
+
+#[async_trait]
+impl CodeReader for RpcCodeReader {
+    async fn code(&self, address: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let provider = ProviderBuilder::new().on_http(self.0.parse()?);
+        let address: Address = address.parse()?;
+        Ok(provider.get_code_at(address).await?.to_vec())
+    }
+}
+
+/// Whether a contract is deployed at the given address (non empty bytecode)
+pub async fn deployed(reader: &dyn CodeReader, address: &str) -> Result<bool, anyhow::Error> {
+    Ok(!reader.code(address).await?.is_empty())
+}
+
 /// Fetch the price of and oracle, in this case of the 'gas_token' of a network
 /// Assume the oracle in under the 'Chainlink' interface
 /// Unwrap are assumed safe, given the configuration SDK is correct.
@@ -185,3 +402,50 @@ pub async fn get_eth_usd_chainlink(rpc: String, feed: String) -> Option<f64> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockCodeReader(HashMap<String, Vec<u8>>);
+
+    #[async_trait]
+    impl CodeReader for MockCodeReader {
+        async fn code(&self, address: &str) -> Result<Vec<u8>, anyhow::Error> {
+            Ok(self.0.get(&address.to_lowercase()).cloned().unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deployed() {
+        let pool = "0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc";
+        let reader = MockCodeReader(HashMap::from([(pool.to_string(), vec![0x60, 0x80, 0x60, 0x40])]));
+        assert!(deployed(&reader, pool).await.unwrap());
+        assert!(!deployed(&reader, "0x0000000000000000000000000000000000000001").await.unwrap());
+    }
+
+    /// Fails the first 'failures' fetches, then returns one token
+    struct FlakyTokenSource(std::sync::atomic::AtomicU32, u32);
+
+    #[async_trait]
+    impl TokenSource for FlakyTokenSource {
+        async fn fetch(&self, _network: &Network) -> Result<Vec<Token>, anyhow::Error> {
+            if self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) < self.1 {
+                return Err(anyhow::anyhow!("Tycho unavailable"));
+            }
+            Ok(vec![Token::from(crate::utils::fixtures::weth())])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tokens_with_retry() {
+        let network = Network::default();
+        let source = FlakyTokenSource(std::sync::atomic::AtomicU32::new(0), 2);
+        let tokens = tokens_with_retry(&source, &network, 3, Duration::from_millis(1)).await.unwrap();
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(source.0.load(std::sync::atomic::Ordering::SeqCst), 3);
+        let source = FlakyTokenSource(std::sync::atomic::AtomicU32::new(0), 5);
+        let error = tokens_with_retry(&source, &network, 3, Duration::from_millis(1)).await.unwrap_err();
+        assert_eq!(error, TokensError::Exhausted { attempts: 3, last: "Tycho unavailable".to_string() });
+    }
+}
@@ -2,174 +2,239 @@ use std::str::FromStr;
 
 use alloy::{
     network::EthereumWallet,
+    node_bindings::Anvil,
     primitives::{Address, B256},
-    providers::{Provider, ProviderBuilder},
+    providers::{Provider, ProviderBuilder, RootProvider},
     rpc::types::{
         simulate::{SimBlock, SimulatePayload},
         TransactionInput, TransactionRequest,
     },
     signers::local::PrivateKeySigner,
     sol_types::SolValue,
+    transports::http::Http,
 };
 use num_bigint::BigUint;
 use tycho_execution::encoding::{
     evm::encoder_builder::EVMEncoderBuilder,
-    models::{Solution, Transaction},
+    models::{NativeAction, Solution, Transaction},
     tycho_encoder::TychoEncoder,
 };
 
 use alloy_primitives::{Bytes as AlloyBytes, U256};
+use async_trait::async_trait;
+use reqwest::Client;
 use tycho_simulation::protocol::models::ProtocolComponent;
 
 use crate::{
-    types::{self, ExecutedPayload, ExecutionRequest, Network, PayloadToExecute},
-    utils::r#static::{execution, maths::BPD},
+    core::{book, protos},
+    types::{self, ExecutedPayload, ExecutionRequest, Network, PayloadToExecute, SimulatedTx, SlippageReport, SrzToken},
+    utils::r#static::{execution, filter::NULL_ADDRESS, maths::BPD},
 };
 
 /// Build 2 transactions for the given solution:
 ///     Approve the given token to the router address.
 ///     Swap the given token for the checked token using the router address.
 /// The transactions are built using the given network and nonce + 1 on the 2nd transaction.
-pub fn prepare(network: Network, solution: Solution, encoded: Transaction, block: alloy::rpc::types::Block, nonce: u64) -> Option<(TransactionRequest, TransactionRequest)> {
+/// If the given token is the native one, there is nothing to approve: only the swap is built, carrying the given amount as value, with the given nonce.
+/// Returns an error if the given amount doesn't fit in a Permit2 amount (uint160).
+pub fn prepare(network: Network, solution: Solution, encoded: Transaction, block: alloy::rpc::types::Block, nonce: u64) -> Result<(Option<TransactionRequest>, TransactionRequest), String> {
     let base_fee = block.header.base_fee_per_gas.expect("Base fee not available");
-    let max_priority_fee_per_gas = 1_000_000_000u128; // 1 Gwei, not suited for L2s.
-    let max_fee_per_gas = base_fee as u128 + max_priority_fee_per_gas;
+    transactions(network, solution, encoded, base_fee as u128, nonce)
+}
+
+/// Same as prepare, with the base fee of the block the transactions are built for
+pub fn transactions(network: Network, solution: Solution, encoded: Transaction, base_fee: u128, nonce: u64) -> Result<(Option<TransactionRequest>, TransactionRequest), String> {
+    let (max_fee_per_gas, max_priority_fee_per_gas) = network.fees(base_fee);
     tracing::debug!("Nonce: {}", nonce);
-    // --- Approve Tx with Permit2 ---
-    let amount: u128 = solution.given_amount.clone().to_string().parse().expect("Couldn't convert given_amount to u128"); // ?
-    let args = (Address::from_str(&network.permit2).expect("Couldn't convert to address"), amount);
-    let data = tycho_execution::encoding::evm::utils::encode_input(execution::APPROVE_FN_SIGNATURE, args.abi_encode());
     let sender = solution.sender.clone().to_string().parse().expect("Failed to parse sender");
-    let approval = TransactionRequest {
-        to: Some(alloy::primitives::TxKind::Call(solution.given_token.clone().to_string().parse().expect("Failed to parse given_token"))),
-        from: Some(sender),
-        value: None,
-        input: TransactionInput {
-            input: Some(AlloyBytes::from(data)),
-            data: None,
-        },
-        gas: Some(execution::DEFAULT_APPROVE_GAS),
-        chain_id: Some(network.chainid),
-        max_fee_per_gas: Some(max_fee_per_gas),
-        max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
-        nonce: Some(nonce),
-        ..Default::default()
+    let native_in = book::is_native(&solution.given_token.to_string());
+    // --- Approve Tx with Permit2 ---
+    let approval = match native_in {
+        true => None,
+        false => {
+            let amount = permit2_amount(&solution.given_amount)?;
+            let args = (Address::from_str(&network.permit2).expect("Couldn't convert to address"), amount);
+            let data = tycho_execution::encoding::evm::utils::encode_input(execution::APPROVE_FN_SIGNATURE, args.abi_encode());
+            Some(TransactionRequest {
+                to: Some(alloy::primitives::TxKind::Call(solution.given_token.clone().to_string().parse().expect("Failed to parse given_token"))),
+                from: Some(sender),
+                value: None,
+                input: TransactionInput {
+                    input: Some(AlloyBytes::from(data)),
+                    data: None,
+                },
+                gas: Some(execution::DEFAULT_APPROVE_GAS),
+                chain_id: Some(network.chainid),
+                max_fee_per_gas: Some(max_fee_per_gas),
+                max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
+                nonce: Some(nonce),
+                ..Default::default()
+            })
+        }
     };
     // --- Swap Tx ---
+    let value = match native_in {
+        true => U256::from_be_slice(&solution.given_amount.to_bytes_be()),
+        false => U256::from(0),
+    };
     let swap = TransactionRequest {
         to: Some(alloy_primitives::TxKind::Call(Address::from_slice(&encoded.to))),
         from: Some(sender),
-        value: Some(U256::from(0)),
+        value: Some(value),
         input: TransactionInput {
             input: Some(AlloyBytes::from(encoded.data)),
             data: None,
         },
-        gas: Some(300_000u64),
+        gas: Some(swap_gas_limit(
+            &solution.swaps.iter().map(|swap| swap.component.protocol_type_name.as_str()).collect::<Vec<&str>>(),
+            execution::SWAP_GAS_SAFETY_MULTIPLIER,
+        )),
         chain_id: Some(network.chainid),
         max_fee_per_gas: Some(max_fee_per_gas),
         max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
-        nonce: Some(nonce + 1),
+        nonce: Some(nonce + approval.is_some() as u64),
         ..Default::default()
     };
-    Some((approval, swap))
+    Ok((approval, swap))
+}
+
+/// Estimates the gas limit of a swap transaction on the given components (one per pool of the split)
+/// Sums the per-protocol swap costs, the router overhead, and an overhead per additional pool, with the default safety multiplier.
+pub fn gas_estimate(components: &[ProtocolComponent]) -> u64 {
+    swap_gas_limit(&components.iter().map(|cp| cp.protocol_type_name.as_str()).collect::<Vec<&str>>(), execution::SWAP_GAS_SAFETY_MULTIPLIER)
+}
+
+/// Gas limit of a swap transaction on pools of the given protocol types, scaled by a safety multiplier
+pub fn swap_gas_limit(protocols: &[&str], multiplier: f64) -> u64 {
+    let swaps = protocols.iter().map(|protocol| protos::swap_gas(protocol)).sum::<u64>();
+    let splits = protocols.len().saturating_sub(1) as u64 * execution::SWAP_SPLIT_OVERHEAD_GAS;
+    ((execution::SWAP_ROUTER_GAS + swaps + splits) as f64 * multiplier).ceil() as u64
+}
+
+/// Converts the given amount into a Permit2 amount, which is a uint160
+/// Amounts above u128 are valid (e.g. 18 decimals tokens with huge supplies), but above uint160 they can't be approved.
+pub fn permit2_amount(given_amount: &BigUint) -> Result<U256, String> {
+    if given_amount.bits() > 160 {
+        return Err(format!("Amount {} exceeds the Permit2 maximum (uint160)", given_amount));
+    }
+    Ok(U256::from_be_slice(&given_amount.to_bytes_be()))
+}
+
+/// Tycho bytes of a token address, the native token placeholders (null or 0xeee..e address) being mapped to the null address used by the Tycho router
+fn token_bytes(address: &str) -> tycho_simulation::tycho_core::Bytes {
+    let address = if book::is_native(address) { NULL_ADDRESS } else { address };
+    tycho_simulation::tycho_core::Bytes::from_str(address.to_lowercase().as_str()).unwrap() // from_str Bytes are assumed safe
+}
+
+/// Validates a distribution (percentages per component, 0-100) and rescales it into fractions summing exactly to 1, ratios preserved
+/// Rejects negative or non-finite entries, more entries than components, and sums further than execution::DISTRIBUTION_SUM_TOLERANCE from 100
+pub fn normalize_distribution(distribution: &[f64], components: usize) -> Result<Vec<f64>, String> {
+    if distribution.len() > components {
+        return Err(format!("Invalid distribution: {} entries for {} components", distribution.len(), components));
+    }
+    if let Some(x) = distribution.iter().position(|d| !d.is_finite() || *d < 0.) {
+        return Err(format!("Invalid distribution: entry #{} is {}, must be a positive percentage", x, distribution[x]));
+    }
+    let sum = distribution.iter().sum::<f64>();
+    if sum <= 0. || (sum - 100.).abs() > execution::DISTRIBUTION_SUM_TOLERANCE {
+        return Err(format!("Invalid distribution: {:?} sums to {}, expected 100 (+/- {})", distribution, sum, execution::DISTRIBUTION_SUM_TOLERANCE));
+    }
+    Ok(distribution.iter().map(|d| d / sum).collect())
+}
+
+/// Tycho bytes of the token a pool swaps: the network wrapped native token in place of the native one, as pools only hold the wrapped one
+fn swap_token_bytes(network: &Network, address: &str) -> tycho_simulation::tycho_core::Bytes {
+    token_bytes(if book::is_native(address) { &network.eth } else { address })
 }
 
 /// Build a swap solution Tycho structure
-pub async fn solution(_network: Network, request: ExecutionRequest, components: Vec<ProtocolComponent>) -> Option<Solution> {
+/// Native input/output (null or 0xeee..e address) is supported: the swaps go through the network wrapped native token, and the solution asks the router
+/// to wrap the native input before them (NativeAction::Wrap), or to unwrap the output after them (NativeAction::Unwrap)
+/// The checked amount is the expected one minus the request slippage (in bps, default execution::EXEC_DEFAULT_SLIPPAGE), which must be within execution::EXEC_MAX_SLIPPAGE_BPS
+/// If the request enforces a minimum output, the router reverts the swap below the checked amount, which must then be positive. Otherwise no amount is checked
+pub async fn solution(network: Network, request: ExecutionRequest, components: Vec<ProtocolComponent>) -> Result<Solution, String> {
     tracing::debug!("Preparing swap. Sender: {} | Orderbook: {:?}", request.sender, request.tag);
-    let sum = request.distribution.iter().fold(0., |acc, x| acc + x);
-    if !(99. ..=101.).contains(&sum) {
-        tracing::debug!("Invalid distribution: {:?}, sum = {}", request.distribution, sum);
-        return None;
-    }
-    // Multiple checks are performed by the Tycho encoder, including
-    // - Failed to encode router calldata: InvalidInput("Split percentage must be less than 1 (100%), got 1")
-    let single_swap = request.distribution.iter().filter(|&&x| x > 0.0).count() == 1; // Couting distribution > 0.0
-    let single_swap_index = request.distribution.iter().position(|&x| x > 0.0).unwrap_or(0);
-    tracing::debug!("Single swap: {} | single_swap_index = {}", single_swap, single_swap_index);
-
-    // Multi (= splitted, not multi hop) trade
-    let distributions: Vec<f64> = request
-        .distribution
-        .clone()
+    let fractions = normalize_distribution(&request.distribution, components.len())?;
+    let slippage = match request.slippage_bps {
+        Some(bps) if bps > execution::EXEC_MAX_SLIPPAGE_BPS => return Err(format!("Slippage of {} bps exceeds the maximum of {} bps", bps, execution::EXEC_MAX_SLIPPAGE_BPS)),
+        Some(bps) => bps as f64 / BPD,
+        None => execution::EXEC_DEFAULT_SLIPPAGE,
+    };
+    // Tycho split swaps: each split is a fraction of the total amount, and the last swap must have a 0 split (it takes the remainder)
+    // Otherwise the encoder fails, e.g. InvalidInput("Split percentage must be less than 1 (100%), got 1")
+    let active = fractions.iter().enumerate().filter(|(_, &f)| f > 0.).map(|(x, _)| x).collect::<Vec<usize>>();
+    tracing::debug!("Normalized distribution: {:?} | Active components: {:?}", fractions, active);
+    let native_action = match (book::is_native(&request.input.address), book::is_native(&request.output.address)) {
+        (true, true) => return Err("Input and output can't both be the native token".to_string()),
+        (true, false) => Some(NativeAction::Wrap),
+        (false, true) => Some(NativeAction::Unwrap),
+        (false, false) => None,
+    };
+    let input = swap_token_bytes(&network, &request.input.address);
+    let output = swap_token_bytes(&network, &request.output.address);
+    // Exact ProtocolComponent structure is needed for the Tycho encoder, it doesn't work to partially convert a SrzProtocolComponent to ProtocolComponent
+    let swaps = active
         .iter()
-        .map(|&x| {
-            let value = x * BPD;
-            let adjusted = match single_swap {
-                true => 0.0, // Single swap must have 0% split, the 100% remaining is considered as the swap amount
-                false => {
-                    if value > 0.0 {
-                        value - 1.0 // Just to make sure it never exceeds 100%
-                    } else {
-                        value
-                    }
-                }
-            };
-            adjusted / BPD / 100.0
+        .enumerate()
+        .map(|(n, &x)| {
+            let split = if n + 1 == active.len() { 0. } else { fractions[x] };
+            tycho_execution::encoding::models::Swap::new(components[x].clone(), input.clone(), output.clone(), split)
         })
-        .collect();
-
-    tracing::debug!(
-        "Initial distribution sum: {} (should be close to 100). Adjusted distribution = {:?} (full 0 if single swap)",
-        sum,
-        distributions.clone()
-    );
-    // Prepare the swaps, adding a swap for each distribution > 0
-    // Exact ProtocolComponent structure is needed for the Tycho encoder, it doesn't work to partially convert a SrzProtocolComponent to ProtocolComponent
-    let mut swaps = vec![];
-    for (x, dist) in distributions.iter().enumerate() {
-        // log::trace!("Distribution #{}: {}", x, dist);
-        let original = components[x].clone(); // get
-        let input = tycho_simulation::tycho_core::Bytes::from_str(request.input.clone().address.to_lowercase().as_str()).unwrap(); // from_str Bytes are assumed safe
-        let output = tycho_simulation::tycho_core::Bytes::from_str(request.output.clone().address.to_lowercase().as_str()).unwrap(); // from_str Bytes are assumed safe
-        if single_swap && x == single_swap_index {
-            swaps.push(tycho_execution::encoding::models::Swap::new(original.clone(), input, output, 0f64));
-            break;
-        } else if *dist > f64::EPSILON {
-            swaps.push(tycho_execution::encoding::models::Swap::new(original.clone(), input, output, *dist));
-        }
-    }
+        .collect::<Vec<_>>();
     let amount_in = BigUint::from((request.amount * 10f64.powi(request.input.decimals as i32)) as u128);
     tracing::debug!("Req.Amount: {} (pow = {}) of {}", request.amount, amount_in, request.input.symbol.clone());
     let expected = request.expected * 10f64.powi(request.output.decimals as i32);
     let expected_bg = BigUint::from(expected as u128);
-    let slippage = execution::EXEC_DEFAULT_SLIPPAGE;
     let checked_amount = expected * (1.0 - slippage);
     let checked_amount_bg = BigUint::from(checked_amount as u128);
-    tracing::debug!("Expected: {} of {} | Checked: {}", expected, request.output.symbol.clone(), checked_amount);
+    tracing::debug!("Expected: {} of {} | Checked: {} | Enforced: {}", expected, request.output.symbol.clone(), checked_amount, request.enforce_min_out);
+    if request.enforce_min_out && checked_amount < 1. {
+        return Err(format!("Minimum output of {} {} rounds to 0, it can't be enforced", request.expected * (1.0 - slippage), request.output.symbol));
+    }
+    // The router derives its minimum output from the checked amount, or from the expected amount and the slippage: none of them means no check
+    let (slippage, expected_bg, checked_amount_bg) = match request.enforce_min_out {
+        true => (Some(slippage), Some(expected_bg), Some(checked_amount_bg)),
+        false => (None, None, None),
+    };
     let solution: Solution = Solution {
         // Addresses
         sender: tycho_simulation::tycho_core::Bytes::from_str(request.sender.to_lowercase().as_str()).unwrap(), // from_str Bytes are assumed safe
         receiver: tycho_simulation::tycho_core::Bytes::from_str(request.sender.to_lowercase().as_str()).unwrap(), // from_str Bytes are assumed safe
-        given_token: tycho_simulation::tycho_core::Bytes::from_str(request.input.clone().address.to_lowercase().as_str()).unwrap(), // from_str Bytes are assumed safe
-        checked_token: tycho_simulation::tycho_core::Bytes::from_str(request.output.clone().address.to_lowercase().as_str()).unwrap(), // from_str Bytes are assumed safe
+        given_token: token_bytes(&request.input.address),
+        checked_token: token_bytes(&request.output.address),
         // Others fields
         given_amount: amount_in.clone(),
-        slippage: Some(slippage),
+        slippage,
         exact_out: false, // It's an exact in solution
-        expected_amount: Some(expected_bg),
-        checked_amount: Some(checked_amount_bg), // The amount out will not be checked in execution
+        expected_amount: expected_bg,
+        checked_amount: checked_amount_bg, // The router reverts if the amount out is below it
         swaps: swaps.clone(),
+        native_action,
         ..Default::default()
     };
     // tracing::trace!("Solution: {:?}", solution);
-    Some(solution)
+    Ok(solution)
 }
 
-pub async fn simulate_execution(network: Network, payload: PayloadToExecute, signer: EthereumWallet) -> bool {
-    let alloy_chain = crate::utils::misc::get_alloy_chain(network.name.clone()).expect("Failed to get alloy chain");
-    let provider = ProviderBuilder::new().with_chain(alloy_chain).wallet(signer.clone()).on_http(network.rpc.parse().unwrap());
-    // --- Simulate ---
-    let payload = SimulatePayload {
+/// eth_simulateV1 request of the payload transactions, in order, in a single block (with validation and transfers traced)
+fn sim_payload(payload: &PayloadToExecute) -> SimulatePayload {
+    SimulatePayload {
         block_state_calls: vec![SimBlock {
             block_overrides: None,
             state_overrides: None,
-            calls: vec![payload.approve.clone(), payload.swap.clone()],
+            calls: payload.calls(),
         }],
         trace_transfers: true,
         validation: true,
         return_full_transactions: true,
-    };
+    }
+}
+
+pub async fn simulate_execution(network: Network, payload: PayloadToExecute, signer: EthereumWallet) -> bool {
+    let alloy_chain = crate::utils::misc::get_alloy_chain(network.name.clone()).expect("Failed to get alloy chain");
+    let provider = ProviderBuilder::new().with_chain(alloy_chain).wallet(signer.clone()).on_http(network.rpc.parse().unwrap());
+    // --- Simulate ---
+    let payload = sim_payload(&payload);
     // eth_simulateV1 seems not available on Base, you can adjust the RPC to another provider supporting it (eth_simulateV1), or comment the simulate part.
     // Example Mainnet: https://etherscan.io/tx/0x5fb3bcc10e21108cc537a6484b7689bd806bdc7702f181e38d9800aba162a44d
     // Example Base: https://basescan.org/tx/0xd3a2a8e2d7b752d857298ef280d63975b072f030f811a65355214fb5de616d06
@@ -202,6 +267,195 @@ pub async fn simulate_execution(network: Network, payload: PayloadToExecute, sig
     is_simulation_success
 }
 
+/// Simulate the payload (eth_simulateV1) without any signer, returning the gas used and the status of each transaction, in order
+/// The output of the swap is the one returned by the router
+/// Requires a RPC supporting eth_simulateV1
+pub async fn simulate_calls(network: Network, payload: PayloadToExecute) -> Result<Vec<SimulatedTx>, String> {
+    let provider = ProviderBuilder::new().on_http(network.rpc.parse().map_err(|e| format!("Invalid RPC URL: {:?}", e))?);
+    let payload = sim_payload(&payload);
+    let output = provider.simulate(&payload).await.map_err(|e| format!("Failed to simulate: {:?}", e))?;
+    let calls = output.iter().flat_map(|block| block.calls.iter()).collect::<Vec<_>>();
+    Ok(calls
+        .iter()
+        .enumerate()
+        .map(|(x, tx)| SimulatedTx {
+            gas_used: tx.gas_used,
+            success: tx.status,
+            output: match x + 1 == calls.len() && tx.status {
+                true => U256::abi_decode(&tx.return_data, true).ok().and_then(|amount| amount.to_string().parse::<u128>().ok()),
+                false => None,
+            },
+        })
+        .collect())
+}
+
+/// Where the execution payloads are simulated and broadcast, abstracted so that the broadcasts can be counted or mocked
+#[async_trait]
+pub trait TxBackend: Send + Sync {
+    /// Simulation of each transaction of the payload, in order
+    async fn simulate(&self, network: &Network, payload: &PayloadToExecute) -> Result<Vec<SimulatedTx>, String>;
+
+    /// Sends the transactions of the payload (see broadcast)
+    async fn broadcast(&self, network: &Network, payload: &PayloadToExecute, pk: Option<String>) -> Result<ExecutedPayload, anyhow::Error>;
+}
+
+/// Simulates and broadcasts through the network RPC (see simulate_calls and broadcast)
+pub struct RpcTxBackend;
+
+#[async_trait]
+impl TxBackend for RpcTxBackend {
+    async fn simulate(&self, network: &Network, payload: &PayloadToExecute) -> Result<Vec<SimulatedTx>, String> {
+        simulate_calls(network.clone(), payload.clone()).await
+    }
+
+    async fn broadcast(&self, network: &Network, payload: &PayloadToExecute, pk: Option<String>) -> Result<ExecutedPayload, anyhow::Error> {
+        broadcast(network.clone(), payload.clone(), pk).await
+    }
+}
+
+/// Simulates on a local fork of the network at its latest block (anvil, spawned for each simulation), broadcasts through the network RPC
+/// The transactions are really mined on the fork, from the impersonated sender, so the gas, the status and the output include every side effect (token transfers, approvals)
+/// A broadcast is preceded by a simulation on the fork, and refused if one of the transactions fails there
+/// Requires the anvil binary in the PATH
+pub struct ForkTxBackend;
+
+#[async_trait]
+impl TxBackend for ForkTxBackend {
+    async fn simulate(&self, network: &Network, payload: &PayloadToExecute) -> Result<Vec<SimulatedTx>, String> {
+        simulate_fork(network.clone(), payload.clone()).await
+    }
+
+    async fn broadcast(&self, network: &Network, payload: &PayloadToExecute, pk: Option<String>) -> Result<ExecutedPayload, anyhow::Error> {
+        let simulation = simulate_fork(network.clone(), payload.clone()).await.map_err(|e| anyhow::anyhow!(e))?;
+        if let Some(x) = simulation.iter().position(|tx| !tx.success) {
+            tracing::error!("Fork simulation failed for tx #{}. No broadcast.", x);
+            return Err(anyhow::anyhow!("Fork simulation failed for tx #{}", x));
+        }
+        broadcast(network.clone(), payload.clone(), pk).await
+    }
+}
+
+/// Backend of the request: simulated on a local fork if the request asks for it (see ExecutionRequest::fork), before a broadcast too, through the RPC (eth_simulateV1) otherwise
+pub fn backend(request: &ExecutionRequest) -> Box<dyn TxBackend> {
+    match request.fork {
+        true => Box::new(ForkTxBackend),
+        false => Box::new(RpcTxBackend),
+    }
+}
+
+/// Balance of the owner in the token, the native balance if the token is the native one
+/// A failed read is an error, not a zero balance (unlike client::erc20b), as the output of a fork swap is measured as a difference of balances
+async fn balance_of(provider: &RootProvider<Http<Client>>, owner: Address, token: &str) -> Result<u128, String> {
+    match book::is_native(token) {
+        true => provider
+            .get_balance(owner)
+            .await
+            .map(|balance| balance.to_string().parse::<u128>().unwrap_or_default())
+            .map_err(|e| format!("Failed to get the balance of {}: {:?}", owner, e)),
+        false => {
+            let address = token.parse::<Address>().map_err(|e| format!("Invalid token address {}: {:?}", token, e))?;
+            types::IERC20::new(address, provider)
+                .balanceOf(owner)
+                .call()
+                .await
+                .map(|res| res.balance.to_string().parse::<u128>().unwrap_or_default())
+                .map_err(|e| format!("Failed to get the {} balance of {}: {:?}", token, owner, e))
+        }
+    }
+}
+
+/// Mine the transactions of the payload, in order, on a local anvil fork of the network, returning the gas used and the status of each one
+/// The output of the swap is the balance delta of the sender in the output token (see PayloadToExecute::output), gas fees excluded if it's the native token
+/// A transaction the fork refuses is reported as failed, and the following ones aren't sent
+pub async fn simulate_fork(network: Network, payload: PayloadToExecute) -> Result<Vec<SimulatedTx>, String> {
+    let rpc = network.rpc.clone();
+    let anvil = tokio::task::spawn_blocking(move || Anvil::new().fork(rpc).try_spawn())
+        .await
+        .map_err(|e| format!("Failed to spawn anvil: {:?}", e))?
+        .map_err(|e| format!("Failed to spawn anvil: {:?}", e))?;
+    let provider = ProviderBuilder::new().on_http(anvil.endpoint_url());
+    let receiver = payload.swap.from.ok_or("Swap has no sender".to_string())?;
+    let before = match payload.output.is_empty() {
+        true => None,
+        false => Some(balance_of(&provider, receiver, &payload.output).await?),
+    };
+    let calls = payload.calls();
+    let last = calls.len() - 1;
+    let mut fees = 0u128;
+    let mut simulation = vec![];
+    for (x, tx) in calls.into_iter().enumerate() {
+        let sender = tx.from.ok_or(format!("Tx #{} has no sender", x))?;
+        provider
+            .raw_request::<_, ()>("anvil_impersonateAccount".into(), (sender,))
+            .await
+            .map_err(|e| format!("Failed to impersonate {}: {:?}", sender, e))?;
+        let receipt = match provider.send_transaction(tx).await {
+            Ok(pending) => pending.get_receipt().await.map_err(|e| format!("Failed to get the receipt of tx #{}: {:?}", x, e))?,
+            Err(e) => {
+                tracing::error!("Fork refused tx #{}: {:?}", x, e);
+                simulation.push(SimulatedTx { gas_used: 0, success: false, output: None });
+                break;
+            }
+        };
+        tracing::debug!("Fork: Tx #{}: Gas: {} | Status: {}", x, receipt.gas_used, receipt.status());
+        fees += receipt.gas_used * receipt.effective_gas_price;
+        let output = match (x == last && receipt.status(), before) {
+            (true, Some(before)) => {
+                let after = balance_of(&provider, receiver, &payload.output).await?;
+                // The gas paid by the sender is taken from the native balance, not from the swap output
+                let fees = if book::is_native(&payload.output) { fees } else { 0 };
+                Some((after + fees).saturating_sub(before))
+            }
+            _ => None,
+        };
+        simulation.push(SimulatedTx {
+            gas_used: receipt.gas_used as u64,
+            success: receipt.status(),
+            output,
+        });
+        if !receipt.status() {
+            break;
+        }
+    }
+    Ok(simulation)
+}
+
+/// Executes the payload built for the request (see create): broadcast, or if the request is simulate-only, simulated without ever being sent, even with a private key
+/// A simulate-only execution returns the simulation of each transaction, with nothing sent
+pub async fn execute(backend: &dyn TxBackend, network: &Network, request: &ExecutionRequest, payload: &PayloadToExecute, pk: Option<String>) -> Result<ExecutedPayload, anyhow::Error> {
+    if !request.simulate_only {
+        return backend.broadcast(network, payload, pk).await;
+    }
+    let simulation = backend.simulate(network, payload).await.map_err(|e| anyhow::anyhow!(e))?;
+    for (x, tx) in simulation.iter().enumerate() {
+        tracing::debug!("Simulate-only: Tx #{}: Gas: {} | Status: {}", x, tx.gas_used, tx.success);
+    }
+    Ok(ExecutedPayload { simulation, ..Default::default() })
+}
+
+/// Compare the expected output of a trade (see Orderbook::expected_output) with its realized output
+pub fn slippage_report(expected: f64, realized: f64) -> SlippageReport {
+    let slippage_bps = if expected > 0. { (expected - realized) / expected * BPD } else { 0. };
+    SlippageReport { expected, realized, slippage_bps }
+}
+
+/// Simulate the payload (eth_simulateV1) and compare the swap output with the expected one (output token, human-readable)
+/// The realized output is the amount returned by the router swap call. Requires a RPC supporting eth_simulateV1.
+pub async fn simulate_slippage(network: Network, payload: PayloadToExecute, output: SrzToken, expected: f64) -> Result<SlippageReport, String> {
+    let provider = ProviderBuilder::new().on_http(network.rpc.parse().map_err(|e| format!("Invalid RPC URL: {:?}", e))?);
+    let payload = sim_payload(&payload);
+    let output_blocks = provider.simulate(&payload).await.map_err(|e| format!("Failed to simulate: {:?}", e))?;
+    let swap = output_blocks.iter().flat_map(|block| block.calls.iter()).next_back().ok_or("Swap call missing from the simulation".to_string())?;
+    if !swap.status {
+        return Err("Simulated swap failed".to_string());
+    }
+    let amount = U256::abi_decode(&swap.return_data, true).map_err(|e| format!("Failed to decode the swap output: {:?}", e))?;
+    let realized = amount.to_string().parse::<f64>().unwrap_or_default() / 10f64.powi(output.decimals as i32);
+    let report = slippage_report(expected, realized);
+    tracing::debug!("Expected: {} {} | Realized: {} | Slippage: {:.2} bps", report.expected, output.symbol, report.realized, report.slippage_bps);
+    Ok(report)
+}
+
 /// Broadcast the given transactions to the network
 pub async fn broadcast(network: Network, transactions: PayloadToExecute, pk: Option<String>) -> Result<ExecutedPayload, anyhow::Error> {
     let mut br = ExecutedPayload::default();
@@ -232,60 +486,71 @@ pub async fn broadcast(network: Network, transactions: PayloadToExecute, pk: Opt
     // Example Base: https://basescan.org/tx/0xd3a2a8e2d7b752d857298ef280d63975b072f030f811a65355214fb5de616d06
     if matching && simulate_execution(network.clone(), transactions.clone(), signer.clone()).await {
         tracing::debug!("Broadcasting to RPC URL: {}", network.rpc);
-        //  --- Broadcast Approval ---
-        match provider.send_transaction(transactions.approve).await {
-            Ok(approve) => {
-                br.approve.sent = true;
-                tracing::debug!("Waiting for receipt on approval tx: {:?}", approve.tx_hash());
-                br.approve.hash = approve.tx_hash().to_string();
-                tracing::debug!("Explorer: {}tx/{}", network.exp, approve.tx_hash());
-                match approve.get_receipt().await {
-                    Ok(receipt) => {
-                        tracing::debug!("Approval receipt: status: {:?}", receipt.status());
-                        br.approve.status = receipt.status();
-                        if receipt.status() {
-                            tracing::debug!("Approval transaction succeeded");
-                            // --- Broadcast Swap ---
-                            br.swap.sent = true;
-                            match provider.send_transaction(transactions.swap).await {
-                                Ok(swap) => {
-                                    br.swap.hash = swap.tx_hash().to_string();
-                                    tracing::debug!("Waiting for receipt on swap tx: {:?}", swap.tx_hash());
-                                    tracing::debug!("Explorer: {}tx/{}", network.exp, swap.tx_hash());
-                                    match swap.get_receipt().await {
-                                        Ok(receipt) => {
-                                            tracing::debug!("Swap receipt: status: {:?}", receipt.status());
-                                            br.swap.status = receipt.status();
-                                            if receipt.status() {
-                                                tracing::debug!("Swap transaction succeeded");
-                                            } else {
-                                                tracing::error!("Swap transaction failed");
-                                            }
-                                        }
-                                        Err(e) => {
-                                            tracing::error!("Failed to wait for swap transaction: {:?}", e);
-                                            br.swap.error = Some(e.to_string());
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    tracing::error!("Failed to send swap transaction: {:?}", e);
-                                    br.swap.error = Some(e.to_string());
-                                }
+        //  --- Broadcast Approval (none if the input is the native token) ---
+        let approved = match transactions.approve {
+            Some(approval) => match provider.send_transaction(approval).await {
+                Ok(approve) => {
+                    br.approve.sent = true;
+                    tracing::debug!("Waiting for receipt on approval tx: {:?}", approve.tx_hash());
+                    br.approve.hash = approve.tx_hash().to_string();
+                    tracing::debug!("Explorer: {}tx/{}", network.exp, approve.tx_hash());
+                    match approve.get_receipt().await {
+                        Ok(receipt) => {
+                            tracing::debug!("Approval receipt: status: {:?}", receipt.status());
+                            br.approve.status = receipt.status();
+                            if receipt.status() {
+                                tracing::debug!("Approval transaction succeeded");
+                            } else {
+                                tracing::error!("Approval transaction failed");
                             }
-                        } else {
-                            tracing::error!("Approval transaction failed");
+                            receipt.status()
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to wait for approval transaction: {:?}", e);
+                            br.approve.error = Some(e.to_string());
+                            false
                         }
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to wait for approval transaction: {:?}", e);
-                        br.approve.error = Some(e.to_string());
                     }
                 }
+                Err(e) => {
+                    tracing::error!("Failed to send approval transaction: {:?}", e);
+                    br.approve.error = Some(e.to_string());
+                    false
+                }
+            },
+            None => {
+                tracing::debug!("Native input, no approval needed");
+                true
             }
-            Err(e) => {
-                tracing::error!("Failed to send approval transaction: {:?}", e);
-                br.approve.error = Some(e.to_string());
+        };
+        // --- Broadcast Swap ---
+        if approved {
+            br.swap.sent = true;
+            match provider.send_transaction(transactions.swap).await {
+                Ok(swap) => {
+                    br.swap.hash = swap.tx_hash().to_string();
+                    tracing::debug!("Waiting for receipt on swap tx: {:?}", swap.tx_hash());
+                    tracing::debug!("Explorer: {}tx/{}", network.exp, swap.tx_hash());
+                    match swap.get_receipt().await {
+                        Ok(receipt) => {
+                            tracing::debug!("Swap receipt: status: {:?}", receipt.status());
+                            br.swap.status = receipt.status();
+                            if receipt.status() {
+                                tracing::debug!("Swap transaction succeeded");
+                            } else {
+                                tracing::error!("Swap transaction failed");
+                            }
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to wait for swap transaction: {:?}", e);
+                            br.swap.error = Some(e.to_string());
+                        }
+                    }
+                }
+                Err(e) => {
+                    tracing::error!("Failed to send swap transaction: {:?}", e);
+                    br.swap.error = Some(e.to_string());
+                }
             }
         }
     } else {
@@ -300,17 +565,24 @@ pub async fn broadcast(network: Network, transactions: PayloadToExecute, pk: Opt
 pub async fn create(network: Network, request: ExecutionRequest, native: Vec<ProtocolComponent>, pk: Option<String>) -> Result<PayloadToExecute, String> {
     tracing::debug!("Building transactions for request. Private key provided: {}", pk.is_some());
     let (_, _, chain) = types::chain(network.name.clone()).unwrap();
-    let tokens = vec![request.input.clone().address, request.output.clone().address];
     let achain = crate::utils::misc::get_alloy_chain(network.name.clone()).expect("Failed to get alloy chain");
     let provider = ProviderBuilder::new().with_chain(achain).on_http(network.rpc.parse().expect("Failed to parse RPC_URL"));
 
     // --- Check if the sender has enough balance of input token ---
-    match super::client::erc20b(&provider, request.sender.clone(), tokens.clone()).await {
-        Ok(balances) => {
-            tracing::debug!("Balances of sender {}: Input: {} | Output: {}", request.sender, balances[0], balances[1]);
+    let balance = match book::is_native(&request.input.address) {
+        // The native balance isn't an ERC20 one
+        true => match request.sender.parse::<Address>() {
+            Ok(sender) => provider.get_balance(sender).await.map(|b| b.to_string().parse::<u128>().unwrap_or_default()).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        false => super::client::erc20b(&provider, request.sender.clone(), vec![request.input.address.clone()]).await.map(|balances| balances[0]),
+    };
+    match balance {
+        Ok(balance) => {
+            tracing::debug!("Input balance of sender {}: {}", request.sender, balance);
             let amount = (request.amount * 10f64.powi(request.input.decimals as i32)) as u128;
-            if amount > balances[0] {
-                tracing::error!("Not enough balance for input token: need {} but sender has {}", amount, balances[0]);
+            if amount > balance {
+                tracing::error!("Not enough balance for input token: need {} but sender has {}", amount, balance);
                 return Err("Not enough balance for input token".to_string());
             }
         }
@@ -320,58 +592,332 @@ pub async fn create(network: Network, request: ExecutionRequest, native: Vec<Pro
     };
 
     tracing::debug!("Building swap calldata and transactions ...");
-    if let Some(solution) = solution(network.clone(), request.clone(), native.clone()).await {
-        let header: alloy::rpc::types::Block = provider.get_block_by_number(alloy::eips::BlockNumberOrTag::Latest, false).await.unwrap().unwrap();
-        let nonce = provider.get_transaction_count(solution.sender.to_string().parse().unwrap()).await.unwrap();
-        std::env::set_var("RPC_URL", network.rpc.clone());
-        // Need a strategy, else we get: FatalError("Please set the chain and strategy before building the encoder")
-        let encoder = match pk {
-            Some(pk) => EVMEncoderBuilder::new().chain(chain).initialize_tycho_router_with_permit2(pk.clone()),
-            None => EVMEncoderBuilder::new().chain(chain).initialize_tycho_router(),
-        };
-        match encoder {
-            Ok(encoder) => {
-                match encoder.build() {
-                    Ok(encoder) => {
-                        match encoder.encode_router_calldata(vec![solution.clone()]) {
-                            Ok(encoded_tx) => {
-                                let encoded_tx = encoded_tx[0].clone();
-                                match prepare(network.clone(), solution.clone(), encoded_tx.clone(), header, nonce) {
-                                    Some((approval, swap)) => {
-                                        let ep = PayloadToExecute {
-                                            approve: approval.clone(),
-                                            swap: swap.clone(),
-                                        };
-                                        // --- Logs ---
-                                        // tracing::debug!("--- Raw Transactions ---");
-                                        // tracing::debug!("Approval: {:?}", approval.clone());
-                                        // tracing::debug!("Swap: {:?}", swap.clone());
-                                        // tracing::debug!("--- Formatted Transactions ---");
-                                        // tracing::debug!("Approval: {:?}", ep.approve);
-                                        // tracing::debug!("Swap: {:?}", ep.swap);
-                                        // tracing::debug!("--- End of Transactions ---");
-                                        return Ok(ep);
-                                    }
-                                    None => {
-                                        tracing::error!("Failed to prepare transactions");
-                                    }
-                                };
-                            }
-                            Err(e) => {
-                                tracing::error!("Failed to encode router calldata: {:?}", e);
-                            }
+    let solution = solution(network.clone(), request.clone(), native.clone()).await?;
+    let header: alloy::rpc::types::Block = provider.get_block_by_number(alloy::eips::BlockNumberOrTag::Latest, false).await.unwrap().unwrap();
+    let nonce = provider.get_transaction_count(solution.sender.to_string().parse().unwrap()).await.unwrap();
+    std::env::set_var("RPC_URL", network.rpc.clone());
+    // Need a strategy, else we get: FatalError("Please set the chain and strategy before building the encoder")
+    let encoder = match pk {
+        Some(pk) => EVMEncoderBuilder::new().chain(chain).initialize_tycho_router_with_permit2(pk.clone()),
+        None => EVMEncoderBuilder::new().chain(chain).initialize_tycho_router(),
+    };
+    match encoder {
+        Ok(encoder) => {
+            match encoder.build() {
+                Ok(encoder) => {
+                    match encoder.encode_router_calldata(vec![solution.clone()]) {
+                        Ok(encoded_tx) => {
+                            let encoded_tx = encoded_tx[0].clone();
+                            match prepare(network.clone(), solution.clone(), encoded_tx.clone(), header, nonce) {
+                                Ok((approval, swap)) => {
+                                    let ep = PayloadToExecute {
+                                        approve: approval.clone(),
+                                        swap: swap.clone(),
+                                        output: request.output.address.clone(),
+                                    };
+                                    // --- Logs ---
+                                    // tracing::debug!("--- Raw Transactions ---");
+                                    // tracing::debug!("Approval: {:?}", approval.clone());
+                                    // tracing::debug!("Swap: {:?}", swap.clone());
+                                    // tracing::debug!("--- Formatted Transactions ---");
+                                    // tracing::debug!("Approval: {:?}", ep.approve);
+                                    // tracing::debug!("Swap: {:?}", ep.swap);
+                                    // tracing::debug!("--- End of Transactions ---");
+                                    return Ok(ep);
+                                }
+                                Err(e) => {
+                                    tracing::error!("Failed to prepare transactions: {}", e);
+                                    return Err(e);
+                                }
+                            };
+                        }
+                        Err(e) => {
+                            tracing::error!("Failed to encode router calldata: {:?}", e);
                         }
-                    }
-                    Err(e) => {
-                        tracing::error!("Failed to build EVMEncoder: {:?}", e);
                     }
                 }
+                Err(e) => {
+                    tracing::error!("Failed to build EVMEncoder: {:?}", e);
+                }
             }
-            Err(e) => {
-                tracing::error!("Failed to build EVMEncoder: {:?}", e);
-            }
+        }
+        Err(e) => {
+            tracing::error!("Failed to build EVMEncoder: {:?}", e);
+        }
+    };
+
+    Err("Failed to build transactions".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::fmt::SrzProtocolComponent;
+    use crate::utils::fixtures::{self, USDC, WETH};
+    use tycho_simulation::evm::tycho_models::Chain;
+
+    #[test]
+    fn test_permit2_amount_above_u128() {
+        let above_u128 = BigUint::from(u128::MAX) * BigUint::from(1_000u32);
+        let amount = permit2_amount(&above_u128).unwrap();
+        assert_eq!(amount.to_string(), above_u128.to_string());
+        let max = (BigUint::from(1u8) << 160) - BigUint::from(1u8);
+        assert!(permit2_amount(&max).is_ok());
+    }
+
+    #[test]
+    fn test_swap_gas_limit() {
+        let single = swap_gas_limit(&["uniswap_v2_pool"], 1.);
+        assert_eq!(single, execution::SWAP_ROUTER_GAS + 120_000);
+        let split = swap_gas_limit(&["uniswap_v2_pool", "uniswap_v3_pool", "curve_pool"], 1.);
+        assert_eq!(split, execution::SWAP_ROUTER_GAS + 120_000 + 150_000 + 250_000 + 2 * execution::SWAP_SPLIT_OVERHEAD_GAS);
+        assert!(split > 3 * single - 2 * execution::SWAP_ROUTER_GAS);
+        assert_eq!(swap_gas_limit(&["uniswap_v2_pool"], 1.5), (single as f64 * 1.5).ceil() as u64);
+        assert!(swap_gas_limit(&["uniswap_v2_pool"], execution::SWAP_GAS_SAFETY_MULTIPLIER) < 300_000);
+    }
+
+    fn request(slippage_bps: Option<u32>) -> ExecutionRequest {
+        ExecutionRequest {
+            sender: "0x0000000000000000000000000000000000000001".to_string(),
+            tag: format!("{}-{}", WETH, USDC),
+            input: fixtures::weth(),
+            output: fixtures::usdc(),
+            amount: 1.,
+            expected: 2_000.,
+            distribution: vec![100.],
+            components: vec![fixtures::component("0x01", "uniswap_v2", vec![fixtures::usdc(), fixtures::weth()], 30)],
+            slippage_bps,
+            enforce_min_out: true,
+            simulate_only: false,
+            fork: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_solution_slippage() {
+        let components = request(None).components.into_iter().map(|cp| SrzProtocolComponent::original(cp, Chain::Ethereum)).collect::<Vec<_>>();
+        let solution = solution(Network::default(), request(Some(50)), components.clone()).await.unwrap();
+        assert_eq!(solution.slippage, Some(0.005));
+        assert_eq!(solution.expected_amount, Some(BigUint::from(2_000_000_000u64)));
+        assert_eq!(solution.checked_amount, Some(BigUint::from(1_990_000_000u64)));
+        let default = solution(Network::default(), request(None), components.clone()).await.unwrap();
+        assert_eq!(default.slippage, Some(execution::EXEC_DEFAULT_SLIPPAGE));
+        assert!(solution(Network::default(), request(Some(5_001)), components).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_enforce_min_out() {
+        let components = request(None).components.into_iter().map(|cp| SrzProtocolComponent::original(cp, Chain::Ethereum)).collect::<Vec<_>>();
+        let enforced = solution(Network::default(), request(Some(100)), components.clone()).await.unwrap();
+        let expected = 2_000. * 1e6 * (1. - 0.01);
+        assert_eq!(enforced.checked_amount, Some(BigUint::from(expected as u128)));
+        let unchecked = ExecutionRequest {
+            enforce_min_out: false,
+            ..request(Some(100))
         };
+        let unchecked = solution(Network::default(), unchecked, components.clone()).await.unwrap();
+        assert_eq!((unchecked.checked_amount, unchecked.expected_amount, unchecked.slippage), (None, None, None));
+        // 0.0000001 USDC can't be represented with 6 decimals
+        let dust = ExecutionRequest { expected: 1e-7, ..request(None) };
+        assert!(solution(Network::default(), dust, components).await.is_err());
     }
 
-    Err("Failed to build transactions".to_string())
+    /// Backend counting the broadcasts, simulating every transaction as a success
+    #[derive(Default)]
+    struct SpyBackend {
+        broadcasts: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait]
+    impl TxBackend for SpyBackend {
+        async fn simulate(&self, _network: &Network, payload: &PayloadToExecute) -> Result<Vec<SimulatedTx>, String> {
+            Ok(payload.calls().iter().map(|_| SimulatedTx { gas_used: 100_000, success: true, output: None }).collect())
+        }
+
+        async fn broadcast(&self, _network: &Network, _payload: &PayloadToExecute, _pk: Option<String>) -> Result<ExecutedPayload, anyhow::Error> {
+            self.broadcasts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(ExecutedPayload::default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulate_only_never_broadcasts() {
+        let backend = SpyBackend::default();
+        let payload = PayloadToExecute {
+            approve: Some(TransactionRequest::default()),
+            swap: TransactionRequest::default(),
+            ..Default::default()
+        };
+        let pk = Some("0x0000000000000000000000000000000000000000000000000000000000000001".to_string());
+        let preview = ExecutionRequest { simulate_only: true, ..request(None) };
+        let executed = execute(&backend, &Network::default(), &preview, &payload, pk.clone()).await.unwrap();
+        assert_eq!(backend.broadcasts.load(std::sync::atomic::Ordering::SeqCst), 0);
+        assert_eq!(executed.simulation.len(), 2);
+        assert!(!executed.approve.sent && !executed.swap.sent);
+        execute(&backend, &Network::default(), &request(None), &payload, pk).await.unwrap();
+        assert_eq!(backend.broadcasts.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    /// Needs the anvil binary and an Ethereum mainnet RPC in FORK_RPC_URL: cargo test --features fork test_fork_simulation
+    #[cfg(feature = "fork")]
+    #[tokio::test]
+    async fn test_fork_simulation() {
+        alloy::sol! {
+            function swapExactETHForTokens(uint256 amountOutMin, address[] path, address to, uint256 deadline);
+        }
+        use alloy::sol_types::SolCall;
+        let rpc = std::env::var("FORK_RPC_URL").expect("FORK_RPC_URL not set");
+        let network = Network { rpc, ..Network::default() };
+        let sender = Address::from_str("0xd8dA6BF26964aF9D7eEd9e03E53415D37aA96045").unwrap();
+        let router = Address::from_str("0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D").unwrap(); // Uniswap V2 router, WETH-USDC pool
+        // A minimum output of 1 makes the swap revert on an empty output
+        let data = swapExactETHForTokensCall {
+            amountOutMin: U256::from(1),
+            path: vec![Address::from_str(WETH).unwrap(), Address::from_str(USDC).unwrap()],
+            to: sender,
+            deadline: U256::MAX,
+        }
+        .abi_encode();
+        let swap = TransactionRequest {
+            from: Some(sender),
+            to: Some(alloy::primitives::TxKind::Call(router)),
+            value: Some(U256::from(10u128.pow(17))),
+            input: TransactionInput::new(AlloyBytes::from(data)),
+            gas: Some(300_000),
+            ..Default::default()
+        };
+        let payload = PayloadToExecute { approve: None, swap, output: USDC.to_string() };
+        let preview = ExecutionRequest { simulate_only: true, fork: true, ..request(None) };
+        let executed = execute(backend(&preview).as_ref(), &network, &preview, &payload, None).await.unwrap();
+        assert_eq!(executed.simulation.len(), 1);
+        assert!(executed.simulation[0].success);
+        assert!(executed.simulation[0].gas_used > 21_000);
+        assert!(executed.simulation[0].output.unwrap_or_default() > 0);
+    }
+
+    #[test]
+    fn test_normalize_distribution() {
+        assert_eq!(normalize_distribution(&[60., 40.], 2).unwrap(), vec![0.6, 0.4]);
+        let normalized = normalize_distribution(&[49.75, 0., 49.75], 3).unwrap();
+        assert_eq!(normalized, vec![0.5, 0., 0.5]);
+        assert!((normalized.iter().sum::<f64>() - 1.).abs() < 1e-12);
+        assert!(normalize_distribution(&[110., -10.], 2).unwrap_err().contains("entry #1"));
+        assert!(normalize_distribution(&[50., 50.], 1).is_err());
+        assert!(normalize_distribution(&[50., 40.], 2).is_err());
+        assert!(normalize_distribution(&[], 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_solution_splits() {
+        let mut request = request(None);
+        request.components.push(fixtures::component("0x02", "uniswap_v3", vec![fixtures::usdc(), fixtures::weth()], 5));
+        let components = request.components.clone().into_iter().map(|cp| SrzProtocolComponent::original(cp, Chain::Ethereum)).collect::<Vec<_>>();
+        // Summing to 100
+        request.distribution = vec![100., 0.];
+        let single = solution(Network::default(), request.clone(), components.clone()).await.unwrap();
+        assert_eq!(single.swaps.len(), 1);
+        assert_eq!(single.swaps[0].split, 0.);
+        // Summing to 99.5, normalized: the first swap takes 30% of the total, the last one the remainder
+        request.distribution = vec![29.85, 69.65];
+        let split = solution(Network::default(), request.clone(), components.clone()).await.unwrap();
+        assert!((split.swaps[0].split - 0.3).abs() < 1e-12);
+        assert_eq!(split.swaps[1].split, 0.);
+        // Negative entry
+        request.distribution = vec![101., -1.];
+        assert!(solution(Network::default(), request, components).await.is_err());
+    }
+
+    #[test]
+    fn test_permit2_amount_overflow() {
+        let above_u160 = BigUint::from(1u8) << 160;
+        assert!(permit2_amount(&above_u160).is_err());
+    }
+
+    #[test]
+    fn test_sim_payload() {
+        let payload = PayloadToExecute {
+            approve: Some(TransactionRequest::default()),
+            ..Default::default()
+        };
+        let sim = sim_payload(&payload);
+        assert_eq!(sim.block_state_calls.len(), 1);
+        assert_eq!(sim.block_state_calls[0].calls.len(), 2);
+        assert!(sim.validation && sim.trace_transfers);
+        assert_eq!(sim_payload(&PayloadToExecute::default()).block_state_calls[0].calls.len(), 1);
+    }
+
+    #[test]
+    fn test_slippage_report() {
+        let report = slippage_report(2_000., 1_990.);
+        assert!((report.slippage_bps - 50.).abs() < 1e-9);
+        assert!(slippage_report(2_000., 2_010.).slippage_bps < 0.);
+        assert_eq!(slippage_report(0., 1.).slippage_bps, 0.);
+    }
+
+    #[tokio::test]
+    async fn test_native_input_no_approval() {
+        let mut request = request(None);
+        request.input.address = NULL_ADDRESS.to_string();
+        let components = request.components.clone().into_iter().map(|cp| SrzProtocolComponent::original(cp, Chain::Ethereum)).collect::<Vec<_>>();
+        let network = Network {
+            eth: WETH.to_string(),
+            permit2: "0x000000000022D473030F116dDEE9F6B43aC78BA3".to_string(),
+            ..Default::default()
+        };
+        let solution = solution(network.clone(), request, components).await.unwrap();
+        let encoded = Transaction {
+            to: tycho_simulation::tycho_core::Bytes::from_str("0x0178f471f219737c51d6005556d2f44de011a08a").unwrap(),
+            value: solution.given_amount.clone(),
+            data: vec![],
+        };
+        let (approval, swap) = transactions(network.clone(), solution.clone(), encoded.clone(), 1, 7).unwrap();
+        assert!(approval.is_none());
+        assert_eq!(swap.value, Some(U256::from(1_000_000_000_000_000_000u128)));
+        assert_eq!(swap.nonce, Some(7));
+        let payload = PayloadToExecute { approve: approval, swap, ..Default::default() };
+        assert_eq!(payload.calls().len(), 1);
+        // ERC20 input: approval first, then the swap without value
+        let mut erc20 = solution;
+        erc20.given_token = token_bytes(WETH);
+        let (approval, swap) = transactions(network, erc20, encoded, 1, 7).unwrap();
+        assert_eq!(approval.unwrap().nonce, Some(7));
+        assert_eq!((swap.value, swap.nonce), (Some(U256::from(0)), Some(8)));
+    }
+
+    #[tokio::test]
+    async fn test_native_solution_encoded() {
+        let network = Network {
+            eth: WETH.to_string(),
+            ..Default::default()
+        };
+        let mut request = request(None);
+        // The encoder needs the pool address as component ID
+        request.components = vec![fixtures::component("0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc", "uniswap_v2", vec![fixtures::usdc(), fixtures::weth()], 30)];
+        request.input.address = NULL_ADDRESS.to_string();
+        let components = request.components.clone().into_iter().map(|cp| SrzProtocolComponent::original(cp, Chain::Ethereum)).collect::<Vec<_>>();
+        let native_in = solution(network.clone(), request.clone(), components.clone()).await.unwrap();
+        assert!(matches!(native_in.native_action, Some(NativeAction::Wrap)));
+        assert_eq!(native_in.given_token, token_bytes(NULL_ADDRESS));
+        assert_eq!(native_in.swaps[0].token_in, token_bytes(WETH));
+        let (_, _, chain) = types::chain("ethereum".to_string()).unwrap();
+        let encoder = EVMEncoderBuilder::new().chain(chain).initialize_tycho_router().unwrap().build().unwrap();
+        let encoded = encoder.encode_router_calldata(vec![native_in.clone()]).unwrap();
+        assert_eq!(encoded[0].value, native_in.given_amount);
+        // Native output: unwrapped after the swaps
+        let native_out = ExecutionRequest {
+            input: fixtures::usdc(),
+            output: SrzToken {
+                address: NULL_ADDRESS.to_string(),
+                ..fixtures::weth()
+            },
+            amount: 2_000.,
+            expected: 1.,
+            ..request.clone()
+        };
+        let native_out = solution(network.clone(), native_out, components.clone()).await.unwrap();
+        assert!(matches!(native_out.native_action, Some(NativeAction::Unwrap)));
+        assert_eq!(native_out.swaps[0].token_out, token_bytes(WETH));
+        request.output.address = NULL_ADDRESS.to_string();
+        assert!(solution(network, request, components).await.is_err());
+    }
 }
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use tycho_simulation::evm::protocol::ekubo::state::EkuboState;
 use tycho_simulation::evm::protocol::filters::{balancer_pool_filter, curve_pool_filter, uniswap_v4_pool_with_hook_filter};
 use tycho_simulation::models::Token;
+use tycho_simulation::tycho_client::feed::component_tracker::ComponentFilter;
 
 use tycho_simulation::evm::protocol::uniswap_v3::state::UniswapV3State;
 use tycho_simulation::evm::protocol::uniswap_v4::state::UniswapV4State;
@@ -12,21 +13,28 @@ use tycho_simulation::evm::{
     stream::ProtocolStreamBuilder,
 };
 use tycho_simulation::protocol::models::ProtocolComponent;
+use tycho_simulation::protocol::state::ProtocolSim;
+
+use alloy::primitives::U256;
+use tycho_common::dto::ResponseProtocolState;
 
 use crate::builder::OrderbookBuilderConfig;
-use crate::data::fmt::SrzProtocolComponent;
+use crate::data::fmt::{SrzProtocolComponent, SrzToken};
 use crate::types;
 use crate::types::Network;
+use crate::types::OrderbookError;
 
 use crate::types::TychoSupportedProtocol;
 
 /// Get the default protocol stream builder
 /// But any other configuration of ProtocolStreamBuilder can be used to build an orderbook
 pub async fn default_protocol_stream_builder(network: Network, apikey: String, config: OrderbookBuilderConfig, tokens: Vec<Token>) -> ProtocolStreamBuilder {
+    registered_protocol_stream_builder(network, apikey, config, tokens).await.0
+}
+
+/// Same as default_protocol_stream_builder, along with the protocol systems registered on it (see Network::protocols)
+pub async fn registered_protocol_stream_builder(network: Network, apikey: String, config: OrderbookBuilderConfig, tokens: Vec<Token>) -> (ProtocolStreamBuilder, Vec<String>) {
     let (_, _, chain) = types::chain(network.name.clone()).expect("Invalid chain");
-    let u4 = uniswap_v4_pool_with_hook_filter;
-    let balancer = balancer_pool_filter;
-    let curve = curve_pool_filter;
     let filter = config.filter.clone();
 
     let mut hmt = HashMap::new();
@@ -36,59 +44,191 @@ pub async fn default_protocol_stream_builder(network: Network, apikey: String, c
 
     tracing::debug!("Tycho endpoint: {} and chain: {}", network.tycho, chain);
     let mut psb = ProtocolStreamBuilder::new(&network.tycho, chain)
-        .exchange::<UniswapV2State>(TychoSupportedProtocol::UniswapV2.to_string().as_str(), filter.clone(), None)
-        .exchange::<UniswapV3State>(TychoSupportedProtocol::UniswapV3.to_string().as_str(), filter.clone(), None)
-        .exchange::<UniswapV4State>(TychoSupportedProtocol::UniswapV4.to_string().as_str(), filter.clone(), Some(u4))
         .auth_key(Some(apikey.clone()))
         .skip_state_decode_failures(true)
         .set_tokens(hmt.clone()) // ALL Tokens
         .await;
+    let mut registered = vec![];
+    for protocol in network.protocols() {
+        tracing::trace!("Adding {} exchange on {}", protocol.to_string(), network.name);
+        psb = register(psb, protocol, filter.clone());
+        registered.push(protocol.to_string());
+    }
+    (psb, registered)
+}
+
+/// Registers a protocol on the stream builder, with its state type and pool filter
+pub fn register(psb: ProtocolStreamBuilder, protocol: TychoSupportedProtocol, filter: ComponentFilter) -> ProtocolStreamBuilder {
+    let name = protocol.to_string();
+    match protocol {
+        TychoSupportedProtocol::UniswapV2 | TychoSupportedProtocol::Sushiswap | TychoSupportedProtocol::PancakeswapV2 => psb.exchange::<UniswapV2State>(&name, filter, None),
+        TychoSupportedProtocol::UniswapV3 | TychoSupportedProtocol::PancakeswapV3 => psb.exchange::<UniswapV3State>(&name, filter, None),
+        TychoSupportedProtocol::UniswapV4 => psb.exchange::<UniswapV4State>(&name, filter, Some(uniswap_v4_pool_with_hook_filter)),
+        TychoSupportedProtocol::EkuboV2 => psb.exchange::<EkuboState>(&name, filter, None),
+        TychoSupportedProtocol::BalancerV2 => psb.exchange::<EVMPoolState<PreCachedDB>>(&name, filter, Some(balancer_pool_filter)),
+        TychoSupportedProtocol::Curve => psb.exchange::<EVMPoolState<PreCachedDB>>(&name, filter, Some(curve_pool_filter)),
+        TychoSupportedProtocol::MaverickV2 => psb.exchange::<EVMPoolState<PreCachedDB>>(&name, filter, None),
+    }
+}
 
-    if network.name.as_str() == "ethereum" {
-        tracing::trace!("Adding mainnet-specific exchanges");
-        psb = psb
-            .exchange::<UniswapV2State>(TychoSupportedProtocol::Sushiswap.to_string().as_str(), filter.clone(), None)
-            .exchange::<UniswapV2State>(TychoSupportedProtocol::PancakeswapV2.to_string().as_str(), filter.clone(), None)
-            .exchange::<UniswapV3State>(TychoSupportedProtocol::PancakeswapV3.to_string().as_str(), filter.clone(), None)
-            .exchange::<EkuboState>(TychoSupportedProtocol::EkuboV2.to_string().as_str(), filter.clone(), None)
-            .exchange::<EVMPoolState<PreCachedDB>>(TychoSupportedProtocol::BalancerV2.to_string().as_str(), filter.clone(), Some(balancer))
-            .exchange::<EVMPoolState<PreCachedDB>>(TychoSupportedProtocol::Curve.to_string().as_str(), filter.clone(), Some(curve));
+/// Rebuild a protosim from the raw state returned by Tycho RPC, matched on the exact protocol system of the component (e.g. 'uniswap_v2')
+/// Only Uniswap v2 forks (uniswap_v2, sushiswap_v2, pancakeswap_v2) can be rebuilt from their attributes (reserves).
+/// The other protocols need the full stream decoding (ticks, VM storage, ...), so None is returned for them
+pub fn rebuild_protosim(protocol_system: &str, raw: &ResponseProtocolState) -> Option<Box<dyn ProtocolSim>> {
+    let v2 = [TychoSupportedProtocol::UniswapV2, TychoSupportedProtocol::Sushiswap, TychoSupportedProtocol::PancakeswapV2];
+    if !v2.iter().any(|protocol| protocol.to_string() == protocol_system) {
+        return None;
     }
-    psb
+    let reserve0 = raw.attributes.get("reserve0").and_then(|b| U256::try_from_be_slice(b.as_ref()))?;
+    let reserve1 = raw.attributes.get("reserve1").and_then(|b| U256::try_from_be_slice(b.as_ref()))?;
+    Some(Box::new(UniswapV2State::new(reserve0, reserve1)))
 }
 
-/// Get the original components from the list of components
+/// Get the original components from the list of components, in the order of 'targets'
 /// Used when Tycho packages require the exact components
 /// Conversion from:: SrzProtocolComponent to ProtocolComponent doesn't work. Idk why.
-pub fn get_original_components(originals: HashMap<String, ProtocolComponent>, targets: Vec<SrzProtocolComponent>) -> Vec<ProtocolComponent> {
-    let mut filtered = Vec::with_capacity(targets.len());
-    for cp in targets.clone().iter().enumerate() {
-        let tgt = cp.1.id.to_string().to_lowercase();
-        if let Some(original) = originals.get(&tgt) {
-            filtered.push(original.clone());
-        } else {
-            tracing::warn!("OBP Event: Error: Component {} not found in the original list, anormal !", tgt);
+/// Originals are expected to be indexed by component key, as in the shared state.
+/// Errors with the keys of the missing targets (in target order), as a partial list would no longer match the execution distribution
+pub fn get_original_components(originals: HashMap<String, ProtocolComponent>, targets: Vec<SrzProtocolComponent>) -> Result<Vec<ProtocolComponent>, String> {
+    let missing = targets.iter().map(|cp| cp.key()).filter(|key| !originals.contains_key(key)).collect::<Vec<String>>();
+    if !missing.is_empty() {
+        tracing::error!("Execution error: {} components not found in the original list: {:?}", missing.len(), missing);
+        return Err(format!("Components not found in the original list: {}", missing.join(", ")));
+    }
+    Ok(targets.iter().filter_map(|cp| originals.get(&cp.key()).cloned()).collect())
+}
+
+/// Looks up reference tokens by symbol (e.g. WETH, USDC, DAI, used to target pairs in tests or demos), indexed by uppercased symbol. The first match of a symbol is kept.
+/// Missing ones are reported with a warning instead of failing, so that a chain without, say, a canonical DAI keeps working with the available ones
+pub fn reference_tokens(tokens: &[SrzToken], symbols: &[&str]) -> HashMap<String, SrzToken> {
+    let mut found = HashMap::new();
+    let mut missing = vec![];
+    for symbol in symbols {
+        match tokens.iter().find(|t| t.symbol.eq_ignore_ascii_case(symbol)) {
+            Some(token) => {
+                found.insert(symbol.to_uppercase(), token.clone());
+            }
+            None => missing.push(symbol.to_string()),
         }
     }
-    if filtered.len() != targets.len() {
-        tracing::error!("Execution error: not all components found in the original list, anormal !");
+    if !missing.is_empty() {
+        tracing::warn!("Reference tokens not found: {:?}. Continuing with {} of them", missing, found.len());
+    }
+    found
+}
+
+/// Parses a pair tag into its (base, quote) lowercased addresses
+/// Accepts '-' or '/' as separator, surrounding whitespace and checksummed addresses. A side that isn't an address is resolved as a token symbol (case insensitive) among `tokens`
+pub fn parse_tag(tag: &str, tokens: &[SrzToken]) -> Result<(String, String), OrderbookError> {
+    let parts = tag.trim().split(['-', '/']).map(str::trim).collect::<Vec<&str>>();
+    if parts.len() != 2 {
+        return Err(OrderbookError::InvalidPair);
+    }
+    let resolve = |part: &str| -> Result<String, OrderbookError> {
+        if let Some(hex) = part.strip_prefix("0x").or_else(|| part.strip_prefix("0X")) {
+            if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(OrderbookError::MalformedTag(format!("'{}' isn't a 20-byte hex address", part)));
+            }
+            return Ok(part.to_lowercase());
+        }
+        let matches = tokens.iter().filter(|t| t.symbol.eq_ignore_ascii_case(part)).collect::<Vec<&SrzToken>>();
+        match matches.as_slice() {
+            [token] => Ok(token.address.to_lowercase()),
+            [] => Err(OrderbookError::MalformedTag(format!("'{}' is neither an address nor a known token symbol", part))),
+            _ => Err(OrderbookError::MalformedTag(format!("symbol '{}' matches {} tokens, use its address", part, matches.len()))),
+        }
+    };
+    Ok((resolve(parts[0])?, resolve(parts[1])?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::r#static::networks;
+
+    #[test]
+    fn test_parse_tag() {
+        use crate::utils::fixtures::{self, USDC, WETH};
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let checksummed = " 0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2 / 0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48 ";
+        assert_eq!(parse_tag(checksummed, &tokens), Ok((WETH.to_string(), USDC.to_string())));
+        assert_eq!(parse_tag("weth-USDC", &tokens), Ok((WETH.to_string(), USDC.to_string())));
+        assert_eq!(parse_tag(WETH, &tokens), Err(OrderbookError::InvalidPair));
+        assert!(matches!(parse_tag(&format!("{}-0x1234", WETH), &tokens), Err(OrderbookError::MalformedTag(reason)) if reason.contains("0x1234")));
+        assert!(matches!(parse_tag(&format!("{}-DAI", WETH), &tokens), Err(OrderbookError::MalformedTag(_))));
+    }
+
+    #[test]
+    fn test_original_components() {
+        use crate::utils::fixtures;
+        use tycho_simulation::evm::tycho_models::Chain;
+        let targets = vec![
+            fixtures::component("0x02", "uniswap_v3", vec![fixtures::usdc(), fixtures::weth()], 5),
+            fixtures::component("0x01", "uniswap_v2", vec![fixtures::usdc(), fixtures::weth()], 30),
+        ];
+        let originals = targets.iter().map(|cp| (cp.key(), SrzProtocolComponent::original(cp.clone(), Chain::Ethereum))).collect::<HashMap<String, ProtocolComponent>>();
+        let found = get_original_components(originals.clone(), targets.clone()).unwrap();
+        assert_eq!(found.iter().map(|cp| cp.id.to_string()).collect::<Vec<String>>(), vec!["0x02".to_string(), "0x01".to_string()]);
+        let mut partial = originals;
+        partial.remove(&targets[0].key());
+        let error = get_original_components(partial, targets.clone()).unwrap_err();
+        assert!(error.contains(&targets[0].key()));
+    }
+
+    #[test]
+    fn test_reference_tokens_missing_dai() {
+        use crate::utils::fixtures;
+        let tokens = vec![fixtures::weth(), fixtures::usdc()];
+        let found = reference_tokens(&tokens, &["weth", "USDC", "DAI"]);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found["WETH"].address, fixtures::WETH);
+        assert!(!found.contains_key("DAI"));
+        assert!(reference_tokens(&[], &["DAI"]).is_empty());
+    }
+
+    #[test]
+    fn test_network_protocols() {
+        let networks = networks();
+        let base = networks.iter().find(|n| n.name == "base").unwrap();
+        assert!(base.protocols().contains(&TychoSupportedProtocol::PancakeswapV3));
+        assert!(!base.protocols().contains(&TychoSupportedProtocol::EkuboV2));
+        let ethereum = networks.iter().find(|n| n.name == "ethereum").unwrap();
+        assert!(ethereum.protocols().contains(&TychoSupportedProtocol::Curve));
+        let unconfigured = Network {
+            name: "ethereum".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(unconfigured.protocols().len(), 4);
+        // Configured protocols not deployed on the network are dropped too
+        let configured = Network {
+            name: "base".to_string(),
+            supported_protocols: vec![TychoSupportedProtocol::UniswapV2, TychoSupportedProtocol::EkuboV2, TychoSupportedProtocol::MaverickV2],
+            ..Default::default()
+        };
+        assert_eq!(configured.protocols(), vec![TychoSupportedProtocol::UniswapV2]);
+    }
+
+    #[test]
+    fn test_protocol_names() {
+        // Config names are the protocol systems, as registered on the stream
+        for name in TychoSupportedProtocol::vectorize() {
+            let protocol: TychoSupportedProtocol = serde_json::from_str(&format!("\"{}\"", name)).unwrap();
+            assert_eq!(protocol.to_string(), name);
+            assert_eq!(serde_json::to_string(&protocol).unwrap(), format!("\"{}\"", name));
+        }
+        let former: TychoSupportedProtocol = serde_json::from_str("\"curve\"").unwrap();
+        assert_eq!(former, TychoSupportedProtocol::Curve);
+    }
+
+    #[tokio::test]
+    async fn test_registered_protocols() {
+        let network = Network {
+            name: "base".to_string(),
+            tycho: "tycho-base-beta.propellerheads.xyz".to_string(),
+            supported_protocols: vec![TychoSupportedProtocol::UniswapV2, TychoSupportedProtocol::PancakeswapV3, TychoSupportedProtocol::EkuboV2],
+            ..Default::default()
+        };
+        let (_, registered) = registered_protocol_stream_builder(network, "key".to_string(), OrderbookBuilderConfig::default(), vec![]).await;
+        assert_eq!(registered, vec!["uniswap_v2".to_string(), "pancakeswap_v3".to_string()]);
     }
-    let order: HashMap<String, usize> = targets.iter().enumerate().map(|(i, item)| (item.id.to_string().to_lowercase(), i)).collect();
-    filtered.sort_by_key(|item| order.get(&item.id.to_string().to_lowercase()).copied().unwrap_or(usize::MAX));
-    // --- Logs ---
-    // for o in filtered.iter() {
-    //     tracing::trace!(" - originals : {}", o.id);
-    //     let attributes = o.static_attributes.clone();
-    //     for a in attributes.iter() {
-    //         tracing::trace!("   - {}: {}", a.0, a.1);
-    //     }
-    // }
-    // for t in targets.iter() {
-    //     tracing::trace!(" - targets   : {}", t.id);
-    //     let attributes = t.static_attributes.clone();
-    //     for a in attributes.iter() {
-    //         tracing::trace!("   - {}: {}", a.0, a.1);
-    //     }
-    // }
-    filtered
 }
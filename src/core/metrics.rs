@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+/// Receives the SDK metrics (orderbook builds, solver steps), e.g. to export them to Prometheus. Every callback does nothing by default
+/// Set it on the solver configuration (see OrderbookSolverConfig::metrics). Callbacks are invoked from the solver threads, so they must be cheap.
+pub trait MetricsSink: Send + Sync {
+    /// An orderbook was simulated for the pair 'tag', over 'pools' components
+    fn on_orderbook_built(&self, _tag: &str, _duration: Duration, _pools: usize) {}
+
+    /// One step (= one trade amount) of a solver sweep was optimized
+    fn on_gradient_step(&self, _duration: Duration) {}
+}
+
+/// Ignores all metrics, the default sink
+#[derive(Default, Debug, Clone)]
+pub struct NoopMetricsSink;
+
+impl MetricsSink for NoopMetricsSink {}
+
+/// Logs the metrics through tracing (builds at info level, steps at trace level)
+#[derive(Default, Debug, Clone)]
+pub struct LoggingMetricsSink;
+
+impl MetricsSink for LoggingMetricsSink {
+    fn on_orderbook_built(&self, tag: &str, duration: Duration, pools: usize) {
+        tracing::info!("📊 Orderbook {} built in {} ms over {} pools", tag, duration.as_millis(), pools);
+    }
+
+    fn on_gradient_step(&self, duration: Duration) {
+        tracing::trace!("📊 Gradient step optimized in {} µs", duration.as_micros());
+    }
+}
@@ -3,5 +3,7 @@ pub mod client;
 pub mod exec;
 pub mod gas;
 pub mod helper;
+pub mod metrics;
+pub mod oracle;
 pub mod protos;
 pub mod solver;
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::{
+    core::client,
+    types::Network,
+    utils::r#static::filter::NULL_ADDRESS,
+};
+
+/// Source of the ETH price in USD, used to value gas costs and liquidity in the orderbook.
+/// Implement it to plug your own price feed (e.g. a cached one, or an internal pricing service).
+#[async_trait]
+pub trait PriceOracle: Send + Sync {
+    /// Returns the value of 1 ETH in USD on the given network
+    async fn eth_usd(&self, network: &Network) -> Result<f64, anyhow::Error>;
+
+    /// Returns the value of 1 ETH in USD, and whether it may be stale (e.g. a cached value that couldn't be refreshed)
+    async fn eth_usd_checked(&self, network: &Network) -> Result<(f64, bool), anyhow::Error> {
+        Ok((self.eth_usd(network).await?, false))
+    }
+}
+
+/// Reads the ETH/USD price from the network Chainlink feed (see Network::chainlink)
+#[derive(Default, Debug, Clone)]
+pub struct ChainlinkOracle;
+
+#[async_trait]
+impl PriceOracle for ChainlinkOracle {
+    async fn eth_usd(&self, network: &Network) -> Result<f64, anyhow::Error> {
+        if network.chainlink.eq_ignore_ascii_case(NULL_ADDRESS) {
+            return Err(anyhow::anyhow!("No Chainlink ETH/USD feed on network {}", network.name));
+        }
+        client::get_eth_usd_chainlink(network.rpc.clone(), network.chainlink.clone())
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Failed to fetch ETH/USD from Chainlink on network {}", network.name))
+    }
+}
+
+/// Reads the ETH/USD price from the CoinGecko public API, regardless of the network
+#[derive(Default, Debug, Clone)]
+pub struct CoinGeckoOracle;
+
+#[async_trait]
+impl PriceOracle for CoinGeckoOracle {
+    async fn eth_usd(&self, _network: &Network) -> Result<f64, anyhow::Error> {
+        client::coingecko().await.ok_or_else(|| anyhow::anyhow!("Failed to fetch ETH/USD from CoinGecko"))
+    }
+}
+
+/// Always returns the same ETH/USD price. Useful for backtests, or to make the USD figures deterministic
+#[derive(Debug, Clone)]
+pub struct FixedOracle(pub f64);
+
+#[async_trait]
+impl PriceOracle for FixedOracle {
+    async fn eth_usd(&self, _network: &Network) -> Result<f64, anyhow::Error> {
+        Ok(self.0)
+    }
+}
+
+/// Caches the price of another oracle, per network, for at most 'eth_usd_max_age'
+/// Once the cached value is older than that, the next call forces a fresh fetch. If it fails, the old value is returned and flagged as stale.
+pub struct CachedOracle<O: PriceOracle> {
+    pub inner: O,
+    pub eth_usd_max_age: Duration,
+    cache: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl<O: PriceOracle> CachedOracle<O> {
+    pub fn new(inner: O, eth_usd_max_age: Duration) -> Self {
+        CachedOracle {
+            inner,
+            eth_usd_max_age,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl<O: PriceOracle> PriceOracle for CachedOracle<O> {
+    async fn eth_usd(&self, network: &Network) -> Result<f64, anyhow::Error> {
+        self.eth_usd_checked(network).await.map(|(price, _)| price)
+    }
+
+    async fn eth_usd_checked(&self, network: &Network) -> Result<(f64, bool), anyhow::Error> {
+        let mut cache = self.cache.lock().await;
+        let cached = cache.get(&network.name).copied();
+        if let Some((price, at)) = cached {
+            if at.elapsed() <= self.eth_usd_max_age {
+                return Ok((price, false));
+            }
+        }
+        match self.inner.eth_usd(network).await {
+            Ok(price) => {
+                cache.insert(network.name.clone(), (price, Instant::now()));
+                Ok((price, false))
+            }
+            Err(e) => match cached {
+                Some((price, at)) => {
+                    tracing::warn!("Failed to refresh ETH/USD on {} ({}), using cached value {} from {}s ago", network.name, e, price, at.elapsed().as_secs());
+                    Ok((price, true))
+                }
+                None => Err(e),
+            },
+        }
+    }
+}
+
+/// Behaviour of a FallbackOracle when none of its sources returns a price
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OraclePolicy {
+    /// Fail, so that no orderbook is built on a made-up ETH price
+    Strict,
+    /// Use the last price fetched on the network, else the given default. The price is flagged as stale
+    Lenient(f64),
+}
+
+/// Queries its sources in order (e.g. Chainlink, then CoinGecko) and returns the first price fetched
+/// If all of them fail, the policy decides between an error and a fallback price (see OraclePolicy)
+pub struct FallbackOracle {
+    pub sources: Vec<Box<dyn PriceOracle>>,
+    pub policy: OraclePolicy,
+    last: Mutex<HashMap<String, f64>>,
+}
+
+impl FallbackOracle {
+    pub fn new(sources: Vec<Box<dyn PriceOracle>>, policy: OraclePolicy) -> Self {
+        FallbackOracle {
+            sources,
+            policy,
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl PriceOracle for FallbackOracle {
+    async fn eth_usd(&self, network: &Network) -> Result<f64, anyhow::Error> {
+        self.eth_usd_checked(network).await.map(|(price, _)| price)
+    }
+
+    async fn eth_usd_checked(&self, network: &Network) -> Result<(f64, bool), anyhow::Error> {
+        for source in self.sources.iter() {
+            match source.eth_usd(network).await {
+                Ok(price) => {
+                    self.last.lock().await.insert(network.name.clone(), price);
+                    return Ok((price, false));
+                }
+                Err(e) => tracing::warn!("ETH/USD source failed on {}: {}", network.name, e),
+            }
+        }
+        match self.policy {
+            OraclePolicy::Strict => Err(anyhow::anyhow!("No ETH/USD source succeeded on network {}", network.name)),
+            OraclePolicy::Lenient(default) => {
+                let price = self.last.lock().await.get(&network.name).copied().unwrap_or(default);
+                tracing::warn!("No ETH/USD source succeeded on {}, using {} (flagged as stale)", network.name, price);
+                Ok((price, true))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Returns 2000 on the first call, then fails
+    struct FlakyOracle(AtomicUsize);
+
+    #[async_trait]
+    impl PriceOracle for FlakyOracle {
+        async fn eth_usd(&self, _network: &Network) -> Result<f64, anyhow::Error> {
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Ok(2000.),
+                _ => Err(anyhow::anyhow!("Feed unavailable")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached_oracle_within_max_age() {
+        let oracle = CachedOracle::new(FlakyOracle(AtomicUsize::new(0)), Duration::from_secs(3600));
+        let network = Network::default();
+        assert_eq!(oracle.eth_usd_checked(&network).await.unwrap(), (2000., false));
+        assert_eq!(oracle.eth_usd_checked(&network).await.unwrap(), (2000., false));
+        assert_eq!(oracle.inner.0.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cached_oracle_stale_after_failed_refresh() {
+        let oracle = CachedOracle::new(FlakyOracle(AtomicUsize::new(0)), Duration::ZERO);
+        let network = Network::default();
+        assert_eq!(oracle.eth_usd_checked(&network).await.unwrap(), (2000., false));
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(oracle.eth_usd_checked(&network).await.unwrap(), (2000., true));
+        assert_eq!(oracle.inner.0.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_fallback_oracle_policies() {
+        let network = Network::default();
+        let strict = FallbackOracle::new(vec![Box::new(FlakyOracle(AtomicUsize::new(1))), Box::new(FlakyOracle(AtomicUsize::new(1)))], OraclePolicy::Strict);
+        assert!(strict.eth_usd_checked(&network).await.is_err());
+        let lenient = FallbackOracle::new(vec![Box::new(FlakyOracle(AtomicUsize::new(1))), Box::new(FlakyOracle(AtomicUsize::new(0)))], OraclePolicy::Lenient(1_500.));
+        assert_eq!(lenient.eth_usd_checked(&network).await.unwrap(), (2000., false));
+        assert_eq!(lenient.eth_usd_checked(&network).await.unwrap(), (2000., true));
+        let fresh = FallbackOracle::new(vec![Box::new(FlakyOracle(AtomicUsize::new(1)))], OraclePolicy::Lenient(1_500.));
+        assert_eq!(fresh.eth_usd_checked(&network).await.unwrap(), (1_500., true));
+    }
+}
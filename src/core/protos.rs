@@ -1,20 +1,114 @@
-use crate::{types::AmmType, utils::r#static::maths::BPD};
+use crate::{data::fmt::SrzProtocolComponent, types::AmmType, utils::r#static::maths::BPD};
+
+/// Fee assumed for Curve pools without fee attribute, in bps
+pub static CURVE_DEFAULT_FEE_BPS: u128 = 4;
+
+/// Static attribute keys holding the fee, depending on the protocol type
+/// Curve pools don't use the same key as the others, depending on their pool type (plain, crypto, factory, ...)
+pub fn fee_keys(protocol: &str) -> &'static [&'static str] {
+    match protocol {
+        "curve_pool" => &["fee", "swap_fee", "pool_fee"],
+        _ => &["key_lp_fee", "fee"],
+    }
+}
+
+/// Protocol systems whose swaps can be encoded by the Tycho execution router
+pub static EXECUTABLE_PROTOCOLS: [&str; 9] = ["uniswap_v2", "sushiswap_v2", "pancakeswap_v2", "uniswap_v3", "pancakeswap_v3", "uniswap_v4", "ekubo_v2", "vm:balancer_v2", "vm:curve"];
+
+/// Whether a component can be routed by the Tycho execution router: its protocol must have a swap encoder, and Uniswap v4 pools can't have hooks
+pub fn executable(component: &SrzProtocolComponent) -> bool {
+    if !EXECUTABLE_PROTOCOLS.contains(&component.protocol_system.as_str()) {
+        return false;
+    }
+    let hooked = component
+        .static_attributes
+        .iter()
+        .any(|(k, v)| k == "hooks" && v.trim_start_matches("0x").chars().any(|c| c != '0'));
+    !hooked
+}
+
+/// Approximate gas cost of a swap on one pool, depending on the protocol type (without the router overhead)
+/// Unknown protocols get the highest estimate
+pub fn swap_gas(protocol: &str) -> u64 {
+    match protocol {
+        "pancakeswap_v2_pool" | "sushiswap_v2_pool" | "uniswap_v2_pool" => 120_000,
+        "pancakeswap_v3_pool" | "uniswap_v3_pool" => 150_000,
+        "uniswap_v4_pool" | "ekubo_v2_pool" => 180_000,
+        "balancer_v2_pool" => 200_000,
+        _ => 250_000, // Curve, and any protocol not listed above
+    }
+}
 
 /// Converts a native fee (as a hex string) into a byte vector representing fee in basis points.
 /// The conversion depends on the protocol type:
 /// - uniswap_v2_pool: fee is already in basis points (e.g., "0x1e" → 30)
 /// - uniswap_v3_pool or uniswap_v4_pool: fee is stored on a 1e6 scale (so 3000 → 30 bps, i.e. divide by 100)
-/// - curve: fee is stored on a pow10 scale (e.g., 4000000 becomes 4 bps, so divide by 1_000_000)
-/// - balancer_v2_pool: fee is stored on a pow18 scale (e.g., 1*10^15 becomes 10 bps, so divide by 1e14)
+/// - curve: fee is stored on a pow10 scale (e.g., 4000000 becomes 4 bps, so divide by 1_000_000). If the pool has no fee attribute, 4 bps is assumed.
+/// - balancer_v2_pool and maverick_v2_pool: fee is stored on a pow18 scale (e.g., 1*10^15 becomes 10 bps, so divide by 1e14)
 pub fn amm_fee_to_bps(protocol: String, _id: String, value: String) -> u128 {
     let fee = value.trim_start_matches("0x");
     let fee = u128::from_str_radix(fee, 16).unwrap_or(0);
     let fee = match AmmType::from(protocol.as_str()) {
         AmmType::PancakeswapV2 | AmmType::Sushiswap | AmmType::UniswapV2 => fee, // Already in bps
         AmmType::PancakeswapV3 | AmmType::UniswapV3 | AmmType::UniswapV4 => fee * (BPD as u128) / 1_000_000,
-        AmmType::Curve => 4,   // Not implemented, assuming 4 bps by default
+        AmmType::Curve => {
+            if fee == 0 {
+                CURVE_DEFAULT_FEE_BPS // Fee attribute missing (or null), assuming the most common Curve fee
+            } else {
+                fee * (BPD as u128) / 10_000_000_000
+            }
+        }
         AmmType::EkuboV2 => 0, // Not implemented, assuming 0 bps by default
         AmmType::Balancer => (fee * (BPD as u128)) / 1e18 as u128,
+        AmmType::MaverickV2 => (fee * (BPD as u128)) / 1e18 as u128,
+        AmmType::Unknown(name) => {
+            tracing::warn!("Unknown protocol type {}, assuming 0 bps fee", name);
+            0
+        }
     };
     fee
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fixtures;
+
+    fn bps(protocol: &str, value: &str) -> u128 {
+        amm_fee_to_bps(protocol.to_string(), "0x01".to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_amm_fee_to_bps() {
+        assert_eq!(bps("uniswap_v2_pool", "0x1e"), 30);
+        assert_eq!(bps("sushiswap_v2_pool", "0x1e"), 30);
+        assert_eq!(bps("pancakeswap_v2_pool", "0x19"), 25);
+        assert_eq!(bps("uniswap_v3_pool", "0x2710"), 100);
+        assert_eq!(bps("pancakeswap_v3_pool", "0x09c4"), 25);
+        assert_eq!(bps("uniswap_v4_pool", "0x0bb8"), 30);
+        assert_eq!(bps("balancer_v2_pool", "0x0aa87bee538000"), 30);
+        assert_eq!(bps("balancer_v2_pool", "0x38d7ea4c68000"), 10);
+        assert_eq!(bps("ekubo_v2_pool", "0x01"), 0);
+    }
+
+    #[test]
+    fn test_executable() {
+        let v2 = fixtures::component("0x01", "uniswap_v2", vec![fixtures::usdc(), fixtures::weth()], 30);
+        assert!(executable(&v2));
+        let unknown = fixtures::component("0x02", "vm:maverick_v2", vec![fixtures::usdc(), fixtures::weth()], 30);
+        assert!(!executable(&unknown));
+        let mut v4 = fixtures::component("0x03", "uniswap_v4", vec![fixtures::usdc(), fixtures::weth()], 30);
+        v4.static_attributes = vec![("hooks".to_string(), "0x0000000000000000000000000000000000000000".to_string())];
+        assert!(executable(&v4));
+        v4.static_attributes = vec![("hooks".to_string(), "0x5cd525c621afca515bf58631d4733fba7b72aae4".to_string())];
+        assert!(!executable(&v4));
+    }
+
+    #[test]
+    fn test_curve_fee_to_bps() {
+        assert_eq!(bps("curve_pool", "0x3D0900"), 4); // 0.04%
+        assert_eq!(bps("curve_pool", "0x1e8480"), 2); // 0.02%
+        assert_eq!(bps("curve_pool", ""), CURVE_DEFAULT_FEE_BPS);
+        assert_eq!(fee_keys("curve_pool"), &["fee", "swap_fee", "pool_fee"]);
+    }
+}
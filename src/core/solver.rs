@@ -1,48 +1,152 @@
+use std::sync::Arc;
+
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use tokio::time::Instant;
 
+use super::metrics::{MetricsSink, NoopMetricsSink};
 use crate::{
     data::fmt::SrzToken,
     maths::{self},
-    types::{ProtoSimComp, TradeResult},
+    types::{ProtoSimComp, Side, TradeResult},
     utils::{self, r#static::maths::ONE_HD},
 };
 
+/// Configuration of the solvers
+#[derive(Clone)]
+pub struct OrderbookSolverConfig {
+    /// Allocations below this percentage (0-100) of a trade are dropped, and the remaining ones renormalized
+    pub min_allocation_pct: f64,
+    /// Sink of the build and step metrics (see MetricsSink), ignored by default
+    pub metrics: Arc<dyn MetricsSink>,
+    /// Amounts to quote given the aggregated liquidity of the input token (see exponential, linear, geometric)
+    pub steps: StepsFn,
+    /// Bids and asks are truncated at the first trade whose price impact (0-1) exceeds it, not truncated if None (see truncate_price_impact)
+    pub max_price_impact: Option<f64>,
+    /// Fraction of the aggregated liquidity of the input token quoted to probe the best bid/ask, instead of BEST_BID_ASK_ETH_BPS of ETH if None (see probe_amount)
+    pub probe_fraction: Option<f64>,
+    /// Step generator of the bids (selling base), `steps` if None. E.g. a finer one for the less liquid side of an asymmetric pair
+    pub steps_bid: Option<StepsFn>,
+    /// Step generator of the asks (selling quote), `steps` if None
+    pub steps_ask: Option<StepsFn>,
+}
+
+/// Step generator: amounts to quote (human-readable) given the aggregated liquidity of the input token
+pub type StepsFn = fn(f64) -> Vec<f64>;
+
+impl Default for OrderbookSolverConfig {
+    fn default() -> Self {
+        OrderbookSolverConfig {
+            min_allocation_pct: utils::r#static::maths::simu::MIN_ALLOCATION_PCT,
+            metrics: Arc::new(NoopMetricsSink),
+            steps: exponential,
+            max_price_impact: Some(utils::r#static::maths::simu::MAX_PRICE_IMPACT),
+            probe_fraction: None,
+            steps_bid: None,
+            steps_ask: None,
+        }
+    }
+}
+
+impl OrderbookSolverConfig {
+    /// Step generator of the side, the common one if the side has none
+    pub fn steps_for(&self, side: Side) -> StepsFn {
+        match side {
+            Side::Bid => self.steps_bid.unwrap_or(self.steps),
+            Side::Ask => self.steps_ask.unwrap_or(self.steps),
+        }
+    }
+}
+
+impl std::fmt::Debug for OrderbookSolverConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OrderbookSolverConfig")
+            .field("min_allocation_pct", &self.min_allocation_pct)
+            .field("max_price_impact", &self.max_price_impact)
+            .field("probe_fraction", &self.probe_fraction)
+            .finish_non_exhaustive()
+    }
+}
+
 pub trait OrderbookSolver: Send + Sync {
-    fn generate_steps(&self, liquidity: f64) -> Vec<f64>;
+    /// Configuration the default methods read their steps, metrics sink and thresholds from
+    fn config(&self) -> &OrderbookSolverConfig;
+    fn generate_steps(&self, liquidity: f64) -> Vec<f64> {
+        (self.config().steps)(liquidity)
+    }
+    /// Amounts to quote on one side (bids sell base, asks sell quote) given the aggregated liquidity of its input token, see core::book::simulate
+    fn generate_side_steps(&self, side: Side, liquidity: f64) -> Vec<f64> {
+        (self.config().steps_for(side))(liquidity)
+    }
     /// Protosims contains the required functions to get the amount out of a swap
     #[allow(clippy::too_many_arguments)]
     fn optimize(&self, protosims: &[ProtoSimComp], steps: Vec<f64>, eth_usd: f64, gas_price: u128, from: &SrzToken, to: &SrzToken, price_from_to: f64, output_eth_worth: f64) -> Vec<TradeResult>;
+    /// Sink of the build metrics, see core::book::simulate
+    fn metrics(&self) -> &dyn MetricsSink {
+        self.config().metrics.as_ref()
+    }
+    /// Price impact (0-1) above which the bids and asks are truncated, see core::book::simulate
+    fn max_price_impact(&self) -> Option<f64> {
+        self.config().max_price_impact
+    }
+    /// Fraction of the aggregated liquidity quoted to probe the best bid/ask, see core::book::probe_amount
+    fn probe_fraction(&self) -> Option<f64> {
+        self.config().probe_fraction
+    }
 }
 
 // Default implementation
 
-pub struct DefaultOrderbookSolver;
+#[derive(Default, Clone)]
+pub struct DefaultOrderbookSolver {
+    pub config: OrderbookSolverConfig,
+}
 
 impl OrderbookSolver for DefaultOrderbookSolver {
-    fn generate_steps(&self, liquidity: f64) -> Vec<f64> {
-        exponential(liquidity)
+    fn config(&self) -> &OrderbookSolverConfig {
+        &self.config
     }
 
     #[allow(clippy::too_many_arguments)]
     fn optimize(&self, protosim: &[ProtoSimComp], steps: Vec<f64>, eth_usd: f64, gas_price: u128, from: &SrzToken, to: &SrzToken, price_from_to: f64, output_eth_worth: f64) -> Vec<TradeResult> {
         tracing::debug!("Default solver: optimize called with steps: {:?}", steps);
-        optimize(protosim, steps, eth_usd, gas_price, from, to, price_from_to, output_eth_worth)
+        optimize(protosim, steps, eth_usd, gas_price, from, to, price_from_to, output_eth_worth, self.config.min_allocation_pct, self.config.metrics.as_ref())
     }
 }
 
-pub struct CustomOrderbookSolver;
+#[derive(Default, Clone)]
+pub struct CustomOrderbookSolver {
+    pub config: OrderbookSolverConfig,
+}
 
 impl OrderbookSolver for CustomOrderbookSolver {
-    fn generate_steps(&self, liquidity: f64) -> Vec<f64> {
-        exponential(liquidity)
+    fn config(&self) -> &OrderbookSolverConfig {
+        &self.config
     }
 
     #[allow(clippy::too_many_arguments)]
     fn optimize(&self, protosim: &[ProtoSimComp], steps: Vec<f64>, eth_usd: f64, gas_price: u128, from: &SrzToken, to: &SrzToken, price_from_to: f64, output_eth_worth: f64) -> Vec<TradeResult> {
         // For custom logic, take the last available step if present.
         tracing::debug!("Custom solver: optimize called with steps: {:?}", steps);
-        optimize(protosim, steps, eth_usd, gas_price, from, to, price_from_to, output_eth_worth)
+        optimize(protosim, steps, eth_usd, gas_price, from, to, price_from_to, output_eth_worth, self.config.min_allocation_pct, self.config.metrics.as_ref())
+    }
+}
+
+/// Solver allocating each step with maths::opti::convex (marginal output equalized with golden-section searches) instead of the gradient heuristic
+/// Being closer to the optimum, its average prices are non-increasing with the trade size (gas aside), so trades aren't post-filtered.
+#[derive(Default, Clone)]
+pub struct ConvexOrderbookSolver {
+    pub config: OrderbookSolverConfig,
+}
+
+impl OrderbookSolver for ConvexOrderbookSolver {
+    fn config(&self) -> &OrderbookSolverConfig {
+        &self.config
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn optimize(&self, protosim: &[ProtoSimComp], steps: Vec<f64>, eth_usd: f64, gas_price: u128, from: &SrzToken, to: &SrzToken, price_from_to: f64, output_eth_worth: f64) -> Vec<TradeResult> {
+        tracing::debug!("Convex solver: optimize called with steps: {:?}", steps);
+        sweep(maths::opti::convex, protosim, steps, eth_usd, gas_price, from, to, price_from_to, output_eth_worth, self.config.min_allocation_pct, self.config.metrics.as_ref())
     }
 }
 
@@ -50,8 +154,42 @@ impl OrderbookSolver for CustomOrderbookSolver {
 /// Use the steps generated by function pointer
 use std::panic::{self, AssertUnwindSafe};
 
+/// Allocations below `min_allocation_pct` (0-100) are dropped and the trade re-evaluated, so that it stays worth executing
+#[allow(clippy::too_many_arguments)]
+pub fn optimize(
+    protosim: &[ProtoSimComp],
+    steps: Vec<f64>,
+    eth_usd: f64,
+    gas_price: u128,
+    from: &SrzToken,
+    to: &SrzToken,
+    spot_price: f64,
+    output_eth_worth: f64,
+    min_allocation_pct: f64,
+    metrics: &dyn MetricsSink,
+) -> Vec<TradeResult> {
+    // The unrealistic tail is truncated by price impact (see truncate_price_impact), instead of removing the trades with a decreasing price
+    sweep(maths::opti::gradient, protosim, steps, eth_usd, gas_price, from, to, spot_price, output_eth_worth, min_allocation_pct, metrics)
+}
+
+/// Function quoting one trade of a sweep (see maths::opti::gradient)
+pub type Quoter = fn(f64, &[ProtoSimComp], SrzToken, SrzToken, f64, u128, f64, f64) -> TradeResult;
+
+/// Quotes each step in parallel with the given quoter, pruning the allocations below `min_allocation_pct`. Steps whose quote panicked are skipped.
 #[allow(clippy::too_many_arguments)]
-pub fn optimize(protosim: &[ProtoSimComp], steps: Vec<f64>, eth_usd: f64, gas_price: u128, from: &SrzToken, to: &SrzToken, spot_price: f64, output_eth_worth: f64) -> Vec<TradeResult> {
+fn sweep(
+    quoter: Quoter,
+    protosim: &[ProtoSimComp],
+    steps: Vec<f64>,
+    eth_usd: f64,
+    gas_price: u128,
+    from: &SrzToken,
+    to: &SrzToken,
+    spot_price: f64,
+    output_eth_worth: f64,
+    min_allocation_pct: f64,
+    metrics: &dyn MetricsSink,
+) -> Vec<TradeResult> {
     let trades: Vec<Option<TradeResult>> = steps
         //.iter()
         .par_iter()
@@ -59,7 +197,16 @@ pub fn optimize(protosim: &[ProtoSimComp], steps: Vec<f64>, eth_usd: f64, gas_pr
         .map(|(x, amount)| {
             let res = panic::catch_unwind(AssertUnwindSafe(|| {
                 let tmstp = Instant::now();
-                let result = maths::opti::gradient(*amount, protosim, from.clone(), to.clone(), eth_usd, gas_price, spot_price, output_eth_worth);
+                let result = quoter(*amount, protosim, from.clone(), to.clone(), eth_usd, gas_price, spot_price, output_eth_worth);
+                let (distribution, pruned) = maths::opti::renormalize(&result.distribution, min_allocation_pct);
+                let result = if pruned > 0 {
+                    let mut smoothed = maths::opti::evaluate(*amount, protosim, from.clone(), to.clone(), &distribution, eth_usd, gas_price, spot_price, output_eth_worth);
+                    smoothed.pruned = pruned;
+                    smoothed
+                } else {
+                    result
+                };
+                metrics.on_gradient_step(tmstp.elapsed());
                 let elapsed = tmstp.elapsed().as_millis();
                 let gas_cost: f64 = result.gas_costs_usd.iter().sum();
                 // let sum_distribution: f64 = result.distribution.iter().sum();
@@ -89,19 +236,23 @@ pub fn optimize(protosim: &[ProtoSimComp], steps: Vec<f64>, eth_usd: f64, gas_pr
         })
         .collect();
     // Filter out any None values.
-    let trades: Vec<TradeResult> = trades.into_iter().flatten().collect();
-    // Remove trades that have a non-increasing price impact.
-    let size = trades.len();
-    // let (filtered_trades, removed) = remove_decreasing_price(&trades); // [old]
-    let (filtered_trades, removed) = remove_decreasing_price_with_sensitivity(&trades, 0.05); // [new]
+    trades.into_iter().flatten().collect()
+}
 
-    tracing::debug!("Removed {} out of {} trades with decreasing price.", removed, size);
-    filtered_trades
+/// Exact-out sweep: for each target output, finds the optimized trade receiving it (see maths::opti::gradient_exact_out)
+/// Targets that can't be reached with the available liquidity are skipped.
+#[allow(clippy::too_many_arguments)]
+pub fn optimize_exact_out(protosim: &[ProtoSimComp], targets: Vec<f64>, eth_usd: f64, gas_price: u128, from: &SrzToken, to: &SrzToken, spot_price: f64, output_eth_worth: f64) -> Vec<TradeResult> {
+    targets
+        .par_iter()
+        .filter_map(|target| maths::opti::gradient_exact_out(*target, protosim, from.clone(), to.clone(), eth_usd, gas_price, spot_price, output_eth_worth))
+        .collect()
 }
 
 /// Default steps function
 /// This function generates a set of quoted amounts based on the aggregated liquidity of the pools.
 /// Up to END_MULTIPLIER % of the aggregated liquidity, it generates a set of amounts using an exponential function with minimum delta percentage.
+/// Dense at small sizes, where the price moves the most, but sparse in the deep part of the book.
 pub fn exponential(liquidity: f64) -> Vec<f64> {
     let start = liquidity / utils::r#static::maths::TEN_MILLIONS;
     let steps = maths::steps::expo(
@@ -110,36 +261,78 @@ pub fn exponential(liquidity: f64) -> Vec<f64> {
         utils::r#static::maths::simu::END_MULTIPLIER,
         utils::r#static::maths::simu::END_MULTIPLIER * utils::r#static::maths::simu::MIN_EXP_DELTA_PCT,
     );
-    let steps = steps.iter().map(|x| x * start).collect::<Vec<f64>>();
-    let r8 = steps.iter().map(|x| (x * 100_000_000.0).round() / 100_000_000.0).collect::<Vec<f64>>();
-    r8
-}
-/// Retains only those trades whose average_sell_price does not drop more than `max_drop_pct`
-/// relative to the previous kept trade. Returns (filtered, removed_count).
-pub fn remove_decreasing_price_with_sensitivity(
-    items: &[TradeResult],
-    max_drop_pct: f64, // e.g. 0.05 for 5%
-) -> (Vec<TradeResult>, usize) {
-    if items.is_empty() {
-        return (Vec::new(), 0);
-    }
-
-    let mut filtered = Vec::with_capacity(items.len());
-    // start with first trade unconditionally
-    let mut last_kept_price = items[0].average_sell_price;
-    filtered.push(items[0].clone());
-
-    for trade in &items[1..] {
-        let price = trade.average_sell_price;
-        // compute relative drop: (previous – current) / previous
-        let drop_ratio = (last_kept_price - price) / last_kept_price;
-        // keep trade only if drop ≤ threshold
-        if drop_ratio <= max_drop_pct {
-            filtered.push(trade.clone());
-            last_kept_price = price;
+    round8(steps.iter().map(|x| x * start).collect())
+}
+
+/// Same range as exponential, with evenly spaced amounts: uniform depth coverage for charting, at the cost of the price resolution near the mid
+pub fn linear(liquidity: f64) -> Vec<f64> {
+    let start = liquidity / utils::r#static::maths::TEN_MILLIONS;
+    let steps = maths::steps::linear(utils::r#static::maths::simu::COUNT, utils::r#static::maths::simu::START_MULTIPLIER, utils::r#static::maths::simu::END_MULTIPLIER);
+    round8(steps.iter().map(|x| x * start).collect())
+}
+
+/// Same range as exponential, with a constant ratio between amounts and all COUNT of them kept: between exponential and linear in density near the mid
+pub fn geometric(liquidity: f64) -> Vec<f64> {
+    let start = liquidity / utils::r#static::maths::TEN_MILLIONS;
+    let steps = maths::steps::geometric_with_floor(utils::r#static::maths::simu::COUNT, 0., utils::r#static::maths::simu::END_MULTIPLIER, utils::r#static::maths::simu::START_MULTIPLIER);
+    round8(steps.iter().map(|x| x * start).collect())
+}
+
+/// Rounds the amounts to 8 decimals
+fn round8(steps: Vec<f64>) -> Vec<f64> {
+    steps.iter().map(|x| (x * 100_000_000.0).round() / 100_000_000.0).collect()
+}
+
+/// Truncates the trades (sorted by amount) at the first one whose price impact exceeds `max_price_impact` (0-1), returning the realistic part of the curve
+/// Returns (kept, truncated_count)
+pub fn truncate_price_impact(mut trades: Vec<TradeResult>, max_price_impact: f64) -> (Vec<TradeResult>, usize) {
+    let size = trades.len();
+    if let Some(x) = trades.iter().position(|trade| trade.price_impact > max_price_impact) {
+        trades.truncate(x);
+    }
+    let truncated = size - trades.len();
+    (trades, truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fixtures;
+
+    /// Compares the convex solver against the default one on 3 pools with different prices and depths
+    #[test]
+    fn test_convex_vs_default() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_010_000.), fixtures::pool("0x03", 200., 395_000.)];
+        let (weth, usdc) = (fixtures::weth(), fixtures::usdc());
+        let steps = vec![1., 10., 50., 100., 200., 400.];
+        let convex = ConvexOrderbookSolver::default().optimize(&pools, steps.clone(), 2000., 0, &weth, &usdc, 2000., 0.0005);
+        assert_eq!(convex.len(), steps.len());
+        let (mut total_convex, mut total_default) = (0., 0.);
+        for trade in convex.iter() {
+            let default = maths::opti::gradient(trade.amount, &pools, weth.clone(), usdc.clone(), 2000., 0, 2000., 0.0005);
+            assert!(trade.output >= default.output * (1. - 1e-4));
+            total_convex += trade.output;
+            total_default += default.output;
+        }
+        assert!(total_convex >= total_default * (1. - 1e-4));
+        // Non-increasing average prices, without post-filtering
+        for pair in convex.windows(2) {
+            assert!(pair[1].average_sell_price <= pair[0].average_sell_price * (1. + 1e-6));
         }
     }
 
-    let removed = items.len() - filtered.len();
-    (filtered, removed)
+    #[test]
+    fn test_selectable_steps() {
+        let solver = DefaultOrderbookSolver {
+            config: OrderbookSolverConfig {
+                steps: linear,
+                ..Default::default()
+            },
+        };
+        let steps = solver.generate_steps(10_000_000.);
+        assert_eq!(steps.len(), utils::r#static::maths::simu::COUNT);
+        assert_eq!((steps[0], steps[steps.len() - 1]), (1., 1_000_000.));
+        assert_eq!(geometric(10_000_000.).len(), utils::r#static::maths::simu::COUNT);
+        assert_eq!(DefaultOrderbookSolver::default().generate_steps(10_000_000.), exponential(10_000_000.));
+    }
 }
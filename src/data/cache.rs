@@ -0,0 +1,107 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::data::store::StateStore;
+use crate::types::{Network, Orderbook, OrderbookRequestParams};
+
+/// Orderbooks cached by pair and block (see OrderbookProvider::get_orderbook_cached), expiring after a few blocks of the network
+/// A cached book is dropped as soon as one of its pools is updated (see invalidate), so that only books of unchanged pools are served
+pub struct OrderbookCache<St: StateStore> {
+    pub store: Arc<St>,
+    /// Time to live of the cached books, in seconds
+    pub ttl_secs: u64,
+    /// Block and component keys of each cached book, indexed by tag
+    pub cached: Mutex<HashMap<String, (u64, Vec<String>)>>,
+}
+
+impl<St: StateStore> OrderbookCache<St> {
+    /// Books live for `blocks` blocks of the network (at least 1 second)
+    pub fn new(store: Arc<St>, network: &Network, blocks: u64) -> Self {
+        OrderbookCache {
+            store,
+            ttl_secs: (network.block_time_ms.saturating_mul(blocks) / 1_000).max(1),
+            cached: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Cache key of the book of a pair (tag 'base-quote', with lowercased addresses) at a block
+    pub fn key(tag: &str, block: u64) -> String {
+        format!("orderbook:{}:{}", tag.to_lowercase(), block)
+    }
+
+    /// Cached book of the pair at the block, if any. A book that can't be read is treated as missing
+    pub async fn get(&self, tag: &str, block: u64) -> Option<Orderbook> {
+        match self.store.get::<Orderbook>(&Self::key(tag, block)).await {
+            Ok(book) => book,
+            Err(e) => {
+                tracing::warn!("Failed to read the cached orderbook {} at block {}: {}", tag, block, e);
+                None
+            }
+        }
+    }
+
+    /// Caches the book under its tag and block
+    pub async fn put(&self, book: &Orderbook) -> Result<(), anyhow::Error> {
+        self.store.set(&Self::key(&book.tag, book.block), book, Some(self.ttl_secs)).await?;
+        self.cached.lock().await.insert(book.tag.clone(), (book.block, book.pools.iter().map(|cp| cp.key()).collect()));
+        Ok(())
+    }
+
+    /// Drops the cached books having one of the updated components (keys, as in OrderbookEvent::NewHeader). Returns the tags dropped
+    pub async fn invalidate(&self, updated: &[String]) -> Vec<String> {
+        let mut cached = self.cached.lock().await;
+        let stale = cached.iter().filter(|(_, (_, keys))| keys.iter().any(|key| updated.contains(key))).map(|(tag, (block, _))| (tag.clone(), *block)).collect::<Vec<(String, u64)>>();
+        for (tag, block) in stale.iter() {
+            cached.remove(tag);
+            if let Err(e) = self.store.del(&Self::key(tag, *block)).await {
+                tracing::warn!("Failed to drop the cached orderbook {} at block {}: {}", tag, block, e);
+            }
+        }
+        stale.into_iter().map(|(tag, _)| tag).collect()
+    }
+}
+
+/// Whether the book requested can be cached: a full book without any option, as the key only holds the pair and the block
+pub fn cacheable(params: &OrderbookRequestParams) -> bool {
+    params.point.is_none()
+        && !params.both_directions
+        && params.fee_overrides.is_empty()
+        && !params.executable_only
+        && params.protocols.is_empty()
+        && !params.with_components
+        && !params.pad_levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use crate::utils::fixtures::{self, CountingStore, USDC, WETH};
+
+    #[tokio::test]
+    async fn test_orderbook_cache() {
+        let store = Arc::new(CountingStore::default());
+        let network = Network { block_time_ms: 12_000, ..Default::default() };
+        let cache = OrderbookCache::new(store.clone(), &network, 2);
+        assert_eq!(cache.ttl_secs, 24);
+        let tag = format!("{}-{}", WETH, USDC);
+        let pool = fixtures::component("0x01", "uniswap_v2", vec![fixtures::usdc(), fixtures::weth()], 30);
+        let book = Orderbook {
+            tag: tag.clone(),
+            block: 10,
+            pools: vec![pool.clone()],
+            ..Default::default()
+        };
+        assert!(cache.get(&tag, 10).await.is_none());
+        cache.put(&book).await.unwrap();
+        assert_eq!(cache.get(&tag, 10).await.map(|cached| cached.block), Some(10));
+        assert!(cache.get(&tag, 11).await.is_none());
+        assert_eq!(store.hits.load(Ordering::SeqCst), 1);
+        // Unrelated component updated: kept
+        assert!(cache.invalidate(&["uniswap_v2:0x02".to_string()]).await.is_empty());
+        assert_eq!(cache.invalidate(&[pool.key()]).await, vec![tag.clone()]);
+        assert!(cache.get(&tag, 10).await.is_none());
+    }
+}
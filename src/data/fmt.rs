@@ -17,7 +17,7 @@ use tycho_simulation::protocol::models::ProtocolComponent;
 use tycho_simulation::tycho_core::Bytes;
 use utoipa::ToSchema;
 
-use crate::core::protos::amm_fee_to_bps;
+use crate::core::protos::{amm_fee_to_bps, fee_keys};
 use crate::utils::misc::current_timestamp;
 
 /// @notice Format of the data that will be read/stored in the database
@@ -27,7 +27,7 @@ use crate::utils::misc::current_timestamp;
 // Tycho Tokens
 // =====================================================================================================================================================================================================
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
+#[derive(Default, Debug, Clone, Eq, PartialEq, Serialize, Deserialize, ToSchema)]
 pub struct SrzToken {
     #[schema(example = "0xTokenAddress")]
     pub address: String,
@@ -109,21 +109,33 @@ pub struct SrzProtocolComponent {
 // uniswap_v3_pool: fee: Bytes(0x2710)
 // uniswap_v4_pool: key_lp_fee: Bytes(0x0bb8)
 // balancer_v2_pool: fee: Bytes(0x0aa87bee538000)
-// curve: not implemented (todo!) = considering 0
+// curve_pool: fee (or swap_fee, pool_fee): on a pow10 scale, considering 4 bps if missing
+
+/// Unique key of a component in the shared state: 'protocol_system:id', lowercased
+/// Component IDs alone could collide across protocols (e.g. Uniswap v4 pool IDs are bytes, not addresses)
+pub fn component_key(protocol_system: &str, id: &str) -> String {
+    format!("{}:{}", protocol_system, id).to_lowercase()
+}
 
 impl SrzProtocolComponent {
     pub fn contains(&self, token: &str) -> bool {
         self.tokens.iter().any(|t| t.symbol.eq_ignore_ascii_case(token))
     }
+
+    /// Unique key of the component in the shared state
+    pub fn key(&self) -> String {
+        component_key(&self.protocol_system, &self.id)
+    }
 }
 
 impl From<ProtocolComponent> for SrzProtocolComponent {
     fn from(pc: ProtocolComponent) -> Self {
-        //  "key_lp_fee" || k == "fee"
+        //  "key_lp_fee" || k == "fee", or the Curve specific keys
+        let keys = fee_keys(pc.protocol_type_name.as_str());
         let fee_value = pc
             .static_attributes
             .iter()
-            .find(|(k, _)| *k == "key_lp_fee" || *k == "fee")
+            .find(|(k, _)| keys.contains(&k.as_str()))
             .map(|(_, v)| v.to_string())
             .unwrap_or_default();
         SrzProtocolComponent {
@@ -144,6 +156,9 @@ impl From<ProtocolComponent> for SrzProtocolComponent {
 }
 
 impl SrzProtocolComponent {
+    /// Rebuilds the component from its serialized version
+    /// Contract IDs, static attributes and creation tx are hex decoded when they are hex (as serialized by From<ProtocolComponent>), so that they round trip.
+    /// Values that aren't hex are taken as the raw bytes of the string, as before: a hex string is thus no longer turned into its UTF-8 bytes
     #[allow(deprecated)]
     pub fn original(srz: SrzProtocolComponent, chain: tycho_simulation::evm::tycho_models::Chain) -> ProtocolComponent {
         ProtocolComponent {
@@ -153,15 +168,20 @@ impl SrzProtocolComponent {
             protocol_system: srz.protocol_system,
             protocol_type_name: srz.protocol_type_name,
             chain,
-            contract_ids: srz.contract_ids.into_iter().map(|s| Bytes::from(s.into_bytes())).collect(),
-            static_attributes: srz.static_attributes.into_iter().map(|(k, v)| (k, Bytes::from(v.into_bytes()))).collect(),
+            contract_ids: srz.contract_ids.into_iter().map(hex_or_raw).collect(),
+            static_attributes: srz.static_attributes.into_iter().map(|(k, v)| (k, hex_or_raw(v))).collect(),
             // Not important.
-            creation_tx: Bytes::from(srz.creation_tx.into_bytes()),
+            creation_tx: hex_or_raw(srz.creation_tx),
             created_at: chrono::NaiveDateTime::default(), // ! Important
         }
     }
 }
 
+/// Bytes of a serialized value: decoded if it's hex (as serialized by From<ProtocolComponent>), raw bytes of the string otherwise
+fn hex_or_raw(value: String) -> Bytes {
+    Bytes::from_str(&value).unwrap_or_else(|_| Bytes::from(value.into_bytes()))
+}
+
 // =====================================================================================================================================================================================================
 // Convert a part of a protocol State to a serialized version (and more readable)
 // Not reversible, because the state is not fully serialized (it contains a lot of data)
@@ -186,6 +206,13 @@ impl From<(UniswapV2State, String)> for SrzUniswapV2State {
     }
 }
 
+/// Reserves are the whole state of a Uniswap v2 pool, so it's the only reversible one
+impl From<SrzUniswapV2State> for UniswapV2State {
+    fn from(srz: SrzUniswapV2State) -> Self {
+        UniswapV2State::new(U256::from(srz.reserve0), U256::from(srz.reserve1))
+    }
+}
+
 // =======> Uniswap v3 <=======
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -206,7 +233,7 @@ impl From<(UniswapV3State, String)> for SrzUniswapV3State {
             sqrt_price: state.sqrt_price,
             fee: state.fee as i32,
             tick: state.tick,
-            ticks: SrzTickList::from(state.ticks), // ! TODO: sort by index
+            ticks: SrzTickList::from(state.ticks),
         }
     }
 }
@@ -260,7 +287,7 @@ impl From<(UniswapV4State, String)> for SrzUniswapV4State {
                 lp_fee: state.fees.lp_fee,
             },
             tick: state.tick,
-            ticks: SrzTickList::from(state.ticks), // ! TODO: sort by index // WTF
+            ticks: SrzTickList::from(state.ticks),
         }
     }
 }
@@ -290,11 +317,14 @@ impl From<TickInfo> for SrzTickInfo {
     }
 }
 
+/// Ticks are sorted by ascending index, whatever their order in the tick list (e.g. to draw the liquidity depth)
 impl From<TickList> for SrzTickList {
     fn from(ticks: TickList) -> Self {
+        let mut srz = ticks.ticks.into_iter().map(SrzTickInfo::from).collect::<Vec<SrzTickInfo>>();
+        srz.sort_by_key(|tick| tick.index);
         SrzTickList {
             tick_spacing: ticks.tick_spacing,
-            ticks: ticks.ticks.into_iter().map(SrzTickInfo::from).collect(),
+            ticks: srz,
         }
     }
 }
@@ -375,4 +405,31 @@ mod tests {
         let converted_token: Token = srz_token.into();
         assert_eq!(original_token, converted_token, "Round trip conversion failed");
     }
+
+    #[test]
+    fn test_tick_list_sorted() {
+        let tick = |index: i32| TickInfo {
+            index,
+            net_liquidity: index as i128 * 1_000,
+            sqrt_price: U256::from(index.unsigned_abs()),
+        };
+        let ticks = TickList {
+            tick_spacing: 60,
+            ticks: vec![tick(120), tick(-60), tick(0), tick(-180)],
+        };
+        let srz = SrzTickList::from(ticks);
+        let json = serde_json::to_string(&srz).unwrap();
+        let decoded: SrzTickList = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.tick_spacing, 60);
+        assert_eq!(decoded.ticks.iter().map(|t| t.index).collect::<Vec<i32>>(), vec![-180, -60, 0, 120]);
+        assert_eq!(decoded.ticks.iter().map(|t| t.net_liquidity).collect::<Vec<i128>>(), vec![-180_000, -60_000, 0, 120_000]);
+    }
+
+    #[test]
+    fn test_component_key_distinguishes_protocols() {
+        let a = component_key("uniswap_v4", "0xABCD");
+        let b = component_key("ekubo_v2", "0xabcd");
+        assert_eq!(a, "uniswap_v4:0xabcd");
+        assert_ne!(a, b);
+    }
 }
@@ -1 +1,3 @@
+pub mod cache;
 pub mod fmt;
+pub mod store;
@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::utils::misc::current_timestamp_ms;
+
+/// Key/value store of JSON values (e.g. cached orderbooks, see OrderbookCache), abstracted so that the SDK can run in-process without Redis
+#[async_trait]
+pub trait StateStore: Send + Sync {
+    /// Stores the value, expiring after `ttl_secs` if any. A ttl of 0 is rejected, as Redis SET EX does
+    async fn set<T: Serialize + Sync>(&self, key: &str, value: &T, ttl_secs: Option<u64>) -> Result<(), anyhow::Error>;
+
+    /// Value of the key, None if missing or expired
+    async fn get<T: DeserializeOwned + Send>(&self, key: &str) -> Result<Option<T>, anyhow::Error>;
+
+    async fn del(&self, key: &str) -> Result<(), anyhow::Error>;
+
+    /// Checks that the store is reachable
+    async fn ping(&self) -> Result<(), anyhow::Error>;
+}
+
+/// Store held in memory, for in-process use. Expired values are dropped when read
+#[derive(Default)]
+pub struct InMemoryStore {
+    /// JSON value and expiry (ms since epoch) of each key
+    pub values: std::sync::Mutex<HashMap<String, (String, Option<u64>)>>,
+}
+
+#[async_trait]
+impl StateStore for InMemoryStore {
+    async fn set<T: Serialize + Sync>(&self, key: &str, value: &T, ttl_secs: Option<u64>) -> Result<(), anyhow::Error> {
+        if ttl_secs == Some(0) {
+            return Err(anyhow::anyhow!("Invalid expire time for {}: the ttl must be at least 1 second", key));
+        }
+        let value = serde_json::to_string(value)?;
+        let expiry = ttl_secs.map(|ttl| current_timestamp_ms().saturating_add(ttl.saturating_mul(1_000)));
+        self.values.lock().unwrap().insert(key.to_string(), (value, expiry));
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned + Send>(&self, key: &str) -> Result<Option<T>, anyhow::Error> {
+        let mut values = self.values.lock().unwrap();
+        let value = match values.get(key) {
+            Some((_, Some(expiry))) if *expiry <= current_timestamp_ms() => {
+                values.remove(key);
+                return Ok(None);
+            }
+            Some((value, _)) => value.clone(),
+            None => return Ok(None),
+        };
+        drop(values);
+        Ok(Some(serde_json::from_str(&value)?))
+    }
+
+    async fn del(&self, key: &str) -> Result<(), anyhow::Error> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Redis store, connecting at each call (multiplexed connection)
+#[cfg(feature = "redis")]
+pub struct RedisStore {
+    pub client: redis::Client,
+}
+
+#[cfg(feature = "redis")]
+impl RedisStore {
+    /// E.g. 'redis://127.0.0.1:6379'
+    pub fn new(url: &str) -> Result<Self, anyhow::Error> {
+        Ok(RedisStore { client: redis::Client::open(url)? })
+    }
+}
+
+#[cfg(feature = "redis")]
+#[async_trait]
+impl StateStore for RedisStore {
+    async fn set<T: Serialize + Sync>(&self, key: &str, value: &T, ttl_secs: Option<u64>) -> Result<(), anyhow::Error> {
+        use redis::AsyncCommands;
+        let value = serde_json::to_string(value)?;
+        let mut con = self.client.get_multiplexed_async_connection().await?;
+        match ttl_secs {
+            Some(ttl) => con.set_ex::<_, _, ()>(key, value, ttl).await?,
+            None => con.set::<_, _, ()>(key, value).await?,
+        }
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned + Send>(&self, key: &str) -> Result<Option<T>, anyhow::Error> {
+        use redis::AsyncCommands;
+        let mut con = self.client.get_multiplexed_async_connection().await?;
+        let value: Option<String> = con.get(key).await?;
+        match value {
+            Some(value) => Ok(Some(serde_json::from_str(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn del(&self, key: &str) -> Result<(), anyhow::Error> {
+        use redis::AsyncCommands;
+        let mut con = self.client.get_multiplexed_async_connection().await?;
+        con.del::<_, ()>(key).await?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), anyhow::Error> {
+        let mut con = self.client.get_multiplexed_async_connection().await?;
+        let _: String = redis::cmd("PING").query_async(&mut con).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Same checks on every store, through the trait only
+    async fn exercise<S: StateStore>(store: &S) {
+        store.ping().await.unwrap();
+        let key = format!("test:store:{}", current_timestamp_ms());
+        assert_eq!(store.get::<Vec<u64>>(&key).await.unwrap(), None);
+        store.set(&key, &vec![1u64, 2, 3], None).await.unwrap();
+        assert_eq!(store.get::<Vec<u64>>(&key).await.unwrap(), Some(vec![1, 2, 3]));
+        assert!(store.get::<String>(&key).await.is_err());
+        store.set(&key, &vec![4u64], Some(60)).await.unwrap();
+        assert_eq!(store.get::<Vec<u64>>(&key).await.unwrap(), Some(vec![4]));
+        // Rejected, the previous value is kept
+        assert!(store.set(&key, &vec![5u64], Some(0)).await.is_err());
+        assert_eq!(store.get::<Vec<u64>>(&key).await.unwrap(), Some(vec![4]));
+        store.del(&key).await.unwrap();
+        assert_eq!(store.get::<Vec<u64>>(&key).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_store() {
+        let store = InMemoryStore::default();
+        exercise(&store).await;
+        store.values.lock().unwrap().insert("expired".to_string(), ("1".to_string(), Some(current_timestamp_ms() - 1)));
+        assert_eq!(store.get::<u64>("expired").await.unwrap(), None);
+        assert!(store.values.lock().unwrap().is_empty());
+    }
+
+    /// Needs a Redis server in REDIS_URL (e.g. redis://127.0.0.1:6379): cargo test --features redis-tests test_redis_store
+    #[cfg(feature = "redis-tests")]
+    #[tokio::test]
+    async fn test_redis_store() {
+        let url = std::env::var("REDIS_URL").expect("REDIS_URL not set");
+        exercise(&RedisStore::new(&url).unwrap()).await;
+    }
+}
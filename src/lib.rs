@@ -1,8 +1,18 @@
+//! Tycho Orderbook: transposes onchain AMM liquidity into an orderbook format.
+//!
+//! # Logging
+//! The library logs exclusively through `tracing`. A single subscriber initialised by the embedding app is enough to see all the output:
+//! ```ignore
+//! tracing_subscriber::fmt().with_env_filter(tracing_subscriber::EnvFilter::from_default_env()).init(); // Level read from RUST_LOG
+//! ```
+//! With its default features, `tracing-subscriber` also captures the `log` records emitted by dependencies (through `tracing-log`), so no `log` backend is needed.
+
 pub mod adapters;
 pub mod builder;
 pub mod core;
 pub mod data;
 pub mod maths;
+pub mod multi;
 pub mod provider;
 pub mod types;
 pub mod utils;
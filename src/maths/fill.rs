@@ -0,0 +1,102 @@
+use crate::types::Side;
+
+/// Model of the probability that a resting order gets filled within a horizon, given the mid price volatility
+/// Implement it to plug your own model (e.g. with drift, jumps, or a fitted distribution)
+pub trait FillModel: Send + Sync {
+    /// `mid` and `price` in quote per base, `volatility` per block (std dev of the mid log returns, see volatility)
+    /// A Bid rests on the bids (buys base, filled when the mid falls to its price), an Ask on the asks (filled when the mid rises to its price)
+    fn probability(&self, mid: f64, price: f64, side: Side, volatility: f64, horizon_blocks: u64) -> f64;
+}
+
+/// First-cut heuristic: the mid follows a driftless random walk on its log, and an order is filled as soon as the mid touches its price
+/// By the reflection principle, P(touch within T blocks) = 2 * (1 - Φ(|ln(price / mid)| / (σ * √T)))
+/// Fees, queue position and depth consumed at the price are ignored.
+#[derive(Default, Debug, Clone)]
+pub struct RandomWalkFillModel;
+
+impl FillModel for RandomWalkFillModel {
+    fn probability(&self, mid: f64, price: f64, side: Side, volatility: f64, horizon_blocks: u64) -> f64 {
+        if mid <= 0. || price <= 0. {
+            return 0.;
+        }
+        let crossed = match side {
+            Side::Bid => price >= mid,
+            Side::Ask => price <= mid,
+        };
+        if crossed {
+            return 1.; // Marketable, filled right away
+        }
+        let spread = volatility * (horizon_blocks as f64).sqrt();
+        if spread <= 0. {
+            return 0.;
+        }
+        let distance = (price / mid).ln().abs();
+        (2. * (1. - normal_cdf(distance / spread))).clamp(0., 1.)
+    }
+}
+
+/// Volatility per block of a mid price history (block, mid), sorted by block: standard deviation of the log returns, each scaled by the square root of the blocks elapsed
+/// Returns 0 with less than 3 points
+pub fn volatility(history: &[(u64, f64)]) -> f64 {
+    let returns = history
+        .windows(2)
+        .filter(|w| w[1].0 > w[0].0 && w[0].1 > 0. && w[1].1 > 0.)
+        .map(|w| (w[1].1 / w[0].1).ln() / ((w[1].0 - w[0].0) as f64).sqrt())
+        .collect::<Vec<f64>>();
+    if returns.len() < 2 {
+        return 0.;
+    }
+    let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+    let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (returns.len() - 1) as f64;
+    variance.sqrt()
+}
+
+/// Standard normal cumulative distribution function (Abramowitz and Stegun 7.1.26 erf approximation, error below 1.5e-7)
+pub fn normal_cdf(x: f64) -> f64 {
+    let z = x.abs() / std::f64::consts::SQRT_2;
+    let t = 1. / (1. + 0.3275911 * z);
+    let poly = t * (0.254829592 + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let erf = 1. - poly * (-z * z).exp();
+    if x >= 0. {
+        0.5 * (1. + erf)
+    } else {
+        0.5 * (1. - erf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normal_cdf() {
+        assert!((normal_cdf(0.) - 0.5).abs() < 1e-7);
+        assert!((normal_cdf(1.96) - 0.975).abs() < 1e-4);
+        assert!((normal_cdf(-1.96) - 0.025).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_volatility() {
+        assert_eq!(volatility(&[(1, 2000.), (2, 2000.), (3, 2000.), (4, 2000.)]), 0.);
+        assert_eq!(volatility(&[(1, 2000.), (2, 2010.)]), 0.);
+        let up = 1.01f64;
+        let history = [(1, 2000.), (2, 2000. * up), (3, 2000.), (4, 2000. * up), (5, 2000.)];
+        let sigma = volatility(&history);
+        assert!((sigma - up.ln() * (4f64 / 3.).sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_random_walk_fill_probability() {
+        let model = RandomWalkFillModel;
+        assert_eq!(model.probability(2000., 2001., Side::Bid, 0.001, 10), 1.);
+        assert_eq!(model.probability(2000., 1999., Side::Ask, 0.001, 10), 1.);
+        assert_eq!(model.probability(2000., 1990., Side::Bid, 0., 10), 0.);
+        let near = model.probability(2000., 1990., Side::Bid, 0.001, 10);
+        let far = model.probability(2000., 1950., Side::Bid, 0.001, 10);
+        let longer = model.probability(2000., 1990., Side::Bid, 0.001, 100);
+        assert!(far < near && near < longer);
+        assert!(near > 0. && longer < 1.);
+        let ask = model.probability(2000., 2000. * 2000. / 1990., Side::Ask, 0.001, 10);
+        assert!((ask - near).abs() < 1e-9);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod fill;
 pub mod opti;
 pub mod path;
 pub mod steps;
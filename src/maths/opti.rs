@@ -1,7 +1,7 @@
 use crate::{
     data::fmt::SrzToken,
     types::{ProtoSimComp, TradeResult},
-    utils::r#static::maths::{BPD, FRACTION_REALLOC, MAX_ITERATIONS, MIN_CONVERGENCE_THRESHOLD, ONE_HD},
+    utils::r#static::maths::{BPD, CLIP_ROUNDS, CLIP_TOLERANCE, CONVEX_ITERATIONS, CONVEX_ROUNDS, CONVEX_TOLERANCE, EXACT_OUT_ITERATIONS, EXACT_OUT_MAX_EXPANSIONS, EXACT_OUT_TOLERANCE, FRACTION_REALLOC, MAX_ITERATIONS, MIN_CONVERGENCE_THRESHOLD, ONE_HD},
 };
 use num_bigint::BigUint;
 use num_traits::{ToPrimitive, Zero};
@@ -129,6 +129,296 @@ pub fn gradient(
         allocations[max_index] = &allocations[max_index] + &reallocate_amount;
     }
 
+    outcome(amount, &amountpow, pools, &tkinput, &tkoutput, &allocations, eth_usd, gas_price, spot_price, out_eth_worth)
+}
+
+/// Exact-out version of gradient: finds the input amount needed to receive 'target' (human-readable) of tkoutput, net of gas
+/// The input is searched by bisection on the optimized (exact-in) trade output. Returns None if the pools can't output the target.
+#[allow(clippy::too_many_arguments)]
+pub fn gradient_exact_out(
+    target: f64,
+    pools: &[ProtoSimComp],
+    tkinput: SrzToken,
+    tkoutput: SrzToken,
+    eth_usd: f64,
+    gas_price: u128,
+    spot_price: f64,
+    out_eth_worth: f64,
+) -> Option<TradeResult> {
+    if target <= 0. || spot_price <= 0. {
+        return None;
+    }
+    let quote = |amount: f64| gradient(amount, pools, tkinput.clone(), tkoutput.clone(), eth_usd, gas_price, spot_price, out_eth_worth);
+    // Upper bound, doubled until the output covers the target
+    let (mut low, mut high) = (0., target / spot_price);
+    let mut best = quote(high);
+    let mut expansions = 0;
+    while best.output < target {
+        expansions += 1;
+        if expansions > EXACT_OUT_MAX_EXPANSIONS {
+            return None;
+        }
+        low = high;
+        high *= 2.;
+        best = quote(high);
+    }
+    for _ in 0..EXACT_OUT_ITERATIONS {
+        let mid = (low + high) / 2.;
+        let result = quote(mid);
+        if result.output >= target {
+            high = mid;
+            best = result;
+        } else {
+            low = mid;
+        }
+        if (high - low) / high < EXACT_OUT_TOLERANCE {
+            break;
+        }
+    }
+    Some(best)
+}
+
+/// Alternative to gradient: allocates 'amount' across the pools by equalizing their marginal output
+/// Starting from the best single pool, the split of each pair of pools is optimized with a golden-section search, until no pair improves the net output.
+/// The output of an AMM being concave in its input, each pairwise split is unimodal (gas aside, the bounds of the split are also checked).
+#[allow(clippy::too_many_arguments)]
+pub fn convex(
+    amount: f64,
+    pools: &[ProtoSimComp],
+    tkinput: SrzToken,
+    tkoutput: SrzToken,
+    eth_usd: f64,
+    gas_price: u128,
+    spot_price: f64,
+    out_eth_worth: f64,
+) -> TradeResult {
+    let (tkin, tkout) = (Token::from(tkinput.clone()), Token::from(tkoutput.clone()));
+    let amount_scaled = (amount * 10f64.powi(tkin.decimals as i32)).round();
+    let output_multiplier = 10f64.powi(tkout.decimals as i32);
+    // Net output (human-readable) of a pool for an allocation in smallest unit
+    let net = |pool: usize, alloc: f64| -> f64 {
+        if alloc < 1. {
+            return 0.;
+        }
+        match pools[pool].protosim.get_amount_out(BigUint::from(alloc as u128), &tkin, &tkout) {
+            Ok(result) => {
+                let gas_units = result.gas.to_u128().unwrap_or_default();
+                let gas_cost_out = gas_units.saturating_mul(gas_price) as f64 / 1e18 / out_eth_worth;
+                result.amount.to_f64().unwrap_or_default() / output_multiplier - gas_cost_out
+            }
+            Err(_) => f64::NEG_INFINITY,
+        }
+    };
+    let mut allocations = vec![0.; pools.len()];
+    if let Some(best) = (0..pools.len()).map(|i| (i, net(i, amount_scaled))).max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal)).map(|(i, _)| i) {
+        allocations[best] = amount_scaled;
+    }
+    for _ in 0..CONVEX_ROUNDS {
+        let mut improved = false;
+        for i in 0..pools.len() {
+            for j in (i + 1)..pools.len() {
+                let total = allocations[i] + allocations[j];
+                if total < 1. {
+                    continue;
+                }
+                let current = net(i, allocations[i]) + net(j, allocations[j]);
+                let split = |t: f64| net(i, total * t) + net(j, total * (1. - t));
+                let t = golden_section(split, CONVEX_ITERATIONS);
+                let (t, value) = [t, 0., 1.].into_iter().map(|t| (t, split(t))).fold((0., f64::NEG_INFINITY), |best, x| if x.1 > best.1 { x } else { best });
+                if value - current > current.abs() * CONVEX_TOLERANCE {
+                    allocations[i] = (total * t).floor();
+                    allocations[j] = total - allocations[i];
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    let distribution = allocations.iter().map(|alloc| alloc * ONE_HD / amount_scaled.max(1.)).collect::<Vec<f64>>();
+    evaluate(amount, pools, tkinput, tkoutput, &distribution, eth_usd, gas_price, spot_price, out_eth_worth)
+}
+
+/// Maximizes an unimodal function on [0, 1] with a golden-section search, returns the argmax
+fn golden_section(f: impl Fn(f64) -> f64, iterations: u32) -> f64 {
+    let ratio = (5f64.sqrt() - 1.) / 2.;
+    let (mut lo, mut hi) = (0., 1.);
+    let (mut a, mut b) = (hi - ratio * (hi - lo), lo + ratio * (hi - lo));
+    let (mut fa, mut fb) = (f(a), f(b));
+    for _ in 0..iterations {
+        if fa < fb {
+            lo = a;
+            a = b;
+            fa = fb;
+            b = lo + ratio * (hi - lo);
+            fb = f(b);
+        } else {
+            hi = b;
+            b = a;
+            fb = fa;
+            a = hi - ratio * (hi - lo);
+            fa = f(a);
+        }
+    }
+    (lo + hi) / 2.
+}
+
+/// Drops the allocations (percentages, 0-100) below `min_pct` and renormalizes the remaining ones so they sum to 100
+/// If every allocation is below the threshold, the largest one is kept. Returns the new distribution and the number of pruned allocations.
+pub fn renormalize(distribution: &[f64], min_pct: f64) -> (Vec<f64>, usize) {
+    let largest = distribution.iter().enumerate().max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal)).map(|(i, _)| i);
+    let mut pruned = 0;
+    let kept: Vec<f64> = distribution
+        .iter()
+        .enumerate()
+        .map(|(i, &pct)| {
+            if pct > 0. && pct < min_pct && Some(i) != largest {
+                pruned += 1;
+                0.
+            } else {
+                pct
+            }
+        })
+        .collect();
+    let sum: f64 = kept.iter().sum();
+    if sum <= 0. {
+        return (kept, pruned);
+    }
+    let renormalized = kept.iter().map(|&pct| pct * ONE_HD / sum).collect();
+    (renormalized, pruned)
+}
+
+/// Evaluates a given distribution (percentages of `amount`, one per pool) and builds the resulting trade
+/// Used to re-quote a trade once its distribution has been post-processed
+#[allow(clippy::too_many_arguments)]
+pub fn evaluate(
+    amount: f64,
+    pools: &[ProtoSimComp],
+    tkinput: SrzToken,
+    tkoutput: SrzToken,
+    distribution: &[f64],
+    eth_usd: f64,
+    gas_price: u128,
+    spot_price: f64,
+    out_eth_worth: f64,
+) -> TradeResult {
+    let tkinput = Token::from(tkinput.clone());
+    let tkoutput = Token::from(tkoutput.clone());
+    let amount_scaled = (amount * 10f64.powi(tkinput.decimals as i32)).round();
+    let amountpow = BigUint::from(amount_scaled as u128);
+    let mut allocations: Vec<BigUint> = distribution.iter().map(|pct| BigUint::from((amount_scaled * pct / ONE_HD).floor() as u128)).collect();
+    // Rounding dust goes to the largest allocation, so that the whole amount is allocated
+    let allocated = allocations.iter().fold(BigUint::zero(), |acc, x| acc + x);
+    if let Some(largest) = distribution.iter().enumerate().max_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(std::cmp::Ordering::Equal)).map(|(i, _)| i) {
+        if allocated < amountpow {
+            allocations[largest] = &allocations[largest] + (&amountpow - &allocated);
+        }
+    }
+    outcome(amount, &amountpow, pools, &tkinput, &tkoutput, &allocations, eth_usd, gas_price, spot_price, out_eth_worth)
+}
+
+/// Clips a trade so that no pool gives more than its cap (output token balance, human-readable, one per pool)
+/// The input of a pool giving too much is reduced to what its cap allows, the excess being moved to the pools with output headroom, proportionally to it.
+/// If no pool has headroom left, the amount is reduced and the trade flagged as capped. The trade is re-evaluated only if an allocation had to be clipped.
+#[allow(clippy::too_many_arguments)]
+pub fn clip(
+    trade: &TradeResult,
+    caps: &[f64],
+    pools: &[ProtoSimComp],
+    tkinput: SrzToken,
+    tkoutput: SrzToken,
+    eth_usd: f64,
+    gas_price: u128,
+    spot_price: f64,
+    out_eth_worth: f64,
+) -> TradeResult {
+    if caps.iter().sum::<f64>() <= 0. || caps.len() != trade.distribution.len() || caps.len() != pools.len() {
+        return trade.clone();
+    }
+    let mut allocations = trade.distribution.iter().map(|pct| pct * trade.amount / ONE_HD).collect::<Vec<f64>>();
+    let outputs = |allocations: &[f64]| allocations.iter().zip(pools.iter()).map(|(alloc, pool)| pool_output(pool, *alloc, &tkinput, &tkoutput)).collect::<Vec<f64>>();
+    let beyond = |outs: &[f64]| outs.iter().zip(caps.iter()).map(|(out, cap)| *out > cap * (1. + CLIP_TOLERANCE)).collect::<Vec<bool>>();
+    if !beyond(&outputs(&allocations)).contains(&true) {
+        return trade.clone();
+    }
+    let mut saturated = vec![false; caps.len()];
+    let mut capped = false;
+    for _ in 0..CLIP_ROUNDS {
+        let outs = outputs(&allocations);
+        let over = beyond(&outs);
+        if !over.contains(&true) {
+            break;
+        }
+        let mut excess = 0.;
+        for (i, _) in over.iter().enumerate().filter(|(_, over)| **over) {
+            let kept = input_for_output(&pools[i], caps[i] * (1. - CLIP_TOLERANCE), allocations[i], &tkinput, &tkoutput);
+            excess += allocations[i] - kept;
+            allocations[i] = kept;
+            saturated[i] = true;
+        }
+        let headroom = (0..caps.len()).map(|i| if saturated[i] { 0. } else { (caps[i] - outs[i]).max(0.) }).collect::<Vec<f64>>();
+        let room: f64 = headroom.iter().sum();
+        if room > 0. {
+            allocations.iter_mut().zip(headroom.iter()).for_each(|(alloc, free)| *alloc += excess * free / room);
+        } else {
+            capped = true;
+        }
+    }
+    let amount: f64 = allocations.iter().sum();
+    if amount <= 0. {
+        return trade.clone();
+    }
+    let distribution = allocations.iter().map(|alloc| alloc * ONE_HD / amount).collect::<Vec<f64>>();
+    let mut result = evaluate(amount, pools, tkinput, tkoutput, &distribution, eth_usd, gas_price, spot_price, out_eth_worth);
+    result.pruned = trade.pruned;
+    result.capped = trade.capped || capped;
+    result.component_ids = trade.component_ids.clone();
+    result
+}
+
+/// Output of a pool (human-readable, gross of gas) for an input (human-readable), infinite if the pool can't quote it
+fn pool_output(pool: &ProtoSimComp, input: f64, tkinput: &SrzToken, tkoutput: &SrzToken) -> f64 {
+    if input <= 0. {
+        return 0.;
+    }
+    let (tkin, tkout) = (Token::from(tkinput.clone()), Token::from(tkoutput.clone()));
+    let scaled = BigUint::from((input * 10f64.powi(tkin.decimals as i32)).floor() as u128);
+    match pool.protosim.get_amount_out(scaled, &tkin, &tkout) {
+        Ok(result) => result.amount.to_f64().unwrap_or(f64::INFINITY) / 10f64.powi(tkout.decimals as i32),
+        Err(_) => f64::INFINITY,
+    }
+}
+
+/// Largest input (human-readable, at most `max_input`) for which a pool gives at most `output` (see pool_output), found by bisection
+fn input_for_output(pool: &ProtoSimComp, output: f64, max_input: f64, tkinput: &SrzToken, tkoutput: &SrzToken) -> f64 {
+    let (mut low, mut high) = (0., max_input);
+    for _ in 0..EXACT_OUT_ITERATIONS {
+        let mid = (low + high) / 2.;
+        if pool_output(pool, mid, tkinput, tkoutput) <= output {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    low
+}
+
+/// Computes the final outputs and distribution of a trade given the allocation (in smallest unit) per pool
+#[allow(clippy::too_many_arguments)]
+fn outcome(
+    amount: f64,
+    amountpow: &BigUint,
+    pools: &[ProtoSimComp],
+    tkinput: &Token,
+    tkoutput: &Token,
+    allocations: &[BigUint],
+    eth_usd: f64,
+    gas_price: u128,
+    spot_price: f64,
+    out_eth_worth: f64,
+) -> TradeResult {
+    let num_pools = pools.len();
     // ------- Compute final outputs and distribution -------
     let mut total_net_output: f64 = 0.0;
     let mut distribution: Vec<f64> = Vec::with_capacity(num_pools);
@@ -139,7 +429,7 @@ pub fn gradient(
     for (i, pool) in pools.iter().enumerate() {
         let alloc = allocations[i].clone();
         if !alloc.is_zero() {
-            if let Ok(result) = pool.protosim.get_amount_out(alloc.clone(), &tkinput, &tkoutput) {
+            if let Ok(result) = pool.protosim.get_amount_out(alloc.clone(), tkinput, tkoutput) {
                 // let gross_tokens = result.amount.to_f64().unwrap_or(0.0); // [old]
                 let gross_tokens = result.amount.to_f64().unwrap_or(0.0); // [new]
                 let gas_units: u128 = result.gas.to_string().parse::<u128>().unwrap_or_default();
@@ -178,9 +468,12 @@ pub fn gradient(
     let input_f = amountpow.to_f64().unwrap_or(1.0);
     let average_sell_price = ((total_net_output * tkinput_multiplier) / input_f) / tkoutput_multiplier;
 
-    // Price impact calculation
-    let delta = average_sell_price - spot_price;
-    let price_impact = ((delta / spot_price) * BPD).round() / BPD;
+    // Price impact calculation, against the pre-trade spot price
+    let price_impact = price_impact(spot_price, average_sell_price);
+
+    // 1 unit of input is worth 'spot_price' units of output
+    let input_worth_usd = spot_price * out_eth_worth * eth_usd;
+    let gas_costs_input_token = gas_costs_in_token(&gas_costs_usd, input_worth_usd);
 
     let sum_distributed: f64 = distributed.iter().sum();
     let distributed_base_bps: Vec<f64> = distributed.iter().map(|&x| (((x * ONE_HD) / sum_distributed) * ONE_HD).round() / ONE_HD).collect();
@@ -192,7 +485,111 @@ pub fn gradient(
         distributed: distributed_base_bps,
         gas_costs: gas_costs_unit,
         gas_costs_usd,
+        gas_costs_input_token,
         average_sell_price,
         price_impact,
+        pruned: 0,
+        capped: false,
+        component_ids: None,
+    }
+}
+
+/// Price impact of a trade: (spot_price - average_sell_price) / spot_price, clamped to [0, 1] and rounded to the bps
+/// The average sell price is the one of the whole trade, net of gas and of the split across pools, not the one of any single pool.
+pub fn price_impact(spot_price: f64, average_sell_price: f64) -> f64 {
+    if spot_price <= 0. || !average_sell_price.is_finite() {
+        return 0.;
+    }
+    let impact = ((spot_price - average_sell_price) / spot_price).clamp(0., 1.);
+    (impact * BPD).round() / BPD
+}
+
+/// Converts gas costs in USD into units of a token worth `worth_usd` (e.g. "this swap costs 0.002 ETH")
+pub fn gas_costs_in_token(gas_costs_usd: &[f64], worth_usd: f64) -> Vec<f64> {
+    if worth_usd <= 0. {
+        return vec![0.; gas_costs_usd.len()];
+    }
+    gas_costs_usd.iter().map(|usd| usd / worth_usd).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fixtures;
+
+    #[test]
+    fn test_clip_to_pool_balances() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 1., 2_000.)];
+        let (weth, usdc) = (fixtures::weth(), fixtures::usdc());
+        let given = |t: &TradeResult| t.distributed.iter().map(|pct| pct * t.output / ONE_HD).collect::<Vec<f64>>();
+        // The small pool holds less USDC than its reserves tell: it can't give more than 500
+        let caps = vec![2_000_000., 500.];
+        let trade = evaluate(4., &pools, weth.clone(), usdc.clone(), &[50., 50.], 2000., 0, 2000., 0.0005);
+        assert!(given(&trade)[1] > 500.);
+        let clipped = clip(&trade, &caps, &pools, weth.clone(), usdc.clone(), 2000., 0, 2000., 0.0005);
+        assert!(!clipped.capped);
+        assert!((clipped.amount - 4.).abs() < 1e-9);
+        assert!(given(&clipped)[1] <= 500. * (1. + 1e-3));
+        assert!(clipped.distribution[1] < 50.);
+        // Both pools beyond their balances: partial fill
+        let whole = evaluate(2_000., &pools, weth.clone(), usdc.clone(), &[90., 10.], 2000., 0, 2000., 0.0005);
+        let caps = vec![1_000_000., 500.];
+        let clipped = clip(&whole, &caps, &pools, weth.clone(), usdc.clone(), 2000., 0, 2000., 0.0005);
+        assert!(clipped.capped);
+        assert!(clipped.amount < 2_000.);
+        assert!(given(&clipped).iter().zip(caps.iter()).all(|(out, cap)| *out <= cap * (1. + 1e-3)));
+        let untouched = clip(&trade, &[2_000_000., 2_000.], &pools, weth, usdc, 2000., 0, 2000., 0.0005);
+        assert_eq!(untouched.distribution, trade.distribution);
+    }
+
+    #[test]
+    fn test_price_impact_bounds() {
+        assert_eq!(price_impact(2000., 2000.), 0.);
+        assert_eq!(price_impact(2000., 2100.), 0.); // Better than spot isn't an impact
+        assert_eq!(price_impact(2000., 1000.), 0.5);
+        assert_eq!(price_impact(2000., 0.), 1.);
+        assert_eq!(price_impact(0., 1000.), 0.);
+    }
+
+    #[test]
+    fn test_price_impact_tiny_and_huge_trades() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let (weth, usdc) = (fixtures::weth(), fixtures::usdc());
+        let spot = pools[0].protosim.spot_price(&Token::from(weth.clone()), &Token::from(usdc.clone())).unwrap();
+        let tiny = gradient(0.001, &pools, weth.clone(), usdc.clone(), 2000., 0, spot, 0.0005);
+        assert!(tiny.price_impact < 0.01, "tiny trade impact = {}", tiny.price_impact);
+        let huge = gradient(100_000., &pools, weth, usdc, 2000., 0, spot, 0.0005);
+        assert!(huge.price_impact > 0.95 && huge.price_impact <= 1., "huge trade impact = {}", huge.price_impact);
+    }
+
+    #[test]
+    fn test_renormalize_prunes_small_allocations() {
+        let distribution = vec![60.0, 0.3, 38.9, 0.8, 0.0];
+        let (renormalized, pruned) = renormalize(&distribution, 1.0);
+        assert_eq!(pruned, 2);
+        assert_eq!(renormalized[1], 0.);
+        assert_eq!(renormalized[3], 0.);
+        assert_eq!(renormalized[4], 0.);
+        assert!(renormalized.iter().all(|&pct| pct == 0. || pct >= 1.0));
+        let sum: f64 = renormalized.iter().sum();
+        assert!((sum - ONE_HD).abs() < 1e-9, "sum = {}", sum);
+    }
+
+    #[test]
+    fn test_gas_costs_in_token() {
+        let gas_costs_usd = vec![4.2, 0., 1.5];
+        let input_worth_usd = 2000.;
+        let converted = gas_costs_in_token(&gas_costs_usd, input_worth_usd);
+        for (usd, token) in gas_costs_usd.iter().zip(converted.iter()) {
+            assert_eq!(*token, usd / input_worth_usd);
+        }
+        assert_eq!(gas_costs_in_token(&gas_costs_usd, 0.), vec![0.; 3]);
+    }
+
+    #[test]
+    fn test_renormalize_keeps_largest_allocation() {
+        let (renormalized, pruned) = renormalize(&[0.4, 0.6], 1.0);
+        assert_eq!(pruned, 1);
+        assert_eq!(renormalized, vec![0., ONE_HD]);
     }
 }
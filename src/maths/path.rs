@@ -2,30 +2,18 @@ use std::collections::{HashMap, HashSet, VecDeque};
 
 use crate::{
     data::fmt::{SrzProtocolComponent, SrzToken},
-    types::{ProtoSimComp, ValorisationPath},
+    types::{ProtoSimComp, RouteCache, UnitWorth, ValorisationPath},
 };
 use tycho_simulation::models::Token;
 
 /// DFS graph traversal method that explores as far as possible along each branch before backtracking
 /// Used to price any token to ETH equivalent value, to reflect gas cost
 /// But can be used to price any token to any other token
-/// Only return the path, not the price
+/// Only return the path (tokens, and components keys), not the price
 pub fn routing(cps: Vec<SrzProtocolComponent>, input: String, target: String) -> Result<ValorisationPath, String> {
-    // (destination token address, component id that provides this conversion)
-    let mut graph: HashMap<String, Vec<(String, String)>> = HashMap::new();
-    for comp in cps {
-        let comp_id = comp.id.clone();
-        let addresses: Vec<String> = comp.tokens.iter().map(|t| t.address.to_lowercase()).collect();
-        for token_in in &addresses {
-            for token_out in &addresses {
-                if token_in != token_out {
-                    graph.entry(token_in.clone()).or_default().push((token_out.clone(), comp_id.clone()));
-                }
-            }
-        }
-    }
+    let graph = graph(&cps);
     // For debugging: print the graph
-    // e.g., log::info!("Graph: {:?}", graph);
+    // e.g., tracing::info!("Graph: {:?}", graph);
     let start = input.to_lowercase();
     let target = target.to_lowercase();
     // Queue items: (current token, token path, component id path)
@@ -56,6 +44,73 @@ pub fn routing(cps: Vec<SrzProtocolComponent>, input: String, target: String) ->
     Err(format!("No path found from {} to {}", input, target))
 }
 
+/// Token graph of the components: for each token address, the (destination token address, component key that provides this conversion)
+fn graph(cps: &[SrzProtocolComponent]) -> HashMap<String, Vec<(String, String)>> {
+    let mut graph: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for comp in cps {
+        let comp_id = comp.key();
+        let addresses: Vec<String> = comp.tokens.iter().map(|t| t.address.to_lowercase()).collect();
+        for token_in in &addresses {
+            for token_out in &addresses {
+                if token_in != token_out {
+                    graph.entry(token_in.clone()).or_default().push((token_out.clone(), comp_id.clone()));
+                }
+            }
+        }
+    }
+    graph
+}
+
+/// BFS over the same token graph as routing, returning every token address reachable from `input` within `max_hops` swaps, with the minimum hop count
+/// The input token itself is excluded. Sorted by hop count, then address
+pub fn reachable(cps: &[SrzProtocolComponent], input: &str, max_hops: usize) -> Vec<(String, usize)> {
+    let graph = graph(cps);
+    let start = input.to_lowercase();
+    let mut hops: HashMap<String, usize> = HashMap::from([(start.clone(), 0)]);
+    let mut queue: VecDeque<String> = VecDeque::from([start.clone()]);
+    while let Some(current) = queue.pop_front() {
+        let depth = hops[&current];
+        if depth >= max_hops {
+            continue;
+        }
+        for (next, _) in graph.get(&current).into_iter().flatten() {
+            if !hops.contains_key(next) {
+                hops.insert(next.clone(), depth + 1);
+                queue.push_back(next.clone());
+            }
+        }
+    }
+    let mut output = hops.into_iter().filter(|(token, _)| *token != start).collect::<Vec<(String, usize)>>();
+    output.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+    output
+}
+
+/// Worth of one unit of 'token' through the first reference of 'refs' (in priority order) it gets a valid quote to, with that reference
+/// 'quote(from, to)' prices one unit of 'from' in 'to' (e.g. routing, then quote). ETH and the anchor stable give Eth and Usd worths,
+/// other references (e.g. WBTC) are themselves valued in ETH, or else in the stable. None if no reference works
+pub fn valuation(token: &str, refs: &[String], eth: &str, stable: Option<&str>, quote: &mut impl FnMut(&str, &str) -> Option<f64>) -> Option<(String, UnitWorth)> {
+    let valid = |q: Option<f64>| q.filter(|q| q.is_finite() && *q > 0.);
+    for reference in refs {
+        let Some(worth) = valid(quote(token, reference)) else {
+            tracing::debug!("No valid quote from {} to reference {}", token, reference);
+            continue;
+        };
+        let unit = if reference.eq_ignore_ascii_case(eth) {
+            Some(UnitWorth::Eth(worth))
+        } else if stable.is_some_and(|stable| reference.eq_ignore_ascii_case(stable)) {
+            Some(UnitWorth::Usd(worth))
+        } else if let Some(eth_worth) = valid(quote(reference, eth)) {
+            Some(UnitWorth::Eth(worth * eth_worth))
+        } else {
+            stable.and_then(|stable| valid(quote(reference, stable))).map(|usd_worth| UnitWorth::Usd(worth * usd_worth))
+        };
+        if let Some(unit) = unit {
+            return Some((reference.clone(), unit));
+        }
+    }
+    None
+}
+
 /// Quote a path of tokens, using components and protosim Tycho functions
 /// Used to calculate the price of a path of tokens, mostly to ETH
 pub fn quote(pts: Vec<ProtoSimComp>, atks: Vec<SrzToken>, path: Vec<String>) -> Option<f64> {
@@ -72,7 +127,7 @@ pub fn quote(pts: Vec<ProtoSimComp>, atks: Vec<SrzToken>, path: Vec<String>) ->
     for window in path.windows(2) {
         let token_in = window[0].to_lowercase();
         let token_out = window[1].to_lowercase();
-        // log::info!("Calculating conversion from {} to {}", token_in, token_out);
+        // tracing::info!("Calculating conversion from {} to {}", token_in, token_out);
         // Find a protocol state that can convert token_in to token_out.
         let mut found = false;
         for state in &pts {
@@ -84,13 +139,13 @@ pub fn quote(pts: Vec<ProtoSimComp>, atks: Vec<SrzToken>, path: Vec<String>) ->
                 let quote = Token::from(atks.iter().find(|t| t.address.to_lowercase() == token_out).unwrap().clone());
                 match state.protosim.spot_price(&base, &quote) {
                     Ok(rate) => {
-                        // log::info!("Found rate {} for {} -> {}", rate, token_in, token_out);
+                        // tracing::info!("Found rate {} for {} -> {}", rate, token_in, token_out);
                         cumulative_price *= rate;
                         found = true;
                         break;
                     }
                     Err(_e) => {
-                        // log::info!("State cannot convert {} -> {}: {}", token_in, token_out, e);
+                        // tracing::info!("State cannot convert {} -> {}: {}", token_in, token_out, e);
                     }
                 }
             }
@@ -103,3 +158,106 @@ pub fn quote(pts: Vec<ProtoSimComp>, atks: Vec<SrzToken>, path: Vec<String>) ->
     tracing::debug!(" - One unit of token ({:?} to {:?}) quoted to ETH = {}", path.first(), path.last(), cumulative_price);
     Some(cumulative_price)
 }
+
+impl RouteCache {
+    /// Cached route of 'token' for the given version of the component set, if any. Counted as a hit or a miss
+    /// The search of a missing route (e.g. maths::path::routing) is left to the caller, so that the cache isn't locked during it (see insert)
+    pub fn get(&mut self, version: u64, token: &str) -> Option<ValorisationPath> {
+        if version != self.version {
+            self.routes.clear();
+            self.version = version;
+        }
+        match self.routes.get(&token.to_lowercase()) {
+            Some(path) => {
+                self.hits += 1;
+                Some(path.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches the route of 'token' searched for the given version of the component set, unless the set changed since
+    /// Failed searches aren't cached, so they are retried on the next build
+    pub fn insert(&mut self, version: u64, token: &str, path: ValorisationPath) {
+        if version == self.version {
+            self.routes.insert(token.to_lowercase(), path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::fixtures::{self, USDC, WETH};
+
+    fn token(address: &str, symbol: &str) -> SrzToken {
+        SrzToken {
+            address: address.to_string(),
+            decimals: 18,
+            symbol: symbol.to_string(),
+            gas: "0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_reachable() {
+        let (dai, wbtc, pepe) = (token("0xda1", "DAI"), token("0xb7c", "WBTC"), token("0x9e9e", "PEPE"));
+        // WETH - USDC - DAI - WBTC - PEPE, with a WETH/DAI shortcut
+        let cps = vec![
+            fixtures::component("0x01", "uniswap_v2", vec![fixtures::weth(), fixtures::usdc()], 30),
+            fixtures::component("0x02", "uniswap_v2", vec![fixtures::usdc(), dai.clone()], 30),
+            fixtures::component("0x03", "uniswap_v2", vec![dai.clone(), wbtc.clone()], 30),
+            fixtures::component("0x04", "uniswap_v2", vec![wbtc.clone(), pepe.clone()], 30),
+            fixtures::component("0x05", "uniswap_v2", vec![fixtures::weth(), dai.clone()], 30),
+        ];
+        let reached = reachable(&cps, &WETH.to_uppercase(), 2);
+        assert_eq!(reached, vec![(USDC.to_string(), 1), (dai.address.clone(), 1), (wbtc.address.clone(), 2)]);
+        let reached = reachable(&cps, WETH, 10);
+        assert_eq!(reached.len(), 4);
+        assert!(reached.contains(&(pepe.address.clone(), 3)));
+        assert!(reachable(&cps, WETH, 0).is_empty());
+        assert!(reachable(&cps, "0xunknown", 3).is_empty());
+    }
+
+    #[test]
+    fn test_valuation_refs() {
+        let wbtc = "0x2260fac5e5542a773aa44fbcfedf7c193bc2c599".to_string();
+        let foo = "0xf00";
+        // FOO only trades against WBTC, itself quoted in ETH
+        let prices = HashMap::from([((foo.to_string(), wbtc.clone()), 0.001), ((wbtc.clone(), WETH.to_string()), 30.)]);
+        let mut quote = |from: &str, to: &str| prices.get(&(from.to_string(), to.to_string())).copied();
+        let refs = vec![WETH.to_string(), USDC.to_string(), wbtc.clone()];
+        let (reference, worth) = valuation(foo, &refs, WETH, Some(USDC), &mut quote).unwrap();
+        assert_eq!(reference, wbtc);
+        assert_eq!(worth, UnitWorth::Eth(0.001 * 30.));
+        // Priority order: ETH first when it works
+        let mut quote = |from: &str, to: &str| if to == WETH { Some(0.5) } else { Some(1.) };
+        assert_eq!(valuation(foo, &refs, WETH, Some(USDC), &mut quote), Some((WETH.to_string(), UnitWorth::Eth(0.5))));
+        // Invalid quotes (0, NaN) are skipped, down to the stable
+        let mut quote = |_: &str, to: &str| if to == USDC { Some(2.) } else { Some(f64::NAN) };
+        assert_eq!(valuation(foo, &refs, WETH, Some(USDC), &mut quote), Some((USDC.to_string(), UnitWorth::Usd(2.))));
+        let mut quote = |_: &str, _: &str| Some(0.);
+        assert_eq!(valuation(foo, &refs, WETH, Some(USDC), &mut quote), None);
+    }
+
+    #[test]
+    fn test_route_cache() {
+        let cps = vec![fixtures::component("0x01", "uniswap_v2", vec![fixtures::weth(), fixtures::usdc()], 30)];
+        let mut cache = RouteCache::default();
+        assert!(cache.get(1, USDC).is_none());
+        let path = routing(cps.clone(), USDC.to_string(), WETH.to_string()).unwrap();
+        cache.insert(1, USDC, path.clone());
+        let cached = cache.get(1, &USDC.to_uppercase()).unwrap();
+        assert_eq!(cached.comp_path, path.comp_path);
+        assert_eq!((cache.hits, cache.misses), (1, 1));
+        // The component set changed
+        assert!(cache.get(2, USDC).is_none());
+        assert!(cache.routes.is_empty());
+        // A route searched for a previous version isn't cached
+        cache.insert(1, USDC, path);
+        assert!(cache.routes.is_empty());
+    }
+}
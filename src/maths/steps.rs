@@ -11,10 +11,10 @@ pub fn depth(components: Vec<SrzProtocolComponent>, targets: Vec<SrzToken>, data
     });
     // Every component containing 'tokens'
     for cp in components.clone().iter() {
-        if let Some(balances) = data.get(&cp.id) {
+        if let Some(balances) = data.get(&cp.key()) {
             for tk in targets.iter() {
                 if let Some(balance) = balances.get(tk.address.to_lowercase().as_str()) {
-                    // log::info!("Component {} has {} of token {}", cp.id, balance, tk.symbol);
+                    // tracing::info!("Component {} has {} of token {}", cp.id, balance, tk.symbol);
                     let c = cumulated.get(tk.address.to_lowercase().as_str()).unwrap();
                     let new = c + balance;
                     cumulated.insert(tk.clone().address, new);
@@ -66,3 +66,47 @@ pub fn expo(n_points: usize, start: f64, end: f64, min_delta: f64) -> Vec<f64> {
 
     result
 }
+
+/// Generates `n_points` evenly spaced between `start` and `end` (both included)
+/// Uniform depth coverage, e.g. for depth charts, but few samples at small sizes where the price moves the most (use expo for those).
+pub fn linear(n_points: usize, start: f64, end: f64) -> Vec<f64> {
+    let divisor = if n_points > 1 { (n_points - 1) as f64 } else { 1.0 };
+    (0..n_points).map(|i| start + (end - start) * i as f64 / divisor).collect()
+}
+
+/// Generates `n_points` with a constant ratio between consecutive ones, from `start` (raised to `floor` if below) to `end` (both included)
+/// Like expo, samples are denser at small sizes, but all of them are kept (no minimum delta) and the floor avoids dust sizes when `start` is 0.
+pub fn geometric_with_floor(n_points: usize, start: f64, end: f64, floor: f64) -> Vec<f64> {
+    let start = start.max(floor);
+    if start <= 0. {
+        return linear(n_points, start, end);
+    }
+    let divisor = if n_points > 1 { (n_points - 1) as f64 } else { 1.0 };
+    (0..n_points).map(|i| start * (end / start).powf(i as f64 / divisor)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(steps: &[f64], count: usize, start: f64, end: f64) {
+        assert_eq!(steps.len(), count);
+        assert!(steps.windows(2).all(|w| w[1] > w[0]));
+        assert!((steps[0] - start).abs() < 1e-9 && (steps[count - 1] - end).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_step_generators() {
+        let steps = linear(30, 1., 1_000.);
+        check(&steps, 30, 1., 1_000.);
+        // Evenly spaced
+        assert!(steps.windows(2).all(|w| (w[1] - w[0] - 999. / 29.).abs() < 1e-9));
+        check(&geometric_with_floor(30, 1., 1_000_000., 0.), 30, 1., 1_000_000.);
+        // The floor replaces a zero start
+        let floored = geometric_with_floor(20, 0., 1_000., 0.5);
+        check(&floored, 20, 0.5, 1_000.);
+        assert!((floored[2] / floored[1] - floored[1] / floored[0]).abs() < 1e-9);
+        let expo = expo(30, 1., 1_000_000., 0.);
+        check(&expo, 30, 1., 1_000_000.);
+    }
+}
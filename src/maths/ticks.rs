@@ -1,3 +1,5 @@
+use alloy::primitives::ruint::aliases::U256;
+
 use crate::{
     data::fmt::{SrzTickList, SrzToken},
     types::{LiquidityTickAmounts, TickDataRange},
@@ -189,6 +191,25 @@ pub fn ticks_liquidity(active: i128, current_tick: i32, tick_spacing: i32, tick_
     output
 }
 
+/// Token amounts (human-readable) locked in each range between two consecutive initialized ticks, at the current √price (raw Q64.96)
+/// The liquidity of a range is the sum of the net liquidity of the ticks up to its lower bound. Ranges without liquidity are skipped.
+/// Indexed by the lower tick of the range (see derive): below the price ranges only hold token1, above it only token0
+pub fn range_amounts(sqrt_price: U256, tick_list: &SrzTickList, t0: SrzToken, t1: SrzToken) -> Vec<LiquidityTickAmounts> {
+    let x96 = sqrt_price.to_string().parse::<f64>().unwrap_or_default();
+    let mut ticks = tick_list.ticks.clone();
+    ticks.sort_by_key(|tick| tick.index);
+    let mut liquidity: i128 = 0;
+    let mut output = vec![];
+    for range in ticks.windows(2) {
+        liquidity += range[0].net_liquidity;
+        if liquidity <= 0 {
+            continue;
+        }
+        output.push(derive(liquidity, x96, range[0].index, range[1].index, t0.clone(), t1.clone(), false));
+    }
+    output
+}
+
 /// Filter and classify liquidity ticks
 pub fn filter_and_classify_ticks(
     ticks: Vec<LiquidityTickAmounts>,
@@ -222,3 +243,42 @@ pub fn filter_and_classify_ticks(
 
     (bids, asks)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::fmt::{SrzTickInfo, SrzUniswapV3State};
+    use crate::utils::fixtures;
+
+    #[test]
+    fn test_range_amounts() {
+        // 2 positions around the price 1 (tick 0): L1 on [-600, 600], L2 on [-120, 120]. WETH as token0 and token1, for 18 decimals on both sides
+        let (l1, l2) = (10i128.pow(21), 5 * 10i128.pow(21));
+        let tick = |index: i32, net_liquidity: i128| SrzTickInfo {
+            index,
+            net_liquidity,
+            sqrt_price: U256::ZERO,
+        };
+        let state = SrzUniswapV3State {
+            id: "0x01".to_string(),
+            liquidity: (l1 + l2) as u128,
+            sqrt_price: U256::from(UNISWAP_Q96),
+            fee: 3000,
+            tick: 0,
+            ticks: SrzTickList {
+                tick_spacing: 60,
+                ticks: vec![tick(600, -l1), tick(-600, l1), tick(120, -l2), tick(-120, l2)],
+            },
+        };
+        let amounts = range_amounts(state.sqrt_price, &state.ticks, fixtures::weth(), fixtures::weth());
+        assert_eq!(amounts.iter().map(|a| a.index).collect::<Vec<i32>>(), vec![-600, -120, 120]);
+        assert!(amounts.iter().all(|a| a.amount0 >= 0. && a.amount1 >= 0. && a.amount0 + a.amount1 > 0.));
+        assert_eq!((amounts[0].amount0, amounts[2].amount1), (0., 0.)); // Below the price: token1 only, above: token0 only
+        let sqrt = |tick: i32| 1.0001_f64.powf(tick as f64 / 2.);
+        let expected0 = (l1 as f64 * (1. - 1. / sqrt(600)) + l2 as f64 * (1. - 1. / sqrt(120))) / 1e18;
+        let expected1 = (l1 as f64 * (1. - sqrt(-600)) + l2 as f64 * (1. - sqrt(-120))) / 1e18;
+        let (sum0, sum1) = amounts.iter().fold((0., 0.), |(a0, a1), a| (a0 + a.amount0, a1 + a.amount1));
+        assert!((sum0 - expected0).abs() / expected0 < 1e-6, "amount0 {} vs {}", sum0, expected0);
+        assert!((sum1 - expected1).abs() / expected1 < 1e-6, "amount1 {} vs {}", sum1, expected1);
+    }
+}
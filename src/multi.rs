@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::task::JoinHandle;
+
+use crate::builder::OrderbookBuilder;
+use crate::core::client;
+use crate::core::oracle::PriceOracle;
+use crate::provider::OrderbookProvider;
+use crate::types::{Network, NetworkHealth, Orderbook, OrderbookError, OrderbookEvent, OrderbookRequestParams};
+use crate::utils::r#static::multi::EVENTS_CAPACITY;
+
+/// Builds the OBP of a network, abstracted so that the (re)starts can be counted or mocked
+#[async_trait]
+pub trait ProviderFactory: Send + Sync {
+    async fn build(&self, network: &Network) -> Result<OrderbookProvider, anyhow::Error>;
+}
+
+/// Builds the OBPs with the default ProtocolStreamBuilder (see OrderbookBuilder), fetching the tokens of the network at each (re)start
+pub struct TychoProviderFactory {
+    /// The API token for Tycho queries
+    pub key: String,
+}
+
+#[async_trait]
+impl ProviderFactory for TychoProviderFactory {
+    async fn build(&self, network: &Network) -> Result<OrderbookProvider, anyhow::Error> {
+        let tokens = client::tokens(network, self.key.clone()).await?;
+        let builder = OrderbookBuilder::new(network.clone(), None, self.key.clone(), tokens).await;
+        builder.build().await.map_err(|e| anyhow::anyhow!("Failed to build the OBP of {}: {}", network.name, e))
+    }
+}
+
+/// Runs one OBP per network, indexed by network name, restarting the ones whose stream stopped, failed or lags (see supervise)
+/// The OrderbookEvents of every provider are drained into a single channel (see events), so that no stream task blocks on a full channel
+pub struct MultiNetworkProvider {
+    /// Managed networks, indexed by name
+    pub networks: HashMap<String, Network>,
+    /// Running providers, indexed by network name. A network whose build failed has none until its next restart
+    pub providers: Arc<RwLock<HashMap<String, Arc<OrderbookProvider>>>>,
+    /// Number of restarts of each network, indexed by network name
+    pub restarts: Arc<RwLock<HashMap<String, u32>>>,
+    pub factory: Arc<dyn ProviderFactory>,
+    /// Events of all the providers, with the name of their network. Dropped when the client doesn't read them fast enough
+    pub events: Mutex<mpsc::Receiver<(String, OrderbookEvent)>>,
+    /// Sender side of the events channel, fed by the forwarding task of each provider
+    pub sender: mpsc::Sender<(String, OrderbookEvent)>,
+    /// Task forwarding the events of each provider, indexed by network name
+    pub forwarders: Mutex<HashMap<String, JoinHandle<()>>>,
+    /// Networks whose stream reported an error (OrderbookEvent::Error) since their last (re)start, restarted by the next restart_stopped
+    pub failed: Arc<RwLock<HashSet<String>>>,
+}
+
+impl MultiNetworkProvider {
+    /// Starts the provider of each network. A failed start is logged, and retried by the next restart_stopped
+    pub async fn new(networks: Vec<Network>, factory: Arc<dyn ProviderFactory>) -> Self {
+        let (sender, receiver) = mpsc::channel(EVENTS_CAPACITY);
+        let multi = MultiNetworkProvider {
+            networks: networks.into_iter().map(|n| (n.name.clone(), n)).collect(),
+            providers: Arc::new(RwLock::new(HashMap::new())),
+            restarts: Arc::new(RwLock::new(HashMap::new())),
+            factory,
+            events: Mutex::new(receiver),
+            sender,
+            forwarders: Mutex::new(HashMap::new()),
+            failed: Arc::new(RwLock::new(HashSet::new())),
+        };
+        for network in multi.networks.values() {
+            match multi.factory.build(network).await {
+                Ok(provider) => multi.install(&network.name, provider).await,
+                Err(e) => tracing::error!("Failed to start the provider of {}: {}", network.name, e),
+            }
+        }
+        multi
+    }
+
+    /// Runs the provider of the network, replacing the previous one if any, with a task forwarding its events to the shared channel
+    /// The forwarding task of the replaced provider is aborted, so that it's dropped with its own task (see OrderbookProvider::shutdown)
+    async fn install(&self, name: &str, provider: OrderbookProvider) {
+        if let Some(previous) = self.forwarders.lock().await.remove(name) {
+            previous.abort();
+        }
+        self.failed.write().await.remove(name);
+        let provider = Arc::new(provider);
+        let (network, obp, sender, failed) = (name.to_string(), provider.clone(), self.sender.clone(), self.failed.clone());
+        let forwarder = tokio::spawn(async move {
+            let mut stream = obp.stream.lock().await;
+            while let Some(event) = stream.recv().await {
+                if let OrderbookEvent::Error(e) = &event {
+                    tracing::error!("Stream error on {}: {:?}", network, e);
+                    failed.write().await.insert(network.clone());
+                }
+                if sender.try_send((network.clone(), event)).is_err() {
+                    tracing::trace!("Events channel full, event of {} dropped", network);
+                }
+            }
+        });
+        self.forwarders.lock().await.insert(name.to_string(), forwarder);
+        self.providers.write().await.insert(name.to_string(), provider);
+    }
+
+    /// Provider of the network, if running
+    pub async fn provider(&self, network: &str) -> Option<Arc<OrderbookProvider>> {
+        self.providers.read().await.get(network).cloned()
+    }
+
+    /// Builds the orderbook of the pair on the given network (see OrderbookProvider::get_orderbook)
+    pub async fn get_orderbook(&self, network: &str, oracle: &dyn PriceOracle, params: OrderbookRequestParams) -> Result<Orderbook, OrderbookError> {
+        let provider = self.provider(network).await.ok_or(OrderbookError::UnknownNetwork(network.to_string()))?;
+        provider.get_orderbook(provider.solver.clone(), oracle, params).await
+    }
+
+    /// Why the provider of the network must be restarted, if it must: not started, stream task ended, stream error, or initialised but lagging more than `max_lag_blocks` blocks (see OrderbookProvider::is_ready)
+    /// A provider still syncing its first block isn't restarted
+    async fn restart_reason(&self, network: &str, max_lag_blocks: u64) -> Option<&'static str> {
+        let provider = match self.provider(network).await {
+            Some(provider) => provider,
+            None => return Some("not running"),
+        };
+        if !provider.is_running() {
+            return Some("stream task ended");
+        }
+        if self.failed.read().await.contains(network) {
+            return Some("stream error");
+        }
+        if provider.is_initialised().await && !provider.is_ready(max_lag_blocks).await {
+            return Some("lagging");
+        }
+        None
+    }
+
+    /// Rebuilds the providers whose stream task ended, whose stream reported an error, that lag more than `max_lag_blocks` blocks, or that failed to start
+    /// Returns the names of the restarted networks
+    pub async fn restart_stopped(&self, max_lag_blocks: u64) -> Vec<String> {
+        let mut restarted = vec![];
+        for network in self.networks.values() {
+            let reason = match self.restart_reason(&network.name, max_lag_blocks).await {
+                Some(reason) => reason,
+                None => continue,
+            };
+            tracing::warn!("Provider of {} stopped ({}), restarting it", network.name, reason);
+            match self.factory.build(network).await {
+                Ok(provider) => {
+                    self.install(&network.name, provider).await;
+                    *self.restarts.write().await.entry(network.name.clone()).or_insert(0) += 1;
+                    restarted.push(network.name.clone());
+                }
+                Err(e) => tracing::error!("Failed to restart the provider of {}: {}", network.name, e),
+            }
+        }
+        restarted
+    }
+
+    /// Checks the providers every `interval`, restarting the stopped, failed or lagging ones (see restart_stopped)
+    pub fn supervise(self: &Arc<Self>, interval: std::time::Duration, max_lag_blocks: u64) -> JoinHandle<()> {
+        let multi = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                let restarted = multi.restart_stopped(max_lag_blocks).await;
+                if !restarted.is_empty() {
+                    tracing::info!("Restarted the providers of {:?}", restarted);
+                }
+            }
+        })
+    }
+
+    /// Health of each managed network, indexed by name. A network is ready if its last block is less than `max_lag_blocks` block times old (see OrderbookProvider::is_ready)
+    pub async fn health(&self, max_lag_blocks: u64) -> HashMap<String, NetworkHealth> {
+        let restarts = self.restarts.read().await.clone();
+        let mut output = HashMap::new();
+        for name in self.networks.keys() {
+            let mut health = NetworkHealth {
+                restarts: restarts.get(name).copied().unwrap_or(0),
+                ..Default::default()
+            };
+            if let Some(provider) = self.provider(name).await {
+                health.running = provider.is_running();
+                health.ready = provider.is_ready(max_lag_blocks).await;
+                health.block = provider.state.read().await.block;
+            }
+            output.insert(name.clone(), health);
+        }
+        output
+    }
+}
+
+impl Drop for MultiNetworkProvider {
+    fn drop(&mut self) {
+        // The forwarding tasks hold the providers, which are only dropped (and their tasks stopped) once these are aborted
+        for forwarder in self.forwarders.get_mut().values() {
+            forwarder.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::oracle::FixedOracle;
+    use crate::core::solver::DefaultOrderbookSolver;
+    use crate::types::{BalanceCache, TychoStreamState};
+    use tycho_simulation::evm::decoder::StreamDecodeError;
+    use crate::utils::fixtures::{self, USDC, WETH};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Serves a WETH-USDC pool on ethereum and nothing on the other networks, counting the builds
+    #[derive(Default)]
+    struct MockFactory {
+        builds: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl ProviderFactory for MockFactory {
+        async fn build(&self, network: &Network) -> Result<OrderbookProvider, anyhow::Error> {
+            self.builds.fetch_add(1, Ordering::SeqCst);
+            let mut state = TychoStreamState::default();
+            let mut tokens = vec![];
+            if network.name == "ethereum" {
+                let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+                state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone()]));
+                tokens = vec![fixtures::weth(), fixtures::usdc()];
+            }
+            OrderbookProvider::from_snapshot(state.to_snapshot(network, &tokens), None, DefaultOrderbookSolver::default()).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_network_routing() {
+        let network = |name: &str| Network {
+            name: name.to_string(),
+            block_time_ms: 12_000,
+            ..fixtures::offline_network()
+        };
+        let factory = Arc::new(MockFactory::default());
+        let multi = MultiNetworkProvider::new(vec![network("ethereum"), network("base")], factory.clone()).await;
+        let ethereum = multi.provider("ethereum").await.unwrap();
+        *ethereum.balances.lock().await = BalanceCache {
+            block: 1,
+            entries: HashMap::from([(fixtures::pool("0x01", 1_000., 2_000_000.).component.key(), HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (USDC.to_string(), 2_000_000 * 10u128.pow(6))]))]),
+            ..Default::default()
+        };
+        let params = || OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let book = multi.get_orderbook("ethereum", &FixedOracle(2_000.), params()).await.unwrap();
+        assert_eq!(book.block, 1);
+        assert!(!book.bids.is_empty());
+        assert_eq!(multi.get_orderbook("base", &FixedOracle(2_000.), params()).await.unwrap_err(), OrderbookError::TokenNotFound(WETH.to_string()));
+        assert_eq!(multi.get_orderbook("solana", &FixedOracle(2_000.), params()).await.unwrap_err(), OrderbookError::UnknownNetwork("solana".to_string()));
+        // A stopped provider is rebuilt, the running ones are kept
+        ethereum.task.abort();
+        tokio::task::yield_now().await;
+        assert!(!multi.health(u64::MAX).await["ethereum"].running);
+        assert_eq!(multi.restart_stopped(u64::MAX).await, vec!["ethereum".to_string()]);
+        assert_eq!(factory.builds.load(Ordering::SeqCst), 3);
+        let health = multi.health(u64::MAX).await;
+        assert_eq!(health["ethereum"], NetworkHealth { running: true, ready: true, block: 1, restarts: 1 });
+        assert_eq!((health["base"].block, health["base"].restarts), (0, 0));
+        assert!(multi.restart_stopped(10).await.is_empty());
+        // The events are forwarded, and a stream error restarts the provider
+        let ethereum = multi.provider("ethereum").await.unwrap();
+        ethereum.sender.send(OrderbookEvent::Error(StreamDecodeError::Fatal("closed".to_string()))).await.unwrap();
+        let mut events = multi.events.lock().await;
+        let mut initialised = vec![];
+        loop {
+            match events.recv().await.unwrap() {
+                (network, OrderbookEvent::Initialised(_)) => initialised.push(network),
+                (network, event) => {
+                    assert!(network == "ethereum" && matches!(event, OrderbookEvent::Error(_)));
+                    break;
+                }
+            }
+        }
+        drop(events);
+        assert!(initialised.contains(&"base".to_string()));
+        assert_eq!(multi.restart_stopped(10).await, vec!["ethereum".to_string()]);
+        // A provider receiving no block for more than 10 block times is restarted
+        let ethereum = multi.provider("ethereum").await.unwrap();
+        ethereum.state.write().await.received_at_ms = 0;
+        assert!(!multi.health(10).await["ethereum"].ready);
+        assert_eq!(multi.restart_stopped(10).await, vec!["ethereum".to_string()]);
+        assert_eq!(multi.health(10).await["ethereum"].restarts, 3);
+        // More events than the channel of a provider holds never block its stream task
+        let ethereum = multi.provider("ethereum").await.unwrap();
+        for block in 0..200 {
+            tokio::time::timeout(std::time::Duration::from_secs(1), ethereum.sender.send(OrderbookEvent::NewHeader(block, vec![]))).await.unwrap().unwrap();
+        }
+    }
+}
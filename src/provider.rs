@@ -1,24 +1,43 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 
 use futures::StreamExt;
 
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tycho_simulation::evm::protocol::uniswap_v2::state::UniswapV2State;
 use tycho_simulation::evm::stream::ProtocolStreamBuilder;
+use tycho_simulation::protocol::models::{BlockUpdate, ProtocolComponent};
 
+use crate::adapters::default::{depth_diff, depth_limit, DefaultOrderBookAdapter};
 use crate::core::book::{self};
+use crate::core::gas;
+use crate::core::client::{self, build_tycho_client, BalanceSource, CodeReader, ComponentStateSource, TychoBalanceSource};
+use crate::core::helper::{parse_tag, rebuild_protosim};
+use crate::core::oracle::PriceOracle;
+use crate::core::protos;
 use crate::core::solver::{DefaultOrderbookSolver, OrderbookSolver};
 use crate::types::TychoStreamState;
-use crate::types::{self, Network, OrderbookEvent};
+use crate::types::{self, AdapterError, AmmType, BalanceCache, RouteCache, UnitWorth, ValorisationPath, BookQuality, ExchangeInfo, BookQualityConfig, DepthConfig, Network, OrderbookDepth, OrderbookError, OrderbookEvent, StateSnapshot};
+use crate::utils::r#static::adapters::INVALID_SYMBOL_CODE;
+use crate::utils::r#static::maths::MID_HISTORY_SIZE;
+use crate::utils::misc::current_timestamp_ms;
 use crate::{data, maths};
 
+use data::cache::{cacheable, OrderbookCache};
+use data::store::StateStore;
+use data::fmt::component_key;
 use data::fmt::SrzProtocolComponent;
 use data::fmt::SrzToken;
+use data::fmt::SrzUniswapV2State;
 use tokio::sync::Mutex;
 use tokio::sync::{mpsc, RwLock};
 use tycho_simulation::tycho_client::stream::StreamError;
+use types::MarketContext;
 use types::Orderbook;
 use types::OrderbookRequestParams;
+use types::SinglePointSimulation;
+use types::TradeResult;
 use types::ProtoSimComp;
 use types::SharedTychoStreamState;
 
@@ -37,10 +56,12 @@ impl Default for OrderbookProviderConfig {
 
 /// SDK prderbook provider (OBP) that wraps a ProtocolStreamBuistrlder stream
 pub struct OrderbookProvider<S: OrderbookSolver = DefaultOrderbookSolver> {
-    /// The spawned task task is stored to ensure the task remains running.
-    pub _task: JoinHandle<()>,
-    /// Tokens given by Tycho
-    pub tokens: Vec<SrzToken>,
+    /// The spawned task task is stored to ensure the task remains running, aborted on shutdown (see shutdown)
+    pub task: JoinHandle<()>,
+    /// Cancelled on shutdown, checked by the stream task between two messages
+    pub cancel: CancellationToken,
+    /// Tokens given by Tycho, shared with the stream task and the tokens refresh (see refresh_tokens_every). Read them with `tokens.read().await`
+    pub tokens: SharedTokens,
     /// The network used
     pub network: Network,
     /// Receiver side of the channel where OrderbookEvents are sent.
@@ -52,6 +73,303 @@ pub struct OrderbookProvider<S: OrderbookSolver = DefaultOrderbookSolver> {
     pub key: Option<String>,
     /// The solver instance used to optimize trades.
     pub solver: S,
+    /// Last seen quality of each assessed pair, indexed by pair tag (see assess)
+    pub qualities: Mutex<HashMap<String, BookQuality>>,
+    /// Thresholds against which the built orderbooks are assessed, default ones unless changed (see assess)
+    pub quality: BookQualityConfig,
+    /// Pairs whose depth is diffed by the stream task at each block, indexed by pair tag (see subscribe)
+    pub subscriptions: Subscriptions,
+    /// Mid price history (block, mid) of each computed pair, indexed by pair tag, used to estimate its volatility (see record_mid)
+    pub mids: Mutex<HashMap<String, Vec<(u64, f64)>>>,
+    /// Sender side of the OrderbookEvents channel, for the events emitted outside of the stream task
+    pub sender: mpsc::Sender<OrderbookEvent>,
+    /// Component balances of the last block, shared by the orderbook builds of that block (see book::build)
+    pub balances: Arc<Mutex<BalanceCache>>,
+    /// Valuation routes of the tokens to their references, reused by the orderbook builds until the component set changes (see RouteCache)
+    pub routes: Arc<std::sync::Mutex<RouteCache>>,
+}
+
+/// Depth subscription of a pair: the oracle used to build its orderbook, the optional depth bucketing, the last depth sent, and the number of subscribers sharing it
+#[derive(Clone)]
+pub struct Subscription {
+    pub oracle: Arc<dyn PriceOracle>,
+    pub bucketing: Option<DepthConfig>,
+    pub depth: Option<OrderbookDepth>,
+    pub subscribers: usize,
+}
+
+/// Depth subscriptions, shared with the stream task
+pub type Subscriptions = Arc<RwLock<HashMap<String, Subscription>>>;
+
+/// Tokens of the provider, shared with the stream task
+pub type SharedTokens = Arc<RwLock<Vec<SrzToken>>>;
+
+/// Stream messages handling, applied by the provider task under a single write lock per message
+impl TychoStreamState {
+    /// Whether a new component is admitted into the state: its protocol type must be supported, and its tokens all whitelisted (if any whitelist)
+    fn admitted(&self, cp: &ProtocolComponent) -> bool {
+        if !supported(cp) {
+            return false;
+        }
+        if !self.whitelist.is_empty() && !cp.tokens.iter().all(|tk| self.whitelist.contains(&tk.address.to_string().to_lowercase())) {
+            tracing::trace!("Component {} skipped: not all of its tokens are whitelisted", cp.id);
+            return false;
+        }
+        true
+    }
+
+    /// Writes the first stream message, containing all the components and their states
+    pub fn initialise(&mut self, msg: &BlockUpdate) {
+        self.initialise_capped(msg, None, &HashMap::new());
+    }
+
+    /// Writes the first stream message, keeping at most `cap` components, ranked by TVL (indexed by component ID, 0 if missing) then ID
+    /// The stream task fetches the TVLs from Tycho (see client::get_component_tvls), components without one being ranked by ID alone
+    /// The others are deferred, and admitted into the state on their next update (see apply), which bounds the cold start time and memory on busy chains.
+    pub fn initialise_capped(&mut self, msg: &BlockUpdate, cap: Option<usize>, tvl: &HashMap<String, f64>) {
+        let mut ranked = msg.new_pairs.values().filter(|cp| self.admitted(cp)).collect::<Vec<&ProtocolComponent>>();
+        self.deferred = HashMap::new();
+        if let Some(cap) = cap.filter(|cap| ranked.len() > *cap) {
+            let worth = |cp: &ProtocolComponent| tvl.get(&cp.id.to_string().to_lowercase()).copied().unwrap_or_default();
+            ranked.sort_by(|a, b| worth(b).partial_cmp(&worth(a)).unwrap_or(std::cmp::Ordering::Equal).then_with(|| a.id.to_string().cmp(&b.id.to_string())));
+            tracing::info!("Initial sync: keeping {} components out of {}, the others are deferred until their next update", cap, ranked.len());
+            for cp in ranked.split_off(cap) {
+                self.deferred.entry(cp.id.to_string().to_lowercase()).or_default().push(cp.clone());
+            }
+        }
+        self.keys = HashMap::new();
+        for cp in ranked.iter() {
+            self.index(cp);
+        }
+        self.components = ranked.iter().map(|cp| (component_key(&cp.protocol_system, &cp.id.to_string()), (*cp).clone())).collect();
+        // States of components not kept (deferred, not admitted or unknown) are dropped, so that no protosim is orphaned
+        let protosims = msg
+            .states
+            .iter()
+            .filter(|(id, _)| self.keys.contains_key(&id.to_lowercase()))
+            .map(|(id, protosim)| (self.state_key(id, protosim.as_ref()), protosim.clone()))
+            .collect();
+        self.protosims = protosims;
+        self.initialised = true;
+        self.version += 1;
+        self.block = msg.block_number;
+        self.received_at_ms = current_timestamp_ms();
+        self.updated_at = self.protosims.keys().map(|key| (key.clone(), msg.block_number)).collect();
+        self.last_updated = vec![];
+    }
+
+    /// Applies a block update (removed and new components, updated states)
+    /// States of components neither in the state nor deferred (not admitted, or unknown) are skipped, so that no protosim is orphaned
+    /// Removals are applied first, so that a component removed then re-added by a reorg is overwritten cleanly by its new definition and state.
+    /// The protosim of a removed component is only dropped if it has no newer state (see updated_at), neither in this update nor from a later block.
+    /// Returns the keys of the components whose state was updated
+    pub fn apply(&mut self, msg: &BlockUpdate) -> Vec<String> {
+        let mut changed = !msg.removed_pairs.is_empty();
+        for x in msg.removed_pairs.values() {
+            let key = component_key(&x.protocol_system, &x.id.to_string());
+            let id = x.id.to_string().to_lowercase();
+            self.components.remove(&key);
+            self.unindex(&id, &key);
+            // A state in this update only counts if the component is re-added by it, else the state is skipped as untracked
+            let readded = msg.new_pairs.keys().any(|nid| nid.eq_ignore_ascii_case(&id)) && msg.states.keys().any(|sid| sid.eq_ignore_ascii_case(&id));
+            let newer = readded || self.updated_at.get(&key).is_some_and(|block| *block > msg.block_number);
+            if !newer {
+                self.protosims.remove(&key);
+                self.updated_at.remove(&key);
+            }
+        }
+        // New pairs are indexed before the states, so that their states below are keyed correctly
+        let admitted = msg.new_pairs.values().filter(|cp| self.admitted(cp)).collect::<Vec<&ProtocolComponent>>();
+        changed |= !admitted.is_empty();
+        for x in admitted {
+            self.index(x);
+            self.components.insert(component_key(&x.protocol_system, &x.id.to_string()), x.clone());
+        }
+        let mut updated = vec![];
+        for x in msg.states.iter() {
+            for cp in self.deferred.remove(&x.0.to_lowercase()).unwrap_or_default() {
+                changed = true;
+                self.index(&cp);
+                self.components.insert(component_key(&cp.protocol_system, &cp.id.to_string()), cp);
+            }
+            if !self.keys.contains_key(&x.0.to_lowercase()) {
+                tracing::trace!("State of untracked component {} skipped", x.0);
+                continue;
+            }
+            let key = self.state_key(x.0, x.1.as_ref());
+            self.protosims.insert(key.clone(), x.1.clone());
+            self.updated_at.insert(key.clone(), msg.block_number);
+            updated.push(key);
+        }
+        if changed {
+            self.version += 1;
+        }
+        self.block = msg.block_number;
+        self.received_at_ms = current_timestamp_ms();
+        self.last_updated = updated.clone();
+        updated
+    }
+
+    /// Buffers a block update until it has 'confirmations' blocks on top of it (see Network::confirmations), then returns it to be applied (see apply)
+    /// Returns the updates confirmed by this block, oldest first. All of them at once (this one included) without confirmations
+    /// A pending update at or after the block received was reorged out: it is dropped, replaced by the new one, without ever being served
+    pub fn confirm(&mut self, msg: BlockUpdate) -> Vec<BlockUpdate> {
+        let before = self.pending.len();
+        self.pending.retain(|pending| pending.block_number < msg.block_number);
+        if self.pending.len() < before {
+            tracing::debug!("Dropped {} pending block updates reorged out by block {}", before - self.pending.len(), msg.block_number);
+        }
+        let tip = msg.block_number;
+        self.pending.push_back(msg);
+        let mut confirmed = vec![];
+        while self.pending.front().is_some_and(|pending| pending.block_number.saturating_add(self.confirmations) <= tip) {
+            confirmed.extend(self.pending.pop_front());
+        }
+        confirmed
+    }
+
+    /// Indexes the key of a component under its ID, along with the components of other protocols sharing that ID (see keys_of)
+    fn index(&mut self, cp: &ProtocolComponent) {
+        let key = component_key(&cp.protocol_system, &cp.id.to_string());
+        let keys = self.keys.entry(cp.id.to_string().to_lowercase()).or_default();
+        if !keys.contains(&key) {
+            keys.push(key);
+        }
+    }
+
+    /// Drops a removed component from the ID indexes, keeping the components of other protocols sharing its ID
+    fn unindex(&mut self, id: &str, key: &str) {
+        if let Some(keys) = self.keys.get_mut(id) {
+            keys.retain(|k| k != key);
+            if keys.is_empty() {
+                self.keys.remove(id);
+            }
+        }
+        if let Some(deferred) = self.deferred.get_mut(id) {
+            deferred.retain(|cp| component_key(&cp.protocol_system, &cp.id.to_string()) != key);
+            if deferred.is_empty() {
+                self.deferred.remove(id);
+            }
+        }
+    }
+
+    /// Whether the state can serve traffic at 'now_ms' (unix ms): initialised, and its last block received less than 'max_lag_blocks' block times ago
+    /// A stream that stopped delivering blocks is reported as not ready, even if initialised
+    pub fn ready(&self, now_ms: u64, block_time_ms: u64, max_lag_blocks: u64) -> bool {
+        self.initialised && now_ms.saturating_sub(self.received_at_ms) <= max_lag_blocks.saturating_mul(block_time_ms)
+    }
+
+    /// Keys of the components of the pair (tag 'base-quote', with addresses) updated in the last block
+    /// Only the components updated in the last block are checked, not the whole state
+    pub fn pair_updates(&self, tag: &str) -> Vec<String> {
+        let targets = tag.split("-").map(|x| x.to_lowercase()).collect::<Vec<String>>();
+        self.last_updated
+            .iter()
+            .filter(|key| {
+                self.components
+                    .get(*key)
+                    .map(|cp| targets.iter().all(|t| cp.tokens.iter().any(|tk| tk.address.to_string().eq_ignore_ascii_case(t))))
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Exchange info of every distinct pair of tokens sharing at least one component, sorted by tag (base is the token with the lowest address)
+    pub fn pairs(&self) -> Vec<ExchangeInfo> {
+        let mut pairs: HashMap<String, ExchangeInfo> = HashMap::new();
+        for cp in self.components.values() {
+            let srz = SrzProtocolComponent::from(cp.clone());
+            let mut tokens = srz.tokens.clone();
+            tokens.sort_by_key(|t| t.address.to_lowercase());
+            for (x, base) in tokens.iter().enumerate() {
+                for quote in tokens.iter().skip(x + 1) {
+                    let symbol = format!("{}-{}", base.address, quote.address).to_lowercase();
+                    let info = pairs.entry(symbol.clone()).or_insert_with(|| ExchangeInfo {
+                        symbol,
+                        timezone: "UTC".to_string(),
+                        base: base.clone(),
+                        quote: quote.clone(),
+                        order_types: vec!["MARKET".to_string()],
+                        components: vec![],
+                    });
+                    info.components.push(srz.clone());
+                }
+            }
+        }
+        let mut output = pairs.into_values().collect::<Vec<ExchangeInfo>>();
+        output.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        output.iter_mut().for_each(|info| info.components.sort_by_key(|cp| cp.key()));
+        output
+    }
+
+    /// Spot price of a single component, in quote per base (addresses), read from its protosim
+    pub fn spot_price(&self, id: &str, base: &str, quote: &str) -> Result<f64, anyhow::Error> {
+        let key = self.key(id);
+        let (Some(component), Some(protosim)) = (self.components.get(&key), self.protosims.get(&key)) else {
+            return Err(anyhow::anyhow!("Component {} is not tracked", id));
+        };
+        let token = |address: &str| component.tokens.iter().find(|tk| tk.address.to_string().eq_ignore_ascii_case(address));
+        let (Some(base), Some(quote)) = (token(base), token(quote)) else {
+            return Err(anyhow::anyhow!("Tokens {} and {} are not both in component {}", base, quote, id));
+        };
+        protosim
+            .spot_price(base, quote)
+            .map_err(|e| anyhow::anyhow!("Failed to get the spot price of component {}: {:?}", id, e))
+    }
+}
+
+/// Snapshots of the state, to persist it and build orderbooks without a Tycho connection (see StateSnapshot)
+impl TychoStreamState {
+    /// Serializable form of the state. Components whose protosim can't be rebuilt from a serialized state are left out, with a warning
+    pub fn to_snapshot(&self, network: &Network, tokens: &[SrzToken]) -> StateSnapshot {
+        let mut snapshot = StateSnapshot {
+            network: network.clone(),
+            block: self.block,
+            tokens: tokens.to_vec(),
+            ..Default::default()
+        };
+        for (key, cp) in self.components.iter() {
+            let id = cp.id.to_string().to_lowercase();
+            match self.protosims.get(key).and_then(|protosim| protosim.as_any().downcast_ref::<UniswapV2State>()) {
+                Some(state) => {
+                    snapshot.components.push(SrzProtocolComponent::from(cp.clone()));
+                    snapshot.uniswap_v2.push(SrzUniswapV2State::from((state.clone(), id)));
+                }
+                None => tracing::warn!("Component {} ({}) left out of the snapshot: its state can't be rebuilt", id, cp.protocol_system),
+            }
+        }
+        snapshot
+    }
+
+    /// Rebuilds the state of a snapshot, as if the first stream message was received at the snapshot block
+    /// Components without a rebuildable state are skipped, with a warning
+    pub fn from_snapshot(snapshot: &StateSnapshot) -> Result<Self, anyhow::Error> {
+        let (_, _, chain) = types::chain(snapshot.network.name.clone()).ok_or_else(|| anyhow::anyhow!("Unknown network: {}", snapshot.network.name))?;
+        let states = snapshot.uniswap_v2.iter().map(|srz| (srz.id.to_lowercase(), srz)).collect::<HashMap<String, &SrzUniswapV2State>>();
+        let mut msg = BlockUpdate {
+            block_number: snapshot.block,
+            states: HashMap::new(),
+            new_pairs: HashMap::new(),
+            removed_pairs: HashMap::new(),
+        };
+        for cp in snapshot.components.iter() {
+            match states.get(&cp.id.to_lowercase()) {
+                Some(srz) => {
+                    msg.states.insert(cp.id.clone(), Box::new(UniswapV2State::from((*srz).clone())));
+                    msg.new_pairs.insert(cp.id.clone(), SrzProtocolComponent::original(cp.clone(), chain));
+                }
+                None => tracing::warn!("Component {} ({}) skipped: no state to rebuild it from in the snapshot", cp.id, cp.protocol_system),
+            }
+        }
+        let mut state = TychoStreamState {
+            whitelist: snapshot.network.token_whitelist.iter().map(|t| t.to_lowercase()).collect(),
+            confirmations: snapshot.network.confirmations,
+            ..Default::default()
+        };
+        state.initialise(&msg);
+        Ok(state)
+    }
 }
 
 /// OrderbookProvider is a struct that manages the protocol stream and shared state, and provides methods to interact with the stream, build orderbooks, and more.
@@ -65,7 +383,7 @@ impl OrderbookProvider {
     /// * A Result containing the OBP instance or a StreamError if the stream could not be built.
     pub async fn new<S>(network: Network, stream: ProtocolStreamBuilder, tokens: Vec<SrzToken>, key: Option<String>, solver: S) -> Result<OrderbookProvider<S>, StreamError>
     where
-        S: OrderbookSolver + 'static,
+        S: OrderbookSolver + Clone + 'static,
     {
         // Build the protocol stream that yields Result<BlockUpdate, StreamDecodeError>.
         match stream.build().await {
@@ -74,14 +392,58 @@ impl OrderbookProvider {
                 let shared = Arc::new(RwLock::new(TychoStreamState {
                     protosims: HashMap::new(),
                     components: HashMap::new(),
+                    keys: HashMap::new(),
                     initialised: false,
+                    block: 0,
+                    updated_at: HashMap::new(),
+                    last_updated: vec![],
+                    deferred: HashMap::new(),
+                    whitelist: network.token_whitelist.iter().map(|t| t.to_lowercase()).collect(),
+                    version: 0,
+                    received_at_ms: 0,
+                    confirmations: network.confirmations,
+                    pending: VecDeque::new(),
                 }));
                 // Why do we need to clone the shared state here ?
                 let state = shared.clone();
+                let subscriptions: Subscriptions = Arc::new(RwLock::new(HashMap::new()));
+                let tokens: SharedTokens = Arc::new(RwLock::new(tokens));
+                let events = sender.clone();
+                let balances = Arc::new(Mutex::new(BalanceCache::default()));
+                let routes = Arc::new(std::sync::Mutex::new(RouteCache::default()));
+                let (subs, ntwk) = (subscriptions.clone(), network.clone());
                 tracing::debug!("Starting stream processing task ...");
+                let cancel = CancellationToken::new();
+                let token = cancel.clone();
+                let queue = Arc::new(DiffQueue::default());
+                let tvl_key = key.clone();
+                tokio::spawn(diff_worker(
+                    shared.clone(),
+                    subscriptions.clone(),
+                    network.clone(),
+                    tokens.clone(),
+                    key.clone(),
+                    solver.clone(),
+                    sender.clone(),
+                    balances.clone(),
+                    routes.clone(),
+                    queue.clone(),
+                    cancel.clone(),
+                ));
                 let task = tokio::spawn(async move {
                     futures::pin_mut!(stream);
-                    while let Some(update) = stream.next().await {
+                    loop {
+                        let update = tokio::select! {
+                            biased;
+                            _ = token.cancelled() => {
+                                tracing::debug!("Stream processing task cancelled");
+                                break;
+                            }
+                            update = stream.next() => match update {
+                                Some(update) => update,
+                                None => break,
+                            },
+                        };
                         // The first message received will contain states for all protocol components registered to
                         // Thereafter, further block updates will only contain data for updated or new components.
                         let mtx = state.read().await;
@@ -98,42 +460,46 @@ impl OrderbookProvider {
                                 );
                                 if !initialised {
                                     tracing::debug!("First stream (initialised was false). Writing the entire streamed data into the shared struct.");
-                                    let mut targets = vec![];
-                                    for (_, comp) in msg.new_pairs.iter() {
-                                        // tracing::debug!("Adding new component {} to the shared state: {}", comp.protocol_system.clone(), comp.protocol_type_name.clone());
-                                        targets.push(comp.id.to_string().to_lowercase());
-                                    }
-                                    let mut writing = state.write().await;
-                                    writing.protosims = msg.states.clone();
-                                    writing.components = msg.new_pairs.clone();
-                                    writing.initialised = true;
-                                    drop(writing);
+                                    // Stream messages don't carry TVL: when capped, it's fetched from Tycho to keep the most liquid components, the others join as soon as they are updated
+                                    let tvl = match ntwk.max_components {
+                                        Some(cap) if msg.new_pairs.len() > cap => match build_tycho_client(&ntwk, tvl_key.clone()) {
+                                            Ok(rpc) => {
+                                                let components = msg.new_pairs.values().map(|cp| (cp.id.to_string(), cp.protocol_system.clone())).collect::<Vec<(String, String)>>();
+                                                client::get_component_tvls(&rpc, ntwk.clone(), &components).await
+                                            }
+                                            Err(e) => {
+                                                tracing::error!("Failed to build the Tycho client to rank the initial components by TVL: {}", e);
+                                                HashMap::new()
+                                            }
+                                        },
+                                        _ => HashMap::new(),
+                                    };
+                                    state.write().await.initialise_capped(&msg, ntwk.max_components, &tvl);
                                     let event = OrderbookEvent::Initialised(msg.block_number);
                                     let _ = sender.send(event).await;
                                 } else {
-                                    let mut updated = vec![];
-                                    if !msg.states.is_empty() {
-                                        let mut writing = state.write().await;
-
-                                        for x in msg.states.iter() {
-                                            writing.protosims.insert(x.0.clone().to_lowercase(), x.1.clone());
-                                            updated.push(x.0.clone().to_lowercase());
+                                    // Blocks are applied once confirmed, so possibly a few at once, or none while they are pending
+                                    let confirmed = state.write().await.confirm(msg);
+                                    for msg in confirmed {
+                                        let updated = state.write().await.apply(&msg);
+                                        if !msg.removed_pairs.is_empty() {
+                                            let _ = sender.send(OrderbookEvent::Reorg(msg.block_number)).await;
                                         }
-                                        drop(writing);
-                                    }
-                                    if !msg.new_pairs.is_empty() || !msg.removed_pairs.is_empty() {
-                                        let mut writing = state.write().await;
-                                        for x in msg.new_pairs.iter() {
-                                            writing.components.insert(x.0.clone().to_lowercase(), x.1.clone());
+                                        if !msg.new_pairs.is_empty() || !msg.removed_pairs.is_empty() {
+                                            tracing::debug!("Received {} new pairs, and {} pairs to be removed. Updating Redis ...", msg.new_pairs.len(), msg.removed_pairs.len());
                                         }
-                                        for x in msg.removed_pairs.iter() {
-                                            writing.components.remove(&x.0.clone().to_lowercase());
+                                        let event = OrderbookEvent::NewHeader(msg.block_number, updated);
+                                        let _ = sender.send(event).await;
+                                        // Subscribed pairs with updated components have their depth diffed aside, to not slow down the stream (see diff_worker)
+                                        let tags = subs.read().await.keys().cloned().collect::<Vec<String>>();
+                                        let mtx = state.read().await;
+                                        let tags = tags.into_iter().filter(|tag| !mtx.pair_updates(tag).is_empty()).collect::<Vec<String>>();
+                                        drop(mtx);
+                                        if !tags.is_empty() {
+                                            queue.tags.lock().await.extend(tags);
+                                            queue.notify.notify_one();
                                         }
-                                        tracing::debug!("Received {} new pairs, and {} pairs to be removed. Updating Redis ...", msg.new_pairs.len(), msg.removed_pairs.len());
-                                        drop(writing);
                                     }
-                                    let event = OrderbookEvent::NewHeader(msg.block_number, updated.clone());
-                                    let _ = sender.send(event).await;
                                 }
                             }
                             Err(err) => {
@@ -148,11 +514,19 @@ impl OrderbookProvider {
                     //stream: receiver,
                     stream: Mutex::new(receiver),
                     state: shared, // ---> Anormal here, but it works, need to clarify. Arc pointing to the same memory location, it should be ok, but incoherent to need dup
-                    _task: task,
-                    tokens: tokens.clone(),
+                    task,
+                    cancel,
+                    tokens,
                     network: network.clone(),
                     key: key.clone(),
                     solver,
+                    qualities: Mutex::new(HashMap::new()),
+                    quality: BookQualityConfig::default(),
+                    subscriptions,
+                    mids: Mutex::new(HashMap::new()),
+                    sender: events,
+                    balances,
+                    routes,
                 };
 
                 Ok(obp)
@@ -167,6 +541,66 @@ impl OrderbookProvider {
         }
     }
 
+    /// Creates an OBP serving the static state of a snapshot, without any Tycho connection (e.g. for backtesting)
+    /// An Initialised event is sent at the snapshot block, then no update is ever received
+    pub async fn from_snapshot<S>(snapshot: StateSnapshot, key: Option<String>, solver: S) -> Result<OrderbookProvider<S>, anyhow::Error>
+    where
+        S: OrderbookSolver + Clone + 'static,
+    {
+        let state = TychoStreamState::from_snapshot(&snapshot)?;
+        tracing::info!("Loaded {} components from the snapshot at block {}", state.components.len(), snapshot.block);
+        let (sender, receiver) = mpsc::channel(100);
+        let (block, events) = (snapshot.block, sender.clone());
+        let cancel = CancellationToken::new();
+        let token = cancel.clone();
+        let task = tokio::spawn(async move {
+            let _ = sender.send(OrderbookEvent::Initialised(block)).await;
+            // Idle like a stream without updates, until shutdown
+            token.cancelled().await;
+        });
+        Ok(OrderbookProvider {
+            stream: Mutex::new(receiver),
+            state: Arc::new(RwLock::new(state)),
+            task,
+            cancel,
+            tokens: Arc::new(RwLock::new(snapshot.tokens)),
+            network: snapshot.network,
+            key,
+            solver,
+            qualities: Mutex::new(HashMap::new()),
+            quality: BookQualityConfig::default(),
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            mids: Mutex::new(HashMap::new()),
+            sender: events,
+            balances: Arc::new(Mutex::new(BalanceCache::default())),
+            routes: Arc::new(std::sync::Mutex::new(RouteCache::default())),
+        })
+    }
+
+    /// Whether the stream task is still running (not ended, cancelled nor aborted)
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// Stops the stream task (cancelled, then aborted if it's still running) and closes the OrderbookEvents channel
+    /// The shared state stays readable, but is never updated anymore. Also done when the OBP is dropped
+    pub async fn shutdown(&self) {
+        self.cancel.cancel();
+        self.task.abort();
+        self.stream.lock().await.close();
+        tracing::debug!("OBP on {} shut down", self.network.name);
+    }
+
+    /// Dumps the shared state to a JSON file at `path`, to be reloaded with OrderbookBuilder::from_snapshot
+    /// Returns the number of components written (see TychoStreamState::to_snapshot for the ones left out)
+    pub async fn snapshot(&self, path: &str) -> Result<usize, anyhow::Error> {
+        let tokens = self.tokens.read().await.clone();
+        let snapshot = self.state.read().await.to_snapshot(&self.network, &tokens);
+        std::fs::write(path, serde_json::to_string(&snapshot)?).map_err(|e| anyhow::anyhow!("Failed to write snapshot {}: {}", path, e))?;
+        tracing::info!("Snapshot of {} components at block {} written to {}", snapshot.components.len(), snapshot.block, path);
+        Ok(snapshot.components.len())
+    }
+
     /// Returns components that contains the given tokens
     /// Example: target is ETH, USDC. It will return all components that contain ETH and USDC
     pub async fn get_components_for_target(&self, targets: Vec<SrzToken>) -> Vec<SrzProtocolComponent> {
@@ -186,88 +620,1731 @@ impl OrderbookProvider {
         output
     }
 
-    /// Compute the orderbook for the given pair by simulating trades on the components matching the requested pair
-    pub async fn get_orderbook<S: OrderbookSolver>(&self, solver: S, params: OrderbookRequestParams) -> Result<Orderbook, anyhow::Error> {
-        let single = params.point.is_some();
+    /// Merges the given tokens into the provider ones (new addresses only), and emits an OrderbookEvent::TokensUpdated if any was added
+    /// Returns the number of tokens added
+    pub async fn merge_tokens(&self, fetched: Vec<SrzToken>) -> usize {
+        merge_tokens(&self.tokens, fetched, &self.sender).await
+    }
+
+    /// Spawns a background task re-fetching the Tycho tokens every `interval`, and merging the new ones into the provider tokens (see merge_tokens)
+    /// This only extends the tokens the pairs and tags are resolved with: the stream decodes components with the token set given at build time (see OrderbookBuilder),
+    /// so the pools of a token listed after the start are not streamed, and its pairs have no liquidity until the provider is rebuilt.
+    /// A failed fetch keeps the current list. The refresh stops with the provider (see shutdown), or when the returned handle is aborted
+    pub fn refresh_tokens_every(&self, interval: std::time::Duration) -> JoinHandle<()> {
+        let (tokens, network, key, sender) = (self.tokens.clone(), self.network.clone(), self.key.clone().unwrap_or_default(), self.sender.clone());
+        let cancel = self.cancel.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await; // The first tick completes immediately, the tokens were just fetched
+            loop {
+                tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => {
+                        tracing::debug!("Tokens refresh task cancelled");
+                        break;
+                    }
+                    _ = ticker.tick() => {}
+                }
+                match client::tokens(&network, key.clone()).await {
+                    Ok(fetched) => {
+                        merge_tokens(&tokens, fetched.into_iter().map(SrzToken::from).collect(), &sender).await;
+                    }
+                    Err(e) => tracing::warn!("Failed to refresh the tokens on {} ({}), keeping the current list", network.name, e),
+                }
+            }
+        })
+    }
+
+    /// Returns every token reachable from the `from` token (address) within `max_hops` swaps via the tracked components, with the minimum hop count (see maths::path::reachable)
+    /// Tokens are resolved from the components, sorted by hop count
+    pub async fn reachable_tokens(&self, from: &str, max_hops: usize) -> Vec<(SrzToken, usize)> {
         let mtx = self.state.read().await;
-        let comp = mtx.components.clone();
+        let acps = mtx.components.values().map(|cp| SrzProtocolComponent::from(cp.clone())).collect::<Vec<SrzProtocolComponent>>();
         drop(mtx);
-        let acps = comp.iter().map(|x| SrzProtocolComponent::from(x.1.clone())).collect::<Vec<SrzProtocolComponent>>(); // Not efficient at all
+        let tokens = acps.iter().flat_map(|cp| cp.tokens.iter()).map(|tk| (tk.address.to_lowercase(), tk.clone())).collect::<HashMap<String, SrzToken>>();
+        maths::path::reachable(&acps, from, max_hops).into_iter().filter_map(|(address, hops)| tokens.get(&address).map(|tk| (tk.clone(), hops))).collect()
+    }
 
-        // --- Check if the pair is valid ---
-        let targets = params.tag.clone().split("-").map(|x| x.to_string().to_lowercase()).collect::<Vec<String>>();
-        if targets.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid pair"));
-        }
-        let all_tokens = self.tokens.clone();
-        let srzt0 = all_tokens
-            .iter()
-            .find(|x| x.address.to_lowercase() == targets[0].clone())
-            .ok_or_else(|| anyhow::anyhow!("Token not found: {}", targets[0]));
-        let srzt1 = all_tokens
+    /// Returns the protosims of the given components (e.g. the pools of an orderbook), in the same order
+    /// Components without protosim in the shared state are skipped
+    pub async fn protosims(&self, components: &[SrzProtocolComponent]) -> Vec<ProtoSimComp> {
+        let mtx = self.state.read().await;
+        let output = components
             .iter()
-            .find(|x| x.address.to_lowercase() == targets[1].clone())
-            .ok_or_else(|| anyhow::anyhow!("Token not found: {}", targets[0]));
-        let (srzt0, srzt1) = match (srzt0, srzt1) {
-            (Ok(t0), Ok(t1)) => (t0.clone(), t1.clone()),
-            (Err(e), _) => return Err(e),
-            (_, Err(e)) => return Err(e),
-        };
-
-        let targets = vec![srzt0.clone(), srzt1.clone()];
-        tracing::debug!("Building orderbook for pair {}-{} | Single point: {}", targets[0].symbol.clone(), targets[1].symbol.clone(), single);
-        // --- Compute path ---
-        let base_to_eth: Result<types::ValorisationPath, String> = maths::path::routing(acps.clone(), srzt0.address.to_string().to_lowercase(), self.network.eth.to_lowercase());
-        let quote_to_eth: Result<types::ValorisationPath, String> = maths::path::routing(acps.clone(), srzt1.address.to_string().to_lowercase(), self.network.eth.to_lowercase());
-        match (base_to_eth, quote_to_eth) {
-            (Ok(base_to_eth), Ok(quote_to_eth)) => {
-                let mut to_eth_pts: Vec<ProtoSimComp> = vec![];
-                let mut pts: Vec<ProtoSimComp> = vec![];
-                let mtx = self.state.read().await;
-                for cp in acps.clone() {
-                    if base_to_eth.comp_path.contains(&cp.id.to_lowercase()) || quote_to_eth.comp_path.contains(&cp.id.to_lowercase()) {
-                        if let Some(protosim) = mtx.protosims.get(&cp.id.to_lowercase()) {
-                            to_eth_pts.push(ProtoSimComp {
-                                component: cp.clone(),
-                                protosim: protosim.clone(),
-                            });
-                        }
+            .filter_map(|cp| {
+                mtx.protosims.get(&cp.key()).map(|protosim| ProtoSimComp {
+                    component: cp.clone(),
+                    protosim: protosim.clone(),
+                })
+            })
+            .collect();
+        drop(mtx);
+        output
+    }
+
+    /// Whether the stream has received its first block. Before that, books can't be built (see OrderbookError::StreamNotInitialised)
+    pub async fn is_initialised(&self) -> bool {
+        self.state.read().await.initialised
+    }
+
+    /// Readiness of the provider, e.g. for a load balancer health check: the stream is initialised and received a block less than `max_lag_blocks` block times ago
+    /// Unlike is_initialised, a provider whose stream stalled is not ready
+    pub async fn is_ready(&self, max_lag_blocks: u64) -> bool {
+        self.state.read().await.ready(current_timestamp_ms(), self.network.block_time_ms, max_lag_blocks)
+    }
+
+    /// Returns the keys of the components whose state hasn't been updated by the stream for more than `max_age_blocks` blocks
+    pub async fn stale(&self, max_age_blocks: u64) -> Vec<String> {
+        let mtx = self.state.read().await;
+        let mut output = vec![];
+        for key in mtx.protosims.keys() {
+            let last = mtx.updated_at.get(key).copied().unwrap_or_default();
+            if mtx.block.saturating_sub(last) > max_age_blocks {
+                output.push(key.clone());
+            }
+        }
+        drop(mtx);
+        output
+    }
+
+    /// Re-fetches via Tycho RPC the state of the components not updated for more than `max_age_blocks` blocks, and overwrites their protosim in the shared state.
+    /// A component that simply stops emitting updates can't be corrected by the stream alone, hence this targeted refresh.
+    /// Only Uniswap v2 forks can be rebuilt from the RPC attributes (see helper::rebuild_protosim): the components of other protocols (v3, v4, Ekubo, VM pools) stay stale.
+    /// Returns the keys of the refreshed components.
+    pub async fn refresh_stale(&self, max_age_blocks: u64) -> Result<Vec<String>, anyhow::Error> {
+        let source = client::TychoStateSource(build_tycho_client(&self.network, self.key.clone())?);
+        Ok(self.refresh_stale_from(&source, max_age_blocks).await)
+    }
+
+    /// Same as refresh_stale, with the states fetched from the given source
+    pub async fn refresh_stale_from(&self, source: &dyn ComponentStateSource, max_age_blocks: u64) -> Vec<String> {
+        let stale = self.stale(max_age_blocks).await;
+        if stale.is_empty() {
+            return vec![];
+        }
+        tracing::debug!("Found {} stale components (older than {} blocks), refreshing ...", stale.len(), max_age_blocks);
+        let mtx = self.state.read().await;
+        let targets: Vec<SrzProtocolComponent> = stale.iter().filter_map(|key| mtx.components.get(key).map(|cp| SrzProtocolComponent::from(cp.clone()))).collect();
+        let block = mtx.block;
+        drop(mtx);
+        let mut refreshed = vec![];
+        for cp in targets.iter() {
+            match source.state(&self.network, &cp.id, &cp.protocol_system).await {
+                Ok(Some(raw)) => match rebuild_protosim(cp.protocol_system.as_str(), &raw) {
+                    Some(protosim) => {
+                        let mut writing = self.state.write().await;
+                        writing.protosims.insert(cp.key(), protosim);
+                        writing.updated_at.insert(cp.key(), block);
+                        drop(writing);
+                        refreshed.push(cp.key());
                     }
-                    if book::matchcp(cp.tokens.clone(), targets.clone()) {
-                        if let Some(protosim) = mtx.protosims.get(&cp.id.to_lowercase()) {
-                            pts.push(ProtoSimComp {
-                                component: cp.clone(),
-                                protosim: protosim.clone(),
-                            });
-                        }
+                    None => tracing::warn!("Stale component {} ({}) can't be rebuilt from RPC state, skipping", cp.id, cp.protocol_system),
+                },
+                Ok(None) => tracing::warn!("Stale component {} has no state on Tycho, skipping", cp.id),
+                Err(e) => tracing::warn!("Failed to fetch the state of stale component {}: {}", cp.id, e),
+            }
+        }
+        tracing::debug!("Refreshed {} out of {} stale components", refreshed.len(), stale.len());
+        refreshed
+    }
+
+    /// Subscribes to the depth of a pair (tag 'base-quote', with addresses): at each block updating one of its components, its orderbook is rebuilt aside by the stream task,
+    /// then an OrderbookEvent::BookUpdate is emitted with the whole orderbook, and an OrderbookEvent::DepthUpdate with the levels that changed since the previous depth (optionally bucketed, see DepthConfig).
+    /// Subscribers of the same pair (e.g. the clients of a WebSocket API) share a single subscription, so the orderbook is computed once per block: the first oracle and bucketing are kept.
+    pub async fn subscribe(&self, tag: &str, oracle: Arc<dyn PriceOracle>, bucketing: Option<DepthConfig>) {
+        let mut subscriptions = self.subscriptions.write().await;
+        match subscriptions.get_mut(&tag.to_lowercase()) {
+            Some(subscription) => subscription.subscribers += 1,
+            None => {
+                let subscription = Subscription {
+                    oracle,
+                    bucketing,
+                    depth: None,
+                    subscribers: 1,
+                };
+                subscriptions.insert(tag.to_lowercase(), subscription);
+            }
+        }
+    }
+
+    /// Removes a subscriber of a pair (e.g. on a client disconnect). The updates of the pair stop with its last subscriber
+    pub async fn unsubscribe(&self, tag: &str) {
+        let mut subscriptions = self.subscriptions.write().await;
+        if let Some(subscription) = subscriptions.get_mut(&tag.to_lowercase()) {
+            subscription.subscribers = subscription.subscribers.saturating_sub(1);
+            if subscription.subscribers == 0 {
+                subscriptions.remove(&tag.to_lowercase());
+            }
+        }
+    }
+
+    /// Binance-compatible depth of a pair (tag 'base-quote', with addresses), backing a GET /depth?symbol={tag}&limit={n} endpoint
+    /// The limit defaults and is clamped as Binance does (see adapters::default::depth_limit). Errors are Binance-like payloads, with INVALID_SYMBOL_CODE if no component matches the pair
+    pub async fn get_depth(&self, oracle: &dyn PriceOracle, tag: &str, limit: Option<u64>) -> Result<OrderbookDepth, AdapterError> {
+        let targets = tag.split("-").map(|x| x.to_lowercase()).collect::<Vec<String>>();
+        let mtx = self.state.read().await;
+        let found = targets.len() == 2 && mtx.components.values().any(|cp| targets.iter().all(|t| cp.tokens.iter().any(|tk| tk.address.to_string().eq_ignore_ascii_case(t))));
+        drop(mtx);
+        if !found {
+            return Err(AdapterError {
+                code: INVALID_SYMBOL_CODE,
+                msg: format!("No components found for pair {}", tag),
+            });
+        }
+        let params = OrderbookRequestParams {
+            tag: tag.to_lowercase(),
+            ..Default::default()
+        };
+        match self.get_orderbook(self.solver.clone(), oracle, params).await {
+            Ok(book) => Ok(book.depth(Some(depth_limit(limit)), None)),
+            Err(e) => Err(AdapterError { code: -1, msg: e.to_string() }),
+        }
+    }
+
+    /// Tradeable pairs as (token0, token1, pool count), token0 being the lowest address so that A-B and B-A are the same pair (see TychoStreamState::pairs)
+    /// Sorted by pool count, the most liquid pairs in number of pools first, then by tokens
+    pub async fn list_pairs(&self) -> Vec<(String, String, usize)> {
+        let mut pairs = self
+            .state
+            .read()
+            .await
+            .pairs()
+            .into_iter()
+            .map(|info| (info.base.address.to_lowercase(), info.quote.address.to_lowercase(), info.components.len()))
+            .collect::<Vec<(String, String, usize)>>();
+        pairs.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1))));
+        pairs
+    }
+
+    /// Binance-like exchange info of the pairs with at least one component, backing a GET /exchangeInfo endpoint (see TychoStreamState::pairs)
+    /// Filtered on a symbol (tag 'base-quote' with addresses, in any order) if given, else paginated with `offset` and `limit`, as enumerating all pairs can be large
+    pub async fn exchange_info(&self, symbol: Option<&str>, offset: usize, limit: usize) -> Vec<ExchangeInfo> {
+        let pairs = self.state.read().await.pairs();
+        match symbol {
+            Some(symbol) => {
+                let mut targets = symbol.split("-").map(|x| x.to_lowercase()).collect::<Vec<String>>();
+                targets.sort();
+                let symbol = targets.join("-");
+                pairs.into_iter().filter(|info| info.symbol == symbol).collect()
+            }
+            None => pairs.into_iter().skip(offset).take(limit).collect(),
+        }
+    }
+
+    /// Returns the keys of the pair components (tag 'base-quote', with addresses) whose state changed in the last block
+    pub async fn pair_updates(&self, tag: &str) -> Vec<String> {
+        self.state.read().await.pair_updates(tag)
+    }
+
+    /// Spot price of a single component (quote per base, addresses), without building an orderbook
+    /// Synchronous: fails instead of waiting if the stream task is currently writing the shared state
+    pub fn spot_price(&self, component_id: &str, base: &str, quote: &str) -> Result<f64, anyhow::Error> {
+        let mtx = self.state.try_read().map_err(|_| anyhow::anyhow!("Shared state is being updated, retry"))?;
+        mtx.spot_price(component_id, base, quote)
+    }
+
+    /// Checks that a tracked component still exists onchain (e.g. after a reorg), to reconcile the shared state
+    /// Components identified by an address are checked via their bytecode on the network RPC, others (e.g. Uniswap v4 pools, living in a singleton) via a Tycho state query.
+    /// A failed RPC or Tycho query is an error, not a missing component
+    pub async fn verify_component_onchain(&self, id: &str) -> Result<bool, anyhow::Error> {
+        let states = client::TychoStateSource(build_tycho_client(&self.network, self.key.clone())?);
+        self.verify_component_onchain_from(&client::RpcCodeReader(self.network.rpc.clone()), &states, id).await
+    }
+
+    /// Same as verify_component_onchain, with the bytecode and the states read from the given sources
+    pub async fn verify_component_onchain_from(&self, code: &dyn CodeReader, states: &dyn ComponentStateSource, id: &str) -> Result<bool, anyhow::Error> {
+        let mtx = self.state.read().await;
+        let key = mtx.key(id);
+        let component = mtx.components.get(&key).cloned();
+        drop(mtx);
+        let Some(component) = component else {
+            return Err(anyhow::anyhow!("Component {} is not tracked", id));
+        };
+        let id = component.id.to_string().to_lowercase();
+        if component.id.len() == 20 {
+            client::deployed(code, &id).await
+        } else {
+            Ok(states.state(&self.network, &id, &component.protocol_system).await?.is_some())
+        }
+    }
+
+    /// Assesses the quality of a (re)computed orderbook against the current block, and compares it with the last seen quality of the pair
+    /// Returns a QualityAlert event when the pair transitions into a degraded quality (Crossed, Thin, Stale), so that risk systems don't have to poll full books.
+    /// Each orderbook built by the provider is assessed against its thresholds (see quality), the alerts being sent on the events stream
+    pub async fn assess(&self, book: &Orderbook, config: &BookQualityConfig) -> Option<OrderbookEvent> {
+        let block = self.state.read().await.block;
+        let quality = book.quality(block, config);
+        let previous = self.qualities.lock().await.insert(book.tag.clone(), quality);
+        if quality.degraded() && previous != Some(quality) {
+            tracing::warn!("Orderbook {} quality changed from {:?} to {:?} at block {}", book.tag, previous, quality, block);
+            return Some(OrderbookEvent::QualityAlert { tag: book.tag.clone(), quality });
+        }
+        None
+    }
+
+    /// Compute the orderbook for the given pair by simulating trades on the components matching the requested pair
+    /// The protosims and the block are snapshotted under a single read lock at start, so the book is consistent to one block even if the stream updates meanwhile
+    /// The ETH price in USD is fetched from the given oracle (e.g. ChainlinkOracle, CoinGeckoOracle)
+    /// Native/wrapper pairs (e.g. ETH/WETH) return an OrderbookError::WrapperPair, as they are exchanged 1:1 and have no meaningful book
+    /// Errors are typed (see OrderbookError) so that callers can match on them, e.g. to map them to HTTP statuses
+    pub async fn get_orderbook<S: OrderbookSolver>(&self, solver: S, oracle: &dyn PriceOracle, params: OrderbookRequestParams) -> Result<Orderbook, OrderbookError> {
+        let tokens = self.tokens.read().await.clone();
+        let pair = snapshot(&self.state, &self.routes, &tokens, &self.network, &params).await?;
+        self.build_pair(solver, oracle, pair, params).await
+    }
+
+    /// Same as get_orderbook, served from the cache if the book of the pair was already built at the current block (see OrderbookCache)
+    /// Only plain requests are cached (see cache::cacheable), the others are always built
+    /// The cached books must be invalidated with the updated components of each new header (see OrderbookCache::invalidate)
+    pub async fn get_orderbook_cached<S: OrderbookSolver>(&self, solver: S, oracle: &dyn PriceOracle, params: OrderbookRequestParams, cache: &OrderbookCache<impl StateStore>) -> Result<Orderbook, OrderbookError> {
+        if !cacheable(&params) {
+            return self.get_orderbook(solver, oracle, params).await;
+        }
+        let tokens = self.tokens.read().await.clone();
+        let (t0, t1) = parse_tag(&params.tag, &tokens)?;
+        let tag = format!("{}-{}", t0, t1);
+        let block = self.state.read().await.block;
+        if let Some(book) = cache.get(&tag, block).await {
+            tracing::debug!("Orderbook {} served from cache at block {}", tag, block);
+            return Ok(book);
+        }
+        let book = self.get_orderbook(solver, oracle, params).await?;
+        if let Err(e) = cache.put(&book).await {
+            tracing::warn!("Failed to cache the orderbook {} at block {}: {}", book.tag, book.block, e);
+        }
+        Ok(book)
+    }
+
+    /// Compute the orderbooks of many pairs (tags 'base-quote', with addresses), at most 'concurrency' at once, indexed by tag
+    /// All pairs are snapshotted under a single read lock, so the books are consistent to the same block
+    /// Being at the same block, the pools of all the pairs are deduplicated and their balances fetched once, before the builds (see get_orderbooks_from)
+    pub async fn get_orderbooks<S: OrderbookSolver + Clone>(&self, solver: S, oracle: &dyn PriceOracle, tags: Vec<String>, concurrency: usize) -> HashMap<String, Result<Orderbook, OrderbookError>> {
+        match build_tycho_client(&self.network, self.key.clone()) {
+            Ok(client) => self.get_orderbooks_from(&TychoBalanceSource(client), solver, oracle, tags, concurrency).await,
+            Err(e) => tags.into_iter().map(|tag| (tag, Err(OrderbookError::Build(format!("Error while building Tycho client: {}", e))))).collect(),
+        }
+    }
+
+    /// Same as get_orderbooks, with the balances of the pools fetched from the given source into the balance cache, which the builds then read them from
+    pub async fn get_orderbooks_from<S: OrderbookSolver + Clone>(
+        &self,
+        source: &dyn BalanceSource,
+        solver: S,
+        oracle: &dyn PriceOracle,
+        tags: Vec<String>,
+        concurrency: usize,
+    ) -> HashMap<String, Result<Orderbook, OrderbookError>> {
+        let tokens = self.tokens.read().await.clone();
+        let mtx = self.state.read().await;
+        let block = mtx.block;
+        let mut pairs = vec![];
+        for tag in tags {
+            let params = OrderbookRequestParams {
+                tag: tag.to_lowercase(),
+                ..Default::default()
+            };
+            let pair = snapshot_locked(&mtx, &tokens, &self.network, &params);
+            pairs.push((tag, pair.map(|pair| (pair, params))));
+        }
+        drop(mtx);
+        for (pair, params) in pairs.iter_mut().filter_map(|(_, pair)| pair.as_mut().ok()) {
+            value_pair(&self.state, &self.routes, &tokens, &self.network, &params.tag, pair).await;
+        }
+        // Pools shared by several pairs (e.g. multi-token pools) are fetched once, in one batch for all the pairs
+        let mut pools: HashMap<String, ProtoSimComp> = HashMap::new();
+        for (pair, _) in pairs.iter().filter_map(|(_, pair)| pair.as_ref().ok()) {
+            for pt in pair.pts.iter() {
+                pools.entry(pt.component.key()).or_insert_with(|| pt.clone());
+            }
+        }
+        let pools = pools.into_values().collect::<Vec<ProtoSimComp>>();
+        BalanceCache::fetch(&self.balances, source, &self.network, &pools, block).await;
+        let semaphore = tokio::sync::Semaphore::new(concurrency.max(1));
+        let builds = pairs.into_iter().map(|(tag, pair)| {
+            let (solver, semaphore) = (solver.clone(), &semaphore);
+            async move {
+                let book = match pair {
+                    Ok((pair, params)) => {
+                        let _permit = semaphore.acquire().await.expect("Semaphore closed");
+                        self.build_pair(solver, oracle, pair, params).await
                     }
+                    Err(e) => Err(e),
+                };
+                (tag, book)
+            }
+        });
+        futures::future::join_all(builds).await.into_iter().collect()
+    }
+
+    /// Builds the orderbook of a snapshotted pair (see get_orderbook)
+    async fn build_pair<S: OrderbookSolver>(&self, solver: S, oracle: &dyn PriceOracle, pair: PairSnapshot, params: OrderbookRequestParams) -> Result<Orderbook, OrderbookError> {
+        let (base_worth_eth, quote_worth_eth, valued) = pair.worths_eth(oracle, &self.network).await?;
+        let mut orderbook = book::build(solver, oracle, self.network.clone(), self.key.clone(), pair.pts, pair.block, pair.targets, params, base_worth_eth, quote_worth_eth, &self.balances)
+            .await
+            .map_err(|e| e.downcast::<OrderbookError>().unwrap_or_else(|e| OrderbookError::Build(e.to_string())))?;
+        orderbook.unsupported_pools = pair.unsupported;
+        (orderbook.base_valuation_ref, orderbook.quote_valuation_ref) = (pair.base_ref, pair.quote_ref);
+        if !valued {
+            orderbook.unvalued();
+        }
+        self.record_mid(&mut orderbook).await;
+        if let Some(alert) = self.assess(&orderbook, &self.quality).await {
+            if let Err(e) = self.sender.try_send(alert) {
+                tracing::warn!("Failed to send the quality alert of {}: {}", orderbook.tag, e);
+            }
+        }
+        Ok(orderbook)
+    }
+
+    /// Dry-run quote: output of selling 'amount' (human-readable) of 'input' for the other token of the pair (tag 'base-quote', with addresses) across all its pools
+    /// Runs a single optimization for that exact amount (see book::quote), without fetching the component balances nor building the bid/ask curves
+    /// Fails with OrderbookError::EthQuoteFailed if the pair tokens can't be valued in ETH, as the gas costs are deducted from the output in its worth
+    pub async fn quote(&self, oracle: &dyn PriceOracle, tag: &str, input: &str, amount: f64) -> Result<TradeResult, anyhow::Error> {
+        if amount <= 0. || !amount.is_finite() {
+            return Err(anyhow::anyhow!("Invalid amount to quote: {}", amount));
+        }
+        let params = OrderbookRequestParams {
+            tag: tag.to_lowercase(),
+            point: Some(SinglePointSimulation {
+                input: input.to_lowercase(),
+                amount,
+                amount_pct_of_liquidity: None,
+                denomination: None,
+            }),
+            ..Default::default()
+        };
+        let tokens = self.tokens.read().await.clone();
+        let pair = snapshot(&self.state, &self.routes, &tokens, &self.network, &params).await?;
+        let (base_worth_eth, quote_worth_eth, valued) = pair.worths_eth(oracle, &self.network).await?;
+        if !valued {
+            return Err(OrderbookError::EthQuoteFailed.into());
+        }
+        let PairSnapshot { pts, targets, block, .. } = pair;
+        let (from, to, output_worth_eth) = match targets.iter().position(|t| t.address.eq_ignore_ascii_case(input)) {
+            Some(0) => (&targets[0], &targets[1], quote_worth_eth),
+            Some(_) => (&targets[1], &targets[0], base_worth_eth),
+            None => return Err(anyhow::anyhow!("Input token {} is not part of pair {}", input, tag)),
+        };
+        let eth_usd = oracle.eth_usd(&self.network).await?;
+        let gas_price = gas::gas_price(self.network.rpc.clone()).await;
+        let context = MarketContext { block, eth_usd, gas_price };
+        Ok(book::quote(&context, &pts, from, to, amount, output_worth_eth))
+    }
+
+    /// Tag of the native/stable pair anchoring the valuations (wrapped native token - Network::stable), e.g. WETH-USDC on Ethereum
+    /// Errors if no stable is configured, or if it's not among the provider tokens
+    pub async fn valuation_anchor_tag(&self) -> Result<String, anyhow::Error> {
+        let stable = self.network.stable().ok_or_else(|| anyhow::anyhow!("No valuation anchor stable configured on {}", self.network.name))?;
+        if !self.tokens.read().await.iter().any(|t| t.address.eq_ignore_ascii_case(&stable)) {
+            return Err(anyhow::anyhow!("Valuation anchor stable {} not found on {}", stable, self.network.name));
+        }
+        Ok(format!("{}-{}", self.network.eth, stable).to_lowercase())
+    }
+
+    /// Compute the orderbook of the native/stable pair anchoring the valuations (see valuation_anchor_tag), so that its drift can be monitored
+    pub async fn valuation_anchor_book<S: OrderbookSolver>(&self, solver: S, oracle: &dyn PriceOracle) -> Result<Orderbook, anyhow::Error> {
+        let tag = self.valuation_anchor_tag().await?;
+        Ok(self.get_orderbook(solver, oracle, OrderbookRequestParams { tag, ..Default::default() }).await?)
+    }
+
+    /// Same as get_orderbook, restricted to the components of a single protocol system (e.g. uniswap_v3), to compare its depth with the one of all venues
+    pub async fn get_orderbook_by_protocol<S: OrderbookSolver>(&self, solver: S, oracle: &dyn PriceOracle, tag: &str, protocol: &str) -> Result<Orderbook, anyhow::Error> {
+        let params = OrderbookRequestParams {
+            tag: tag.to_string(),
+            protocols: vec![protocol.to_lowercase()],
+            ..Default::default()
+        };
+        Ok(self.get_orderbook(solver, oracle, params).await?)
+    }
+
+    /// Same as get_orderbook, but with the given component balances instead of fetching them from Tycho (component ID => token address => balance, in smallest unit)
+    /// Every component matching the pair must be in 'balances', else an error is returned.
+    pub async fn get_orderbook_with_balances<S: OrderbookSolver>(
+        &self,
+        solver: S,
+        oracle: &dyn PriceOracle,
+        params: OrderbookRequestParams,
+        balances: HashMap<String, HashMap<String, u128>>,
+    ) -> Result<Orderbook, anyhow::Error> {
+        let tokens = self.tokens.read().await.clone();
+        let pair = snapshot(&self.state, &self.routes, &tokens, &self.network, &params).await?;
+        let (base_worth_eth, quote_worth_eth, valued) = pair.worths_eth(oracle, &self.network).await?;
+        let PairSnapshot {
+            pts,
+            targets,
+            block,
+            unsupported,
+            base_ref,
+            quote_ref,
+            ..
+        } = pair;
+        let balances: HashMap<String, HashMap<String, u128>> = balances
+            .into_iter()
+            .map(|(id, tokens)| (id.to_lowercase(), tokens.into_iter().map(|(tk, bal)| (tk.to_lowercase(), bal)).collect()))
+            .collect();
+        let (eth_usd, eth_usd_stale) = oracle.eth_usd_checked(&self.network).await.map_err(|e| {
+            tracing::error!("Failed to fetch the ETH price on {}: {}", self.network.name, e);
+            OrderbookError::EthQuoteFailed
+        })?;
+        let gas_price = gas::gas_price(self.network.rpc.clone()).await;
+        let context = MarketContext { block, eth_usd, gas_price };
+        let mut orderbook = book::assemble(solver, self.network.clone(), context, pts, targets, params, &balances, base_worth_eth, quote_worth_eth).await?;
+        orderbook.eth_usd_stale = eth_usd_stale;
+        orderbook.unsupported_pools = unsupported;
+        (orderbook.base_valuation_ref, orderbook.quote_valuation_ref) = (base_ref, quote_ref);
+        if !valued {
+            orderbook.unvalued();
+        }
+        self.record_mid(&mut orderbook).await;
+        Ok(orderbook)
+    }
+
+    /// Hits and misses of the balance cache (see BalanceCache), for observability
+    pub async fn balance_cache_stats(&self) -> (u64, u64) {
+        let cache = self.balances.lock().await;
+        (cache.hits, cache.misses)
+    }
+
+    /// Appends the mid price of a computed book to the pair history (one per block, the last MID_HISTORY_SIZE kept), and sets the book volatility from it
+    pub async fn record_mid(&self, book: &mut Orderbook) {
+        let mut mids = self.mids.lock().await;
+        let history = mids.entry(book.tag.to_lowercase()).or_default();
+        let mid = book.mpd_base_to_quote.mid;
+        if mid > 0. && history.last().is_none_or(|(block, _)| *block < book.block) {
+            history.push((book.block, mid));
+            if history.len() > MID_HISTORY_SIZE {
+                history.remove(0);
+            }
+        }
+        book.volatility = maths::fill::volatility(history);
+    }
+}
+
+/// Dropping the OBP stops its stream task, instead of leaking it (see shutdown)
+impl<S: OrderbookSolver> Drop for OrderbookProvider<S> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+        self.task.abort();
+    }
+}
+
+/// Whether the component protocol type is handled by the SDK (see AmmType), unknown ones are skipped with a warning instead of breaking the ingestion
+fn supported(cp: &ProtocolComponent) -> bool {
+    let known = AmmType::from(cp.protocol_type_name.as_str()).is_known();
+    if !known {
+        tracing::warn!("Component {} skipped: unknown protocol type {} ({})", cp.id, cp.protocol_type_name, cp.protocol_system);
+    }
+    known
+}
+
+/// Adds the fetched tokens missing from the shared ones (by address), and emits an OrderbookEvent::TokensUpdated if any was added
+async fn merge_tokens(tokens: &SharedTokens, fetched: Vec<SrzToken>, sender: &mpsc::Sender<OrderbookEvent>) -> usize {
+    let mut mtx = tokens.write().await;
+    let mut known = mtx.iter().map(|tk| tk.address.to_lowercase()).collect::<HashSet<String>>();
+    let mut added = 0;
+    for token in fetched {
+        if known.insert(token.address.to_lowercase()) {
+            mtx.push(token);
+            added += 1;
+        }
+    }
+    drop(mtx);
+    if added > 0 {
+        tracing::info!("{} new tokens added to the provider", added);
+        let _ = sender.send(OrderbookEvent::TokensUpdated(added)).await;
+    }
+    added
+}
+
+/// Rebuilds the orderbook of the given subscribed pairs, and emits a DepthUpdate event with the depth levels that changed since the previous one
+#[allow(clippy::too_many_arguments)]
+async fn diff_subscriptions<S: OrderbookSolver + Clone>(
+    state: SharedTychoStreamState,
+    subscriptions: Subscriptions,
+    network: Network,
+    tokens: SharedTokens,
+    key: Option<String>,
+    solver: S,
+    tags: Vec<String>,
+    sender: mpsc::Sender<OrderbookEvent>,
+    balances: Arc<Mutex<BalanceCache>>,
+    routes: Arc<std::sync::Mutex<RouteCache>>,
+) {
+    for tag in tags {
+        let Some(subscription) = subscriptions.read().await.get(&tag).cloned() else {
+            continue;
+        };
+        let params = OrderbookRequestParams {
+            tag: tag.clone(),
+            ..Default::default()
+        };
+        let all_tokens = tokens.read().await.clone();
+        let built = match snapshot(&state, &routes, &all_tokens, &network, &params).await {
+            Ok(pair) => match pair.worths_eth(subscription.oracle.as_ref(), &network).await {
+                Ok((base_worth_eth, quote_worth_eth, valued)) => book::build(
+                    solver.clone(),
+                    subscription.oracle.as_ref(),
+                    network.clone(),
+                    key.clone(),
+                    pair.pts,
+                    pair.block,
+                    pair.targets,
+                    params,
+                    base_worth_eth,
+                    quote_worth_eth,
+                    &balances,
+                )
+                .await
+                .map(|mut book| {
+                    book.unsupported_pools = pair.unsupported;
+                    (book.base_valuation_ref, book.quote_valuation_ref) = (pair.base_ref, pair.quote_ref);
+                    if !valued {
+                        book.unvalued();
+                    }
+                    book
+                }),
+                Err(e) => Err(e.into()),
+            },
+            Err(e) => Err(e.into()),
+        };
+        match built {
+            Ok(orderbook) => {
+                let depth = orderbook.depth(None, subscription.bucketing.clone());
+                let previous = subscription.depth.clone().unwrap_or(OrderbookDepth {
+                    last_update_id: 0,
+                    bids: vec![],
+                    asks: vec![],
+                });
+                let (bid_changes, ask_changes) = depth_diff(&previous, &depth);
+                if let Some(sub) = subscriptions.write().await.get_mut(&tag) {
+                    sub.depth = Some(depth);
                 }
-                drop(mtx);
-                if pts.is_empty() {
-                    return Err(anyhow::anyhow!("No components found for the given pair"));
+                let event = OrderbookEvent::BookUpdate {
+                    tag: tag.clone(),
+                    book: Box::new(orderbook.clone()),
+                };
+                let _ = sender.send(event).await;
+                if !bid_changes.is_empty() || !ask_changes.is_empty() {
+                    let event = OrderbookEvent::DepthUpdate {
+                        tag: tag.clone(),
+                        block: orderbook.block,
+                        bid_changes,
+                        ask_changes,
+                    };
+                    let _ = sender.send(event).await;
                 }
-                tracing::debug!("Found {} components for the pair. Evaluation t0/t1 ETH value ...", pts.len());
-                let unit_base_eth_worth = maths::path::quote(to_eth_pts.clone(), all_tokens.clone(), base_to_eth.token_path.clone());
-                let unit_quote_eth_worth = maths::path::quote(to_eth_pts.clone(), all_tokens.clone(), quote_to_eth.token_path.clone());
-                match (unit_base_eth_worth, unit_quote_eth_worth) {
-                    (Some(unit_base_eth_worth), Some(unit_quote_eth_worth)) => {
-                        book::build(
-                            solver,
-                            self.network.clone(),
-                            self.key.clone(),
-                            pts.clone(),
-                            targets.clone(),
-                            params.clone(),
-                            unit_base_eth_worth,
-                            unit_quote_eth_worth,
-                        )
-                        .await
+            }
+            Err(e) => tracing::warn!("Failed to rebuild subscribed orderbook {}: {}", tag, e),
+        }
+    }
+}
+
+/// Subscribed pairs waiting for their depth to be diffed, queued by the stream task (see diff_worker)
+#[derive(Default)]
+struct DiffQueue {
+    tags: Mutex<HashSet<String>>,
+    notify: tokio::sync::Notify,
+}
+
+/// Diffs the subscribed pairs queued by the stream task, one pass at a time, until the provider is shut down
+/// Each pass builds the books at the latest block: pairs queued again during a pass are diffed once by the next one, skipping the blocks in between,
+/// so that a single diff per pair is in flight however slow the builds are compared to the block time
+#[allow(clippy::too_many_arguments)]
+async fn diff_worker<S: OrderbookSolver + Clone>(
+    state: SharedTychoStreamState,
+    subscriptions: Subscriptions,
+    network: Network,
+    tokens: SharedTokens,
+    key: Option<String>,
+    solver: S,
+    sender: mpsc::Sender<OrderbookEvent>,
+    balances: Arc<Mutex<BalanceCache>>,
+    routes: Arc<std::sync::Mutex<RouteCache>>,
+    queue: Arc<DiffQueue>,
+    cancel: CancellationToken,
+) {
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = queue.notify.notified() => {}
+        }
+        let tags = std::mem::take(&mut *queue.tags.lock().await).into_iter().collect::<Vec<String>>();
+        if tags.is_empty() {
+            continue;
+        }
+        let diff = diff_subscriptions(
+            state.clone(),
+            subscriptions.clone(),
+            network.clone(),
+            tokens.clone(),
+            key.clone(),
+            solver.clone(),
+            tags,
+            sender.clone(),
+            balances.clone(),
+            routes.clone(),
+        );
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = diff => {}
+        }
+    }
+    tracing::debug!("Depth diff task cancelled");
+}
+
+/// Components of a pair and their protosims, taken under a single read lock of the shared state (see snapshot)
+struct PairSnapshot {
+    pts: Vec<ProtoSimComp>,
+    /// Base and quote tokens
+    targets: Vec<SrzToken>,
+    block: u64,
+    /// Worth of one unit of base
+    base_worth: UnitWorth,
+    /// Worth of one unit of quote
+    quote_worth: UnitWorth,
+    /// Reference base was valued through, None if unvalued
+    base_ref: Option<String>,
+    /// Reference quote was valued through, None if unvalued
+    quote_ref: Option<String>,
+    /// IDs of the components matching the pair without protosim (protocol not registered), whose liquidity is omitted
+    unsupported: Vec<String>,
+}
+
+impl PairSnapshot {
+    /// ETH worth of one unit of base and quote, the USD valued ones being converted with the oracle ETH price
+    /// Unknown worths are set to 1, and the returned flag is false: the book can be built, but without meaningful USD figures (see Orderbook::unvalued)
+    async fn worths_eth(&self, oracle: &dyn PriceOracle, network: &Network) -> Result<(f64, f64, bool), OrderbookError> {
+        let eth_usd = match (self.base_worth, self.quote_worth) {
+            (UnitWorth::Usd(_), _) | (_, UnitWorth::Usd(_)) => oracle.eth_usd(network).await.map_err(|e| {
+                tracing::error!("Failed to fetch the ETH price on {}: {}", network.name, e);
+                OrderbookError::EthQuoteFailed
+            })?,
+            _ => 0.,
+        };
+        let eth = |worth: UnitWorth| match worth {
+            UnitWorth::Eth(worth) => Some(worth),
+            UnitWorth::Usd(worth) if eth_usd > 0. => Some(worth / eth_usd),
+            _ => None,
+        };
+        match (eth(self.base_worth), eth(self.quote_worth)) {
+            (Some(base), Some(quote)) => Ok((base, quote, true)),
+            _ => Ok((1., 1., false)),
+        }
+    }
+}
+
+/// Snapshots the components matching the requested pair and their protosims, with the block of the snapshot and the ETH worth of one unit of base and quote
+/// The state is only read-locked to clone the matching protosims, then for the valuation quotes (see value_pair), not during the route searches
+async fn snapshot(
+    state: &SharedTychoStreamState,
+    routes: &std::sync::Mutex<RouteCache>,
+    tokens: &[SrzToken],
+    network: &Network,
+    params: &OrderbookRequestParams,
+) -> Result<PairSnapshot, OrderbookError> {
+    let mtx = state.read().await; // Held until the matching protosims are cloned
+    let mut pair = snapshot_locked(&mtx, tokens, network, params)?;
+    drop(mtx);
+    value_pair(state, routes, tokens, network, &params.tag, &mut pair).await;
+    Ok(pair)
+}
+
+/// Components of the requested pair and their protosims from an already read-locked state, not valued yet (see value_pair)
+/// Many pairs can so be snapshotted at the same block (see OrderbookProvider::get_orderbooks)
+fn snapshot_locked(mtx: &TychoStreamState, tokens: &[SrzToken], network: &Network, params: &OrderbookRequestParams) -> Result<PairSnapshot, OrderbookError> {
+    let single = params.point.is_some();
+    if !mtx.initialised {
+        return Err(OrderbookError::StreamNotInitialised);
+    }
+    let comp = mtx.components.clone();
+    let block = mtx.block;
+    let acps = comp.iter().map(|x| SrzProtocolComponent::from(x.1.clone())).collect::<Vec<SrzProtocolComponent>>(); // Not efficient at all
+
+    // --- Check if the pair is valid ---
+    let (t0, t1) = parse_tag(&params.tag, tokens)?;
+    let targets = [t0, t1];
+    let all_tokens = tokens.to_vec();
+    let srzt0 = all_tokens
+        .iter()
+        .find(|x| x.address.to_lowercase() == targets[0].clone())
+        .ok_or_else(|| OrderbookError::TokenNotFound(targets[0].clone()));
+    let srzt1 = all_tokens
+        .iter()
+        .find(|x| x.address.to_lowercase() == targets[1].clone())
+        .ok_or_else(|| OrderbookError::TokenNotFound(targets[1].clone()));
+    let (srzt0, srzt1) = match (srzt0, srzt1) {
+        (Ok(t0), Ok(t1)) => (t0.clone(), t1.clone()),
+        (Err(e), _) => return Err(e),
+        (_, Err(e)) => return Err(e),
+    };
+
+    if book::is_wrapper_pair(network, &srzt0.address, &srzt1.address) {
+        return Err(OrderbookError::WrapperPair {
+            base: srzt0.symbol.clone(),
+            quote: srzt1.symbol.clone(),
+        });
+    }
+    let targets = vec![srzt0.clone(), srzt1.clone()];
+    tracing::debug!("Building orderbook for pair {}-{} | Single point: {}", targets[0].symbol.clone(), targets[1].symbol.clone(), single);
+    let mut pts: Vec<ProtoSimComp> = vec![];
+    let mut unsupported: Vec<String> = vec![];
+    for cp in acps.clone() {
+        if book::matchcp(cp.tokens.clone(), targets.clone()) {
+            if !params.protocols.is_empty() && !params.protocols.iter().any(|p| p.eq_ignore_ascii_case(&cp.protocol_system)) {
+                continue;
+            }
+            if params.executable_only && !protos::executable(&cp) {
+                tracing::info!("Component {} ({}) excluded from the {} orderbook: not executable by the router", cp.id, cp.protocol_system, params.tag);
+                continue;
+            }
+            match mtx.protosims.get(&cp.key()) {
+                Some(protosim) => pts.push(ProtoSimComp {
+                    component: cp.clone(),
+                    protosim: protosim.clone(),
+                }),
+                None => {
+                    tracing::warn!("Component {} ({}) matches pair {} but has no protosim (protocol not registered?), its liquidity is omitted", cp.id, cp.protocol_type_name, params.tag);
+                    unsupported.push(cp.id.clone());
+                }
+            }
+        }
+    }
+    if pts.is_empty() {
+        return Err(OrderbookError::NoComponents);
+    }
+    tracing::debug!("Found {} components for the pair", pts.len());
+    Ok(PairSnapshot {
+        base_worth: UnitWorth::Unknown,
+        quote_worth: UnitWorth::Unknown,
+        base_ref: None,
+        quote_ref: None,
+        pts,
+        targets,
+        block,
+        unsupported,
+    })
+}
+
+/// Values the base and quote of a pair, each through the first reference it has a route and a valid quote to (see Network::valuation_refs)
+/// Routes only change with the component set, so they are searched once per version of it (see RouteCache). The state is read-locked for the quotes only:
+/// a route missing from the cache stops the valuation, the lock is released while it is searched, then the valuation is retried with it.
+async fn value_pair(state: &SharedTychoStreamState, routes: &std::sync::Mutex<RouteCache>, tokens: &[SrzToken], network: &Network, tag: &str, pair: &mut PairSnapshot) {
+    let stable = network.stable();
+    let refs = network.valuation_refs();
+    let mut searched: HashMap<String, ValorisationPath> = HashMap::new();
+    let mut failed: HashSet<String> = HashSet::new();
+    loop {
+        let mtx = state.read().await;
+        let mut missing: Option<(String, String)> = None;
+        let mut quote = |from: &str, to: &str| -> Option<f64> {
+            let key = format!("{}>{}", from, to);
+            if missing.is_some() || failed.contains(&key) {
+                return None;
+            }
+            let cached = searched.get(&key).cloned().or_else(|| routes.lock().unwrap().get(mtx.version, &key));
+            let Some(path) = cached else {
+                missing = Some((from.to_string(), to.to_string()));
+                return None;
+            };
+            let pts = path
+                .comp_path
+                .iter()
+                .filter_map(|key| {
+                    let component = SrzProtocolComponent::from(mtx.components.get(key)?.clone());
+                    mtx.protosims.get(key).map(|protosim| ProtoSimComp { component, protosim: protosim.clone() })
+                })
+                .collect::<Vec<ProtoSimComp>>();
+            maths::path::quote(pts, tokens.to_vec(), path.token_path)
+        };
+        let mut value = |token: &SrzToken| maths::path::valuation(&token.address.to_lowercase(), &refs, &network.eth.to_lowercase(), stable.as_deref(), &mut quote);
+        let (base_valued, quote_valued) = (value(&pair.targets[0]), value(&pair.targets[1]));
+        let Some((from, to)) = missing else {
+            drop(mtx);
+            for (token, valued, worth, reference) in [(&pair.targets[0], base_valued, &mut pair.base_worth, &mut pair.base_ref), (&pair.targets[1], quote_valued, &mut pair.quote_worth, &mut pair.quote_ref)] {
+                match valued {
+                    Some((valued_ref, valued_worth)) => {
+                        *reference = Some(valued_ref);
+                        *worth = valued_worth;
                     }
-                    _ => Err(anyhow::anyhow!("Failed to quote the pair in ETH")),
+                    None => tracing::warn!("Failed to value {} through any of {:?}, the {} orderbook won't have USD figures", token.symbol, refs, tag),
                 }
             }
-            (Err(e), _) => Err(anyhow::anyhow!(e)),
-            (_, Err(e)) => Err(anyhow::anyhow!(e)),
+            return;
+        };
+        let (version, acps) = (mtx.version, mtx.components.values().map(|cp| SrzProtocolComponent::from(cp.clone())).collect::<Vec<SrzProtocolComponent>>());
+        drop(mtx);
+        let key = format!("{}>{}", from, to);
+        match maths::path::routing(acps, from.to_lowercase(), to.to_lowercase()) {
+            Ok(path) => {
+                routes.lock().unwrap().insert(version, &key, path.clone());
+                searched.insert(key, path);
+            }
+            Err(_) => {
+                failed.insert(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::oracle::FixedOracle;
+    use crate::utils::fixtures::{self, DAI, USDC, WETH};
+
+    fn state() -> TychoStreamState {
+        TychoStreamState {
+            protosims: HashMap::new(),
+            components: HashMap::new(),
+            keys: HashMap::new(),
+            initialised: false,
+            block: 0,
+            updated_at: HashMap::new(),
+            last_updated: vec![],
+            deferred: HashMap::new(),
+            whitelist: vec![],
+            version: 0,
+            received_at_ms: 0,
+            confirmations: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn test_readiness() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let mut state = state();
+        // Still syncing
+        assert!(!state.ready(current_timestamp_ms(), 12_000, 5));
+        state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone()]));
+        let received = state.received_at_ms;
+        assert!(received > 0);
+        assert!(state.ready(received + 60_000, 12_000, 5));
+        // Stalled stream: no block for more than 5 block times
+        assert!(!state.ready(received + 60_001, 12_000, 5));
+        state.apply(&fixtures::update(2, &pools, &[]));
+        assert!(state.received_at_ms >= received);
+    }
+
+    #[test]
+    fn test_block_confirmations() {
+        let pool = |usdc: f64| vec![fixtures::pool("0x01", 1_000., usdc)];
+        let mut state = TychoStreamState { confirmations: 2, ..state() };
+        state.initialise(&fixtures::update(10, &pool(2_000_000.), &[pool(2_000_000.)[0].component.clone()]));
+        let feed = |state: &mut TychoStreamState, block: u64, usdc: f64| state.confirm(fixtures::update(block, &pool(usdc), &[])).into_iter().map(|msg| state.apply(&msg).len()).sum::<usize>();
+        // Blocks N and N+1 wait for their confirmations
+        assert_eq!(feed(&mut state, 11, 2_100_000.), 0);
+        assert_eq!(feed(&mut state, 12, 2_200_000.), 0);
+        assert_eq!(state.block, 10);
+        // Block N+1 is reorged out before being confirmed
+        assert_eq!(feed(&mut state, 12, 2_300_000.), 0);
+        assert_eq!(state.pending.iter().map(|msg| msg.block_number).collect::<Vec<u64>>(), vec![11, 12]);
+        // N+2 confirms N only
+        assert_eq!(feed(&mut state, 13, 2_400_000.), 1);
+        assert_eq!(state.block, 11);
+        assert_eq!(state.pending.len(), 2);
+        // Without confirmations, applied at once
+        state.confirmations = 0;
+        assert_eq!(feed(&mut state, 14, 2_500_000.), 3);
+        assert_eq!(state.block, 14);
+        assert!(state.pending.is_empty());
+    }
+
+    /// Serves doubled USDC reserves for every component but 0x02
+    struct ReservesSource;
+
+    #[async_trait::async_trait]
+    impl ComponentStateSource for ReservesSource {
+        async fn state(&self, _network: &Network, id: &str, _protocol_system: &str) -> Result<Option<tycho_common::dto::ResponseProtocolState>, anyhow::Error> {
+            let reserve = |amount: u128| tycho_common::Bytes::from(alloy::primitives::U256::from(amount).to_be_bytes::<32>().to_vec());
+            Ok((id != "0x02").then(|| tycho_common::dto::ResponseProtocolState {
+                component_id: id.to_string(),
+                attributes: HashMap::from([("reserve0".to_string(), reserve(4_000_000 * 10u128.pow(6))), ("reserve1".to_string(), reserve(1_000 * 10u128.pow(18)))]),
+                balances: HashMap::new(),
+            }))
         }
     }
+
+    /// RPC and Tycho both unreachable
+    struct Unreachable;
+
+    #[async_trait::async_trait]
+    impl CodeReader for Unreachable {
+        async fn code(&self, _address: &str) -> Result<Vec<u8>, anyhow::Error> {
+            Err(anyhow::anyhow!("RPC unreachable"))
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ComponentStateSource for Unreachable {
+        async fn state(&self, _network: &Network, _id: &str, _protocol_system: &str) -> Result<Option<tycho_common::dto::ResponseProtocolState>, anyhow::Error> {
+            Err(anyhow::anyhow!("Tycho unreachable"))
+        }
+    }
+
+    /// Bytecode deployed at every address
+    struct Deployed;
+
+    #[async_trait::async_trait]
+    impl CodeReader for Deployed {
+        async fn code(&self, _address: &str) -> Result<Vec<u8>, anyhow::Error> {
+            Ok(vec![0x60, 0x80])
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_component_onchain() {
+        let pair = "0xb4e16d0168e52d35cacd2c6185b44281ec28c9dc";
+        let pools = vec![fixtures::pool(pair, 1_000., 2_000_000.), fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 1_000., 2_000_000.)];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &pools.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>()));
+        let network = Network {
+            name: "ethereum".to_string(),
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(StateSnapshot { network, ..Default::default() }, None, DefaultOrderbookSolver::default()).await.unwrap();
+        *provider.state.write().await = state;
+        // By address: bytecode on the RPC
+        assert!(provider.verify_component_onchain_from(&Deployed, &Unreachable, pair).await.unwrap());
+        assert!(provider.verify_component_onchain_from(&Unreachable, &ReservesSource, pair).await.is_err());
+        // By ID: state on Tycho (none for 0x02)
+        assert!(provider.verify_component_onchain_from(&Unreachable, &ReservesSource, "0x01").await.unwrap());
+        assert!(!provider.verify_component_onchain_from(&Unreachable, &ReservesSource, "0x02").await.unwrap());
+        assert!(provider.verify_component_onchain_from(&Deployed, &Unreachable, "0x01").await.is_err());
+        assert!(provider.verify_component_onchain_from(&Deployed, &ReservesSource, "0x03").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_stale() {
+        let mut v3 = fixtures::pool("0x03", 1_000., 2_000_000.);
+        v3.component = fixtures::component("0x03", "uniswap_v3", vec![fixtures::usdc(), fixtures::weth()], 5);
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 1_000., 2_000_000.), v3];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &pools.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>()));
+        let fresh = fixtures::pool("0x04", 1_000., 2_000_000.);
+        state.apply(&fixtures::update(10, &[fresh.clone()], &[fresh.component.clone()]));
+        let network = Network {
+            name: "ethereum".to_string(),
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(StateSnapshot { network, ..Default::default() }, None, DefaultOrderbookSolver::default()).await.unwrap();
+        *provider.state.write().await = state;
+        let before = provider.state.read().await.spot_price("0x01", WETH, USDC).unwrap();
+        // 0x02 has no state at the source, and the v3 pool 0x03 can't be rebuilt from its attributes
+        assert_eq!(provider.refresh_stale_from(&ReservesSource, 5).await, vec!["uniswap_v2:0x01".to_string()]);
+        let mtx = provider.state.read().await;
+        assert_eq!(mtx.updated_at["uniswap_v2:0x01"], 10);
+        assert!((mtx.spot_price("0x01", WETH, USDC).unwrap() / before - 2.).abs() < 1e-6);
+        assert_eq!(mtx.updated_at["uniswap_v2:0x02"], 1);
+        assert_eq!(mtx.updated_at["uniswap_v3:0x03"], 1);
+    }
+
+    #[test]
+    fn test_pair_updates() {
+        let dai = fixtures::dai();
+        let (p1, p2) = (fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.));
+        let p3 = ProtoSimComp {
+            component: fixtures::component("0x03", "uniswap_v2", vec![fixtures::weth(), dai], 30),
+            protosim: p1.protosim.clone(),
+        };
+        let all = vec![p1.clone(), p2, p3.clone()];
+        let components = all.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &all, &components));
+        assert!(state.pair_updates(&format!("{}-{}", WETH, USDC)).is_empty());
+        let updated = state.apply(&fixtures::update(2, &[p1.clone(), p3], &[]));
+        assert_eq!(updated.len(), 2);
+        assert_eq!(state.pair_updates(&format!("{}-{}", WETH, USDC)), vec![p1.component.key()]);
+        assert_eq!(state.pair_updates(&format!("{}-{}", USDC, WETH)), vec![p1.component.key()]);
+    }
+
+    #[test]
+    fn test_initialise_capped() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.), fixtures::pool("0x03", 10., 20_000.)];
+        let components = pools.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let tvl = HashMap::from([("0x01".to_string(), 4e6), ("0x02".to_string(), 2e6), ("0x03".to_string(), 4e4)]);
+        let mut state = state();
+        state.initialise_capped(&fixtures::update(1, &pools, &components), Some(2), &tvl);
+        assert_eq!(state.protosims.len(), 2);
+        assert!(state.components.contains_key(&pools[0].component.key()));
+        assert!(state.components.contains_key(&pools[1].component.key()));
+        assert!(state.deferred.contains_key("0x03"));
+        // The deferred component joins the state on its next update
+        let updated = state.apply(&fixtures::update(2, &[pools[2].clone()], &[]));
+        assert_eq!(updated, vec![pools[2].component.key()]);
+        assert!(state.components.contains_key(&pools[2].component.key()));
+        assert!(state.deferred.is_empty());
+    }
+
+    #[test]
+    fn test_reorg_remove_and_readd() {
+        let (old, newer) = (fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x01", 1_000., 2_100_000.));
+        let key = old.component.key();
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &[old.clone()], &[old.component.clone()]));
+        // Removed then re-added within the same update: the newer state is kept
+        let mut msg = fixtures::update(2, &[newer.clone()], &[newer.component.clone()]);
+        let original = msg.new_pairs.values().next().unwrap().clone();
+        msg.removed_pairs.insert("0x01".to_string(), original.clone());
+        assert_eq!(state.apply(&msg), vec![key.clone()]);
+        assert!(state.components.contains_key(&key));
+        let price = state.spot_price("0x01", WETH, USDC).unwrap();
+        assert!((price - 2_100.).abs() / 2_100. < 0.01);
+        // Removed alone: the protosim is dropped
+        let mut msg = fixtures::update(3, &[], &[]);
+        msg.removed_pairs.insert("0x01".to_string(), original.clone());
+        state.apply(&msg);
+        assert!(!state.protosims.contains_key(&key) && !state.components.contains_key(&key));
+        // Re-added by a later block: overwritten cleanly
+        state.apply(&fixtures::update(4, &[old.clone()], &[old.component.clone()]));
+        assert_eq!(state.updated_at[&key], 4);
+        assert!(state.spot_price("0x01", WETH, USDC).unwrap() < price);
+    }
+
+    #[test]
+    fn test_shared_component_id() {
+        let v2 = fixtures::pool("0x01", 1_000., 2_000_000.);
+        let mut sushi = fixtures::pool("0x01", 1_000., 2_100_000.);
+        sushi.component = fixtures::component("0x01", "sushiswap_v2", vec![fixtures::usdc(), fixtures::weth()], 30);
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &[v2.clone()], &[v2.component.clone()]));
+        // Same ID on another protocol: both components are kept
+        let msg = fixtures::update(2, &[sushi.clone()], &[sushi.component.clone()]);
+        let original = msg.new_pairs.values().next().unwrap().clone();
+        state.apply(&msg);
+        assert_eq!(state.keys_of("0x01"), vec![v2.component.key(), sushi.component.key()]);
+        assert!(state.protosims.contains_key(&v2.component.key()) && state.protosims.contains_key(&sushi.component.key()));
+        // Removing one of them keeps the other indexed
+        let mut msg = fixtures::update(3, &[], &[]);
+        msg.removed_pairs.insert("0x01".to_string(), original);
+        state.apply(&msg);
+        assert_eq!(state.keys_of("0x01"), vec![v2.component.key()]);
+        assert_eq!(state.apply(&fixtures::update(4, &[v2.clone()], &[])), vec![v2.component.key()]);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let snapshot = StateSnapshot {
+            network: Network {
+                name: "ethereum".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(snapshot.clone(), None, DefaultOrderbookSolver::default()).await.unwrap();
+        let task = provider.task.abort_handle();
+        let refresh = provider.refresh_tokens_every(std::time::Duration::from_secs(3_600));
+        tokio::task::yield_now().await;
+        assert!(provider.is_running() && !refresh.is_finished());
+        drop(provider);
+        let terminated = async {
+            while !task.is_finished() || !refresh.is_finished() {
+                tokio::task::yield_now().await;
+            }
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(1), terminated).await.unwrap();
+        // Explicit shutdown: the already sent events are still received, then the channel is closed
+        let provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        tokio::task::yield_now().await;
+        provider.shutdown().await;
+        assert!(provider.cancel.is_cancelled());
+        assert!(matches!(provider.stream.lock().await.recv().await, Some(OrderbookEvent::Initialised(_))));
+        assert!(provider.stream.lock().await.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscriptions() {
+        let snapshot = StateSnapshot {
+            network: Network {
+                name: "ethereum".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        let tag = format!("{}-{}", WETH, USDC);
+        let oracle: Arc<dyn PriceOracle> = Arc::new(crate::core::oracle::FixedOracle(2_000.));
+        provider.subscribe(&tag, oracle.clone(), None).await;
+        provider.subscribe(&tag.to_uppercase(), oracle, None).await;
+        assert_eq!(provider.subscriptions.read().await[&tag].subscribers, 2);
+        provider.unsubscribe(&tag).await;
+        assert!(provider.subscriptions.read().await.contains_key(&tag));
+        provider.unsubscribe(&tag).await;
+        assert!(provider.subscriptions.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_depth_unknown_pair() {
+        let snapshot = StateSnapshot {
+            network: Network {
+                name: "ethereum".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        let error = provider.get_depth(&crate::core::oracle::FixedOracle(2_000.), &format!("{}-{}", WETH, USDC), None).await.unwrap_err();
+        assert_eq!(error.code, INVALID_SYMBOL_CODE);
+    }
+
+    #[tokio::test]
+    async fn test_get_orderbook_before_initialisation() {
+        let snapshot = StateSnapshot {
+            network: Network {
+                name: "ethereum".to_string(),
+                eth: WETH.to_string(),
+                ..Default::default()
+            },
+            tokens: vec![fixtures::weth(), fixtures::usdc()],
+            ..Default::default()
+        };
+        let mut provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        assert!(provider.is_initialised().await);
+        // As a provider streaming from Tycho, before its first message
+        provider.state = Arc::new(RwLock::new(TychoStreamState::default()));
+        assert!(!provider.is_initialised().await);
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let error = provider.get_orderbook(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), params).await.err();
+        assert_eq!(error, Some(OrderbookError::StreamNotInitialised));
+    }
+
+    #[tokio::test]
+    async fn test_get_orderbooks_concurrently() {
+        let dai = SrzToken { decimals: 6, ..fixtures::dai() }; // Same as USDC, for the fixture reserves
+        let mut weth_dai = fixtures::pool("0x02", 1_000., 2_000_000.);
+        weth_dai.component.tokens = vec![dai.clone(), fixtures::weth()];
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), weth_dai];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &pools.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>()));
+        let network = fixtures::offline_network();
+        let tokens = [fixtures::weth(), fixtures::usdc(), dai.clone()];
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &tokens), None, DefaultOrderbookSolver::default()).await.unwrap();
+        let balances = |token: &str| HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (token.to_string(), 2_000_000 * 10u128.pow(6))]);
+        *provider.balances.lock().await = BalanceCache {
+            block: 1,
+            entries: HashMap::from([(pools[0].component.key(), balances(USDC)), (pools[1].component.key(), balances(&dai.address))]),
+            ..Default::default()
+        };
+        let tags = vec![format!("{}-{}", WETH, USDC), format!("{}-{}", WETH, dai.address), "invalid".to_string()];
+        let books = provider.get_orderbooks(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), tags.clone(), 2).await;
+        assert_eq!(books.len(), 3);
+        for tag in tags[..2].iter() {
+            let book = books[tag].as_ref().unwrap();
+            assert_eq!(book.block, 1);
+            assert!(!book.bids.is_empty() && !book.asks.is_empty());
+        }
+        assert_eq!(books["invalid"].as_ref().err(), Some(&OrderbookError::InvalidPair));
+        assert_eq!(provider.balance_cache_stats().await, (2, 0));
+        // Two concurrent builds of the same pair both read the cache, without holding it during their fetches
+        let params = OrderbookRequestParams { tag: tags[0].clone(), ..Default::default() };
+        let (first, second) = tokio::join!(
+            provider.get_orderbook(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), params.clone()),
+            provider.get_orderbook(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), params)
+        );
+        assert_eq!(first.unwrap().bids.len(), second.unwrap().bids.len());
+        assert_eq!(provider.balance_cache_stats().await, (4, 0));
+    }
+
+    /// Counts the balance fetches of each component, and returns the same balances for any of them
+    #[derive(Default)]
+    struct CountingBalanceSource(std::sync::Mutex<HashMap<String, usize>>);
+
+    #[async_trait::async_trait]
+    impl BalanceSource for CountingBalanceSource {
+        async fn balances(&self, _network: &Network, id: &str, _protosys: &str, _block: u64) -> Option<HashMap<String, u128>> {
+            *self.0.lock().unwrap().entry(id.to_lowercase()).or_default() += 1;
+            let balance = 1_000 * 10u128.pow(18);
+            Some(HashMap::from([(WETH.to_string(), balance), (USDC.to_string(), balance), (DAI.to_string(), balance)]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_orderbooks_shared_pools() {
+        let dai = SrzToken { decimals: 6, ..fixtures::dai() };
+        let mut weth_dai = fixtures::pool("0x02", 1_000., 2_000_000.);
+        weth_dai.component.tokens = vec![dai.clone(), fixtures::weth()];
+        // Three-token pool, part of both pairs
+        let mut tri = fixtures::pool("0x03", 1_000., 2_000_000.);
+        tri.component.tokens = vec![fixtures::usdc(), fixtures::weth(), dai.clone()];
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.), weth_dai, tri];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &pools.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>()));
+        let network = fixtures::offline_network();
+        let tokens = [fixtures::weth(), fixtures::usdc(), dai.clone()];
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &tokens), None, DefaultOrderbookSolver::default()).await.unwrap();
+        let source = CountingBalanceSource::default();
+        let tags = vec![format!("{}-{}", WETH, USDC), format!("{}-{}", WETH, dai.address)];
+        let books = provider.get_orderbooks_from(&source, DefaultOrderbookSolver::default(), &FixedOracle(2_000.), tags, 2).await;
+        assert_eq!(books.len(), 2);
+        // One fetch per pool, the shared one included, and the builds read them all from the cache
+        assert_eq!(*source.0.lock().unwrap(), HashMap::from([("0x01".to_string(), 1), ("0x02".to_string(), 1), ("0x03".to_string(), 1)]));
+        assert_eq!(provider.balance_cache_stats().await, (4, 3));
+    }
+
+    /// Advances the stream state by one block at each balance fetch, as if blocks were received mid-build
+    struct AdvancingBalanceSource(SharedTychoStreamState);
+
+    #[async_trait::async_trait]
+    impl BalanceSource for AdvancingBalanceSource {
+        async fn balances(&self, _network: &Network, _id: &str, _protosys: &str, _block: u64) -> Option<HashMap<String, u128>> {
+            self.0.write().await.block += 1;
+            Some(HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (USDC.to_string(), 2_000_000 * 10u128.pow(6))]))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_orderbook_reports_snapshot_block() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone()]));
+        let network = fixtures::offline_network();
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &[fixtures::weth(), fixtures::usdc()]), None, DefaultOrderbookSolver::default()).await.unwrap();
+        let source = AdvancingBalanceSource(provider.state.clone());
+        let tag = format!("{}-{}", WETH, USDC);
+        let books = provider.get_orderbooks_from(&source, DefaultOrderbookSolver::default(), &FixedOracle(2_000.), vec![tag.clone()], 1).await;
+        // The book is the one of the snapshot block, not of the block reached by the stream during the build
+        assert!(provider.state.read().await.block > 1);
+        let book = books[&tag].as_ref().unwrap();
+        assert_eq!(book.block, 1);
+        assert!(!book.bids.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_orderbook_served_from_cache() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone()]));
+        let network = fixtures::offline_network();
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &[fixtures::weth(), fixtures::usdc()]), None, DefaultOrderbookSolver::default()).await.unwrap();
+        *provider.balances.lock().await = BalanceCache {
+            block: 1,
+            entries: HashMap::from([(pools[0].component.key(), HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (USDC.to_string(), 2_000_000 * 10u128.pow(6))]))]),
+            ..Default::default()
+        };
+        let store = Arc::new(fixtures::CountingStore::default());
+        let cache = OrderbookCache::new(store.clone(), &network, 1);
+        let params = |tag: String| OrderbookRequestParams { tag, ..Default::default() };
+        let built = provider.get_orderbook_cached(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), params(format!("{}-{}", WETH, USDC)), &cache).await.unwrap();
+        // Same pair by symbols, at the same block
+        let cached = provider.get_orderbook_cached(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), params("WETH-USDC".to_string()), &cache).await.unwrap();
+        assert_eq!((store.writes.load(std::sync::atomic::Ordering::SeqCst), store.hits.load(std::sync::atomic::Ordering::SeqCst)), (1, 1));
+        assert_eq!((cached.tag, cached.block, cached.bids.len()), (built.tag, built.block, built.bids.len()));
+        // Options are not part of the key: always built
+        let padded = OrderbookRequestParams { pad_levels: true, ..params(format!("{}-{}", WETH, USDC)) };
+        provider.get_orderbook_cached(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), padded, &cache).await.unwrap();
+        assert_eq!(store.hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_orderbook_eth_quote_failed() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone()]));
+        // No Chainlink feed: the oracle fails once the balances are read from the cache
+        let network = Network {
+            chainlink: crate::utils::r#static::filter::NULL_ADDRESS.to_string(),
+            ..fixtures::offline_network()
+        };
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &[fixtures::weth(), fixtures::usdc()]), None, DefaultOrderbookSolver::default()).await.unwrap();
+        *provider.balances.lock().await = BalanceCache {
+            block: 1,
+            entries: HashMap::from([(pools[0].component.key(), HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (USDC.to_string(), 2_000_000 * 10u128.pow(6))]))]),
+            ..Default::default()
+        };
+        let params = OrderbookRequestParams { tag: format!("{}-{}", WETH, USDC), ..Default::default() };
+        let error = provider.get_orderbook(DefaultOrderbookSolver::default(), &crate::core::oracle::ChainlinkOracle, params).await.err();
+        assert_eq!(error, Some(OrderbookError::EthQuoteFailed));
+    }
+
+    #[tokio::test]
+    async fn test_quality_alert() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone()]));
+        let network = fixtures::offline_network();
+        let mut provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &[fixtures::weth(), fixtures::usdc()]), None, DefaultOrderbookSolver::default()).await.unwrap();
+        *provider.balances.lock().await = BalanceCache {
+            block: 1,
+            entries: HashMap::from([(pools[0].component.key(), HashMap::from([(WETH.to_string(), 1_000 * 10u128.pow(18)), (USDC.to_string(), 2_000_000 * 10u128.pow(6))]))]),
+            ..Default::default()
+        };
+        // Far more depth required than the pool holds: the book is thin
+        provider.quality = BookQualityConfig { min_depth_usd: 1e12, ..Default::default() };
+        let params = OrderbookRequestParams { tag: format!("{}-{}", WETH, USDC), ..Default::default() };
+        provider.get_orderbook(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), params.clone()).await.unwrap();
+        match provider.stream.lock().await.try_recv() {
+            Ok(OrderbookEvent::QualityAlert { quality, .. }) => assert_eq!(quality, BookQuality::Thin),
+            _ => panic!("Expected a quality alert"),
+        }
+        // Still thin: alerted once per transition
+        provider.get_orderbook(DefaultOrderbookSolver::default(), &FixedOracle(2_000.), params).await.unwrap();
+        assert!(provider.stream.lock().await.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_merge_tokens() {
+        let snapshot = StateSnapshot {
+            network: Network {
+                name: "ethereum".to_string(),
+                ..Default::default()
+            },
+            tokens: vec![fixtures::weth()],
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        let mut weth = fixtures::weth();
+        weth.address = weth.address.to_uppercase();
+        assert_eq!(provider.merge_tokens(vec![weth, fixtures::usdc()]).await, 1);
+        assert_eq!(provider.tokens.read().await.len(), 2);
+        assert_eq!(provider.merge_tokens(vec![fixtures::usdc()]).await, 0);
+        let mut stream = provider.stream.lock().await;
+        assert!(matches!(stream.recv().await, Some(OrderbookEvent::Initialised(0))));
+        assert!(matches!(stream.recv().await, Some(OrderbookEvent::TokensUpdated(1))));
+    }
+
+    #[tokio::test]
+    async fn test_exchange_info() {
+        let dai = fixtures::dai();
+        let (p1, p2) = (fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.));
+        let p3 = ProtoSimComp {
+            component: fixtures::component("0x03", "uniswap_v2", vec![fixtures::weth(), dai], 30),
+            protosim: p1.protosim.clone(),
+        };
+        let all = vec![p1, p2, p3];
+        let components = all.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &all, &components));
+        let pairs = state.pairs();
+        assert_eq!(pairs.len(), 2);
+        let network = Network {
+            name: "ethereum".to_string(),
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &[]), None, DefaultOrderbookSolver::default()).await.unwrap();
+        let infos = provider.exchange_info(Some(&format!("{}-{}", WETH, USDC.to_uppercase())), 0, 0).await;
+        assert_eq!(infos.len(), 1);
+        assert_eq!((infos[0].base.symbol.as_str(), infos[0].quote.symbol.as_str()), ("USDC", "WETH"));
+        assert_eq!(infos[0].components.len(), 2);
+        assert_eq!(infos[0].order_types, vec!["MARKET".to_string()]);
+        assert_eq!(provider.exchange_info(None, 1, 10).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_pairs() {
+        let dai = fixtures::dai();
+        let (p1, p2) = (fixtures::pool("0x01", 1_000., 2_000_000.), fixtures::pool("0x02", 500., 1_000_000.));
+        // Same pair with the tokens in the other order
+        let p3 = ProtoSimComp {
+            component: fixtures::component("0x03", "uniswap_v2", vec![fixtures::weth(), fixtures::usdc()], 5),
+            protosim: p1.protosim.clone(),
+        };
+        let p4 = ProtoSimComp {
+            component: fixtures::component("0x04", "uniswap_v2", vec![fixtures::weth(), dai.clone()], 30),
+            protosim: p1.protosim.clone(),
+        };
+        let all = vec![p1, p2, p3, p4];
+        let components = all.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &all, &components));
+        let network = Network {
+            name: "ethereum".to_string(),
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &[]), None, DefaultOrderbookSolver::default()).await.unwrap();
+        let pairs = provider.list_pairs().await;
+        assert_eq!(pairs, vec![(USDC.to_string(), WETH.to_string(), 3), (dai.address.clone(), WETH.to_string(), 1)]);
+    }
+
+    #[test]
+    fn test_spot_price() {
+        let pool = fixtures::pool("0x01", 1_000., 2_000_000.);
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &[pool.clone()], &[pool.component.clone()]));
+        let price = state.spot_price("0x01", WETH, USDC).unwrap();
+        assert!((price - 2_000.).abs() / 2_000. < 0.01);
+        let inverse = state.spot_price("0X01", USDC, WETH).unwrap();
+        assert!((price * inverse - 1.).abs() < 0.01);
+        assert!(state.spot_price("0x02", WETH, USDC).is_err());
+        assert!(state.spot_price("0x01", WETH, DAI).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_valuation_anchor_tag() {
+        let network = Network {
+            name: "ethereum".to_string(),
+            eth: WETH.to_uppercase(),
+            stable: USDC.to_uppercase(),
+            ..Default::default()
+        };
+        // A token claiming the USDC symbol is never taken as the anchor
+        let spoof = SrzToken {
+            address: "0x1111111111111111111111111111111111111111".to_string(),
+            ..fixtures::usdc()
+        };
+        let snapshot = StateSnapshot {
+            network: network.clone(),
+            tokens: vec![fixtures::weth(), spoof.clone(), fixtures::usdc()],
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        assert_eq!(provider.valuation_anchor_tag().await.unwrap(), format!("{}-{}", WETH, USDC));
+        let snapshot = StateSnapshot {
+            network: network.clone(),
+            tokens: vec![fixtures::weth(), spoof],
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        assert!(provider.valuation_anchor_tag().await.is_err());
+        let snapshot = StateSnapshot {
+            network: Network { stable: String::new(), ..network },
+            tokens: vec![fixtures::weth(), fixtures::usdc()],
+            ..Default::default()
+        };
+        let provider = OrderbookProvider::from_snapshot(snapshot, None, DefaultOrderbookSolver::default()).await.unwrap();
+        assert!(provider.valuation_anchor_tag().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_single_protocol_book() {
+        let v3 = |id: &str, weth: f64, usdc: f64| {
+            let mut pool = fixtures::pool(id, weth, usdc);
+            pool.component.protocol_system = "uniswap_v3".to_string();
+            pool
+        };
+        let all = vec![fixtures::pool("0x01", 5_000., 10_000_000.), v3("0x02", 1_000., 2_000_000.), v3("0x03", 500., 1_000_000.)];
+        let components = all.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &all, &components));
+        let shared = Arc::new(RwLock::new(state));
+        let network = Network {
+            name: "ethereum".to_string(),
+            eth: WETH.to_string(),
+            ..Default::default()
+        };
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            protocols: vec!["uniswap_v3".to_string()],
+            ..Default::default()
+        };
+        let pair = snapshot(&shared, &std::sync::Mutex::new(RouteCache::default()), &[fixtures::weth(), fixtures::usdc()], &network, &params).await.unwrap();
+        let (base_worth_eth, quote_worth_eth, valued) = pair.worths_eth(&FixedOracle(2_000.), &network).await.unwrap();
+        assert!(valued);
+        let PairSnapshot { pts, targets, block, .. } = pair;
+        assert_eq!(pts.len(), 2);
+        let balances = [("0x01", 5_000., 10_000_000.), ("0x02", 1_000., 2_000_000.), ("0x03", 500., 1_000_000.)]
+            .iter()
+            .map(|(id, weth, usdc)| (id.to_string(), HashMap::from([(WETH.to_string(), (weth * 1e18) as u128), (USDC.to_string(), (usdc * 1e6) as u128)])))
+            .collect::<HashMap<_, _>>();
+        let context = MarketContext {
+            block,
+            eth_usd: 2_000.,
+            gas_price: 1_000_000_000,
+        };
+        let book = book::assemble(DefaultOrderbookSolver::default(), network, context, pts, targets, params, &balances, base_worth_eth, quote_worth_eth).await.unwrap();
+        assert!(book.pools.iter().all(|pool| pool.protocol_system == "uniswap_v3"));
+        assert_eq!(book.protocols, vec!["uniswap_v3".to_string()]);
+        assert_eq!(book.dominant_protocol(), Some("uniswap_v3".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_routes_cached_across_builds() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let components = pools.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &components));
+        let shared = Arc::new(RwLock::new(state));
+        let routes = std::sync::Mutex::new(RouteCache::default());
+        let network = Network {
+            eth: WETH.to_string(),
+            ..Default::default()
+        };
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let tokens = [fixtures::weth(), fixtures::usdc()];
+        snapshot(&shared, &routes, &tokens, &network, &params).await.unwrap();
+        let stats = |cache: &RouteCache| (cache.hits, cache.misses);
+        assert_eq!(stats(&routes.lock().unwrap()), (0, 2));
+        snapshot(&shared, &routes, &tokens, &network, &params).await.unwrap();
+        assert_eq!(stats(&routes.lock().unwrap()), (2, 2));
+        // A state update alone keeps the routes, a new pair invalidates them
+        shared.write().await.apply(&fixtures::update(2, &pools, &[]));
+        snapshot(&shared, &routes, &tokens, &network, &params).await.unwrap();
+        assert_eq!(routes.lock().unwrap().misses, 2);
+        let added = vec![fixtures::pool("0x02", 500., 1_000_000.)];
+        shared.write().await.apply(&fixtures::update(3, &added, &[added[0].component.clone()]));
+        snapshot(&shared, &routes, &tokens, &network, &params).await.unwrap();
+        assert_eq!(routes.lock().unwrap().misses, 4);
+    }
+
+    #[tokio::test]
+    async fn test_pair_without_eth_route() {
+        let token = |address: &str, symbol: &str, decimals: usize| SrzToken {
+            address: address.to_string(),
+            decimals,
+            symbol: symbol.to_string(),
+            gas: "0".to_string(),
+        };
+        let (foo, bar) = (token("0x1111111111111111111111111111111111111111", "FOO", 6), token("0x2222222222222222222222222222222222222222", "BAR", 18));
+        let network = Network {
+            eth: WETH.to_string(),
+            stable: USDC.to_string(),
+            ..Default::default()
+        };
+        let tokens = [foo.clone(), bar.clone(), fixtures::weth(), fixtures::usdc()];
+        let oracle = FixedOracle(2_000.);
+        // FOO/BAR is isolated from WETH and USDC
+        let mut exotic = fixtures::pool("0x01", 1_000., 2_000_000.);
+        exotic.component.tokens = vec![foo.clone(), bar.clone()];
+        let pools = vec![exotic, fixtures::pool("0x02", 1_000., 2_000_000.)];
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &pools.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>()));
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", foo.address, bar.address),
+            ..Default::default()
+        };
+        // A dry-run quote can't price the gas in the output token without its worth
+        let provider = OrderbookProvider::from_snapshot(state.to_snapshot(&network, &tokens), None, DefaultOrderbookSolver::default()).await.unwrap();
+        let error = provider.quote(&oracle, &params.tag, &foo.address, 1.).await.err().and_then(|e| e.downcast::<OrderbookError>().ok());
+        assert_eq!(error, Some(OrderbookError::EthQuoteFailed));
+        let pair = snapshot(&Arc::new(RwLock::new(state)), &std::sync::Mutex::new(RouteCache::default()), &tokens, &network, &params).await.unwrap();
+        assert_eq!((pair.base_worth, pair.quote_worth), (UnitWorth::Unknown, UnitWorth::Unknown));
+        assert_eq!((pair.base_ref.clone(), pair.quote_ref.clone()), (None, None));
+        let (base_worth_eth, quote_worth_eth, valued) = pair.worths_eth(&oracle, &network).await.unwrap();
+        assert_eq!((base_worth_eth, quote_worth_eth, valued), (1., 1., false));
+        let balances = HashMap::from([("0x01".to_string(), HashMap::from([(foo.address.clone(), 2_000_000_000_000u128), (bar.address.clone(), 1_000 * 10u128.pow(18))]))]);
+        let context = MarketContext { block: 1, eth_usd: 2_000., gas_price: 1 };
+        let mut book = book::assemble(DefaultOrderbookSolver::default(), network.clone(), context, pair.pts, pair.targets, params, &balances, base_worth_eth, quote_worth_eth).await.unwrap();
+        book.unvalued();
+        assert!(!book.bids.is_empty() && !book.asks.is_empty());
+        assert!(book.usd_unavailable);
+        assert_eq!(book.aggregated_balance_base_worth_usd, 0.);
+        // FOO/USDC without any WETH pool: valued in USD through the anchor stable, then converted with the oracle
+        let mut stable = fixtures::pool("0x03", 1_000., 2_000_000.);
+        stable.component.tokens = vec![foo.clone(), fixtures::usdc()];
+        let mut state = self::state();
+        state.initialise(&fixtures::update(1, &[stable.clone()], &[stable.component.clone()]));
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", foo.address, USDC),
+            ..Default::default()
+        };
+        let pair = snapshot(&Arc::new(RwLock::new(state)), &std::sync::Mutex::new(RouteCache::default()), &tokens, &network, &params).await.unwrap();
+        assert!(matches!(pair.base_worth, UnitWorth::Usd(worth) if worth > 0.));
+        assert_eq!(pair.base_ref, Some(USDC.to_string()));
+        assert_eq!(pair.quote_worth, UnitWorth::Usd(1.));
+        let (_, quote_worth_eth, valued) = pair.worths_eth(&oracle, &network).await.unwrap();
+        assert!(valued);
+        assert_eq!(quote_worth_eth, 1. / 2_000.);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_pools_reported() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        // Matches the pair, but no exchange was registered for its protocol, so the stream never gives it a state
+        let orphan = fixtures::component("0x02", "uniswap_v3", vec![fixtures::usdc(), fixtures::weth()], 5);
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone(), orphan]));
+        let network = Network {
+            eth: WETH.to_string(),
+            ..Default::default()
+        };
+        let params = OrderbookRequestParams {
+            tag: format!("{}-{}", WETH, USDC),
+            ..Default::default()
+        };
+        let pair = snapshot(&Arc::new(RwLock::new(state)), &std::sync::Mutex::new(RouteCache::default()), &[fixtures::weth(), fixtures::usdc()], &network, &params).await.unwrap();
+        assert_eq!(pair.pts.len(), 1);
+        assert_eq!(pair.unsupported, vec!["0x02".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_orderbook_errors() {
+        let pools = vec![fixtures::pool("0x01", 1_000., 2_000_000.)];
+        let network = Network {
+            eth: WETH.to_string(),
+            chainlink: crate::utils::r#static::filter::NULL_ADDRESS.to_string(),
+            ..Default::default()
+        };
+        let tokens = [fixtures::weth(), fixtures::usdc()];
+        let params = |tag: String| OrderbookRequestParams { tag, ..Default::default() };
+        let tag = format!("{}-{}", WETH, USDC);
+        let uninitialised = Arc::new(RwLock::new(state()));
+        let error = snapshot(&uninitialised, &std::sync::Mutex::new(RouteCache::default()), &tokens, &network, &params(tag.clone())).await.err();
+        assert_eq!(error, Some(OrderbookError::StreamNotInitialised));
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &pools, &[pools[0].component.clone()]));
+        let shared = Arc::new(RwLock::new(state));
+        let routes = std::sync::Mutex::new(RouteCache::default());
+        let error = snapshot(&shared, &routes, &tokens, &network, &params(WETH.to_string())).await.err();
+        assert_eq!(error, Some(OrderbookError::InvalidPair));
+        let dai = DAI;
+        let error = snapshot(&shared, &routes, &tokens, &network, &params(format!("{}-{}", WETH, dai))).await.err();
+        assert_eq!(error, Some(OrderbookError::TokenNotFound(dai.to_string())));
+        let with_dai = [
+            fixtures::weth(),
+            fixtures::usdc(),
+            fixtures::dai(),
+        ];
+        let error = snapshot(&shared, &routes, &with_dai, &network, &params(format!("{}-{}", WETH, dai))).await.err();
+        assert_eq!(error, Some(OrderbookError::NoComponents));
+        // Still matchable once converted into anyhow::Error
+        let error: anyhow::Error = error.unwrap().into();
+        assert!(matches!(error.downcast_ref::<OrderbookError>(), Some(OrderbookError::NoComponents)));
+        // USD valued worths need the oracle, which has no feed here
+        let mut pair = snapshot(&shared, &routes, &tokens, &network, &params(tag)).await.unwrap();
+        pair.quote_worth = UnitWorth::Usd(1.);
+        assert_eq!(pair.worths_eth(&crate::core::oracle::ChainlinkOracle, &network).await.err(), Some(OrderbookError::EthQuoteFailed));
+    }
+
+    #[test]
+    fn test_unknown_protocol_type_skipped() {
+        let mut unknown = fixtures::pool("0x02", 500., 1_000_000.);
+        unknown.component = fixtures::component("0x02", "mystery", vec![fixtures::usdc(), fixtures::weth()], 30);
+        let all = vec![fixtures::pool("0x01", 1_000., 2_000_000.), unknown];
+        let components = all.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let mut state = state();
+        state.initialise(&fixtures::update(1, &all, &components));
+        assert_eq!((state.components.len(), state.protosims.len()), (1, 1));
+        let later = fixtures::component("0x03", "mystery", vec![fixtures::usdc(), fixtures::weth()], 30);
+        state.apply(&fixtures::update(2, &[], &[later]));
+        assert_eq!(state.components.len(), 1);
+        assert_eq!(state.pairs().len(), 1);
+        // States of the skipped component, or of a component never announced, don't leave orphan protosims
+        let mut stray = fixtures::pool("0x04", 10., 20_000.);
+        stray.component.protocol_system = "mystery".to_string();
+        let updated = state.apply(&fixtures::update(3, &[all[1].clone(), stray], &[]));
+        assert!(updated.is_empty());
+        assert_eq!(state.protosims.len(), 1);
+        assert!(!state.protosims.contains_key("0x02") && !state.protosims.contains_key("0x04"));
+    }
+
+    #[test]
+    fn test_token_whitelist() {
+        let dai = fixtures::dai();
+        let p1 = fixtures::pool("0x01", 1_000., 2_000_000.);
+        let p2 = ProtoSimComp {
+            component: fixtures::component("0x02", "uniswap_v2", vec![fixtures::weth(), dai.clone()], 30),
+            protosim: p1.protosim.clone(),
+        };
+        let all = vec![p1, p2];
+        let components = all.iter().map(|pt| pt.component.clone()).collect::<Vec<_>>();
+        let mut state = state();
+        state.whitelist = vec![WETH.to_string(), USDC.to_string()];
+        state.initialise(&fixtures::update(1, &all, &components));
+        assert_eq!(state.components.len(), 1);
+        assert!(state.components.contains_key(&all[0].component.key()));
+        assert_eq!(state.protosims.len(), 1);
+        let p3 = fixtures::component("0x03", "uniswap_v2", vec![dai, fixtures::usdc()], 30);
+        state.apply(&fixtures::update(2, &[], &[p3]));
+        assert_eq!(state.components.len(), 1);
+        // A state streamed for a non-whitelisted component leaves no orphan protosim
+        let updated = state.apply(&fixtures::update(3, &[all[1].clone()], &[]));
+        assert!(updated.is_empty());
+        assert_eq!(state.protosims.len(), 1);
+        assert!(!state.protosims.contains_key(&all[1].component.key()) && !state.protosims.contains_key("0x02"));
+    }
 }
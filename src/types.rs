@@ -1,4 +1,7 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
 
 use alloy::rpc::types::TransactionRequest;
 use alloy_primitives::TxKind;
@@ -6,9 +9,13 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 use utoipa::ToSchema;
 
-use super::data::fmt::{SrzProtocolComponent, SrzToken};
+use super::data::fmt::{SrzProtocolComponent, SrzToken, SrzUniswapV2State};
+use super::utils::r#static::execution;
 use tycho_simulation::evm::decoder::StreamDecodeError;
-use tycho_simulation::protocol::{models::ProtocolComponent, state::ProtocolSim};
+use tycho_simulation::protocol::{
+    models::{BlockUpdate, ProtocolComponent},
+    state::ProtocolSim,
+};
 
 pub type SharedTychoStreamState = Arc<RwLock<TychoStreamState>>;
 
@@ -41,6 +48,11 @@ pub struct Network {
     pub chainid: u64,
     #[schema(example = "0x")]
     pub eth: String,
+    /// Address of the stable quoted against `eth` to anchor the USD valuations (e.g. USDC), no anchor if empty
+    /// Configured like `eth` rather than looked up by symbol, as any token can claim a symbol
+    #[serde(default)]
+    #[schema(example = "0x")]
+    pub stable: String,
     #[schema(example = "https://rpc.payload.de")]
     pub rpc: String,
     #[schema(example = "https://etherscan.io/")]
@@ -57,19 +69,122 @@ pub struct Network {
     pub chainlink: String,
     #[schema(example = "12000")]
     pub block_time_ms: u64,
+    /// Maximum number of components accepted into the shared state at the initial sync, unlimited if None
+    /// The components kept are the most liquid ones, by TVL fetched from Tycho (as stream messages don't carry it): the others join on their next update
+    #[serde(default)]
+    #[schema(example = "5000")]
+    pub max_components: Option<usize>,
+    /// Protocols registered on the stream for this network (see Network::protocols)
+    #[serde(default)]
+    pub supported_protocols: Vec<TychoSupportedProtocol>,
+    /// Priority fee (in wei) of the execution transactions, execution::DEFAULT_PRIORITY_FEE_WEI if None (see Network::fees)
+    #[serde(default)]
+    #[schema(example = "1000000000")]
+    pub priority_fee_wei: Option<u128>,
+    /// Max fee per gas (in wei) used as is instead of base fee + priority fee, for chains with a different fee model
+    #[serde(default)]
+    pub max_fee_per_gas_wei: Option<u128>,
+    /// Token addresses the components must only hold to be kept in the shared state, all components if empty (see OrderbookBuilder::with_token_whitelist)
+    #[serde(default)]
+    pub token_whitelist: Vec<String>,
+    /// Token addresses the pairs are valued through, in priority order (e.g. [WETH, USDC, WBTC]). If empty, ETH then the anchor stable (see Network::valuation_refs)
+    #[serde(default)]
+    pub valuation_refs: Vec<String>,
+    /// Number of blocks a block update waits for before being applied to the shared state, so that short reorgs of the tip are not served (see TychoStreamState::confirm). 0 to apply them at once
+    #[serde(default)]
+    #[schema(example = "0")]
+    pub confirmations: u64,
+}
+
+impl Network {
+    /// (max_fee_per_gas, max_priority_fee_per_gas) of the execution transactions, given the base fee (in wei) of the block
+    /// The priority fee is capped at a configured max fee per gas, as EIP-1559 rejects a priority fee above the max fee
+    pub fn fees(&self, base_fee: u128) -> (u128, u128) {
+        let priority_fee = self.priority_fee_wei.unwrap_or(execution::DEFAULT_PRIORITY_FEE_WEI);
+        match self.max_fee_per_gas_wei {
+            Some(max_fee) => (max_fee, priority_fee.min(max_fee)),
+            None => (base_fee + priority_fee, priority_fee),
+        }
+    }
+
+    /// Lowercased address of the anchor stable, if configured
+    pub fn stable(&self) -> Option<String> {
+        (!self.stable.is_empty()).then(|| self.stable.to_lowercase())
+    }
+
+    /// Lowercased references to value the tokens through, in priority order: the configured ones, or if none, ETH then the anchor stable
+    pub fn valuation_refs(&self) -> Vec<String> {
+        if !self.valuation_refs.is_empty() {
+            return self.valuation_refs.iter().map(|r| r.to_lowercase()).collect();
+        }
+        std::iter::once(self.eth.to_lowercase()).chain(self.stable()).collect()
+    }
+
+    /// Protocols to register on the stream: the configured ones, or if none, the Uniswap ones. Only those deployed on the network are kept (see TychoSupportedProtocol::deployed)
+    pub fn protocols(&self) -> Vec<TychoSupportedProtocol> {
+        if !self.supported_protocols.is_empty() {
+            let (deployed, missing): (Vec<TychoSupportedProtocol>, Vec<TychoSupportedProtocol>) = self.supported_protocols.iter().partition(|protocol| protocol.deployed(&self.name));
+            if !missing.is_empty() {
+                tracing::warn!("Protocols {:?} are not deployed on {}, not registered", missing, self.name);
+            }
+            return deployed;
+        }
+        let mut protocols = vec![TychoSupportedProtocol::UniswapV2, TychoSupportedProtocol::UniswapV3, TychoSupportedProtocol::UniswapV4];
+        if TychoSupportedProtocol::EkuboV2.deployed(&self.name) {
+            protocols.push(TychoSupportedProtocol::EkuboV2);
+        }
+        protocols
+    }
+
+    /// Checks that the required fields are set, that the address fields are valid addresses, and that a max fee per gas is not below the priority fee
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() {
+            return Err("Network name is empty".to_string());
+        }
+        if self.chainid == 0 {
+            return Err(format!("Network {}: chainid is missing", self.name));
+        }
+        for (field, value) in [("rpc", &self.rpc), ("tycho", &self.tycho)] {
+            if value.is_empty() {
+                return Err(format!("Network {}: '{}' is empty", self.name, field));
+            }
+        }
+        for (field, value) in [("eth", &self.eth), ("router", &self.router), ("permit2", &self.permit2), ("chainlink", &self.chainlink)] {
+            if value.parse::<alloy::primitives::Address>().is_err() {
+                return Err(format!("Network {}: '{}' is not a valid address: '{}'", self.name, field, value));
+            }
+        }
+        if let (Some(max_fee), Some(priority_fee)) = (self.max_fee_per_gas_wei, self.priority_fee_wei) {
+            if max_fee < priority_fee {
+                return Err(format!("Network {}: max_fee_per_gas_wei ({}) is below priority_fee_wei ({})", self.name, max_fee, priority_fee));
+            }
+        }
+        if !self.stable.is_empty() && self.stable.parse::<alloy::primitives::Address>().is_err() {
+            return Err(format!("Network {}: 'stable' is not a valid address: '{}'", self.name, self.stable));
+        }
+        Ok(())
+    }
 }
 
 /// Tycho protocol, used to configure ProtocolStreamBuilder
+/// Serialized as its Tycho protocol system (see to_string), e.g. 'vm:curve' in a networks config. The former snake_case names are still accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
 pub enum TychoSupportedProtocol {
     PancakeswapV2,
     PancakeswapV3,
+    #[serde(rename = "sushiswap_v2", alias = "sushiswap")]
     Sushiswap,
     UniswapV2,
     UniswapV3,
     UniswapV4,
     EkuboV2,
+    #[serde(rename = "vm:balancer_v2", alias = "balancer_v2")]
     BalancerV2,
+    #[serde(rename = "vm:curve", alias = "curve")]
     Curve,
+    #[serde(rename = "vm:maverick_v2", alias = "maverick_v2")]
+    MaverickV2,
 }
 
 #[allow(clippy::to_string_trait_impl)]
@@ -85,6 +200,7 @@ impl ToString for TychoSupportedProtocol {
             TychoSupportedProtocol::EkuboV2 => "ekubo_v2".to_string(),
             TychoSupportedProtocol::BalancerV2 => "vm:balancer_v2".to_string(),
             TychoSupportedProtocol::Curve => "vm:curve".to_string(),
+            TychoSupportedProtocol::MaverickV2 => "vm:maverick_v2".to_string(),
         }
     }
 }
@@ -102,8 +218,18 @@ impl TychoSupportedProtocol {
             TychoSupportedProtocol::EkuboV2.to_string(),
             TychoSupportedProtocol::BalancerV2.to_string(),
             TychoSupportedProtocol::Curve.to_string(),
+            TychoSupportedProtocol::MaverickV2.to_string(),
         ]
     }
+
+    /// Whether the protocol is deployed (and indexed by Tycho) on the given network
+    /// Protocols not listed here are registered on every network
+    pub fn deployed(&self, network: &str) -> bool {
+        match self {
+            TychoSupportedProtocol::EkuboV2 | TychoSupportedProtocol::MaverickV2 => matches!(network, "ethereum"),
+            _ => true,
+        }
+    }
 }
 
 /// Tycho Protocol type name, used to add exchanges
@@ -117,6 +243,16 @@ pub enum AmmType {
     EkuboV2,
     Balancer,
     Curve,
+    MaverickV2,
+    /// Protocol type not handled by the SDK yet (e.g. newly added to Tycho), with its name
+    Unknown(String),
+}
+
+impl AmmType {
+    /// Whether the protocol type is handled by the SDK
+    pub fn is_known(&self) -> bool {
+        !matches!(self, AmmType::Unknown(_))
+    }
 }
 
 #[allow(clippy::to_string_trait_impl)]
@@ -132,6 +268,8 @@ impl ToString for AmmType {
             AmmType::EkuboV2 => "ekubo_v2_pool".to_string(),
             AmmType::Balancer => "balancer_v2_pool".to_string(),
             AmmType::Curve => "curve_pool".to_string(), // ?
+            AmmType::MaverickV2 => "maverick_v2_pool".to_string(),
+            AmmType::Unknown(name) => name.clone(),
         }
     }
 }
@@ -148,7 +286,8 @@ impl From<&str> for AmmType {
             "balancer_v2_pool" => AmmType::Balancer,
             "curve_pool" => AmmType::Curve,      // ?
             "ekubo_v2_pool" => AmmType::EkuboV2, // ?
-            _ => panic!("Unknown AMM type"),
+            "maverick_v2_pool" => AmmType::MaverickV2,
+            _ => AmmType::Unknown(s.to_string()),
         }
     }
 }
@@ -163,17 +302,52 @@ pub struct ExecTxResult {
     pub error: Option<String>,
 }
 
+/// Simulation (eth_simulateV1, or mined on a local fork) of one transaction of a payload
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+pub struct SimulatedTx {
+    pub gas_used: u64,
+    pub success: bool,
+    /// Output of the swap (raw amount of the output token), only set on the swap transaction, when it succeeds and its output can be read
+    #[serde(default)]
+    pub output: Option<u128>,
+}
+
 #[derive(Default, Debug, Clone)]
 pub struct ExecutedPayload {
     pub approve: ExecTxResult,
     pub swap: ExecTxResult,
+    /// Simulation of each transaction, in order (approval if any, then swap). Only set for simulate-only requests (see ExecutionRequest::simulate_only)
+    pub simulation: Vec<SimulatedTx>,
 }
 
 /// Result of the execution
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct PayloadToExecute {
-    pub approve: TransactionRequest,
+    /// Permit2 approval of the input token, None if the input is the native token (sent as the swap value)
+    #[serde(default)]
+    pub approve: Option<TransactionRequest>,
     pub swap: TransactionRequest,
+    /// Output token of the swap (address), whose balance delta is the output of a swap mined on a fork (see exec::simulate_fork). Empty if unknown
+    #[serde(default)]
+    pub output: String,
+}
+
+impl PayloadToExecute {
+    /// Transactions to send in order: the approval if any, then the swap
+    pub fn calls(&self) -> Vec<TransactionRequest> {
+        self.approve.iter().cloned().chain(std::iter::once(self.swap.clone())).collect()
+    }
+}
+
+/// Slippage of an execution: the output expected from the orderbook vs the output of the simulated (or executed) swap
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct SlippageReport {
+    /// Expected output (output token, human-readable), see Orderbook::expected_output
+    pub expected: f64,
+    /// Realized output (output token, human-readable)
+    pub realized: f64,
+    /// Slippage in bps, positive when the realized output is below the expected one
+    pub slippage_bps: f64,
 }
 
 /// Execution request, used to simulate a trade
@@ -187,6 +361,22 @@ pub struct ExecutionRequest {
     pub expected: f64,
     pub distribution: Vec<f64>, // Percentage distribution per pool (0–100)
     pub components: Vec<SrzProtocolComponent>,
+    /// Slippage tolerated on the expected amount, in bps (0–5000). Default slippage if None (see execution::EXEC_DEFAULT_SLIPPAGE)
+    #[serde(default)]
+    pub slippage_bps: Option<u32>,
+    /// Make the swap revert on-chain if its output is below the expected amount minus the slippage. True by default, false sends the swap without minimum output
+    #[serde(default = "enforced")]
+    pub enforce_min_out: bool,
+    /// Only simulate the transactions (see exec::execute), never broadcasting them even with a private key, e.g. to preview a trade
+    #[serde(default)]
+    pub simulate_only: bool,
+    /// Simulate on a local fork of the network (anvil) rather than through the RPC eth_simulateV1, also before a broadcast, see exec::backend
+    #[serde(default)]
+    pub fork: bool,
+}
+
+fn enforced() -> bool {
+    true
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -304,20 +494,229 @@ pub fn chain(name: String) -> Option<(ChainCommon, ChainSimCore, ChainSimu)> {
 pub enum OrderbookEvent {
     /// Event when the stream is initialised = connected to Tycho
     Initialised(u64),
-    /// Emited when a new header is received, with components ID that have changed
+    /// Emited when a new header is received, with the keys of the components that have changed
     NewHeader(u64, Vec<String>),
+    /// Emited when components are removed by a block update (e.g. on a reorg), so that clients can invalidate their cached orderbooks
+    Reorg(u64),
+    /// Emited when tokens newly listed by Tycho are added to the provider (see OrderbookProvider::refresh_tokens_every), with the number of tokens added. Their pools are not streamed until the provider is rebuilt
+    TokensUpdated(usize),
     /// Stream Error
     Error(StreamDecodeError),
+    /// Emited for subscribed pairs (see OrderbookProvider::subscribe) when their depth changed, with the (price, size) levels that changed since the previous depth. Removed levels have a size of 0.
+    DepthUpdate {
+        tag: String,
+        block: u64,
+        bid_changes: Vec<(f64, f64)>,
+        ask_changes: Vec<(f64, f64)>,
+    },
+    /// Emited for subscribed pairs (see OrderbookProvider::subscribe) with their orderbook, recomputed once per block updating one of their components, whatever the number of subscribers
+    BookUpdate { tag: String, book: Box<Orderbook> },
+    /// Emited when the recomputed orderbook of a pair becomes degraded (crossed, thin or stale), see OrderbookProvider::assess
+    QualityAlert { tag: String, quality: BookQuality },
+}
+
+/// Errors returned when an orderbook can't be built (see OrderbookProvider::get_orderbook)
+/// Convertible into anyhow::Error, from which they can be matched back with 'downcast_ref'
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderbookError {
+    /// Base and quote are the native token and its wrapper (e.g. ETH/WETH), always exchanged 1:1 by (un)wrapping, not through AMMs
+    WrapperPair { base: String, quote: String },
+    /// Tag isn't of the form 'base-quote'
+    InvalidPair,
+    /// One side of the tag is neither a 20-byte hex address nor a known token symbol (see helper::parse_tag)
+    MalformedTag(String),
+    /// No component (with a protosim) matches the pair
+    NoComponents,
+    /// Token address not among the provider tokens
+    TokenNotFound(String),
+    /// ETH price couldn't be fetched from the oracle
+    EthQuoteFailed,
+    /// Stream hasn't received its first block yet
+    StreamNotInitialised,
+    /// Any other failure while building the book (balances, gas price, solver, ...)
+    Build(String),
+    /// Network not managed, or its provider isn't running (see MultiNetworkProvider)
+    UnknownNetwork(String),
+}
+
+impl std::fmt::Display for OrderbookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OrderbookError::WrapperPair { base, quote } => write!(f, "Pair {}-{} is the native token and its wrapper, exchanged 1:1 without orderbook", base, quote),
+            OrderbookError::InvalidPair => write!(f, "Invalid pair"),
+            OrderbookError::MalformedTag(reason) => write!(f, "Malformed tag: {}", reason),
+            OrderbookError::NoComponents => write!(f, "No components found for the given pair"),
+            OrderbookError::TokenNotFound(address) => write!(f, "Token not found: {}", address),
+            OrderbookError::EthQuoteFailed => write!(f, "Failed to fetch the ETH price"),
+            OrderbookError::StreamNotInitialised => write!(f, "Stream not initialised yet"),
+            OrderbookError::Build(msg) => write!(f, "Failed to build the orderbook: {}", msg),
+            OrderbookError::UnknownNetwork(name) => write!(f, "No provider running for network {}", name),
+        }
+    }
+}
+
+impl std::error::Error for OrderbookError {}
+
+/// Health of the provider of one network (see MultiNetworkProvider::health)
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct NetworkHealth {
+    /// Stream task of the provider is running
+    pub running: bool,
+    /// Initialised, and its last block isn't lagging (see TychoStreamState::ready)
+    pub ready: bool,
+    /// Last block received
+    pub block: u64,
+    /// Number of times the provider was restarted after its stream stopped
+    pub restarts: u32,
+}
+
+/// Errors returned when the token list can't be fetched (see client::tokens_with_retry)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokensError {
+    /// Every attempt failed, with the error of the last one
+    Exhausted { attempts: u32, last: String },
+}
+
+impl std::fmt::Display for TokensError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokensError::Exhausted { attempts, last } => write!(f, "Failed to get the tokens after {} attempts: {}", attempts, last),
+        }
+    }
+}
+
+impl std::error::Error for TokensError {}
+
+/// Quality of an orderbook, from the best to the worst
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum BookQuality {
+    /// Best bid is below best ask, with enough liquidity, and recent enough
+    Healthy,
+    /// Aggregated liquidity is below the configured minimum, or one side is empty
+    Thin,
+    /// Best bid is above best ask, the book can't be trusted (e.g. a pool with a broken state)
+    Crossed,
+    /// Book was computed too many blocks ago
+    Stale,
+}
+
+impl BookQuality {
+    /// True if the quality is anything but Healthy
+    pub fn degraded(&self) -> bool {
+        *self != BookQuality::Healthy
+    }
+}
+
+/// Thresholds used to assess the quality of an orderbook
+#[derive(Debug, Clone)]
+pub struct BookQualityConfig {
+    /// Minimum aggregated liquidity (base + quote) worth in USD
+    pub min_depth_usd: f64,
+    /// Maximum number of blocks between the book block and the current one
+    pub max_age_blocks: u64,
+}
+
+impl Default for BookQualityConfig {
+    fn default() -> Self {
+        BookQualityConfig {
+            min_depth_usd: 10_000.,
+            max_age_blocks: 10,
+        }
+    }
 }
 
 /// Tycho Stream Data, stored in a Mutex/Arc for shared access between the SDK stream and the client or API.
+/// Components are indexed by their unique key 'protocol_system:id' (see data::fmt::component_key)
+#[derive(Default)]
 pub struct TychoStreamState {
-    // ProtocolSim instances, indexed by their unique identifier. Impossible to store elsewhere than memory
+    // ProtocolSim instances, indexed by their unique key. Impossible to store elsewhere than memory
     pub protosims: HashMap<String, Box<dyn ProtocolSim>>,
-    // Components instances, indexed by their unique identifier. Serialised and stored in Redis
+    // Components instances, indexed by their unique key. Serialised and stored in Redis
     pub components: HashMap<String, ProtocolComponent>,
+    // Unique keys of the components, indexed by component ID (the stream only gives IDs for state updates). Several if protocols share an ID (see TychoStreamState::keys_of)
+    pub keys: HashMap<String, Vec<String>>,
     // Indicates whether the ProtocolStreamBuilder has been initialised (true if first stream has been received and saved)
     pub initialised: bool,
+    // Last block received from the stream
+    pub block: u64,
+    // Block at which each component state was last updated, indexed by component unique key
+    pub updated_at: HashMap<String, u64>,
+    // Keys of the components updated in the last block (= the ones of the last NewHeader event)
+    pub last_updated: Vec<String>,
+    // Components left out at the initial sync (see Network::max_components), indexed by component ID. Admitted into the state on their next update
+    pub deferred: HashMap<String, Vec<ProtocolComponent>>,
+    // Lowercased token addresses the components must only hold to be admitted, all if empty (see Network::token_whitelist)
+    pub whitelist: Vec<String>,
+    // Incremented each time the set of components changes (initial sync, new or removed pairs), to invalidate what is derived from it (see RouteCache)
+    pub version: u64,
+    // Wall-clock time (unix ms) at which the last block was received, 0 before the first one (see TychoStreamState::ready)
+    pub received_at_ms: u64,
+    // Blocks an update waits for before being applied (see Network::confirmations)
+    pub confirmations: u64,
+    // Received block updates not confirmed yet, oldest first (see TychoStreamState::confirm)
+    pub pending: VecDeque<BlockUpdate>,
+}
+
+/// Component balances fetched at one block, reused by the orderbook builds of that block (see book::BalanceCache::fetch)
+/// Entries are indexed by component key (IDs can be shared by protocols), and dropped as soon as a build is made at another block
+#[derive(Default, Debug, Clone)]
+pub struct BalanceCache {
+    pub block: u64,
+    pub entries: HashMap<String, HashMap<String, u128>>,
+    /// Components being fetched by a build, indexed by component key. The receiver is closed once the build recorded them
+    pub pending: HashMap<String, tokio::sync::watch::Receiver<()>>,
+    /// Balances served from the cache
+    pub hits: u64,
+    /// Balances fetched from the source
+    pub misses: u64,
+}
+
+/// Valuation routes of the tokens (see maths::path::routing), indexed by lowercased "token>reference" addresses
+/// Only valid for one version of the component set (see TychoStreamState::version), dropped as soon as it changes
+#[derive(Default, Debug, Clone)]
+pub struct RouteCache {
+    pub version: u64,
+    pub routes: HashMap<String, ValorisationPath>,
+    /// Routes served from the cache
+    pub hits: u64,
+    /// Routes searched
+    pub misses: u64,
+}
+
+/// Shared state dumped to disk, to build orderbooks without a Tycho connection, e.g. for backtesting (see OrderbookProvider::snapshot)
+/// Protosims aren't serializable, only the states that can be rebuilt from their serialized form are stored (Uniswap v2 forks for now)
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub network: Network,
+    pub block: u64,
+    pub tokens: Vec<SrzToken>,
+    pub components: Vec<SrzProtocolComponent>,
+    pub uniswap_v2: Vec<SrzUniswapV2State>,
+}
+
+impl TychoStreamState {
+    /// Keys of the components with the given ID, several if protocols share the ID (e.g. Uniswap v4 pool IDs are bytes, not addresses)
+    pub fn keys_of(&self, id: &str) -> Vec<String> {
+        self.keys.get(&id.to_lowercase()).cloned().unwrap_or_default()
+    }
+
+    /// Returns the unique key of a component given its ID (the first one if protocols share the ID, see keys_of), or the lowercased ID if the component is unknown
+    pub fn key(&self, id: &str) -> String {
+        self.keys_of(id).into_iter().next().unwrap_or(id.to_lowercase())
+    }
+
+    /// Key of the component a streamed state belongs to. The stream only gives IDs for state updates: if protocols share the ID,
+    /// the component whose protosim has the same type is picked, else the last one indexed. The lowercased ID if the component is unknown (its state is then skipped, see TychoStreamState::apply)
+    pub fn state_key(&self, id: &str, protosim: &dyn ProtocolSim) -> String {
+        let keys = self.keys_of(id);
+        if keys.len() > 1 {
+            let same_type = |key: &&String| self.protosims.get(*key).is_some_and(|current| std::any::Any::type_id(current.as_any()) == std::any::Any::type_id(protosim.as_any()));
+            if let Some(key) = keys.iter().find(same_type) {
+                return key.clone();
+            }
+        }
+        keys.last().cloned().unwrap_or(id.to_lowercase())
+    }
 }
 
 /// One component of the Tycho protocol, with his simulation instance
@@ -327,14 +726,32 @@ pub struct ProtoSimComp {
     pub protosim: Box<dyn ProtocolSim>,
 }
 
+/// Worth of one unit of a token, depending on the reference it was valued through (see maths::path::valuation)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnitWorth {
+    Eth(f64),
+    Usd(f64),
+    /// No valid quote to any reference
+    Unknown,
+}
+
 #[derive(Clone, Debug)]
 pub struct ValorisationPath {
     pub token_path: Vec<String>,
     pub comp_path: Vec<String>,
 }
 
+/// Side of the orderbook, from the taker point of view
+/// - Bid = the base token is sold for the quote token (same direction as the 'bids' trades)
+/// - Ask = the quote token is sold for the base token (same direction as the 'asks' trades)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub enum Side {
+    Bid,
+    Ask,
+}
+
 /// Orderbook request params used to build a orderbook for a given pair
-#[derive(Clone, Debug, Deserialize, ToSchema)]
+#[derive(Default, Clone, Debug, Deserialize, ToSchema)]
 pub struct OrderbookRequestParams {
     /// Pair uniq identifier: token0-token1 => base-quote
     /// Example: ETH/USDC
@@ -344,6 +761,26 @@ pub struct OrderbookRequestParams {
     pub tag: String,
     /// Optional single point simulation, used to simulate 1 trade only
     pub point: Option<SinglePointSimulation>,
+    /// If true, the exact-out curves (bids_exact_out, asks_exact_out) are computed along the exact-in ones. Ignored for single point simulations.
+    #[serde(default)]
+    pub both_directions: bool,
+    /// Fee (in bps) to use instead of the reported one, indexed by component ID. Only used by the fee aware metrics (e.g. blended fee), the simulation uses the protocol state fee.
+    /// Useful for pools reporting a fee different from the one applied (e.g. dynamic fee Uniswap v4 hooks)
+    #[serde(default)]
+    pub fee_overrides: HashMap<String, u128>,
+    /// Only keep the components the Tycho execution router can route (see protos::executable), so that the book distributions can be executed
+    #[serde(default)]
+    pub executable_only: bool,
+    /// Only keep the components of these protocol systems (e.g. uniswap_v3), all of them if empty
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    /// If true, each trade carries the IDs of the components its distribution applies to (see TradeResult::component_ids), ready for exec::create
+    #[serde(default)]
+    pub with_components: bool,
+    /// If true, the bids and asks are also given with the steps filtered out or without a trade as zero-output levels with an interpolated price, so that depth charts are gap-free
+    /// They are set apart (see Orderbook::bids_chart), as zero-output levels aren't tradable: bids and asks are unchanged
+    #[serde(default)]
+    pub pad_levels: bool,
 }
 
 /// Orderbook query, but for one point (= 1 trade = 1 amount in)
@@ -353,8 +790,45 @@ pub struct SinglePointSimulation {
     #[schema(example = "0xETH")]
     pub input: String,
     // Divided by input decimals
+    #[serde(default)]
     #[schema(example = "10")]
     pub amount: f64,
+    // Amount as a percentage (0–100) of the aggregated liquidity of the input token, instead of an absolute amount
+    #[serde(default)]
+    #[schema(example = "5")]
+    pub amount_pct_of_liquidity: Option<f64>,
+    // Address of the token 'amount' is expressed in (base or quote), e.g. to sell base worth 2000 quote. Defaults to the input token
+    #[serde(default)]
+    #[schema(example = "0xUSDC")]
+    pub denomination: Option<String>,
+}
+
+impl SinglePointSimulation {
+    /// Absolute amount to simulate, given the aggregated balance of the input token across the pools
+    /// Errors if both amount forms are set, or if the percentage is out of ]0, 100]
+    pub fn resolve(&self, aggregated_balance: f64) -> Result<f64, anyhow::Error> {
+        match self.amount_pct_of_liquidity {
+            Some(_) if self.amount != 0. => Err(anyhow::anyhow!("Only one of amount and amount_pct_of_liquidity can be set")),
+            Some(pct) if pct <= 0. || pct > 100. => Err(anyhow::anyhow!("amount_pct_of_liquidity must be in ]0, 100], got {}", pct)),
+            Some(pct) => Ok(aggregated_balance * pct / 100.),
+            None => Ok(self.amount),
+        }
+    }
+
+    /// Converts a resolved amount into input token units if it's denominated in the other token of the pair, at 'price_input_to_other' (other per input)
+    /// Errors if the denomination isn't one of the pair tokens, or if it's set along a percentage of liquidity (always of the input token)
+    pub fn to_input_units(&self, amount: f64, input: &str, other: &str, price_input_to_other: f64) -> Result<f64, anyhow::Error> {
+        match self.denomination.as_deref() {
+            None => Ok(amount),
+            Some(token) if token.eq_ignore_ascii_case(input) => Ok(amount),
+            Some(_) if self.amount_pct_of_liquidity.is_some() => Err(anyhow::anyhow!("amount_pct_of_liquidity is always relative to the input token, denomination can't be set")),
+            Some(token) if token.eq_ignore_ascii_case(other) => match price_input_to_other > 0. {
+                true => Ok(amount / price_input_to_other),
+                false => Err(anyhow::anyhow!("No price to convert the amount from {} to {}", other, input)),
+            },
+            Some(token) => Err(anyhow::anyhow!("Denomination {} is not a token of the pair", token)),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -383,13 +857,33 @@ pub struct TradeResult {
     #[schema(example = "[0.42, 0.37, 0.77]")]
     pub gas_costs_usd: Vec<f64>,
 
+    // Gas costs in input token human–readable units, depending the pool
+    #[serde(default)]
+    #[schema(example = "[0.00021, 0.000185, 0.000385]")]
+    pub gas_costs_input_token: Vec<f64>,
+
     // output per unit input (human–readable)
     #[schema(example = "0.0005")]
     pub average_sell_price: f64,
 
-    // Price impact of the trade (0–1). In absolute value, bc cannot be positive.
+    // Price impact of the trade (0–1): (spot_price - average_sell_price) / spot_price, net of the split across pools (see maths::opti::price_impact)
     #[schema(example = "0.05")]
     pub price_impact: f64,
+
+    // Number of allocations dropped for being below the solver's minimum allocation, the rest being renormalized
+    #[serde(default)]
+    #[schema(example = "0")]
+    pub pruned: usize,
+
+    // True if the requested amount needed more of the output token than the pools hold, and was reduced to what they can give (partial fill)
+    #[serde(default)]
+    #[schema(example = "false")]
+    pub capped: bool,
+
+    // IDs of the book pools the distribution applies to (same order), to build an ExecutionRequest for this level. Only set if requested (see OrderbookRequestParams::with_components)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(example = "[\"0x88e6...5640\"]")]
+    pub component_ids: Option<Vec<String>>,
 }
 
 /// Orderbook data used to compute spread, and other metrics
@@ -401,6 +895,9 @@ pub struct MidPriceData {
     pub bid: f64,
     // Mid price (ask + bid) / 2
     pub mid: f64,
+    /// Mid price with ask and bid weighted by the USD liquidity backing each of them (shifts toward the deeper side)
+    #[serde(default)]
+    pub weighted_mid: f64,
     // Spread is the difference between the best ask and bid price
     pub spread: f64,
     pub spread_pct: f64,
@@ -412,8 +909,19 @@ pub struct MidPriceData {
     pub distribution: Vec<f64>,
 }
 
+/// Market data shared by every simulation of an orderbook build, fetched once at build start
+#[derive(Default, Debug, Clone)]
+pub struct MarketContext {
+    /// Block of the state snapshot used to build the orderbook
+    pub block: u64,
+    /// Current value of ETH in USD
+    pub eth_usd: f64,
+    /// Gas price in wei
+    pub gas_price: u128,
+}
+
 /// FuLL orderbook data response. Key struct of the SDK
-#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Orderbook {
     pub tag: String,
     /// Block number of the orderbook, state at which the orderbook was built
@@ -432,15 +940,64 @@ pub struct Orderbook {
     pub bids: Vec<TradeResult>,
     /// Array of resulat for the optimal single hop route
     pub asks: Vec<TradeResult>,
+    /// Exact-out trades selling base, one per bid, with the bid output as target (only if requested with 'both_directions')
+    #[serde(default)]
+    pub bids_exact_out: Vec<TradeResult>,
+    /// Exact-out trades selling quote, one per ask, with the ask output as target (only if requested with 'both_directions')
+    #[serde(default)]
+    pub asks_exact_out: Vec<TradeResult>,
+    /// Bids with a zero-output level for each step without a trade, for gap-free depth charts only (only if requested with 'pad_levels', see book::pad_levels)
+    #[serde(default)]
+    pub bids_chart: Vec<TradeResult>,
+    /// Asks with a zero-output level for each step without a trade, for gap-free depth charts only (only if requested with 'pad_levels', see book::pad_levels)
+    #[serde(default)]
+    pub asks_chart: Vec<TradeResult>,
     /// Cumulated liquidity for base, always divided by decimals, combining all pools/components
     pub base_lqdty: Vec<f64>,
     /// Cumulated liquidity for quote, always divided by decimals, combining all pools/components
     pub quote_lqdty: Vec<f64>,
     /// All components used to build the orderbook (= pools that include both token0 and token1)
     pub pools: Vec<SrzProtocolComponent>,
+    /// Fee of each pool in bps, aligned with 'pools' (fee overrides included)
+    #[serde(default)]
+    pub fees_bps: Vec<u128>,
+    /// Fee in bps actually paid by the best bid, the pool fees weighted by its distribution (see Orderbook::blended_fee_bps). 0 without bid
+    #[serde(default)]
+    pub effective_fee_bps: f64,
+    /// Number of bids and asks dropped beyond the maximum price impact of the solver (see solver::truncate_price_impact)
+    /// The exact-out trades and the charts aren't truncated themselves, but they follow the kept bids and asks: exact-out targets are their outputs, and charts stop at the largest one
+    #[serde(default)]
+    pub truncated: usize,
+    /// IDs of the components whose balances couldn't be fetched. If not empty, the liquidity (and so the depth) of the book is partial
+    #[serde(default)]
+    pub missing_balances: Vec<String>,
+    /// IDs of the components matching the pair on a protocol without registered exchange (so without protosim). Their liquidity is omitted from the book
+    #[serde(default)]
+    pub unsupported_pools: Vec<String>,
+    /// Reference token (see Network::valuation_refs) base was valued through, None if unvalued
+    #[serde(default)]
+    pub base_valuation_ref: Option<String>,
+    /// Reference token (see Network::valuation_refs) quote was valued through, None if unvalued
+    #[serde(default)]
+    pub quote_valuation_ref: Option<String>,
+    /// True if base or quote couldn't be valued (no route to ETH nor to the anchor stable): the USD figures and ETH worths are zeroed, only the token amounts are meaningful
+    #[serde(default)]
+    pub usd_unavailable: bool,
     /// Current value of ETH in USD
     pub eth_usd: f64,
-    /// Mid price data for token0 to token1
+    /// True if the ETH price couldn't be refreshed and a cached one was used, so the USD figures may be stale (see CachedOracle)
+    #[serde(default)]
+    pub eth_usd_stale: bool,
+    /// Gas price (in wei) used to simulate the trades
+    #[serde(default)]
+    pub gas_price: u128,
+    /// Volatility per block of the mid price, estimated from the provider's mid history of the pair (see maths::fill::volatility). 0 if unknown
+    #[serde(default)]
+    pub volatility: f64,
+    /// True if the best bid exceeds the best ask (see Orderbook::is_crossed), clients should discard such a book
+    #[serde(default)]
+    pub crossed: bool,
+    /// Mid price data for token0 to token1, from trades of the solver probe size (see book::probe_amount)
     pub mpd_base_to_quote: MidPriceData,
     /// Mid price data for token1 to token0
     pub mpd_quote_to_base: MidPriceData,
@@ -452,17 +1009,100 @@ pub struct Orderbook {
     pub aggregated_balance_base_worth_usd: f64,
     // The TVL value in USD for the quote token
     pub aggregated_balance_quote_worth_usd: f64,
+    /// Currency of the USD figures (eth_usd, gas costs, TVL) if converted to another one (see Orderbook::convert_currency), None for USD
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Protocol systems the book was restricted to (see OrderbookRequestParams::protocols), empty if all
+    #[serde(default)]
+    pub protocols: Vec<String>,
+}
+
+/// One level of a cumulative orderbook, exchange-style: sizes in base, prices in quote per base
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CumulativeLevel {
+    /// Cumulative size of the level, in base (includes all smaller levels)
+    #[schema(example = "10.0")]
+    pub size: f64,
+    /// Cumulative amount of quote exchanged for 'size'
+    #[schema(example = "20000.0")]
+    pub total: f64,
+    /// Average price of the whole size, in quote per base
+    #[schema(example = "2000.0")]
+    pub price: f64,
+    /// Price of the increment from the previous level to this one, in quote per base
+    #[schema(example = "1995.0")]
+    pub marginal_price: f64,
 }
 
+/// Orderbook as cumulative levels, derived from the independent optimized trades of an Orderbook (see Orderbook::cumulative)
+#[derive(Default, Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct CumulativeOrderbook {
+    /// Base of the orderbook
+    pub base: SrzToken,
+    /// Quote of the orderbook
+    pub quote: SrzToken,
+    /// Levels at which base can be sold, by increasing size
+    pub bids: Vec<CumulativeLevel>,
+    /// Levels at which base can be bought, by increasing size
+    pub asks: Vec<CumulativeLevel>,
+}
+
+/// Bucketing of the orderbook depth into fixed price bands relative to the mid price, like an exchange depth chart
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct DepthConfig {
+    /// Width of each price band, in bps of the mid price
+    pub bucket_bps: u32,
+    /// Maximum number of bands per side
+    pub max_levels: usize,
+}
+
+/// Binance GET /api/v3/depth format: { lastUpdateId, bids: [[price, qty]], asks: [[price, qty]] }, prices and quantities being strings as on Binance
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct OrderbookDepth {
     pub last_update_id: u64,
+    #[serde(with = "depth_levels")]
     pub bids: Vec<(f64, f64)>,
+    #[serde(with = "depth_levels")]
     pub asks: Vec<(f64, f64)>,
 }
 
+/// (price, qty) levels serialized as [["price", "qty"]], as Binance does to keep the precision. Numbers are accepted too when deserializing
+mod depth_levels {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Text(String),
+        Number(f64),
+    }
+
+    pub fn serialize<S: Serializer>(levels: &[(f64, f64)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(levels.iter().map(|(price, qty)| [price.to_string(), qty.to_string()]))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(f64, f64)>, D::Error> {
+        let parse = |value: Value| match value {
+            Value::Text(text) => text.parse::<f64>().map_err(serde::de::Error::custom),
+            Value::Number(number) => Ok(number),
+        };
+        Vec::<(Value, Value)>::deserialize(deserializer)?.into_iter().map(|(price, qty)| Ok((parse(price)?, parse(qty)?))).collect()
+    }
+}
+
+/// Binance error payload: { code, msg }
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AdapterError {
+    pub code: i64,
+    pub msg: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExchangeInfo {
+    /// Pair tag 'base-quote', with addresses
+    #[serde(default)]
+    pub symbol: String,
     pub timezone: String,
     pub base: SrzToken,
     pub quote: SrzToken,
@@ -482,3 +1122,56 @@ pub struct CoinGeckoResponse {
 pub struct CryptoPrice {
     pub usd: f64,
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_ekubo_deployment() {
+        assert!(TychoSupportedProtocol::EkuboV2.deployed("ethereum"));
+        assert!(!TychoSupportedProtocol::EkuboV2.deployed("base"));
+        assert!(!TychoSupportedProtocol::EkuboV2.deployed("unichain"));
+        assert_eq!(TychoSupportedProtocol::EkuboV2.to_string(), "ekubo_v2");
+        assert!(TychoSupportedProtocol::vectorize().contains(&"ekubo_v2".to_string()));
+    }
+
+    #[test]
+    fn test_l2_priority_fee() {
+        let networks = crate::utils::r#static::networks();
+        let fees = |name: &str| networks.iter().find(|n| n.name == name).unwrap().fees(10_000_000);
+        let (ethereum, base) = (fees("ethereum"), fees("base"));
+        assert_eq!(ethereum, (1_010_000_000, 1_000_000_000));
+        assert!(base.1 * 100 <= ethereum.1);
+        let fixed = Network {
+            max_fee_per_gas_wei: Some(42),
+            ..Default::default()
+        };
+        // Priority fee capped at the max fee
+        assert_eq!(fixed.fees(10_000_000), (42, 42));
+        let fixed = Network {
+            max_fee_per_gas_wei: Some(2 * execution::DEFAULT_PRIORITY_FEE_WEI),
+            ..Default::default()
+        };
+        assert_eq!(fixed.fees(10_000_000), (2 * execution::DEFAULT_PRIORITY_FEE_WEI, execution::DEFAULT_PRIORITY_FEE_WEI));
+    }
+
+    #[test]
+    fn test_unknown_amm_type() {
+        let amm = AmmType::from("mystery_pool");
+        assert!(!amm.is_known());
+        assert_eq!(amm.to_string(), "mystery_pool");
+        assert!(AmmType::from("uniswap_v3_pool").is_known());
+    }
+
+    #[test]
+    fn test_maverick_v2() {
+        let amm = AmmType::from("maverick_v2_pool");
+        assert!(matches!(amm, AmmType::MaverickV2));
+        assert_eq!(AmmType::from(amm.to_string().as_str()).to_string(), "maverick_v2_pool");
+        assert_eq!(TychoSupportedProtocol::MaverickV2.to_string(), "vm:maverick_v2");
+        assert!(TychoSupportedProtocol::vectorize().contains(&"vm:maverick_v2".to_string()));
+        assert!(!TychoSupportedProtocol::MaverickV2.deployed("base"));
+    }
+}
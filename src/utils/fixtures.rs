@@ -0,0 +1,169 @@
+//! Test fixtures: tokens and Uniswap v2 pools built in memory, without any network access.
+//! Shared by the unit tests and the benches, hidden from the documentation.
+
+use std::collections::HashMap;
+
+use alloy::primitives::U256;
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tycho_simulation::evm::protocol::uniswap_v2::state::UniswapV2State;
+use tycho_simulation::evm::tycho_models::Chain;
+use tycho_simulation::protocol::models::BlockUpdate;
+
+use crate::{
+    core::metrics::MetricsSink,
+    data::store::StateStore,
+    data::fmt::{SrzProtocolComponent, SrzToken},
+    types::{Network, ProtoSimComp, TradeResult},
+};
+
+pub static WETH: &str = "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2";
+pub static USDC: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+pub static DAI: &str = "0x6b175474e89094c44da98b954cedeac495271d0f";
+
+pub fn weth() -> SrzToken {
+    SrzToken {
+        address: WETH.to_string(),
+        decimals: 18,
+        symbol: "WETH".to_string(),
+        gas: "0".to_string(),
+    }
+}
+
+pub fn usdc() -> SrzToken {
+    SrzToken {
+        address: USDC.to_string(),
+        decimals: 6,
+        symbol: "USDC".to_string(),
+        gas: "0".to_string(),
+    }
+}
+
+pub fn dai() -> SrzToken {
+    SrzToken {
+        address: DAI.to_string(),
+        decimals: 18,
+        symbol: "DAI".to_string(),
+        gas: "0".to_string(),
+    }
+}
+
+/// Ethereum network with no Tycho nor RPC reachable: the balances must be cached for the block, and the gas price falls back to 0
+pub fn offline_network() -> Network {
+    Network {
+        name: "ethereum".to_string(),
+        eth: WETH.to_string(),
+        tycho: "localhost".to_string(),
+        rpc: "http://127.0.0.1:1".to_string(),
+        ..Default::default()
+    }
+}
+
+/// Serialized component holding the given tokens
+pub fn component(id: &str, protocol_system: &str, tokens: Vec<SrzToken>, fee: u128) -> SrzProtocolComponent {
+    SrzProtocolComponent {
+        address: id.to_lowercase(),
+        id: id.to_lowercase(),
+        tokens,
+        protocol_system: protocol_system.to_string(),
+        protocol_type_name: format!("{}_pool", protocol_system),
+        contract_ids: vec![],
+        static_attributes: vec![],
+        creation_tx: "0x".to_string(),
+        fee,
+        last_updated_at: 0,
+    }
+}
+
+/// Uniswap v2 WETH/USDC pool, with reserves given in human-readable units
+/// USDC address is lower than WETH's, so USDC is token0
+pub fn pool(id: &str, weth_reserve: f64, usdc_reserve: f64) -> ProtoSimComp {
+    let reserve0 = U256::from((usdc_reserve * 1e6) as u128);
+    let reserve1 = U256::from((weth_reserve * 1e18) as u128);
+    ProtoSimComp {
+        component: component(id, "uniswap_v2", vec![usdc(), weth()], 30),
+        protosim: Box::new(UniswapV2State::new(reserve0, reserve1)),
+    }
+}
+
+/// Trade of `amount` for `output`, without gas nor distribution
+pub fn trade(amount: f64, output: f64) -> TradeResult {
+    TradeResult {
+        amount,
+        output,
+        distribution: vec![],
+        distributed: vec![],
+        gas_costs: vec![],
+        gas_costs_usd: vec![],
+        gas_costs_input_token: vec![],
+        average_sell_price: output / amount,
+        price_impact: 0.,
+        pruned: 0,
+        capped: false,
+        component_ids: None,
+    }
+}
+
+/// Stream message for the given block, with the states of 'states' and 'new_pairs' as new components
+pub fn update(block: u64, states: &[ProtoSimComp], new_pairs: &[SrzProtocolComponent]) -> BlockUpdate {
+    BlockUpdate {
+        block_number: block,
+        states: states.iter().map(|pt| (pt.component.id.clone(), pt.protosim.clone())).collect(),
+        new_pairs: new_pairs.iter().map(|cp| (cp.id.clone(), SrzProtocolComponent::original(cp.clone(), Chain::Ethereum))).collect(),
+        removed_pairs: HashMap::new(),
+    }
+}
+
+/// Metrics sink recording the calls it receives
+#[derive(Default, Debug)]
+pub struct CapturingMetricsSink {
+    /// Tag and pool count of each built orderbook
+    pub builds: std::sync::Mutex<Vec<(String, usize)>>,
+    /// Number of optimized steps
+    pub steps: std::sync::Mutex<usize>,
+}
+
+impl MetricsSink for CapturingMetricsSink {
+    fn on_orderbook_built(&self, tag: &str, _duration: std::time::Duration, pools: usize) {
+        self.builds.lock().unwrap().push((tag.to_string(), pools));
+    }
+
+    fn on_gradient_step(&self, _duration: std::time::Duration) {
+        *self.steps.lock().unwrap() += 1;
+    }
+}
+
+/// In-memory store (no expiry), counting the reads that hit and the writes
+#[derive(Default)]
+pub struct CountingStore {
+    pub values: std::sync::Mutex<HashMap<String, String>>,
+    pub hits: std::sync::atomic::AtomicUsize,
+    pub writes: std::sync::atomic::AtomicUsize,
+}
+
+#[async_trait]
+impl StateStore for CountingStore {
+    async fn set<T: Serialize + Sync>(&self, key: &str, value: &T, _ttl_secs: Option<u64>) -> Result<(), anyhow::Error> {
+        self.writes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        self.values.lock().unwrap().insert(key.to_string(), serde_json::to_string(value)?);
+        Ok(())
+    }
+
+    async fn get<T: DeserializeOwned + Send>(&self, key: &str) -> Result<Option<T>, anyhow::Error> {
+        let value = self.values.lock().unwrap().get(key).cloned();
+        if value.is_some() {
+            self.hits.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        Ok(value.map(|value| serde_json::from_str(&value)).transpose()?)
+    }
+
+    async fn del(&self, key: &str) -> Result<(), anyhow::Error> {
+        self.values.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
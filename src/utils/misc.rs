@@ -1,6 +1,8 @@
 use alloy_chains::NamedChain;
 use tycho_simulation::models::Token;
 
+use crate::types::Network;
+
 /// Test logs
 pub fn tlog()  {
     tracing::info!("Tycho log");
@@ -25,6 +27,11 @@ pub fn current_timestamp() -> u64 {
     std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("Time went backwards").as_secs()
 }
 
+/// Returns the current timestamp in milliseconds
+pub fn current_timestamp_ms() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("Time went backwards").as_millis() as u64
+}
+
 /// Get the Alloy chain based on the network name
 pub fn get_alloy_chain(network: String) -> Result<NamedChain, String> {
     match network.as_str() {
@@ -50,4 +57,54 @@ pub fn filter_valid_strings(input: Vec<Token>) -> Vec<Token> {
         s.address.to_string().starts_with("0x")
     })
     .collect()
-}
\ No newline at end of file
+}
+
+/// Reads and validates a networks config file (JSON array of Network, see r#static::networks for the expected fields)
+pub fn read_networks(path: &str) -> Result<Vec<Network>, anyhow::Error> {
+    let content = std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Failed to read networks config {}: {}", path, e))?;
+    parse_networks(&content).map_err(|e| anyhow::anyhow!("Invalid networks config {}: {}", path, e))
+}
+
+/// Parses and validates networks from a JSON array (see Network::validate)
+/// Errors carry the offending field and position (e.g. "missing field `rpc` at line 4 column 5"), instead of panicking
+pub fn parse_networks(content: &str) -> Result<Vec<Network>, anyhow::Error> {
+    let networks: Vec<Network> = serde_json::from_str(content).map_err(|e| anyhow::anyhow!("{} ({:?} error)", e, e.classify()))?;
+    for network in networks.iter() {
+        network.validate().map_err(|e| anyhow::anyhow!(e))?;
+    }
+    Ok(networks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::r#static::networks;
+
+    #[test]
+    fn test_parse_networks() {
+        let content = serde_json::to_string(&networks()).unwrap();
+        assert_eq!(parse_networks(&content).unwrap().len(), networks().len());
+    }
+
+    #[test]
+    fn test_parse_networks_errors() {
+        let truncated = r#"[{"name": "ethereum", "chainid": 1, "eth": "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2""#;
+        let error = parse_networks(truncated).unwrap_err().to_string();
+        assert!(error.contains("line 1"), "{}", error);
+        let missing = r#"[{"name": "ethereum", "chainid": 1}]"#;
+        let error = parse_networks(missing).unwrap_err().to_string();
+        assert!(error.contains("missing field"), "{}", error);
+        let mut network = networks()[0].clone();
+        network.router = "0xnotanaddress".to_string();
+        let error = parse_networks(&serde_json::to_string(&vec![network]).unwrap()).unwrap_err().to_string();
+        assert!(error.contains("'router'"), "{}", error);
+        let mut network = networks()[0].clone();
+        network.stable = "USDC".to_string();
+        let error = parse_networks(&serde_json::to_string(&vec![network]).unwrap()).unwrap_err().to_string();
+        assert!(error.contains("'stable'"), "{}", error);
+        let mut network = networks()[0].clone();
+        network.max_fee_per_gas_wei = Some(1);
+        let error = parse_networks(&serde_json::to_string(&vec![network]).unwrap()).unwrap_err().to_string();
+        assert!(error.contains("max_fee_per_gas_wei"), "{}", error);
+    }
+}
@@ -1,2 +1,4 @@
+#[doc(hidden)]
+pub mod fixtures;
 pub mod misc;
 pub mod r#static;
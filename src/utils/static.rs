@@ -1,4 +1,4 @@
-use crate::types::Network;
+use crate::types::{Network, TychoSupportedProtocol};
 
 /// Static data for the networks
 /// https://docs.propellerheads.xyz/tycho/for-solvers/execution/contract-addresses
@@ -8,6 +8,7 @@ pub fn networks() -> Vec<Network> {
             chainid: 1,
             name: "ethereum".to_string(),
             eth: "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2".to_string(),
+            stable: "0xA0b86991c6218b36c1d19d4a2e9eB0cE3606eB48".to_string(), // USDC
             chainlink: "0x5f4eC3Df9cbd43714FE2740f5E3616155c5b8419".to_string(),
             rpc: "https://ethereum-rpc.publicnode.com".to_string(),
             exp: "https://etherscan.io/".to_string(),
@@ -16,11 +17,30 @@ pub fn networks() -> Vec<Network> {
             router: "0x0178f471f219737c51d6005556d2f44de011a08a".to_string(),
             tag: "🟣".to_string(),
             block_time_ms: 12000,
+            confirmations: 0,
+            max_components: None,
+            priority_fee_wei: Some(1_000_000_000), // 1 Gwei
+            max_fee_per_gas_wei: None,
+            token_whitelist: vec![],
+            valuation_refs: vec![],
+            supported_protocols: vec![
+                TychoSupportedProtocol::UniswapV2,
+                TychoSupportedProtocol::UniswapV3,
+                TychoSupportedProtocol::UniswapV4,
+                TychoSupportedProtocol::Sushiswap,
+                TychoSupportedProtocol::PancakeswapV2,
+                TychoSupportedProtocol::PancakeswapV3,
+                TychoSupportedProtocol::BalancerV2,
+                TychoSupportedProtocol::Curve,
+                TychoSupportedProtocol::EkuboV2,
+                TychoSupportedProtocol::MaverickV2,
+            ],
         },
         Network {
             chainid: 8453,
             name: "base".to_string(),
             eth: "0x4200000000000000000000000000000000000006".to_string(),
+            stable: "0x833589fCD6eDb6E08f4c7C32D4f71b54bdA02913".to_string(), // USDC
             chainlink: "0x71041dddad3595F9CEd3DcCFBe3D1F4b0a16Bb70".to_string(),
             rpc: "https://base.llamarpc.com".to_string(),
             exp: "https://basescan.io/".to_string(),
@@ -29,11 +49,24 @@ pub fn networks() -> Vec<Network> {
             router: "0xC2C23b0199525DE070D126860133dc3badaD2EEb".to_string(),
             tag: "🔵".to_string(),
             block_time_ms: 250,
+            confirmations: 2, // Fast blocks, one-block reorgs of the tip are more frequent
+            max_components: None,
+            priority_fee_wei: Some(1_000_000), // 0.001 Gwei, L2 priority fees are tiny
+            max_fee_per_gas_wei: None,
+            token_whitelist: vec![],
+            valuation_refs: vec![],
+            supported_protocols: vec![
+                TychoSupportedProtocol::UniswapV2,
+                TychoSupportedProtocol::UniswapV3,
+                TychoSupportedProtocol::UniswapV4,
+                TychoSupportedProtocol::PancakeswapV3,
+            ],
         },
         Network {
             chainid: 130,
             name: "unichain".to_string(),
             eth: "0x4200000000000000000000000000000000000006".to_string(),
+            stable: "0x078D782b760474a361dDA0AF3839290b0EF57AD6".to_string(), // USDC
             chainlink: "0x0000000000000000000000000000000000000000".to_string(), // None, using Coingecko instead ?
             rpc: "https://unichain.drpc.org".to_string(),
             exp: "https://uniscan.xyz/".to_string(),
@@ -42,6 +75,13 @@ pub fn networks() -> Vec<Network> {
             router: "0x9bdc3be75440dbe563527cb39bb11cfbd1e21b09".to_string(),
             tag: "🟡".to_string(),
             block_time_ms: 1000,
+            confirmations: 1,
+            max_components: None,
+            priority_fee_wei: Some(1_000_000), // 0.001 Gwei, L2 priority fees are tiny
+            max_fee_per_gas_wei: None,
+            token_whitelist: vec![],
+            valuation_refs: vec![],
+            supported_protocols: vec![TychoSupportedProtocol::UniswapV2, TychoSupportedProtocol::UniswapV3, TychoSupportedProtocol::UniswapV4],
         },
     ]
 }
@@ -56,7 +96,17 @@ pub mod maths {
     pub static MAX_ITERATIONS: u32 = 50; // 50 iteration maximum to optimize allocation
     pub static MIN_CONVERGENCE_THRESHOLD: f64 = 1e-10; // The lower, the less accurate the result, but faster
     pub static FRACTION_REALLOC: u32 = 2;
+    pub static EXACT_OUT_ITERATIONS: u32 = 40; // Bisection steps to find the input of an exact-out trade
+    pub static EXACT_OUT_MAX_EXPANSIONS: u32 = 20; // Doublings of the exact-out input upper bound before giving up
+    pub static EXACT_OUT_TOLERANCE: f64 = 1e-6; // Relative precision of the exact-out input
+    pub static CONVEX_ROUNDS: u32 = 10; // Passes over every pair of pools to equalize their marginal output
+    pub static CONVEX_ITERATIONS: u32 = 40; // Golden-section steps per pairwise split
+    pub static CONVEX_TOLERANCE: f64 = 1e-9; // Relative improvement below which a pairwise split is left unchanged
+    pub static CLIP_ROUNDS: u32 = 8; // Passes moving the output beyond the pools balances to the other pools, see maths::opti::clip
+    pub static CLIP_TOLERANCE: f64 = 1e-6; // Relative margin under the pool output balance a clipped allocation aims at
     pub static BEST_BID_ASK_ETH_BPS: f64 = 100.; // 100/10_000 = 0.01 ETH = ~20$
+    pub static MID_HISTORY_SIZE: usize = 100; // Mid prices kept per pair to estimate its volatility
+    pub static SKEW_NOTIONALS_USD: [f64; 5] = [1_000., 5_000., 10_000., 50_000., 100_000.]; // Sizes at which Orderbook::skew compares the price impact of both sides
 
     pub mod simu {
 
@@ -67,21 +117,50 @@ pub mod maths {
 
         // pub static MIN_EXP_DELTA: f64 = 15.; Instead of hardcoding it, we use a % of the end multiplier
         pub static MIN_EXP_DELTA_PCT: f64 = 0.00005; // To ensure distance between points
+
+        // Allocations below this percentage (0-100) of the amount in aren't worth the gas to execute
+        pub static MIN_ALLOCATION_PCT: f64 = 1.;
+
+        // Trades beyond this price impact (0-1) are unrealistic, the curve is truncated at the first one (see solver::truncate_price_impact)
+        pub static MAX_PRICE_IMPACT: f64 = 0.5;
     }
 }
 
 pub mod filter {
     pub static REMOVE_TVL_THRESHOLD: f64 = 1.; // 50 iteration maximum to optimize allocation
     pub static ADD_TVL_THRESHOLD: f64 = 100.; // 50 iteration maximum to optimize allocation
+    pub static DEFAULT_TVL_RANGE: (f64, f64) = (ADD_TVL_THRESHOLD, ADD_TVL_THRESHOLD); // (remove, add) TVL thresholds of the stream components
     pub static NULL_ADDRESS: &str = "0x0000000000000000000000000000000000000000";
+    pub static NATIVE_ADDRESS: &str = "0xeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeeee"; // Common placeholder for the native token, besides the null address
 }
 
 pub mod execution {
     pub static EXEC_DEFAULT_SLIPPAGE: f64 = 0.0025;
+    pub static EXEC_MAX_SLIPPAGE_BPS: u32 = 5_000;
+    pub static DISTRIBUTION_SUM_TOLERANCE: f64 = 1.; // Percentage points a distribution sum can deviate from 100 before being rejected
     pub static APPROVE_FN_SIGNATURE: &str = "approve(address,uint256)";
     pub static DEFAULT_APPROVE_GAS: u64 = 100_000;
+    pub static DEFAULT_PRIORITY_FEE_WEI: u128 = 1_000_000_000; // 1 Gwei, see Network::priority_fee_wei
+    pub static SWAP_ROUTER_GAS: u64 = 50_000; // Router overhead (transfers, Permit2 checks), paid once per swap transaction
+    pub static SWAP_SPLIT_OVERHEAD_GAS: u64 = 20_000; // Paid for each additional pool of a split
+    pub static SWAP_GAS_SAFETY_MULTIPLIER: f64 = 1.25; // Applied to the estimate to size the gas limit
+}
+
+pub mod adapters {
+    pub static DEPTH_DEFAULT_LIMIT: u64 = 100; // Same default as Binance GET /api/v3/depth
+    pub static DEPTH_MAX_LIMIT: u64 = 5_000; // Same maximum as Binance GET /api/v3/depth
+    pub static INVALID_SYMBOL_CODE: i64 = -1121; // Binance error code for an unknown symbol
+}
+
+pub mod retry {
+    pub static TOKENS_MAX_ATTEMPTS: u32 = 5;
+    pub static TOKENS_RETRY_BASE_DELAY_MS: u64 = 500; // Doubled at each attempt
 }
 
 pub mod endpoints {
     pub static COINGECKO_ETH_USD: &str = "https://api.coingecko.com/api/v3/simple/price?ids=ethereum&vs_currencies=usd";
 }
+
+pub mod multi {
+    pub static EVENTS_CAPACITY: usize = 1_000; // Events of all the networks buffered for the client (see MultiNetworkProvider::events), the newest are dropped when full
+}